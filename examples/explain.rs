@@ -18,8 +18,7 @@ fn process_maildirs(maildirs: impl IntoIterator<Item = Maildir>) -> Result<(), i
     maildirs.into_iter().try_for_each(|mdir| {
         mdir.list_new()
             .chain(mdir.list_cur())
-            .map(|r| r.map(list_mail))
-            .collect::<Result<_, _>>()
+            .try_for_each(|r| r.map(list_mail))
     })
 }
 
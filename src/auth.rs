@@ -0,0 +1,55 @@
+//! Authentication for the servers in this crate.
+//!
+//! The servers ask an [`Authenticator`] to check the credentials a client
+//! logs in with, and to provide the maildir of that user. Implement the
+//! trait to plug in your own user database, or use [`StaticAuthenticator`]
+//! for a fixed set of users.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::path::PathBuf;
+
+use crate::Maildir;
+
+/// Checks credentials and maps users to their maildirs.
+pub trait Authenticator: Send + Sync {
+    /// Returns the maildir of the user if the password is correct, and
+    /// `None` otherwise.
+    fn authenticate(&self, user: &str, password: &str) -> Option<Maildir>;
+}
+
+/// An authenticator with a fixed list of users, passwords and maildirs.
+#[derive(Clone, Default)]
+pub struct StaticAuthenticator {
+    users: HashMap<String, (String, PathBuf)>,
+}
+
+impl StaticAuthenticator {
+    pub fn new() -> StaticAuthenticator {
+        StaticAuthenticator::default()
+    }
+
+    /// Adds a user, replacing any previous user with the same name.
+    pub fn add_user<P: Into<PathBuf>>(&mut self, user: &str, password: &str, maildir: P) {
+        self.users
+            .insert(user.to_string(), (password.to_string(), maildir.into()));
+    }
+}
+
+impl fmt::Debug for StaticAuthenticator {
+    // leaves out the passwords
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("StaticAuthenticator")
+            .field("users", &self.users.keys().collect::<Vec<_>>())
+            .finish()
+    }
+}
+
+impl Authenticator for StaticAuthenticator {
+    fn authenticate(&self, user: &str, password: &str) -> Option<Maildir> {
+        match self.users.get(user) {
+            Some((expected, path)) if expected == password => Some(Maildir::from(path.clone())),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,224 @@
+//! Incremental one-way mirroring of a maildir tree, for backups.
+//!
+//! A [`Backup`] makes a destination maildir mirror a source maildir and its
+//! subfolders. Since the content of a maildir message never changes, a
+//! message is only copied the first time it is seen. When the flags of a
+//! message change, or it moves from `new` to `cur`, only its file name
+//! changes, and the file is renamed on the destination instead of being
+//! copied again. Messages removed from the source are removed from the
+//! destination too, unless they are retained.
+//!
+//! Messages are copied into the `tmp` folder of the destination first and
+//! then renamed into place, so an interrupted run never leaves a partial
+//! message behind, and simply running the backup again resumes it.
+//!
+//! ```no_run
+//! use maildir::backup::Backup;
+//! use maildir::Maildir;
+//!
+//! let source = Maildir::from("/home/me/Mail");
+//! let destination = Maildir::from("/mnt/backup/Mail");
+//! let report = Backup::new(&source, &destination)
+//!     .retain_deleted(true)
+//!     .run()
+//!     .unwrap();
+//! println!("copied {} messages", report.copied);
+//! ```
+
+use std::collections::{BTreeSet, HashMap};
+use std::fs;
+use std::io;
+use std::ops::Deref;
+use std::path::PathBuf;
+
+use crate::{Durability, Maildir};
+
+// The suffix of the temporary files that messages are copied to.
+const PARTIAL_SUFFIX: &str = ".partial";
+
+/// What `Backup::run` did.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct BackupReport {
+    /// The number of messages copied to the destination.
+    pub copied: usize,
+    /// The number of bytes copied to the destination.
+    pub bytes_copied: u64,
+    /// The number of messages renamed on the destination because their
+    /// flags or folder changed on the source.
+    pub renamed: usize,
+    /// The number of messages removed from the destination because they
+    /// are no longer on the source.
+    pub removed: usize,
+    /// The number of messages kept on the destination although they are no
+    /// longer on the source.
+    pub retained: usize,
+    /// The number of messages that were already up to date.
+    pub unchanged: usize,
+}
+
+/// Mirrors a maildir tree to another, see the module documentation.
+#[derive(Debug)]
+pub struct Backup<'a> {
+    source: &'a Maildir,
+    destination: &'a Maildir,
+    retain_deleted: bool,
+    recursive: bool,
+}
+
+impl<'a> Backup<'a> {
+    /// Creates a backup of `source` to `destination`. The destination is
+    /// written with its own durability level and permissions, and is
+    /// created if it doesn't exist. It should not be written to by anything
+    /// else.
+    pub fn new(source: &'a Maildir, destination: &'a Maildir) -> Backup<'a> {
+        Backup {
+            source,
+            destination,
+            retain_deleted: false,
+            recursive: true,
+        }
+    }
+
+    /// Sets whether messages that were removed from the source are kept on
+    /// the destination. Off by default.
+    pub fn retain_deleted(mut self, retain_deleted: bool) -> Backup<'a> {
+        self.retain_deleted = retain_deleted;
+        self
+    }
+
+    /// Sets whether subfolders are mirrored too. On by default.
+    pub fn recursive(mut self, recursive: bool) -> Backup<'a> {
+        self.recursive = recursive;
+        self
+    }
+
+    /// Runs the backup. If this fails part way through, the messages
+    /// handled until then are in place on the destination, and running it
+    /// again picks up where it stopped. Fails without touching the
+    /// destination if the source maildir, or its `cur` or `new` folder,
+    /// can't be read.
+    pub fn run(&self) -> io::Result<BackupReport> {
+        self.source.check_readable()?;
+        let mut report = BackupReport::default();
+        self.mirror(self.source, self.destination, &mut report)?;
+        if self.recursive {
+            // folders that are only on the destination are mirrored from
+            // an empty source, so their messages are removed or retained
+            let mut folders = BTreeSet::new();
+            for maildir in &[self.source, self.destination] {
+                for subdir in maildir.list_subdirs() {
+                    if let Some(name) = subdir?.path().file_name() {
+                        folders.insert(name.to_string_lossy().deref().to_string());
+                    }
+                }
+            }
+            for folder in &folders {
+                let subfolder = |maildir: &Maildir| {
+                    maildir
+                        .subfolder(folder)
+                        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e.to_string()))
+                };
+                let destination = subfolder(self.destination)?;
+                if !self.retain_deleted || self.source.path().join(folder).exists() {
+                    self.mirror(&subfolder(self.source)?, &destination, &mut report)?;
+                } else {
+                    report.retained += destination.count_new() + destination.count_cur();
+                }
+            }
+        }
+        Ok(report)
+    }
+
+    fn mirror(
+        &self,
+        source: &Maildir,
+        destination: &Maildir,
+        report: &mut BackupReport,
+    ) -> io::Result<()> {
+        // a subfolder that is only on the destination has no source to
+        // check, and is mirrored as empty on purpose
+        if source.path().exists() {
+            source.check_readable()?;
+        }
+        destination.create_dirs()?;
+        remove_partial_files(destination)?;
+
+        let mut existing = list(destination)?;
+        for (id, (folder, file_name)) in list(source)? {
+            let dst_dir = destination.path().join(folder);
+            let dst_path = dst_dir.join(&file_name);
+            match existing.remove(&id) {
+                Some((old_folder, old_name)) => {
+                    if old_folder == folder && old_name == file_name {
+                        report.unchanged += 1;
+                        continue;
+                    }
+                    let old_dir = destination.path().join(old_folder);
+                    fs::rename(old_dir.join(old_name), &dst_path)?;
+                    destination.sync_dirs(&[&dst_dir, &old_dir])?;
+                    report.renamed += 1;
+                }
+                None => {
+                    let src_path = source.path().join(folder).join(&file_name);
+                    let mut tmp_name = file_name.clone().into_os_string();
+                    tmp_name.push(PARTIAL_SUFFIX);
+                    let tmp_path = destination.path().join("tmp").join(tmp_name);
+                    report.bytes_copied += fs::copy(&src_path, &tmp_path)?;
+                    {
+                        let file = fs::File::open(&tmp_path)?;
+                        #[cfg(unix)]
+                        destination.apply_file_permissions(&file)?;
+                        if destination.durability() != Durability::None {
+                            file.sync_all()?;
+                        }
+                    }
+                    fs::rename(&tmp_path, &dst_path)?;
+                    destination.sync_dirs(&[&dst_dir])?;
+                    report.copied += 1;
+                }
+            }
+        }
+
+        for (_, (folder, file_name)) in existing {
+            if self.retain_deleted {
+                report.retained += 1;
+            } else {
+                let dir = destination.path().join(folder);
+                fs::remove_file(dir.join(file_name))?;
+                destination.sync_dirs(&[&dir])?;
+                report.removed += 1;
+            }
+        }
+        Ok(())
+    }
+}
+
+// Lists the messages of a maildir, as a map from id to the folder they are
+// in and their file name.
+fn list(maildir: &Maildir) -> io::Result<HashMap<String, (&'static str, PathBuf)>> {
+    let mut messages = HashMap::new();
+    for (folder, entries) in [("new", maildir.list_new()), ("cur", maildir.list_cur())] {
+        for entry in entries {
+            let entry = entry?;
+            if let Some(file_name) = entry.path().file_name() {
+                messages.insert(entry.id().to_string(), (folder, PathBuf::from(file_name)));
+            }
+        }
+    }
+    Ok(messages)
+}
+
+// Removes the temporary files left behind by an interrupted run.
+fn remove_partial_files(maildir: &Maildir) -> io::Result<()> {
+    for entry in fs::read_dir(maildir.path().join("tmp"))? {
+        let entry = entry?;
+        if entry
+            .file_name()
+            .to_string_lossy()
+            .ends_with(PARTIAL_SUFFIX)
+        {
+            fs::remove_file(entry.path())?;
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,139 @@
+//! Delivers a message read from stdin into a maildir, for use as the mail
+//! delivery agent of an MTA or of fetchmail. For example, with Postfix:
+//!
+//! ```text
+//! mailbox_command = /usr/bin/maildir-deliver -f "$SENDER" -d "$RECIPIENT" --received $HOME/Maildir
+//! ```
+//!
+//! The exit status follows `sysexits.h`, which MTAs use to tell temporary
+//! failures, that are retried later, from permanent ones, that bounce.
+
+use std::env;
+use std::io::{self, Read};
+use std::process;
+
+use maildir::delivery::DeliveryHeaders;
+use maildir::{Durability, MaildirBuilder, MaildirError};
+
+// The exit codes of sysexits.h.
+const EX_OK: i32 = 0;
+const EX_USAGE: i32 = 64;
+const EX_DATAERR: i32 = 65;
+const EX_SOFTWARE: i32 = 70;
+const EX_TEMPFAIL: i32 = 75;
+
+const USAGE: &str = "usage: maildir-deliver [options] MAILDIR
+
+Reads a message from stdin and stores it in the new folder of MAILDIR,
+creating the maildir if needed.
+
+options:
+  -f, --from SENDER           add a Return-Path header with the envelope sender
+  -d, --delivered-to ADDRESS  add a Delivered-To header with the recipient
+  -r, --received              add a Received header naming this host
+  -s, --subfolder NAME        deliver to the subfolder NAME, like .Spam
+  -h, --help                  show this help";
+
+#[derive(Debug, Default)]
+struct Options {
+    from: Option<String>,
+    delivered_to: Option<String>,
+    received: bool,
+    subfolder: Option<String>,
+    maildir: String,
+}
+
+// Parses the command line. Returns `None` after printing the help.
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<Option<Options>, String> {
+    let mut options = Options::default();
+    let mut maildir = None;
+    while let Some(arg) = args.next() {
+        let mut value = |name: &str| args.next().ok_or(format!("{} needs a value", name));
+        match arg.as_str() {
+            "-f" | "--from" => options.from = Some(value(&arg)?),
+            "-d" | "--delivered-to" => options.delivered_to = Some(value(&arg)?),
+            "-r" | "--received" => options.received = true,
+            "-s" | "--subfolder" => options.subfolder = Some(value(&arg)?),
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                return Ok(None);
+            }
+            _ if arg.starts_with('-') && arg.len() > 1 => {
+                return Err(format!("unknown option {}", arg))
+            }
+            _ if maildir.is_none() => maildir = Some(arg),
+            _ => return Err(format!("unexpected argument {}", arg)),
+        }
+    }
+    options.maildir = maildir.ok_or("missing MAILDIR")?;
+    Ok(Some(options))
+}
+
+// Maps a failure to store the message to an exit code. Anything that might
+// be fixed by trying again, like a full disk or wrong permissions, is
+// temporary, so the message stays queued.
+fn exit_code(error: &MaildirError) -> i32 {
+    match *error {
+        MaildirError::Io(_) | MaildirError::Time(_) => EX_TEMPFAIL,
+        MaildirError::InvalidFolderName(_) => EX_USAGE,
+        _ => EX_SOFTWARE,
+    }
+}
+
+fn deliver(options: &Options) -> Result<String, (i32, String)> {
+    let mut data = Vec::new();
+    io::stdin()
+        .read_to_end(&mut data)
+        .map_err(|e| (EX_TEMPFAIL, format!("reading the message failed: {}", e)))?;
+    if data.is_empty() {
+        return Err((EX_DATAERR, "the message is empty".to_string()));
+    }
+
+    let mut headers = DeliveryHeaders::new();
+    if let Some(ref from) = options.from {
+        headers = headers.return_path(from);
+    }
+    if let Some(ref recipient) = options.delivered_to {
+        headers = headers.delivered_to(recipient);
+    }
+    if options.received {
+        headers = headers.received(&gethostname::gethostname().to_string_lossy());
+    }
+    let data = headers.prepend_to(&data);
+
+    let failed = |e: MaildirError| (exit_code(&e), e.to_string());
+    let mut maildir = MaildirBuilder::new(&options.maildir)
+        .durability(Durability::FileAndDirectory)
+        .build();
+    maildir
+        .create_dirs()
+        .map_err(|e| failed(MaildirError::Io(e)))?;
+    if let Some(ref name) = options.subfolder {
+        let name = if name.starts_with('.') {
+            name.clone()
+        } else {
+            format!(".{}", name)
+        };
+        maildir.create_subfolder_dirs(&name).map_err(failed)?;
+        maildir = maildir.subfolder(&name).map_err(failed)?;
+    }
+    maildir.store_new(&data).map_err(failed)
+}
+
+fn main() {
+    let options = match parse_args(env::args().skip(1)) {
+        Ok(Some(options)) => options,
+        Ok(None) => process::exit(EX_OK),
+        Err(e) => {
+            eprintln!("maildir-deliver: {}\n\n{}", e, USAGE);
+            process::exit(EX_USAGE);
+        }
+    };
+    match deliver(&options) {
+        Ok(_) => process::exit(EX_OK),
+        Err((code, message)) => {
+            eprintln!("maildir-deliver: {}", message);
+            process::exit(code);
+        }
+    }
+}
@@ -0,0 +1,299 @@
+//! A command-line tool to inspect and change maildirs without breaking the
+//! naming rules of the format, which renaming files by hand easily does.
+//!
+//! Output is tab-separated, one record per line, so it can be processed
+//! with `cut`, `awk` or `sort`. Tabs and line breaks within fields are
+//! replaced with spaces.
+//!
+//! The tool is only built with the `fsck` feature.
+
+use std::env;
+use std::io::{self, Write};
+use std::process;
+
+use mailparse::MailHeaderMap;
+
+use maildir::fsck::{Fsck, Problem};
+use maildir::{MailEntry, Maildir};
+
+const USAGE: &str = "usage: maildir-tool COMMAND [arguments]
+
+commands:
+  ls [-r] MAILDIR                  list messages: mailbox, id, folder, flags,
+                                   size, date, from and subject
+  show MAILDIR ID                  print a message
+  flag add|remove|set MAILDIR ID FLAGS
+                                   change the flags of a message
+  mv MAILDIR ID TARGET             move a message to another maildir
+  cp MAILDIR ID TARGET             copy a message to another maildir
+  rm MAILDIR ID...                 delete messages
+  count MAILDIR                    count the messages in new and cur
+  stats [-r] MAILDIR               show message counts, sizes and dates
+  mkdir MAILDIR [SUBFOLDER]        create a maildir or one of its subfolders
+  fsck [-r] [--repair] MAILDIR     check a maildir for misnamed files
+
+With -r, the subfolders of the maildir are included. Dates are Unix
+timestamps. fsck exits with status 1 if problems remain.";
+
+// A failed command, with the exit status to use.
+struct Failure(i32, String);
+
+impl From<io::Error> for Failure {
+    fn from(e: io::Error) -> Failure {
+        Failure(1, e.to_string())
+    }
+}
+
+impl From<maildir::MaildirError> for Failure {
+    fn from(e: maildir::MaildirError) -> Failure {
+        Failure(1, e.to_string())
+    }
+}
+
+fn usage() -> Failure {
+    Failure(2, USAGE.to_string())
+}
+
+// Splits off the given switches, which may appear anywhere among the
+// arguments.
+fn switches<'a>(
+    args: &'a [String],
+    names: &[&str],
+) -> Result<(Vec<&'a str>, Vec<&'a str>), Failure> {
+    let mut found = Vec::new();
+    let mut rest = Vec::new();
+    for arg in args {
+        if names.contains(&arg.as_str()) {
+            found.push(arg.as_str());
+        } else if arg.starts_with('-') && arg.len() > 1 {
+            return Err(Failure(2, format!("unknown option {}\n\n{}", arg, USAGE)));
+        } else {
+            rest.push(arg.as_str());
+        }
+    }
+    Ok((found, rest))
+}
+
+// Returns the maildir and its subfolders, as pairs of mailbox name and
+// maildir, with "" for the maildir itself.
+fn mailboxes(maildir: Maildir, recursive: bool) -> Result<Vec<(String, Maildir)>, Failure> {
+    let mut result = Vec::new();
+    if recursive {
+        for subdir in maildir.list_subdirs() {
+            let subdir = subdir?;
+            let name = subdir
+                .path()
+                .file_name()
+                .map(|n| n.to_string_lossy().to_string());
+            if let Some(name) = name {
+                result.push((name, subdir));
+            }
+        }
+        result.sort_by(|a, b| a.0.cmp(&b.0));
+    }
+    result.insert(0, (String::new(), maildir));
+    Ok(result)
+}
+
+fn find(maildir: &Maildir, id: &str) -> Result<MailEntry, Failure> {
+    maildir
+        .find(id)
+        .ok_or_else(|| Failure(1, format!("no message with id {}", id)))
+}
+
+// Makes a value safe to print as a field.
+fn field(value: &str) -> String {
+    value
+        .chars()
+        .map(|c| if c.is_control() { ' ' } else { c })
+        .collect()
+}
+
+fn ls(args: &[String], out: &mut impl Write) -> Result<(), Failure> {
+    let (switches, rest) = switches(args, &["-r"])?;
+    let path = match rest[..] {
+        [path] => path,
+        _ => return Err(usage()),
+    };
+    for (mailbox, maildir) in mailboxes(Maildir::from(path), !switches.is_empty())? {
+        for (folder, entries) in [("new", maildir.list_new()), ("cur", maildir.list_cur())] {
+            for entry in entries {
+                let mut entry = entry?;
+                let size = entry.size()?;
+                let date = entry.date().map(|d| d.to_string()).unwrap_or_default();
+                let (from, subject) = match entry.headers() {
+                    Ok(headers) => (
+                        headers.get_first_value("From").unwrap_or_default(),
+                        headers.get_first_value("Subject").unwrap_or_default(),
+                    ),
+                    Err(_) => (String::new(), String::new()),
+                };
+                writeln!(
+                    out,
+                    "{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
+                    field(&mailbox),
+                    field(entry.id()),
+                    folder,
+                    field(entry.flags()),
+                    size,
+                    date,
+                    field(&from),
+                    field(&subject)
+                )?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn flag(args: &[String]) -> Result<(), Failure> {
+    let (operation, path, id, flags) = match args {
+        [operation, path, id, flags] => (operation.as_str(), path, id, flags),
+        _ => return Err(usage()),
+    };
+    let maildir = Maildir::from(path.as_str());
+    let entry = find(&maildir, id)?;
+    let in_new = entry
+        .path()
+        .parent()
+        .is_some_and(|dir| dir.ends_with("new"));
+    match (operation, in_new) {
+        // a message in new has no flags yet, and moves to cur to get some
+        ("add" | "set", true) => maildir.move_new_to_cur_with_flags(id, flags)?,
+        ("remove", true) => maildir.move_new_to_cur(id)?,
+        ("add", false) => maildir.add_flags(id, flags)?,
+        ("remove", false) => maildir.remove_flags(id, flags)?,
+        ("set", false) => maildir.set_flags(id, flags)?,
+        _ => return Err(usage()),
+    }
+    Ok(())
+}
+
+fn stats(args: &[String], out: &mut impl Write) -> Result<(), Failure> {
+    let (switches, rest) = switches(args, &["-r"])?;
+    let maildir = match rest[..] {
+        [path] => Maildir::from(path),
+        _ => return Err(usage()),
+    };
+    let stats = if switches.is_empty() {
+        maildir.stats()?
+    } else {
+        maildir.stats_recursive()?
+    };
+    writeln!(out, "messages\t{}", stats.count())?;
+    writeln!(out, "new\t{}", stats.new)?;
+    writeln!(out, "cur\t{}", stats.cur)?;
+    writeln!(out, "size\t{}", stats.size)?;
+    let date = |d: Option<i64>| d.map(|d| d.to_string()).unwrap_or_default();
+    writeln!(out, "oldest\t{}", date(stats.oldest_delivery))?;
+    writeln!(out, "newest\t{}", date(stats.newest_delivery))?;
+    for (flag, flag_stats) in &stats.flags {
+        writeln!(
+            out,
+            "flag\t{}\t{}\t{}",
+            flag, flag_stats.count, flag_stats.size
+        )?;
+    }
+    Ok(())
+}
+
+fn fsck(args: &[String], out: &mut impl Write) -> Result<(), Failure> {
+    let (switches, rest) = switches(args, &["-r", "--repair"])?;
+    let maildir = match rest[..] {
+        [path] => Maildir::from(path),
+        _ => return Err(usage()),
+    };
+    let report = Fsck::new(&maildir)
+        .recursive(switches.contains(&"-r"))
+        .repair(switches.contains(&"--repair"))
+        .run()?;
+    for issue in &report.issues {
+        let status = if issue.repaired { "repaired" } else { "found" };
+        let description = match issue.problem {
+            Problem::MissingFolder(ref path) => format!("missing-folder\t{}", path.display()),
+            Problem::BadName {
+                ref path,
+                ref fixed,
+            } => format!("bad-name\t{}\t{}", path.display(), fixed.display()),
+            Problem::DuplicateId { ref id, ref paths } => {
+                let paths: Vec<String> = paths.iter().map(|p| p.display().to_string()).collect();
+                format!("duplicate-id\t{}\t{}", field(id), paths.join("\t"))
+            }
+            Problem::StaleTemporaryFile(ref path) => format!("stale-tmp\t{}", path.display()),
+        };
+        writeln!(out, "{}\t{}", status, description)?;
+    }
+    if report.is_clean() {
+        Ok(())
+    } else {
+        Err(Failure(1, "problems remain".to_string()))
+    }
+}
+
+fn run(args: &[String]) -> Result<(), Failure> {
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    let (command, args) = match args.split_first() {
+        Some((command, args)) => (command.as_str(), args),
+        None => return Err(usage()),
+    };
+    match (command, args) {
+        ("-h" | "--help" | "help", _) => {
+            writeln!(out, "{}", USAGE)?;
+            Ok(())
+        }
+        ("ls", _) => ls(args, &mut out),
+        ("show", [path, id]) => {
+            let mut entry = find(&Maildir::from(path.as_str()), id)?;
+            out.write_all(entry.data()?)?;
+            Ok(())
+        }
+        ("flag", _) => flag(args),
+        ("mv" | "cp", [path, id, target]) => {
+            let maildir = Maildir::from(path.as_str());
+            find(&maildir, id)?;
+            let target = Maildir::from(target.as_str());
+            if command == "mv" {
+                maildir.move_to(id, &target)?;
+            } else {
+                maildir.copy_to(id, &target)?;
+            }
+            Ok(())
+        }
+        ("rm", [path, ids @ ..]) if !ids.is_empty() => {
+            let maildir = Maildir::from(path.as_str());
+            for id in ids {
+                find(&maildir, id)?;
+                maildir.delete(id)?;
+            }
+            Ok(())
+        }
+        ("count", [path]) => {
+            let maildir = Maildir::from(path.as_str());
+            writeln!(out, "new\t{}", maildir.count_new())?;
+            writeln!(out, "cur\t{}", maildir.count_cur())?;
+            Ok(())
+        }
+        ("stats", _) => stats(args, &mut out),
+        ("mkdir", [path]) => Ok(Maildir::from(path.as_str()).create_dirs()?),
+        ("mkdir", [path, subfolder]) => {
+            let subfolder = if subfolder.starts_with('.') {
+                subfolder.clone()
+            } else {
+                format!(".{}", subfolder)
+            };
+            Ok(Maildir::from(path.as_str()).create_subfolder_dirs(&subfolder)?)
+        }
+        ("fsck", _) => fsck(args, &mut out),
+        _ => Err(usage()),
+    }
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    if let Err(Failure(code, message)) = run(&args) {
+        let _ = io::stdout().flush();
+        eprintln!("maildir: {}", message);
+        process::exit(code);
+    }
+}
@@ -0,0 +1,124 @@
+//! Compressed message files, compatible with Dovecot's zlib plugin.
+//!
+//! A maildir built with `MaildirBuilder::compression` compresses every
+//! message as it is written, whether it is stored with `Maildir::store_new`,
+//! `Maildir::store_cur_with_flags` or `Maildir::store_new_linked`.
+//! `MailEntry::parsed`, `MailEntry::headers` and `MailEntry::data`
+//! decompress them again, so compressed messages can be used like any
+//! other, and maildirs mixing compressed and uncompressed messages work.
+//!
+//! The `,S=` and `,W=` attributes in the file name still hold the sizes of
+//! the uncompressed message, which is what `MailEntry::size` reports. They
+//! are written even if `MaildirBuilder::size_attributes` turns them off, as
+//! the size of the file isn't the size of the message.
+//!
+//! Messages in `cur` carry the `Z` flag that Dovecot uses to mark
+//! compressed files, and only messages with the flag are decompressed
+//! there. Files in `new` have no flags, so they are recognized by their
+//! content instead, and get the `Z` flag when they are moved to `cur` with
+//! `Maildir::move_new_to_cur_with_flags`. `Maildir::set_flags` and
+//! `Maildir::remove_flags` leave the flag alone.
+//!
+//! Gzip is always available with the `compression` feature. Zstandard
+//! needs the `compression-zstd` feature, which builds the zstd C library.
+//!
+//! ```no_run
+//! use maildir::compression::Compression;
+//! use maildir::MaildirBuilder;
+//!
+//! let archive = MaildirBuilder::new("path/to/archive")
+//!     .compression(Compression::Gzip)
+//!     .build();
+//! let id = archive.store_cur_with_flags(b"Subject: old\n\nnews\n", "S").unwrap();
+//! let mut entry = archive.find(&id).unwrap();
+//! assert_eq!(entry.flags(), "SZ");
+//! assert_eq!(entry.data().unwrap(), b"Subject: old\n\nnews\n");
+//! ```
+
+use std::fs;
+use std::io::{self, BufRead, Read, Seek, Write};
+use std::path::Path;
+
+use flate2::read::MultiGzDecoder;
+use flate2::write::GzEncoder;
+
+/// The flag marking compressed messages in `cur`.
+pub const COMPRESSED_FLAG: char = 'Z';
+
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+#[cfg(feature = "zstd")]
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+
+/// How messages are compressed when they are stored.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Compression {
+    /// Gzip with the default compression level.
+    Gzip,
+    /// Zstandard with the default compression level.
+    #[cfg(feature = "zstd")]
+    Zstd,
+}
+
+/// Returns true if the data starts like a compressed message.
+pub fn is_compressed(data: &[u8]) -> bool {
+    #[cfg(feature = "zstd")]
+    if data.starts_with(ZSTD_MAGIC) {
+        return true;
+    }
+    data.starts_with(GZIP_MAGIC)
+}
+
+pub(crate) fn compress(data: &[u8], compression: Compression) -> io::Result<Vec<u8>> {
+    match compression {
+        Compression::Gzip => {
+            let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+            encoder.write_all(data)?;
+            encoder.finish()
+        }
+        #[cfg(feature = "zstd")]
+        Compression::Zstd => zstd::encode_all(data, 0),
+    }
+}
+
+/// Decompresses a message, or returns `None` if it isn't compressed.
+pub(crate) fn decompress(data: &[u8]) -> io::Result<Option<Vec<u8>>> {
+    #[cfg(feature = "zstd")]
+    if data.starts_with(ZSTD_MAGIC) {
+        return zstd::decode_all(data).map(Some);
+    }
+    if data.starts_with(GZIP_MAGIC) {
+        let mut decompressed = Vec::new();
+        MultiGzDecoder::new(data).read_to_end(&mut decompressed)?;
+        return Ok(Some(decompressed));
+    }
+    Ok(None)
+}
+
+// Wraps a message file in a reader that decompresses it while it is read,
+// if it is compressed.
+pub(crate) fn decoder(file: fs::File) -> io::Result<Box<dyn Read>> {
+    let mut reader = io::BufReader::new(file);
+    let magic = reader.fill_buf()?;
+    #[cfg(feature = "zstd")]
+    if magic.starts_with(ZSTD_MAGIC) {
+        return Ok(Box::new(zstd::Decoder::with_buffer(reader)?));
+    }
+    if magic.starts_with(GZIP_MAGIC) {
+        return Ok(Box::new(MultiGzDecoder::new(reader)));
+    }
+    Ok(Box::new(reader))
+}
+
+// Checks whether a message file without the `Z` flag is compressed: it
+// has to start like a compressed message and decompress without errors,
+// since an uncompressed message may happen to start with the same bytes.
+pub(crate) fn is_compressed_file(path: &Path) -> io::Result<bool> {
+    let mut magic = Vec::with_capacity(4);
+    let mut file = fs::File::open(path)?;
+    Read::by_ref(&mut file).take(4).read_to_end(&mut magic)?;
+    if !is_compressed(&magic) {
+        return Ok(false);
+    }
+    file.rewind()?;
+    Ok(io::copy(&mut decoder(file)?, &mut io::sink()).is_ok())
+}
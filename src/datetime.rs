@@ -0,0 +1,73 @@
+//! Calendar arithmetic on Unix timestamps, for the few places that need
+//! to go between timestamps and dates without pulling in a date library.
+//! All dates are in UTC.
+
+pub(crate) const SECONDS_PER_DAY: i64 = 86_400;
+
+/// Returns the number of days since 1970-01-01 of the given date. Months
+/// and days are 1-based.
+pub(crate) fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    // see http://howardhinnant.github.io/date_algorithms.html
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let month = month as i64;
+    let day_of_year =
+        (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day as i64 - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+/// Returns the year, month and day of the given number of days since
+/// 1970-01-01. Months and days are 1-based.
+pub(crate) fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days - era * 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+pub(crate) const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+/// Parses a date of the form `1-Feb-1994`, as used by IMAP, into the
+/// timestamp of the start of that day.
+pub(crate) fn parse_imap_date(date: &str) -> Option<i64> {
+    let mut parts = date.split('-');
+    let day: u32 = parts.next()?.parse().ok()?;
+    let month = parts.next()?;
+    let month = MONTHS.iter().position(|m| m.eq_ignore_ascii_case(month))? as u32 + 1;
+    let year: i64 = parts.next()?.parse().ok()?;
+    if parts.next().is_some() || day == 0 || day > 31 {
+        return None;
+    }
+    Some(days_from_civil(year, month, day) * SECONDS_PER_DAY)
+}
+
+const WEEKDAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+
+/// Formats a timestamp as an RFC 2822 date, like
+/// `Thu, 1 Jan 1970 00:00:00 +0000`.
+pub(crate) fn format_rfc2822(timestamp: i64) -> String {
+    let days = timestamp.div_euclid(SECONDS_PER_DAY);
+    let seconds = timestamp.rem_euclid(SECONDS_PER_DAY);
+    let (year, month, day) = civil_from_days(days);
+    format!(
+        "{}, {} {} {:04} {:02}:{:02}:{:02} +0000",
+        WEEKDAYS[days.rem_euclid(7) as usize],
+        day,
+        MONTHS[month as usize - 1],
+        year,
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60
+    )
+}
@@ -0,0 +1,99 @@
+//! Trace headers added to a message when it is delivered.
+//!
+//! Delivery agents record the envelope of a message in its header before
+//! storing it: `Return-Path` holds the envelope sender, where bounces go,
+//! and `Delivered-To` the envelope recipient, which is how mail loops are
+//! detected. A `Received` header records the host and time of the delivery.
+//! [`DeliveryHeaders`] prepends these headers to a message, using the line
+//! endings the message already uses.
+//!
+//! ```no_run
+//! use maildir::delivery::DeliveryHeaders;
+//! use maildir::Maildir;
+//!
+//! let maildir = Maildir::from("path/to/maildir");
+//! let message = b"Subject: hello\n\nhi\n";
+//! let message = DeliveryHeaders::new()
+//!     .return_path("alice@example.org")
+//!     .delivered_to("bob@example.org")
+//!     .prepend_to(message);
+//! maildir.store_new(&message).unwrap();
+//! ```
+
+use std::time;
+
+use crate::datetime::format_rfc2822;
+
+/// The trace headers to add to a message, see the module documentation.
+#[derive(Clone, Debug, Default)]
+pub struct DeliveryHeaders {
+    return_path: Option<String>,
+    delivered_to: Option<String>,
+    received_by: Option<String>,
+}
+
+impl DeliveryHeaders {
+    pub fn new() -> DeliveryHeaders {
+        DeliveryHeaders::default()
+    }
+
+    /// Sets the envelope sender for the `Return-Path` header. An empty
+    /// sender, as used by bounces, gives `Return-Path: <>`.
+    pub fn return_path(mut self, sender: &str) -> DeliveryHeaders {
+        self.return_path = Some(sanitize(sender));
+        self
+    }
+
+    /// Sets the envelope recipient for the `Delivered-To` header.
+    pub fn delivered_to(mut self, recipient: &str) -> DeliveryHeaders {
+        self.delivered_to = Some(sanitize(recipient));
+        self
+    }
+
+    /// Adds a `Received` header naming the given host, and the recipient
+    /// if one is set, dated when the headers are prepended.
+    pub fn received(mut self, host: &str) -> DeliveryHeaders {
+        self.received_by = Some(sanitize(host));
+        self
+    }
+
+    /// Returns the message with the headers prepended.
+    pub fn prepend_to(&self, message: &[u8]) -> Vec<u8> {
+        let eol = match message.iter().position(|&b| b == b'\n') {
+            Some(pos) if pos > 0 && message[pos - 1] == b'\r' => "\r\n",
+            _ => "\n",
+        };
+        let mut headers = String::new();
+        if let Some(ref sender) = self.return_path {
+            headers.push_str(&format!("Return-Path: <{}>{}", sender, eol));
+        }
+        if let Some(ref recipient) = self.delivered_to {
+            headers.push_str(&format!("Delivered-To: {}{}", recipient, eol));
+        }
+        if let Some(ref host) = self.received_by {
+            headers.push_str(&format!("Received: by {}", host));
+            if let Some(ref recipient) = self.delivered_to {
+                headers.push_str(&format!(" for <{}>", recipient));
+            }
+            let now = time::SystemTime::now()
+                .duration_since(time::UNIX_EPOCH)
+                .map_or(0, |d| d.as_secs() as i64);
+            headers.push_str(&format!(";{}\t{}{}", eol, format_rfc2822(now), eol));
+        }
+        let mut result = Vec::with_capacity(headers.len() + message.len());
+        result.extend_from_slice(headers.as_bytes());
+        result.extend_from_slice(message);
+        result
+    }
+}
+
+// Keeps an address from breaking the header it is put in.
+fn sanitize(address: &str) -> String {
+    address
+        .trim()
+        .trim_start_matches('<')
+        .trim_end_matches('>')
+        .chars()
+        .filter(|c| !c.is_control())
+        .collect()
+}
@@ -0,0 +1,318 @@
+//! Comparison of two maildir trees.
+//!
+//! A [`Diff`] compares two maildirs, and optionally their subfolders, and
+//! reports the messages found on one side only, the messages whose flags
+//! differ, and the messages whose content differs. This is meant for
+//! checking the result of a migration or of a synchronization tool before
+//! removing the original.
+//!
+//! Messages are matched by id first. Messages that could not be matched by
+//! id can then be matched by their `Message-ID` header, for when a tool
+//! gave the copies new names. The contents of matched messages are
+//! compared by size first, and byte by byte if the sizes agree. Compressed
+//! messages are decompressed for this, and the `Z` flag that marks them is
+//! left out when comparing flags, so a compressed copy of a message is
+//! identical to the original.
+//!
+//! ```no_run
+//! use maildir::diff::Diff;
+//! use maildir::Maildir;
+//!
+//! let old = Maildir::from("/srv/mail/old");
+//! let new = Maildir::from("/srv/mail/new");
+//! let report = Diff::new(&old, &new).by_message_id(true).run().unwrap();
+//! for difference in &report.differences {
+//!     println!("{:?}", difference);
+//! }
+//! ```
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::io::{self, Read};
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+
+use mailparse::MailHeaderMap;
+
+use crate::{MailEntry, MailEntryError, Maildir};
+
+/// A difference between two maildirs. The `mailbox` fields hold the name
+/// of the subfolder, like `.Archive`, or an empty string for the maildirs
+/// themselves.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Difference {
+    /// A message that is only in the left maildir.
+    OnlyLeft { mailbox: String, id: String },
+    /// A message that is only in the right maildir.
+    OnlyRight { mailbox: String, id: String },
+    /// A message with different flags on each side.
+    Flags {
+        mailbox: String,
+        left_id: String,
+        right_id: String,
+        left_flags: String,
+        right_flags: String,
+    },
+    /// A message with different content on each side.
+    Content {
+        mailbox: String,
+        left_id: String,
+        right_id: String,
+    },
+}
+
+/// The result of `Diff::run`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct DiffReport {
+    /// The number of messages found on both sides.
+    pub matched: usize,
+    /// How many of the matched messages were matched by `Message-ID`
+    /// rather than by id.
+    pub matched_by_message_id: usize,
+    /// The differences found, ordered by mailbox.
+    pub differences: Vec<Difference>,
+}
+
+impl DiffReport {
+    /// Returns true if no differences were found.
+    pub fn is_identical(&self) -> bool {
+        self.differences.is_empty()
+    }
+}
+
+/// Compares two maildirs, see the module documentation.
+#[derive(Debug)]
+pub struct Diff<'a> {
+    left: &'a Maildir,
+    right: &'a Maildir,
+    recursive: bool,
+    by_message_id: bool,
+    compare_content: bool,
+}
+
+// A message on one side, with the flags it is compared by.
+#[derive(Debug)]
+struct Message {
+    id: String,
+    path: PathBuf,
+    file_flags: String,
+    flags: String,
+}
+
+impl Message {
+    // Returns an entry for the message, without reading it yet.
+    fn entry(&self) -> MailEntry {
+        MailEntry::new(self.id.clone(), self.file_flags.clone(), self.path.clone())
+    }
+}
+
+impl<'a> Diff<'a> {
+    pub fn new(left: &'a Maildir, right: &'a Maildir) -> Diff<'a> {
+        Diff {
+            left,
+            right,
+            recursive: true,
+            by_message_id: false,
+            compare_content: true,
+        }
+    }
+
+    /// Sets whether subfolders are compared too. On by default.
+    pub fn recursive(mut self, recursive: bool) -> Diff<'a> {
+        self.recursive = recursive;
+        self
+    }
+
+    /// Sets whether messages that have no counterpart with the same id are
+    /// matched by their `Message-ID` header. This reads the headers of all
+    /// those messages. Off by default.
+    pub fn by_message_id(mut self, by_message_id: bool) -> Diff<'a> {
+        self.by_message_id = by_message_id;
+        self
+    }
+
+    /// Sets whether the contents of matched messages are compared. On by
+    /// default.
+    pub fn compare_content(mut self, compare_content: bool) -> Diff<'a> {
+        self.compare_content = compare_content;
+        self
+    }
+
+    /// Runs the comparison. Neither maildir is modified.
+    pub fn run(&self) -> io::Result<DiffReport> {
+        let mut report = DiffReport::default();
+        let mut mailboxes = BTreeSet::new();
+        mailboxes.insert(String::new());
+        if self.recursive {
+            for maildir in &[self.left, self.right] {
+                for subdir in maildir.list_subdirs() {
+                    if let Some(name) = subdir?.path().file_name() {
+                        mailboxes.insert(name.to_string_lossy().deref().to_string());
+                    }
+                }
+            }
+        }
+        for mailbox in &mailboxes {
+            let left = list(&self.left.path().join(mailbox))?;
+            let right = list(&self.right.path().join(mailbox))?;
+            self.compare(mailbox, left, right, &mut report)?;
+        }
+        Ok(report)
+    }
+
+    fn compare(
+        &self,
+        mailbox: &str,
+        mut left: BTreeMap<String, Message>,
+        mut right: BTreeMap<String, Message>,
+        report: &mut DiffReport,
+    ) -> io::Result<()> {
+        let mut pairs = Vec::new();
+        let common: Vec<String> = left
+            .keys()
+            .filter(|id| right.contains_key(*id))
+            .cloned()
+            .collect();
+        for id in common {
+            let l = left.remove(&id).unwrap();
+            let r = right.remove(&id).unwrap();
+            pairs.push((id.clone(), l, id, r));
+        }
+
+        if self.by_message_id && !left.is_empty() && !right.is_empty() {
+            let mut right_by_message_id = HashMap::new();
+            for (id, message) in &right {
+                if let Some(message_id) = message_id(message)? {
+                    right_by_message_id
+                        .entry(message_id)
+                        .or_insert_with(|| id.clone());
+                }
+            }
+            let left_ids: Vec<String> = left.keys().cloned().collect();
+            for left_id in left_ids {
+                let message_id = match message_id(&left[&left_id])? {
+                    Some(message_id) => message_id,
+                    None => continue,
+                };
+                if let Some(right_id) = right_by_message_id.remove(&message_id) {
+                    let l = left.remove(&left_id).unwrap();
+                    let r = right.remove(&right_id).unwrap();
+                    pairs.push((left_id, l, right_id, r));
+                    report.matched_by_message_id += 1;
+                }
+            }
+        }
+
+        for id in left.into_keys() {
+            report.differences.push(Difference::OnlyLeft {
+                mailbox: mailbox.to_string(),
+                id,
+            });
+        }
+        for id in right.into_keys() {
+            report.differences.push(Difference::OnlyRight {
+                mailbox: mailbox.to_string(),
+                id,
+            });
+        }
+
+        for (left_id, l, right_id, r) in pairs {
+            report.matched += 1;
+            if l.flags != r.flags {
+                report.differences.push(Difference::Flags {
+                    mailbox: mailbox.to_string(),
+                    left_id: left_id.clone(),
+                    right_id: right_id.clone(),
+                    left_flags: l.flags.clone(),
+                    right_flags: r.flags.clone(),
+                });
+            }
+            if self.compare_content && !same_content(&l.entry(), &r.entry())? {
+                report.differences.push(Difference::Content {
+                    mailbox: mailbox.to_string(),
+                    left_id,
+                    right_id,
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
+// Lists the messages of the maildir at the given path, keyed by id.
+fn list(path: &Path) -> io::Result<BTreeMap<String, Message>> {
+    let maildir = Maildir::from(path.to_path_buf());
+    let mut messages = BTreeMap::new();
+    for entry in maildir.list_new().chain(maildir.list_cur()) {
+        let entry = entry?;
+        messages.insert(
+            entry.id().to_string(),
+            Message {
+                id: entry.id().to_string(),
+                path: entry.path().clone(),
+                file_flags: entry.flags().to_string(),
+                flags: Maildir::normalize_flags(
+                    &entry
+                        .flags()
+                        .chars()
+                        .filter(|&c| !Maildir::is_storage_flag(c))
+                        .collect::<String>(),
+                ),
+            },
+        );
+    }
+    Ok(messages)
+}
+
+// Reads the Message-ID header through MailEntry, so compressed messages
+// match their uncompressed copies. The entry is dropped right away rather
+// than kept in the listing, which would keep every message in memory.
+fn message_id(message: &Message) -> io::Result<Option<String>> {
+    let mut entry = message.entry();
+    let headers = match entry.headers() {
+        Ok(headers) => headers,
+        Err(MailEntryError::IOError(e)) => return Err(e),
+        Err(_) => return Ok(None),
+    };
+    Ok(headers
+        .get_first_value("Message-ID")
+        .map(|value| value.trim().to_string())
+        .filter(|value| !value.is_empty()))
+}
+
+// Compares the contents of two messages, decompressing them if needed. The
+// sizes are compared first where they are known without decompressing.
+fn same_content(left: &MailEntry, right: &MailEntry) -> io::Result<bool> {
+    if let (Some(left), Some(right)) = (left.known_size()?, right.known_size()?) {
+        if left != right {
+            return Ok(false);
+        }
+    }
+    let mut left = left.open()?;
+    let mut right = right.open()?;
+    let mut left_buffer = [0; 8192];
+    let mut right_buffer = [0; 8192];
+    loop {
+        let read = read_full(&mut left, &mut left_buffer)?;
+        if read != read_full(&mut right, &mut right_buffer)?
+            || left_buffer[..read] != right_buffer[..read]
+        {
+            return Ok(false);
+        }
+        if read == 0 {
+            return Ok(true);
+        }
+    }
+}
+
+// Fills the buffer as far as the message allows, so chunks of both messages
+// line up even if a read returns less than asked for.
+fn read_full(file: &mut dyn Read, buffer: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buffer.len() {
+        match file.read(&mut buffer[filled..])? {
+            0 => break,
+            read => filled += read,
+        }
+    }
+    Ok(filled)
+}
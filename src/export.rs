@@ -0,0 +1,212 @@
+//! Export of message metadata as JSON Lines.
+//!
+//! An [`Exporter`] walks a maildir, and optionally its subfolders, and
+//! writes one JSON object per message to a writer, one per line. Each
+//! object is an [`ExportRecord`] holding the metadata of the message, its
+//! key headers and a summary of its MIME structure. Messages are read one
+//! at a time and written out right away, so memory use does not grow with
+//! the size of the maildir.
+//!
+//! This module is only available with the `export` feature.
+//!
+//! ```no_run
+//! use std::io;
+//!
+//! use maildir::export::Exporter;
+//! use maildir::Maildir;
+//!
+//! let maildir = Maildir::from("path/to/maildir");
+//! let stdout = io::stdout();
+//! let count = Exporter::new(stdout.lock()).export(&maildir).unwrap();
+//! eprintln!("exported {} messages", count);
+//! ```
+
+use std::io::{self, Write};
+use std::ops::Deref;
+
+use mailparse::{DispositionType, MailHeaderMap, ParsedMail};
+use serde::{Deserialize, Serialize};
+
+use crate::{MailEntry, Maildir};
+
+/// The exported metadata of a message.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ExportRecord {
+    /// The name of the subfolder the message is in, like `.Archive`, or an
+    /// empty string for the maildir itself.
+    pub mailbox: String,
+    pub id: String,
+    /// The folder the message is in, `new` or `cur`.
+    pub folder: String,
+    pub flags: String,
+    /// See `MailEntry::sizes`.
+    pub size: u64,
+    pub rfc822_size: u64,
+    /// See `MailEntry::delivery_time`.
+    pub delivery_time: Option<i64>,
+    pub from: Option<String>,
+    pub to: Option<String>,
+    pub cc: Option<String>,
+    pub subject: Option<String>,
+    /// The `Date` header, as written by the sender.
+    pub date: Option<String>,
+    pub message_id: Option<String>,
+    /// The MIME structure of the message. This is `None` if the message
+    /// could not be parsed.
+    pub mime: Option<MimeSummary>,
+    /// Why the message could not be read or parsed, if it couldn't. The
+    /// other fields are still filled in as far as possible.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// A MIME part of a message, with its subparts.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MimeSummary {
+    /// The MIME type of the part, like `text/plain`.
+    pub content_type: String,
+    pub charset: String,
+    /// True if the part has an `attachment` disposition.
+    pub attachment: bool,
+    /// The file name of the part, from the `Content-Disposition` or the
+    /// `Content-Type` header.
+    pub filename: Option<String>,
+    /// The size of the part in bytes, including its headers.
+    pub size: usize,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub parts: Vec<MimeSummary>,
+}
+
+impl MimeSummary {
+    fn new(mail: &ParsedMail) -> MimeSummary {
+        let disposition = mail.get_content_disposition();
+        let filename = disposition
+            .params
+            .get("filename")
+            .or_else(|| mail.ctype.params.get("name"))
+            .cloned();
+        MimeSummary {
+            content_type: mail.ctype.mimetype.clone(),
+            charset: mail.ctype.charset.clone(),
+            attachment: disposition.disposition == DispositionType::Attachment,
+            filename,
+            size: mail.raw_bytes.len(),
+            parts: mail.subparts.iter().map(MimeSummary::new).collect(),
+        }
+    }
+}
+
+impl ExportRecord {
+    /// Reads the message and gathers its exported metadata. This only fails
+    /// if the message file cannot be accessed at all; problems reading or
+    /// parsing the message are recorded in `error`.
+    pub fn new(mailbox: &str, entry: &mut MailEntry) -> io::Result<ExportRecord> {
+        let summary = entry.summary(&[]).map_err(into_io_error)?;
+        let mut record = ExportRecord {
+            mailbox: mailbox.to_string(),
+            id: summary.id,
+            folder: summary.folder,
+            flags: summary.flags,
+            size: summary.size,
+            rfc822_size: summary.size,
+            delivery_time: summary.delivery_time,
+            from: None,
+            to: None,
+            cc: None,
+            subject: None,
+            date: None,
+            message_id: None,
+            mime: None,
+            error: None,
+        };
+        match entry.sizes() {
+            Ok(sizes) => record.rfc822_size = sizes.rfc822_size,
+            Err(e) => {
+                record.error = Some(e.to_string());
+                return Ok(record);
+            }
+        }
+        match entry.parsed() {
+            Ok(parsed) => {
+                let header = |name| parsed.headers.get_first_value(name);
+                record.from = header("From");
+                record.to = header("To");
+                record.cc = header("Cc");
+                record.subject = header("Subject");
+                record.date = header("Date");
+                record.message_id = header("Message-ID");
+                record.mime = Some(MimeSummary::new(&parsed));
+            }
+            Err(e) => record.error = Some(e.to_string()),
+        }
+        Ok(record)
+    }
+}
+
+fn into_io_error(e: crate::MailEntryError) -> io::Error {
+    match e {
+        crate::MailEntryError::IOError(e) => e,
+        e => io::Error::other(e.to_string()),
+    }
+}
+
+/// Writes the messages of maildirs as JSON Lines, see the module
+/// documentation.
+#[derive(Debug)]
+pub struct Exporter<W: Write> {
+    out: W,
+    recursive: bool,
+}
+
+impl<W: Write> Exporter<W> {
+    /// Creates an exporter writing to `out`. Subfolders are included by
+    /// default.
+    pub fn new(out: W) -> Exporter<W> {
+        Exporter {
+            out,
+            recursive: true,
+        }
+    }
+
+    /// Sets whether the subfolders returned by `Maildir::list_subdirs` are
+    /// exported along with the maildir itself.
+    pub fn recursive(mut self, recursive: bool) -> Exporter<W> {
+        self.recursive = recursive;
+        self
+    }
+
+    /// Exports the messages of the maildir, and of its subfolders if
+    /// enabled, and returns the number of messages written. The writer is
+    /// flushed at the end.
+    pub fn export(&mut self, maildir: &Maildir) -> io::Result<usize> {
+        let mut count = self.export_mailbox("", maildir)?;
+        if self.recursive {
+            for subdir in maildir.list_subdirs() {
+                let subdir = subdir?;
+                let name = match subdir.path().file_name() {
+                    Some(name) => name.to_string_lossy().deref().to_string(),
+                    None => continue,
+                };
+                count += self.export_mailbox(&name, &subdir)?;
+            }
+        }
+        self.out.flush()?;
+        Ok(count)
+    }
+
+    fn export_mailbox(&mut self, mailbox: &str, maildir: &Maildir) -> io::Result<usize> {
+        let mut count = 0;
+        for entry in maildir.list_new().chain(maildir.list_cur()) {
+            let record = ExportRecord::new(mailbox, &mut entry?)?;
+            serde_json::to_writer(&mut self.out, &record)?;
+            self.out.write_all(b"\n")?;
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    /// Returns the underlying writer.
+    pub fn into_inner(self) -> W {
+        self.out
+    }
+}
@@ -0,0 +1,148 @@
+//! Test support for checking the crash safety of message delivery.
+//!
+//! A `FaultInjector` can be attached to a `Maildir` with
+//! `Maildir::set_fault_injector`. Every step that `store_new`,
+//! `store_cur_with_flags` and `Maildir::store_new_linked` perform on the
+//! filesystem first consults the injector, which can be told to make a
+//! particular step fail. This makes it possible to verify that a failed
+//! delivery never leaves a partial message in `new` or `cur`, and never
+//! leaks a file in `tmp`. Flushing a directory is also reported as a
+//! step, which makes it possible to check which directories an operation
+//! flushes.
+//!
+//! The injector is only available with the `fault-injection` feature.
+//! Without it only [`StoreStep`] is left, and storing a message doesn't
+//! check for faults at all.
+
+#[cfg(feature = "fault-injection")]
+use std::collections::HashMap;
+#[cfg(feature = "fault-injection")]
+use std::fs;
+#[cfg(feature = "fault-injection")]
+use std::io;
+#[cfg(feature = "fault-injection")]
+use std::ops::Deref;
+#[cfg(feature = "fault-injection")]
+use std::path::PathBuf;
+#[cfg(feature = "fault-injection")]
+use std::sync::Mutex;
+
+#[cfg(feature = "fault-injection")]
+use crate::Maildir;
+
+/// The filesystem steps performed while storing a message.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum StoreStep {
+    /// Creating the temporary file in `tmp`.
+    Create,
+    /// Writing the message data to the temporary file. When this step is
+    /// made to fail, half of the message is written first, simulating a
+    /// short write.
+    Write,
+    /// Flushing the temporary file to disk.
+    Sync,
+    /// Reading the metadata of the temporary file.
+    Metadata,
+    /// Renaming the temporary file into `new` or `cur`, or linking it into
+    /// the `new` folder of each target of `Maildir::store_new_linked`.
+    Rename,
+    /// Flushing a directory after a file was added to or removed from it.
+    /// This is only reached under `Durability::FileAndDirectory`, by storing
+    /// as well as by moving, copying and deleting messages.
+    SyncDir,
+}
+
+impl StoreStep {
+    /// The steps that every delivery performs, in the order in which they
+    /// are performed. `SyncDir` is left out since it depends on the
+    /// durability of the maildir.
+    pub const ALL: [StoreStep; 5] = [
+        StoreStep::Create,
+        StoreStep::Write,
+        StoreStep::Sync,
+        StoreStep::Metadata,
+        StoreStep::Rename,
+    ];
+}
+
+#[cfg(feature = "fault-injection")]
+#[derive(Debug, Default)]
+struct State {
+    // Number of times each step has been reached so far.
+    hits: HashMap<StoreStep, usize>,
+    // For each step, the values of `hits` at which that step should fail.
+    failures: HashMap<StoreStep, Vec<usize>>,
+}
+
+/// Decides which filesystem steps of a delivery should fail. An injector
+/// can be shared between several `Maildir` instances; it keeps count of how
+/// many times each step was reached across all of them.
+#[cfg(feature = "fault-injection")]
+#[derive(Debug, Default)]
+pub struct FaultInjector {
+    state: Mutex<State>,
+}
+
+#[cfg(feature = "fault-injection")]
+impl FaultInjector {
+    pub fn new() -> FaultInjector {
+        FaultInjector::default()
+    }
+
+    /// Makes the next occurrence of `step` fail.
+    pub fn fail_next(&self, step: StoreStep) {
+        self.fail_nth(step, 0)
+    }
+
+    /// Makes the `n`th occurrence of `step` fail, counting from zero and
+    /// starting at the current point in time.
+    pub fn fail_nth(&self, step: StoreStep, n: usize) {
+        let mut state = self.state.lock().unwrap();
+        let at = state.hits.get(&step).copied().unwrap_or(0) + n;
+        state.failures.entry(step).or_default().push(at);
+    }
+
+    /// Returns how many times `step` has been reached, including the times
+    /// it was made to fail.
+    pub fn hits(&self, step: StoreStep) -> usize {
+        let state = self.state.lock().unwrap();
+        state.hits.get(&step).copied().unwrap_or(0)
+    }
+
+    /// Forgets all pending failures. The hit counts are kept.
+    pub fn clear(&self) {
+        self.state.lock().unwrap().failures.clear();
+    }
+
+    pub(crate) fn check(&self, step: StoreStep) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        let hit = {
+            let count = state.hits.entry(step).or_insert(0);
+            *count += 1;
+            *count - 1
+        };
+        let failures = state.failures.entry(step).or_default();
+        match failures.iter().position(|&at| at == hit) {
+            Some(index) => {
+                failures.remove(index);
+                Err(io::Error::other(format!("injected fault at {:?}", step)))
+            }
+            None => Ok(()),
+        }
+    }
+}
+
+/// Returns the files left behind in the `tmp` folder of the maildir. After
+/// a failed delivery this is expected to be empty. Files starting with a
+/// dot (.) character are ignored, like they are in `cur` and `new`.
+#[cfg(feature = "fault-injection")]
+pub fn leaked_tmp_files(maildir: &Maildir) -> io::Result<Vec<PathBuf>> {
+    let mut leaked = Vec::new();
+    for entry in fs::read_dir(maildir.path().join("tmp"))? {
+        let entry = entry?;
+        if !entry.file_name().to_string_lossy().deref().starts_with('.') {
+            leaked.push(entry.path());
+        }
+    }
+    Ok(leaked)
+}
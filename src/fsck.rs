@@ -0,0 +1,256 @@
+//! Consistency checks for maildirs.
+//!
+//! Moving or renaming message files by hand easily breaks the naming rules
+//! of the maildir format, and the messages then no longer show up in mail
+//! clients, or show up with the wrong flags. [`Fsck`] walks a maildir, and
+//! optionally its subfolders, and reports:
+//!
+//! * missing `cur`, `new` or `tmp` folders,
+//! * message files whose name isn't in canonical form: files in `cur`
+//!   without the `:2,` info suffix, files in `new` with one, and flags
+//!   that are unsorted, repeated or mixed with other characters,
+//! * messages with the same id in several files,
+//! * files left in `tmp` for longer than 36 hours, which the maildir
+//!   specification says are safe to remove.
+//!
+//! With `repair`, missing folders are created, misnamed files are renamed
+//! to their canonical names, and stale temporary files are removed. Files
+//! sharing an id are only reported, since deciding which copy to keep needs
+//! a human.
+//!
+//! This module is only available with the `fsck` feature, which the
+//! `maildir-tool` binary needs as well.
+//!
+//! ```no_run
+//! use maildir::fsck::Fsck;
+//! use maildir::Maildir;
+//!
+//! let maildir = Maildir::from("path/to/maildir");
+//! let report = Fsck::new(&maildir).repair(true).run().unwrap();
+//! for issue in &report.issues {
+//!     println!("{:?} (repaired: {})", issue.problem, issue.repaired);
+//! }
+//! ```
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use crate::{Maildir, INFORMATIONAL_SUFFIX_SEPARATOR, MAILDIR_FOLDER_LIST};
+
+// How long a file may stay in `tmp`, as suggested by the maildir
+// specification.
+const DEFAULT_STALE_AFTER: Duration = Duration::from_secs(36 * 60 * 60);
+
+/// A problem found by `Fsck::run`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Problem {
+    /// One of the `cur`, `new` and `tmp` folders doesn't exist.
+    MissingFolder(PathBuf),
+    /// A message file whose name isn't in canonical form, with the path it
+    /// should have.
+    BadName { path: PathBuf, fixed: PathBuf },
+    /// Several message files with the same id.
+    DuplicateId { id: String, paths: Vec<PathBuf> },
+    /// A file that has been in `tmp` for too long.
+    StaleTemporaryFile(PathBuf),
+}
+
+/// A problem and whether it was repaired.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Issue {
+    pub problem: Problem,
+    pub repaired: bool,
+}
+
+/// The result of `Fsck::run`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct FsckReport {
+    /// The number of messages checked.
+    pub messages: usize,
+    /// The problems found, ordered by mailbox.
+    pub issues: Vec<Issue>,
+}
+
+impl FsckReport {
+    /// Returns true if every problem found was repaired.
+    pub fn is_clean(&self) -> bool {
+        self.issues.iter().all(|issue| issue.repaired)
+    }
+}
+
+/// Checks a maildir, see the module documentation.
+#[derive(Debug)]
+pub struct Fsck<'a> {
+    maildir: &'a Maildir,
+    recursive: bool,
+    repair: bool,
+    stale_after: Duration,
+}
+
+impl<'a> Fsck<'a> {
+    pub fn new(maildir: &'a Maildir) -> Fsck<'a> {
+        Fsck {
+            maildir,
+            recursive: true,
+            repair: false,
+            stale_after: DEFAULT_STALE_AFTER,
+        }
+    }
+
+    /// Sets whether subfolders are checked too. On by default.
+    pub fn recursive(mut self, recursive: bool) -> Fsck<'a> {
+        self.recursive = recursive;
+        self
+    }
+
+    /// Sets whether the problems found are repaired where possible. Off by
+    /// default, so the maildir is only read.
+    pub fn repair(mut self, repair: bool) -> Fsck<'a> {
+        self.repair = repair;
+        self
+    }
+
+    /// Sets how long a file may stay in `tmp` before it is reported. The
+    /// default is 36 hours.
+    pub fn stale_after(mut self, stale_after: Duration) -> Fsck<'a> {
+        self.stale_after = stale_after;
+        self
+    }
+
+    /// Runs the checks.
+    pub fn run(&self) -> io::Result<FsckReport> {
+        let mut report = FsckReport::default();
+        self.check(self.maildir.path(), &mut report)?;
+        if self.recursive {
+            let mut folders = Vec::new();
+            for subdir in self.maildir.list_subdirs() {
+                folders.push(subdir?.path().to_path_buf());
+            }
+            folders.sort();
+            for folder in folders {
+                self.check(&folder, &mut report)?;
+            }
+        }
+        Ok(report)
+    }
+
+    fn check(&self, path: &Path, report: &mut FsckReport) -> io::Result<()> {
+        for folder in MAILDIR_FOLDER_LIST {
+            let dir = path.join(folder);
+            if !dir.is_dir() {
+                let repaired = self.repair && fs::create_dir_all(&dir).is_ok();
+                report.issues.push(Issue {
+                    problem: Problem::MissingFolder(dir),
+                    repaired,
+                });
+            }
+        }
+
+        let mut ids: BTreeMap<String, Vec<PathBuf>> = BTreeMap::new();
+        // both folders are listed before any file is moved from one to the
+        // other, so no file is seen twice
+        let listed = [
+            ("new", file_names(&path.join("new"))?),
+            ("cur", file_names(&path.join("cur"))?),
+        ];
+        for (folder, names) in listed {
+            let dir = path.join(folder);
+            for name in names {
+                report.messages += 1;
+                let (id, fixed_name) = canonical_name(folder, &name);
+                let mut file = dir.join(&name);
+                if let Some(fixed_name) = fixed_name {
+                    let fixed = path.join("cur").join(fixed_name);
+                    // never overwrite another message
+                    let repaired =
+                        self.repair && !fixed.exists() && fs::rename(&file, &fixed).is_ok();
+                    report.issues.push(Issue {
+                        problem: Problem::BadName {
+                            path: file.clone(),
+                            fixed: fixed.clone(),
+                        },
+                        repaired,
+                    });
+                    if repaired {
+                        self.maildir.sync_dirs(&[&path.join("cur"), &dir])?;
+                        file = fixed;
+                    }
+                }
+                ids.entry(id).or_default().push(file);
+            }
+        }
+        for (id, paths) in ids {
+            if paths.len() > 1 {
+                report.issues.push(Issue {
+                    problem: Problem::DuplicateId { id, paths },
+                    repaired: false,
+                });
+            }
+        }
+
+        let tmp = path.join("tmp");
+        let now = SystemTime::now();
+        for name in file_names(&tmp)? {
+            let path = tmp.join(name);
+            let modified = fs::metadata(&path)?.modified()?;
+            let age = now.duration_since(modified).unwrap_or_default();
+            if age >= self.stale_after {
+                let repaired = self.repair && fs::remove_file(&path).is_ok();
+                report.issues.push(Issue {
+                    problem: Problem::StaleTemporaryFile(path),
+                    repaired,
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
+// Lists the names of the files in a folder, skipping the ones starting with
+// a dot like `MailEntries` does, ordered by name.
+fn file_names(dir: &Path) -> io::Result<Vec<String>> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    let mut names = Vec::new();
+    for entry in entries {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().deref().to_string();
+        if !name.starts_with('.') && entry.file_type()?.is_file() {
+            names.push(name);
+        }
+    }
+    names.sort();
+    Ok(names)
+}
+
+// Returns the id of a message file, and the name it should have in `cur`
+// if its current name isn't canonical.
+fn canonical_name(folder: &str, name: &str) -> (String, Option<String>) {
+    let info = format!("{}2,", INFORMATIONAL_SUFFIX_SEPARATOR);
+    let (id, flags) = match name.find(&info) {
+        Some(pos) => (&name[..pos], Some(&name[pos + info.len()..])),
+        None => (name, None),
+    };
+    let fixed = match (folder, flags) {
+        ("new", None) => return (id.to_string(), None),
+        (_, flags) => {
+            let flags = flags.unwrap_or("");
+            let mut letters: Vec<char> = flags.chars().filter(char::is_ascii_alphabetic).collect();
+            letters.sort_unstable();
+            letters.dedup();
+            let letters: String = letters.into_iter().collect();
+            if folder == "cur" && flags == letters && name.len() > id.len() {
+                return (id.to_string(), None);
+            }
+            format!("{}{}{}", id, info, letters)
+        }
+    };
+    (id.to_string(), Some(fixed))
+}
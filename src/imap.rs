@@ -0,0 +1,1457 @@
+//! An IMAP server backed by maildirs.
+//!
+//! [`ImapServer`] implements the subset of IMAP4rev1 (RFC 3501) that mail
+//! clients need to read and organize the mail in a maildir tree: `LOGIN`,
+//! `LIST`, `LSUB`, `STATUS`, `SELECT`, `EXAMINE`, `FETCH`, `SEARCH`,
+//! `STORE`, `EXPUNGE`, `CLOSE`, their `UID` variants, and the literals of
+//! RFC 7888 (`LITERAL+`). Clients can change the flags of messages with
+//! `STORE` and delete them with `EXPUNGE`, unless the mailbox was opened
+//! with `EXAMINE`. Mailboxes cannot be created, renamed or deleted, and
+//! messages cannot be appended or copied. There is no TLS support, so
+//! the server is meant to be run on localhost or on a trusted network.
+//! Users are checked by an [`Authenticator`],
+//! which also provides the maildir of each user.
+//!
+//! The maildir itself is the `INBOX`, and its subfolders are the other
+//! mailboxes, named without their leading period and with `.` as the
+//! hierarchy delimiter, so `.Archive.2020` is listed as `Archive.2020`.
+//! The IMAP system flags map to the maildir flags `S` (`\Seen`), `R`
+//! (`\Answered`), `F` (`\Flagged`), `T` (`\Deleted`) and `D` (`\Draft`).
+//! Messages in `new` are `\Recent`, and are moved to `cur` when a client
+//! selects their mailbox.
+//!
+//! IMAP identifies messages by UIDs that must not change between sessions,
+//! so the server keeps a `maildir.uidlist` file in each mailbox that maps
+//! maildir ids to UIDs. New messages get the next free UIDs in the order of
+//! their ids. Removing the file makes the server assign new UIDs with a new
+//! `UIDVALIDITY`, which makes clients discard their caches.
+//!
+//! This module is only available with the `imap` feature.
+//!
+//! ```no_run
+//! use std::net::TcpListener;
+//!
+//! use maildir::auth::StaticAuthenticator;
+//! use maildir::imap::ImapServer;
+//!
+//! let mut users = StaticAuthenticator::new();
+//! users.add_user("alice", "secret", "/home/alice/Maildir");
+//! let listener = TcpListener::bind("127.0.0.1:1143").unwrap();
+//! ImapServer::new(users).serve(listener).unwrap();
+//! ```
+
+use std::collections::HashMap;
+use std::fs;
+use std::io::prelude::*;
+use std::io::{self, BufReader, BufWriter};
+use std::iter::Peekable;
+use std::net::{TcpListener, TcpStream};
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use std::str::Chars;
+use std::sync::Mutex;
+use std::time::{self, Duration};
+
+use mailparse::{MailAddr, MailHeaderMap, SingleInfo};
+
+use crate::auth::Authenticator;
+use crate::datetime::{civil_from_days, MONTHS, SECONDS_PER_DAY};
+use crate::search::Query;
+use crate::server;
+use crate::{MailEntry, Maildir, INFORMATIONAL_SUFFIX_SEPARATOR};
+
+/// The name of the file that holds the UIDs of the messages of a mailbox.
+pub const UIDLIST_FILE_NAME: &str = "maildir.uidlist";
+
+const UIDLIST_HEADER: &str = "maildir-uidlist 1";
+
+// RFC 3501 asks for an inactivity timer of at least 30 minutes.
+const TIMEOUT: Duration = Duration::from_secs(30 * 60);
+
+// Longer command lines are rejected.
+const MAX_LINE: u64 = 8192;
+
+// Larger literals are rejected. Clients only send literals for strings like
+// passwords and search terms, since appending messages isn't supported.
+const MAX_LITERAL: usize = 64 * 1024;
+
+// The IMAP system flags and the maildir flags they map to.
+const FLAGS: [(char, &str); 5] = [
+    ('R', "\\Answered"),
+    ('F', "\\Flagged"),
+    ('T', "\\Deleted"),
+    ('S', "\\Seen"),
+    ('D', "\\Draft"),
+];
+
+/// An IMAP server, see the module documentation.
+#[derive(Debug)]
+pub struct ImapServer<A> {
+    authenticator: A,
+    // serializes the updates of the uidlist files between sessions
+    uidlist_lock: Mutex<()>,
+}
+
+// A message of the selected mailbox.
+#[derive(Debug)]
+struct Message {
+    uid: u32,
+    id: String,
+    path: PathBuf,
+    flags: String,
+    recent: bool,
+}
+
+// The selected mailbox.
+#[derive(Debug)]
+struct Mailbox {
+    maildir: Maildir,
+    read_only: bool,
+    messages: Vec<Message>,
+}
+
+// The contents of a uidlist file: the UIDVALIDITY of the mailbox, the UID
+// the next new message gets, and the UIDs by id.
+struct UidList {
+    validity: u32,
+    next: u32,
+    uids: HashMap<String, u32>,
+}
+
+enum State {
+    NotAuthenticated,
+    Authenticated(Maildir),
+    Selected(Maildir, Mailbox),
+}
+
+// An argument of a command: an atom, a quoted string or literal, or a
+// parenthesized list.
+#[derive(Debug)]
+enum Arg {
+    Atom(String),
+    Str(String),
+    List(Vec<Arg>),
+}
+
+// A data item of FETCH.
+#[derive(Debug)]
+enum Item {
+    Flags,
+    Uid,
+    Size,
+    InternalDate,
+    Envelope,
+    Body {
+        label: String,
+        section: Section,
+        partial: Option<(usize, usize)>,
+        peek: bool,
+    },
+}
+
+// A section of a message for BODY[...].
+#[derive(Debug)]
+enum Section {
+    Full,
+    Header,
+    Text,
+    HeaderFields(Vec<String>, bool),
+    Part(Vec<usize>),
+}
+
+struct Session<'s, A, W> {
+    server: &'s ImapServer<A>,
+    out: W,
+    state: State,
+}
+
+impl<A: Authenticator> ImapServer<A> {
+    pub fn new(authenticator: A) -> ImapServer<A> {
+        ImapServer {
+            authenticator,
+            uidlist_lock: Mutex::new(()),
+        }
+    }
+
+    /// Accepts connections on the listener and serves each of them on its
+    /// own thread. This only returns if accepting a connection fails.
+    pub fn serve(&self, listener: TcpListener) -> io::Result<()> {
+        server::serve(listener.incoming(), |stream| self.handle_stream(stream))
+    }
+
+    /// Serves a single client connected over TCP.
+    pub fn handle_stream(&self, stream: TcpStream) -> io::Result<()> {
+        stream.set_read_timeout(Some(TIMEOUT))?;
+        let reader = BufReader::new(stream.try_clone()?);
+        self.handle(reader, BufWriter::new(stream))
+    }
+
+    /// Runs an IMAP session, reading commands from `input` and writing the
+    /// responses to `output`, until the client logs out or disconnects.
+    pub fn handle<R: BufRead, W: Write>(&self, mut input: R, output: W) -> io::Result<()> {
+        let mut session = Session {
+            server: self,
+            out: output,
+            state: State::NotAuthenticated,
+        };
+        session
+            .out
+            .write_all(b"* OK [CAPABILITY IMAP4rev1 LITERAL+] IMAP server ready\r\n")?;
+        session.out.flush()?;
+        loop {
+            let line = match read_command(&mut input, &mut session.out) {
+                Ok(Some(line)) => line,
+                Ok(None) => return Ok(()),
+                Err(ref e) if e.kind() == io::ErrorKind::InvalidData => {
+                    write!(session.out, "* BYE {}\r\n", e)?;
+                    return session.out.flush();
+                }
+                Err(e) => return Err(e),
+            };
+            let logout = session.command(&line)?;
+            session.out.flush()?;
+            if logout {
+                return Ok(());
+            }
+        }
+    }
+
+    // Loads the messages of a mailbox, assigning UIDs to new ones. Returns
+    // the UIDVALIDITY, the next UID and the messages ordered by UID.
+    fn load(&self, maildir: &Maildir) -> io::Result<(u32, u32, Vec<Message>)> {
+        let _guard = self.uidlist_lock.lock().unwrap_or_else(|e| e.into_inner());
+        let path = maildir.path().join(UIDLIST_FILE_NAME);
+        let UidList {
+            validity,
+            mut next,
+            uids,
+        } = match read_uidlist(&path)? {
+            Some(uidlist) => uidlist,
+            None => {
+                let now = time::SystemTime::now()
+                    .duration_since(time::UNIX_EPOCH)
+                    .map_or(1, |d| d.as_secs());
+                UidList {
+                    validity: now as u32,
+                    next: 1,
+                    uids: HashMap::new(),
+                }
+            }
+        };
+        let mut messages = Vec::new();
+        let mut unassigned = Vec::new();
+        for (entries, recent) in [(maildir.list_new(), true), (maildir.list_cur(), false)] {
+            for entry in entries {
+                let entry = entry?;
+                let message = Message {
+                    uid: uids.get(entry.id()).cloned().unwrap_or(0),
+                    id: entry.id().to_string(),
+                    path: entry.path().clone(),
+                    flags: entry.flags().to_string(),
+                    recent,
+                };
+                if message.uid == 0 {
+                    unassigned.push(message);
+                } else {
+                    messages.push(message);
+                }
+            }
+        }
+        let changed = !unassigned.is_empty() || messages.len() != uids.len();
+        unassigned.sort_by(|a, b| a.id.cmp(&b.id));
+        for mut message in unassigned {
+            message.uid = next;
+            next += 1;
+            messages.push(message);
+        }
+        messages.sort_by_key(|m| m.uid);
+        if changed {
+            write_uidlist(&path, validity, next, &messages)?;
+        }
+        Ok((validity, next, messages))
+    }
+}
+
+impl<'s, A: Authenticator, W: Write> Session<'s, A, W> {
+    // Runs a command and writes its responses. Returns true when the
+    // session ends.
+    fn command(&mut self, line: &str) -> io::Result<bool> {
+        let mut parts = line.splitn(3, ' ');
+        let tag = parts.next().unwrap_or("");
+        let mut name = parts.next().unwrap_or("").to_ascii_uppercase();
+        let mut rest = parts.next().unwrap_or("");
+        if tag.is_empty() || name.is_empty() {
+            self.out.write_all(b"* BAD missing command\r\n")?;
+            return Ok(false);
+        }
+        let uid = name == "UID";
+        if uid {
+            let mut parts = rest.splitn(2, ' ');
+            name = parts.next().unwrap_or("").to_ascii_uppercase();
+            rest = parts.next().unwrap_or("");
+        }
+        let args = match parse_args(rest) {
+            Some(args) => args,
+            None => {
+                self.tagged(tag, "BAD invalid arguments")?;
+                return Ok(false);
+            }
+        };
+
+        let authenticated = !matches!(self.state, State::NotAuthenticated);
+        let selected = matches!(self.state, State::Selected(..));
+        match name.as_str() {
+            "CAPABILITY" if !uid => {
+                self.out.write_all(b"* CAPABILITY IMAP4rev1 LITERAL+\r\n")?;
+                self.tagged(tag, "OK CAPABILITY completed")?;
+            }
+            "NOOP" | "CHECK" if !uid => {
+                if let State::Selected(_, ref mut mailbox) = self.state {
+                    refresh(self.server, mailbox, &mut self.out)?;
+                }
+                self.tagged(tag, &format!("OK {} completed", name))?;
+            }
+            "LOGOUT" if !uid => {
+                self.out.write_all(b"* BYE logging out\r\n")?;
+                self.tagged(tag, "OK LOGOUT completed")?;
+                return Ok(true);
+            }
+            "LOGIN" if !uid && !authenticated => {
+                let user = string_arg(args.first());
+                let password = string_arg(args.get(1));
+                let authenticated = match (user, password) {
+                    (Some(user), Some(password)) => {
+                        self.server.authenticator.authenticate(&user, &password)
+                    }
+                    _ => {
+                        self.tagged(tag, "BAD LOGIN needs a user and a password")?;
+                        return Ok(false);
+                    }
+                };
+                match authenticated {
+                    Some(maildir) => {
+                        self.state = State::Authenticated(maildir);
+                        self.tagged(tag, "OK LOGIN completed")?;
+                    }
+                    None => self.tagged(tag, "NO [AUTHENTICATIONFAILED] invalid credentials")?,
+                }
+            }
+            "LIST" | "LSUB" if !uid && authenticated => self.list(tag, &name, &args)?,
+            "STATUS" if !uid && authenticated => self.status(tag, &args)?,
+            "SELECT" | "EXAMINE" if !uid && authenticated => {
+                self.select(tag, &args, name == "EXAMINE")?
+            }
+            "CLOSE" | "UNSELECT" if !uid && selected => {
+                let state = std::mem::replace(&mut self.state, State::NotAuthenticated);
+                if let State::Selected(root, mut mailbox) = state {
+                    self.state = State::Authenticated(root);
+                    if name == "CLOSE" && !mailbox.read_only {
+                        expunge(&mut mailbox, &mut io::sink())?;
+                    }
+                }
+                self.tagged(tag, &format!("OK {} completed", name))?;
+            }
+            "EXPUNGE" if !uid && selected => {
+                if let State::Selected(_, ref mut mailbox) = self.state {
+                    if mailbox.read_only {
+                        self.tagged(tag, "NO mailbox is read-only")?;
+                    } else {
+                        expunge(mailbox, &mut self.out)?;
+                        self.tagged(tag, "OK EXPUNGE completed")?;
+                    }
+                }
+            }
+            "FETCH" if selected => self.fetch(tag, &args, uid)?,
+            "STORE" if selected => self.store(tag, &args, uid)?,
+            "SEARCH" if selected => self.search(tag, rest, uid)?,
+            "CAPABILITY" | "NOOP" | "CHECK" | "LOGOUT" | "LOGIN" | "LIST" | "LSUB" | "STATUS"
+            | "SELECT" | "EXAMINE" | "CLOSE" | "UNSELECT" | "EXPUNGE" | "FETCH" | "STORE"
+            | "SEARCH" => self.tagged(tag, &format!("BAD {} not allowed now", name))?,
+            _ => self.tagged(tag, "BAD unknown or unsupported command")?,
+        }
+        Ok(false)
+    }
+
+    fn tagged(&mut self, tag: &str, response: &str) -> io::Result<()> {
+        write!(self.out, "{} {}\r\n", tag, response)
+    }
+
+    fn root(&self) -> &Maildir {
+        match self.state {
+            State::Authenticated(ref root) | State::Selected(ref root, _) => root,
+            State::NotAuthenticated => unreachable!("no maildir before LOGIN"),
+        }
+    }
+
+    fn list(&mut self, tag: &str, command: &str, args: &[Arg]) -> io::Result<()> {
+        let (reference, pattern) = match (string_arg(args.first()), string_arg(args.get(1))) {
+            (Some(reference), Some(pattern)) => (reference, pattern),
+            _ => return self.tagged(tag, "BAD LIST needs a reference and a pattern"),
+        };
+        if pattern.is_empty() {
+            write!(self.out, "* {} (\\Noselect) \".\" \"\"\r\n", command)?;
+            return self.tagged(tag, &format!("OK {} completed", command));
+        }
+        let pattern = reference + &pattern;
+        let mut names = vec!["INBOX".to_string()];
+        let mut subfolders = Vec::new();
+        for subdir in self.root().list_subdirs() {
+            if let Some(name) = subdir?.path().file_name() {
+                subfolders.push(name.to_string_lossy().deref()[1..].to_string());
+            }
+        }
+        subfolders.sort();
+        names.extend(subfolders);
+        for name in names {
+            let matches = if name == "INBOX" {
+                pattern_matches(&pattern.to_ascii_uppercase(), &name)
+            } else {
+                pattern_matches(&pattern, &name)
+            };
+            if matches {
+                write!(self.out, "* {} () \".\" {}\r\n", command, string(&name))?;
+            }
+        }
+        self.tagged(tag, &format!("OK {} completed", command))
+    }
+
+    fn status(&mut self, tag: &str, args: &[Arg]) -> io::Result<()> {
+        let (name, items) = match (string_arg(args.first()), args.get(1)) {
+            (Some(name), Some(Arg::List(items))) => (name, items),
+            _ => return self.tagged(tag, "BAD STATUS needs a mailbox and a list of items"),
+        };
+        let maildir = match mailbox_maildir(self.root(), &name) {
+            Some(maildir) => maildir,
+            None => return self.tagged(tag, "NO [NONEXISTENT] no such mailbox"),
+        };
+        let (validity, next, messages) = self.server.load(&maildir)?;
+        let mut values = Vec::new();
+        for item in items {
+            let item = match *item {
+                Arg::Atom(ref item) => item.to_ascii_uppercase(),
+                _ => return self.tagged(tag, "BAD invalid status item"),
+            };
+            let value = match item.as_str() {
+                "MESSAGES" => messages.len(),
+                "RECENT" => messages.iter().filter(|m| m.recent).count(),
+                "UIDNEXT" => next as usize,
+                "UIDVALIDITY" => validity as usize,
+                "UNSEEN" => messages.iter().filter(|m| !m.flags.contains('S')).count(),
+                _ => return self.tagged(tag, "BAD invalid status item"),
+            };
+            values.push(format!("{} {}", item, value));
+        }
+        write!(
+            self.out,
+            "* STATUS {} ({})\r\n",
+            string(&name),
+            values.join(" ")
+        )?;
+        self.tagged(tag, "OK STATUS completed")
+    }
+
+    fn select(&mut self, tag: &str, args: &[Arg], read_only: bool) -> io::Result<()> {
+        let command = if read_only { "EXAMINE" } else { "SELECT" };
+        let root = self.root().clone();
+        let maildir = string_arg(args.first()).and_then(|name| mailbox_maildir(&root, &name));
+        // a failed SELECT still closes the selected mailbox
+        self.state = State::Authenticated(root);
+        let maildir = match maildir {
+            Some(maildir) => maildir,
+            None => return self.tagged(tag, "NO [NONEXISTENT] no such mailbox"),
+        };
+        let (validity, next_uid, mut messages) = self.server.load(&maildir)?;
+        if !read_only {
+            let mut gone = Vec::new();
+            for message in messages.iter_mut().filter(|m| m.recent) {
+                match maildir.move_new_to_cur(&message.id) {
+                    Ok(()) => message.path = cur_path(&maildir, &message.id, &message.flags),
+                    // another client moved or deleted the message since it
+                    // was listed
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                        match maildir.find(&message.id) {
+                            Some(entry) => {
+                                message.path = entry.path().clone();
+                                message.flags = entry.flags().to_string();
+                            }
+                            None => gone.push(message.uid),
+                        }
+                    }
+                    Err(e) => return Err(e),
+                }
+            }
+            messages.retain(|m| !gone.contains(&m.uid));
+        }
+
+        let names: Vec<&str> = FLAGS.iter().map(|&(_, name)| name).collect();
+        write!(self.out, "* FLAGS ({})\r\n", names.join(" "))?;
+        write!(self.out, "* {} EXISTS\r\n", messages.len())?;
+        write!(
+            self.out,
+            "* {} RECENT\r\n",
+            messages.iter().filter(|m| m.recent).count()
+        )?;
+        if let Some(index) = messages.iter().position(|m| !m.flags.contains('S')) {
+            write!(
+                self.out,
+                "* OK [UNSEEN {}] first unseen message\r\n",
+                index + 1
+            )?;
+        }
+        if read_only {
+            self.out
+                .write_all(b"* OK [PERMANENTFLAGS ()] read-only\r\n")?;
+        } else {
+            write!(
+                self.out,
+                "* OK [PERMANENTFLAGS ({})] flags permitted\r\n",
+                names.join(" ")
+            )?;
+        }
+        write!(self.out, "* OK [UIDVALIDITY {}] UIDs valid\r\n", validity)?;
+        write!(
+            self.out,
+            "* OK [UIDNEXT {}] predicted next UID\r\n",
+            next_uid
+        )?;
+
+        let root = match std::mem::replace(&mut self.state, State::NotAuthenticated) {
+            State::Authenticated(root) => root,
+            _ => unreachable!("the state was set above"),
+        };
+        self.state = State::Selected(
+            root,
+            Mailbox {
+                maildir,
+                read_only,
+                messages,
+            },
+        );
+        let access = if read_only { "READ-ONLY" } else { "READ-WRITE" };
+        self.tagged(tag, &format!("OK [{}] {} completed", access, command))
+    }
+
+    fn fetch(&mut self, tag: &str, args: &[Arg], uid: bool) -> io::Result<()> {
+        let command = if uid { "UID FETCH" } else { "FETCH" };
+        let mailbox = match self.state {
+            State::Selected(_, ref mut mailbox) => mailbox,
+            _ => unreachable!("FETCH needs a selected mailbox"),
+        };
+        let selected = match args.first() {
+            Some(Arg::Atom(set)) => select_messages(mailbox, set, uid),
+            _ => None,
+        };
+        let mut items = match args.get(1).and_then(parse_fetch_items) {
+            Some(items) if args.len() == 2 => items,
+            _ => return write!(self.out, "{} BAD invalid {} arguments\r\n", tag, command),
+        };
+        let selected = match selected {
+            Some(selected) => selected,
+            None => return write!(self.out, "{} BAD invalid message set\r\n", tag),
+        };
+        if uid && !items.iter().any(|item| matches!(item, Item::Uid)) {
+            items.insert(0, Item::Uid);
+        }
+        let sets_seen = !mailbox.read_only
+            && items
+                .iter()
+                .any(|item| matches!(*item, Item::Body { peek: false, .. }));
+
+        let mut missing = 0;
+        for index in selected {
+            let message = &mut mailbox.messages[index];
+            let mut flags_changed = false;
+            if sets_seen && !message.flags.contains('S') {
+                let flags = format!("{}S", message.flags);
+                match set_message_flags(&mailbox.maildir, message, &flags) {
+                    Ok(()) => flags_changed = true,
+                    Err(ref e) if e.kind() == io::ErrorKind::NotFound => (),
+                    Err(e) => return Err(e),
+                }
+            }
+            let response = match fetch_response(message, &items, flags_changed) {
+                Ok(response) => response,
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                    // removed by another program, the next NOOP reports it
+                    missing += 1;
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
+            write!(self.out, "* {} FETCH (", index + 1)?;
+            self.out.write_all(&response)?;
+            self.out.write_all(b")\r\n")?;
+        }
+        if missing > 0 {
+            write!(self.out, "{} NO some messages no longer exist\r\n", tag)
+        } else {
+            write!(self.out, "{} OK {} completed\r\n", tag, command)
+        }
+    }
+
+    fn store(&mut self, tag: &str, args: &[Arg], uid: bool) -> io::Result<()> {
+        let command = if uid { "UID STORE" } else { "STORE" };
+        let mailbox = match self.state {
+            State::Selected(_, ref mut mailbox) => mailbox,
+            _ => unreachable!("STORE needs a selected mailbox"),
+        };
+        if mailbox.read_only {
+            return write!(self.out, "{} NO mailbox is read-only\r\n", tag);
+        }
+        let (set, item) = match (args.first(), args.get(1)) {
+            (Some(Arg::Atom(set)), Some(Arg::Atom(item))) => (set, item.to_ascii_uppercase()),
+            _ => return write!(self.out, "{} BAD invalid {} arguments\r\n", tag, command),
+        };
+        let (item, silent) = match item.strip_suffix(".SILENT") {
+            Some(item) => (item, true),
+            None => (item.as_str(), false),
+        };
+        let mode = match item {
+            "FLAGS" => '=',
+            "+FLAGS" => '+',
+            "-FLAGS" => '-',
+            _ => return write!(self.out, "{} BAD invalid {} item\r\n", tag, command),
+        };
+        let flag_args = match args.get(2) {
+            Some(Arg::List(list)) if args.len() == 3 => &list[..],
+            Some(_) => &args[2..],
+            None => return write!(self.out, "{} BAD missing flags\r\n", tag),
+        };
+        let mut requested = String::new();
+        for arg in flag_args {
+            let name = match *arg {
+                Arg::Atom(ref name) => name,
+                _ => return write!(self.out, "{} BAD invalid flag\r\n", tag),
+            };
+            // keywords and \Recent can't be stored in a maildir
+            if let Some(&(flag, _)) = FLAGS.iter().find(|(_, n)| n.eq_ignore_ascii_case(name)) {
+                requested.push(flag);
+            }
+        }
+        let selected = match select_messages(mailbox, set, uid) {
+            Some(selected) => selected,
+            None => return write!(self.out, "{} BAD invalid message set\r\n", tag),
+        };
+
+        let mut missing = 0;
+        for index in selected {
+            let message = &mut mailbox.messages[index];
+            let flags: String = match mode {
+                // letters without an IMAP name, like P, are kept
+                '=' => message
+                    .flags
+                    .chars()
+                    .filter(|&c| FLAGS.iter().all(|&(flag, _)| flag != c))
+                    .chain(requested.chars())
+                    .collect(),
+                '+' => format!("{}{}", message.flags, requested),
+                _ => message
+                    .flags
+                    .chars()
+                    .filter(|&c| !requested.contains(c))
+                    .collect(),
+            };
+            match set_message_flags(&mailbox.maildir, message, &flags) {
+                Ok(()) => (),
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                    missing += 1;
+                    continue;
+                }
+                Err(e) => return Err(e),
+            }
+            if !silent {
+                write!(
+                    self.out,
+                    "* {} FETCH (FLAGS {}",
+                    index + 1,
+                    flag_list(message)
+                )?;
+                if uid {
+                    write!(self.out, " UID {}", message.uid)?;
+                }
+                self.out.write_all(b")\r\n")?;
+            }
+        }
+        if missing > 0 {
+            write!(self.out, "{} NO some messages no longer exist\r\n", tag)
+        } else {
+            write!(self.out, "{} OK {} completed\r\n", tag, command)
+        }
+    }
+
+    fn search(&mut self, tag: &str, args: &str, uid: bool) -> io::Result<()> {
+        let command = if uid { "UID SEARCH" } else { "SEARCH" };
+        let mailbox = match self.state {
+            State::Selected(_, ref mailbox) => mailbox,
+            _ => unreachable!("SEARCH needs a selected mailbox"),
+        };
+        // all strings are matched as UTF-8, whatever the charset
+        let mut args = args.trim();
+        if args
+            .get(..8)
+            .is_some_and(|p| p.eq_ignore_ascii_case("CHARSET "))
+        {
+            args = args[8..].trim_start();
+            args = args.find(' ').map_or("", |pos| &args[pos + 1..]);
+        }
+        let query = Query::parse_with_sets(args, |set, uid| {
+            select_messages(mailbox, set, uid).map(|selected| {
+                selected
+                    .into_iter()
+                    .map(|index| mailbox.messages[index].id.clone())
+                    .collect()
+            })
+        });
+        let query = match query {
+            Ok(query) => query,
+            Err(e) => return write!(self.out, "{} BAD {}\r\n", tag, e),
+        };
+
+        let mut entries = HashMap::new();
+        for entry in mailbox.maildir.list_new().chain(mailbox.maildir.list_cur()) {
+            let entry = entry?;
+            entries.insert(entry.id().to_string(), entry);
+        }
+        let mut found = Vec::new();
+        for (index, message) in mailbox.messages.iter().enumerate() {
+            let entry: &mut MailEntry = match entries.get_mut(&message.id) {
+                Some(entry) => entry,
+                None => continue,
+            };
+            // messages that can't be parsed match nothing
+            let matched = if message.recent {
+                query.matches(entry).unwrap_or(false) || query_matches_recent(&query, entry)
+            } else {
+                query.matches(entry).unwrap_or(false)
+            };
+            if matched {
+                found.push(if uid { message.uid } else { index as u32 + 1 });
+            }
+        }
+        self.out.write_all(b"* SEARCH")?;
+        for number in found {
+            write!(self.out, " {}", number)?;
+        }
+        self.out.write_all(b"\r\n")?;
+        write!(self.out, "{} OK {} completed\r\n", tag, command)
+    }
+}
+
+// Messages that SELECT moved to `cur` are still recent for the session, but
+// `Query::Recent` looks at the folder, so queries on them are evaluated
+// again with `Query::Recent` taken as true.
+fn query_matches_recent(query: &Query, entry: &mut MailEntry) -> bool {
+    fn substitute(query: &Query) -> Query {
+        match *query {
+            Query::Recent => Query::All,
+            Query::Not(ref q) => !substitute(q),
+            Query::And(ref qs) => Query::And(qs.iter().map(substitute).collect()),
+            Query::Or(ref l, ref r) => Query::or(substitute(l), substitute(r)),
+            ref q => q.clone(),
+        }
+    }
+    substitute(query).matches(entry).unwrap_or(false)
+}
+
+// Rescans the selected mailbox and reports the changes made by other
+// sessions and programs.
+fn refresh<A: Authenticator, W: Write>(
+    server: &ImapServer<A>,
+    mailbox: &mut Mailbox,
+    out: &mut W,
+) -> io::Result<()> {
+    let (_, _, fresh) = server.load(&mailbox.maildir)?;
+    let mut fresh: HashMap<u32, Message> = fresh.into_iter().map(|m| (m.uid, m)).collect();
+    let mut index = 0;
+    while index < mailbox.messages.len() {
+        let message = &mut mailbox.messages[index];
+        match fresh.remove(&message.uid) {
+            Some(current) => {
+                message.path = current.path;
+                if current.flags != message.flags {
+                    message.flags = current.flags;
+                    write!(
+                        out,
+                        "* {} FETCH (FLAGS {})\r\n",
+                        index + 1,
+                        flag_list(message)
+                    )?;
+                }
+                index += 1;
+            }
+            None => {
+                write!(out, "* {} EXPUNGE\r\n", index + 1)?;
+                mailbox.messages.remove(index);
+            }
+        }
+    }
+    let last_uid = mailbox.messages.last().map_or(0, |m| m.uid);
+    let mut added: Vec<Message> = fresh.into_values().filter(|m| m.uid > last_uid).collect();
+    if !added.is_empty() {
+        added.sort_by_key(|m| m.uid);
+        mailbox.messages.extend(added);
+        write!(out, "* {} EXISTS\r\n", mailbox.messages.len())?;
+        let recent = mailbox.messages.iter().filter(|m| m.recent).count();
+        write!(out, "* {} RECENT\r\n", recent)?;
+    }
+    Ok(())
+}
+
+// Deletes the messages flagged as deleted, reporting each of them in
+// descending order so the message numbers of the others stay valid.
+fn expunge<W: Write>(mailbox: &mut Mailbox, out: &mut W) -> io::Result<()> {
+    let mut index = mailbox.messages.len();
+    while index > 0 {
+        index -= 1;
+        if !mailbox.messages[index].flags.contains('T') {
+            continue;
+        }
+        match fs::remove_file(&mailbox.messages[index].path) {
+            Ok(()) => (),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => (),
+            Err(e) => return Err(e),
+        }
+        write!(out, "* {} EXPUNGE\r\n", index + 1)?;
+        mailbox.messages.remove(index);
+    }
+    let cur = mailbox.maildir.path().join("cur");
+    let new = mailbox.maildir.path().join("new");
+    mailbox.maildir.sync_dirs(&[&cur, &new])
+}
+
+// Renames a message file to carry the given flags, moving it to `cur` if
+// it is still in `new`.
+fn set_message_flags(maildir: &Maildir, message: &mut Message, flags: &str) -> io::Result<()> {
+    let flags = Maildir::normalize_flags(flags);
+    let path = cur_path(maildir, &message.id, &flags);
+    if path != message.path {
+        let old_dir = message.path.parent().map(Path::to_path_buf);
+        fs::rename(&message.path, &path)?;
+        let cur = maildir.path().join("cur");
+        match old_dir {
+            Some(ref old_dir) if *old_dir != cur => maildir.sync_dirs(&[&cur, old_dir])?,
+            _ => maildir.sync_dirs(&[&cur])?,
+        }
+        message.path = path;
+    }
+    message.flags = flags;
+    Ok(())
+}
+
+fn cur_path(maildir: &Maildir, id: &str, flags: &str) -> PathBuf {
+    maildir.path().join("cur").join(format!(
+        "{}{}2,{}",
+        id, INFORMATIONAL_SUFFIX_SEPARATOR, flags
+    ))
+}
+
+// Maps a mailbox name to the maildir holding it.
+fn mailbox_maildir(root: &Maildir, name: &str) -> Option<Maildir> {
+    let maildir = if name.eq_ignore_ascii_case("INBOX") {
+        root.clone()
+    } else if name.is_empty()
+        || name.starts_with('.')
+        || name.contains(['/', '\\'])
+        || name.contains("..")
+    {
+        return None;
+    } else {
+        root.subfolder(&format!(".{}", name)).ok()?
+    };
+    if maildir.path().join("cur").is_dir() {
+        Some(maildir)
+    } else {
+        None
+    }
+}
+
+// Matches a mailbox name against a LIST pattern, where `*` matches anything
+// and `%` matches anything but the hierarchy delimiter. This takes time
+// proportional to the length of the pattern times that of the name, however
+// many wildcards the pattern has.
+fn pattern_matches(pattern: &str, name: &str) -> bool {
+    let name: Vec<char> = name.chars().collect();
+    // matched[i] tells whether the pattern so far matches the first i
+    // characters of the name
+    let mut matched = vec![false; name.len() + 1];
+    matched[0] = true;
+    for c in pattern.chars() {
+        let mut next = vec![false; name.len() + 1];
+        for i in 0..=name.len() {
+            next[i] = match c {
+                '*' | '%' => {
+                    matched[i] || (i > 0 && next[i - 1] && (c == '*' || name[i - 1] != '.'))
+                }
+                _ => i > 0 && matched[i - 1] && name[i - 1] == c,
+            };
+        }
+        matched = next;
+    }
+    matched[name.len()]
+}
+
+// Returns the indexes of the messages in a sequence set, or a UID set.
+fn select_messages(mailbox: &Mailbox, set: &str, uid: bool) -> Option<Vec<usize>> {
+    let largest = if uid {
+        mailbox.messages.last().map_or(0, |m| m.uid)
+    } else {
+        mailbox.messages.len() as u32
+    };
+    let bound = |s: &str| -> Option<u32> {
+        match s {
+            "*" => Some(largest),
+            _ => s.parse().ok().filter(|&n| n > 0),
+        }
+    };
+    let mut ranges = Vec::new();
+    for range in set.split(',') {
+        let mut bounds = range.splitn(2, ':');
+        let first = bound(bounds.next()?)?;
+        let last = match bounds.next() {
+            Some(last) => bound(last)?,
+            None => first,
+        };
+        ranges.push((first.min(last), first.max(last)));
+    }
+    let in_set = |n: u32| ranges.iter().any(|&(first, last)| first <= n && n <= last);
+    Some(
+        mailbox
+            .messages
+            .iter()
+            .enumerate()
+            .filter(|&(index, message)| in_set(if uid { message.uid } else { index as u32 + 1 }))
+            .map(|(index, _)| index)
+            .collect(),
+    )
+}
+
+fn parse_fetch_items(arg: &Arg) -> Option<Vec<Item>> {
+    match *arg {
+        Arg::List(ref list) => list
+            .iter()
+            .map(|arg| match *arg {
+                Arg::Atom(ref atom) => parse_fetch_item(atom),
+                _ => None,
+            })
+            .collect(),
+        Arg::Atom(ref atom) => match atom.to_ascii_uppercase().as_str() {
+            "ALL" | "FULL" => Some(vec![
+                Item::Flags,
+                Item::InternalDate,
+                Item::Size,
+                Item::Envelope,
+            ]),
+            "FAST" => Some(vec![Item::Flags, Item::InternalDate, Item::Size]),
+            _ => parse_fetch_item(atom).map(|item| vec![item]),
+        },
+        Arg::Str(_) => None,
+    }
+}
+
+fn parse_fetch_item(atom: &str) -> Option<Item> {
+    let upper = atom.to_ascii_uppercase();
+    let body = |label: &str, section, peek| Item::Body {
+        label: label.to_string(),
+        section,
+        partial: None,
+        peek,
+    };
+    Some(match upper.as_str() {
+        "FLAGS" => Item::Flags,
+        "UID" => Item::Uid,
+        "RFC822.SIZE" => Item::Size,
+        "INTERNALDATE" => Item::InternalDate,
+        "ENVELOPE" => Item::Envelope,
+        "RFC822" => body("RFC822", Section::Full, false),
+        "RFC822.HEADER" => body("RFC822.HEADER", Section::Header, true),
+        "RFC822.TEXT" => body("RFC822.TEXT", Section::Text, false),
+        _ => {
+            let (prefix, peek) = if upper.starts_with("BODY.PEEK[") {
+                ("BODY.PEEK[".len(), true)
+            } else if upper.starts_with("BODY[") {
+                ("BODY[".len(), false)
+            } else {
+                return None;
+            };
+            let end = prefix + atom[prefix..].find(']')?;
+            let spec = &atom[prefix..end];
+            let partial = match &atom[end + 1..] {
+                "" => None,
+                origin => {
+                    let origin = origin.strip_prefix('<')?.strip_suffix('>')?;
+                    let (start, length) = origin.split_once('.')?;
+                    Some((start.parse().ok()?, length.parse().ok()?))
+                }
+            };
+            let mut label = format!("BODY[{}]", spec);
+            if let Some((start, _)) = partial {
+                label.push_str(&format!("<{}>", start));
+            }
+            Item::Body {
+                label,
+                section: parse_section(spec)?,
+                partial,
+                peek,
+            }
+        }
+    })
+}
+
+fn parse_section(spec: &str) -> Option<Section> {
+    let upper = spec.to_ascii_uppercase();
+    Some(match upper.as_str() {
+        "" => Section::Full,
+        "HEADER" => Section::Header,
+        "TEXT" => Section::Text,
+        _ if upper.starts_with("HEADER.FIELDS") => {
+            let (not, rest) = match upper.strip_prefix("HEADER.FIELDS.NOT") {
+                Some(_) => (true, &spec["HEADER.FIELDS.NOT".len()..]),
+                None => (false, &spec["HEADER.FIELDS".len()..]),
+            };
+            let fields = match parse_args(rest)?.pop() {
+                Some(Arg::List(fields)) => fields,
+                _ => return None,
+            };
+            let fields = fields
+                .iter()
+                .map(|field| string_arg(Some(field)))
+                .collect::<Option<Vec<String>>>()?;
+            Section::HeaderFields(fields, not)
+        }
+        _ => Section::Part(
+            spec.split('.')
+                .map(|n| n.parse().ok().filter(|&n| n > 0))
+                .collect::<Option<Vec<usize>>>()?,
+        ),
+    })
+}
+
+// Builds the data items of a FETCH response for a message.
+fn fetch_response(message: &Message, items: &[Item], with_flags: bool) -> io::Result<Vec<u8>> {
+    let mut data = None;
+    let mut response = Vec::new();
+    let mut has_flags = false;
+    for item in items {
+        if !response.is_empty() {
+            response.push(b' ');
+        }
+        match *item {
+            Item::Flags => {
+                has_flags = true;
+                write!(response, "FLAGS {}", flag_list(message))?;
+            }
+            Item::Uid => write!(response, "UID {}", message.uid)?,
+            Item::Size => {
+                // the ,W= attribute saves reading the message
+                let mut entry = MailEntry::new(
+                    message.id.clone(),
+                    message.flags.clone(),
+                    message.path.clone(),
+                );
+                let sizes = entry.sizes().map_err(|e| match e {
+                    crate::MailEntryError::IOError(e) => e,
+                    e => io::Error::new(io::ErrorKind::InvalidData, e.to_string()),
+                })?;
+                write!(response, "RFC822.SIZE {}", sizes.rfc822_size)?;
+            }
+            Item::InternalDate => {
+                let modified = fs::metadata(&message.path)?.modified()?;
+                let mtime = match modified.duration_since(time::UNIX_EPOCH) {
+                    Ok(d) => d.as_secs() as i64,
+                    Err(e) => -(e.duration().as_secs() as i64),
+                };
+                write!(response, "INTERNALDATE \"{}\"", internal_date(mtime))?;
+            }
+            Item::Envelope => {
+                let data = message_data(&mut data, &message.path)?;
+                write!(response, "ENVELOPE {}", envelope(data))?;
+            }
+            Item::Body {
+                ref label,
+                ref section,
+                partial,
+                ..
+            } => {
+                let data = message_data(&mut data, &message.path)?;
+                let mut content = section_data(data, section);
+                if let Some((start, length)) = partial {
+                    let start = start.min(content.len());
+                    let end = start.saturating_add(length).min(content.len());
+                    content = content[start..end].to_vec();
+                }
+                write!(response, "{} {{{}}}\r\n", label, content.len())?;
+                response.extend_from_slice(&content);
+            }
+        }
+    }
+    if with_flags && !has_flags {
+        write!(response, " FLAGS {}", flag_list(message))?;
+    }
+    Ok(response)
+}
+
+// Reads a message once per FETCH, with CRLF line endings as IMAP requires.
+fn message_data<'d>(data: &'d mut Option<Vec<u8>>, path: &Path) -> io::Result<&'d [u8]> {
+    if data.is_none() {
+        let raw = fs::read(path)?;
+        #[cfg(feature = "compression")]
+        let raw = crate::compression::decompress(&raw)?.unwrap_or(raw);
+        let mut crlf = Vec::with_capacity(raw.len() + raw.len() / 32);
+        for (i, &b) in raw.iter().enumerate() {
+            if b == b'\n' && (i == 0 || raw[i - 1] != b'\r') {
+                crlf.push(b'\r');
+            }
+            crlf.push(b);
+        }
+        *data = Some(crlf);
+    }
+    Ok(data.as_deref().unwrap_or_default())
+}
+
+// Splits a message into its header, including the empty line, and body.
+fn split_message(data: &[u8]) -> (&[u8], &[u8]) {
+    if data.starts_with(b"\r\n") {
+        return data.split_at(2);
+    }
+    match data.windows(4).position(|w| w == b"\r\n\r\n") {
+        Some(pos) => data.split_at(pos + 4),
+        None => (data, &[]),
+    }
+}
+
+fn section_data(data: &[u8], section: &Section) -> Vec<u8> {
+    match *section {
+        Section::Full => data.to_vec(),
+        Section::Header => split_message(data).0.to_vec(),
+        Section::Text => split_message(data).1.to_vec(),
+        Section::HeaderFields(ref fields, not) => {
+            let mut result = Vec::new();
+            let mut included = false;
+            for line in split_message(data).0.split_inclusive(|&b| b == b'\n') {
+                if line == b"\r\n" {
+                    break;
+                }
+                if !line.starts_with(b" ") && !line.starts_with(b"\t") {
+                    let name = line.split(|&b| b == b':').next().unwrap_or_default();
+                    let name = String::from_utf8_lossy(name);
+                    let listed = fields.iter().any(|f| f.eq_ignore_ascii_case(name.trim()));
+                    included = listed != not;
+                }
+                if included {
+                    result.extend_from_slice(line);
+                }
+            }
+            result.extend_from_slice(b"\r\n");
+            result
+        }
+        Section::Part(ref path) => {
+            let parsed = match mailparse::parse_mail(data) {
+                Ok(parsed) => parsed,
+                Err(_) => return Vec::new(),
+            };
+            let mut part = &parsed;
+            for (depth, &number) in path.iter().enumerate() {
+                part = match part.subparts.get(number - 1) {
+                    Some(subpart) => subpart,
+                    // a message that isn't multipart has a single part
+                    None if depth == 0 && number == 1 && parsed.subparts.is_empty() => part,
+                    None => return Vec::new(),
+                };
+            }
+            split_message(part.raw_bytes).1.to_vec()
+        }
+    }
+}
+
+fn flag_list(message: &Message) -> String {
+    let mut names: Vec<&str> = FLAGS
+        .iter()
+        .filter(|&&(flag, _)| message.flags.contains(flag))
+        .map(|&(_, name)| name)
+        .collect();
+    if message.recent {
+        names.push("\\Recent");
+    }
+    format!("({})", names.join(" "))
+}
+
+// Formats a timestamp as an IMAP date-time, in UTC.
+fn internal_date(timestamp: i64) -> String {
+    let days = timestamp.div_euclid(SECONDS_PER_DAY);
+    let seconds = timestamp.rem_euclid(SECONDS_PER_DAY);
+    let (year, month, day) = civil_from_days(days);
+    format!(
+        "{:02}-{}-{:04} {:02}:{:02}:{:02} +0000",
+        day,
+        MONTHS[month as usize - 1],
+        year,
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60
+    )
+}
+
+fn envelope(data: &[u8]) -> String {
+    let headers = match mailparse::parse_headers(data) {
+        Ok((headers, _)) => headers,
+        Err(_) => Vec::new(),
+    };
+    let text = |name: &str| -> String {
+        match headers.get_first_header(name) {
+            Some(header) => string(header.get_value().trim()),
+            None => "NIL".to_string(),
+        }
+    };
+    let addresses = |name: &str| -> Option<String> {
+        let list = mailparse::addrparse_header(headers.get_first_header(name)?).ok()?;
+        if list.is_empty() {
+            return None;
+        }
+        let mut result = String::from("(");
+        for addr in list.iter() {
+            match *addr {
+                MailAddr::Single(ref info) => result.push_str(&address(info)),
+                MailAddr::Group(ref group) => {
+                    result.push_str(&format!("(NIL NIL {} NIL)", string(&group.group_name)));
+                    for info in &group.addrs {
+                        result.push_str(&address(info));
+                    }
+                    result.push_str("(NIL NIL NIL NIL)");
+                }
+            }
+        }
+        result.push(')');
+        Some(result)
+    };
+    let from = addresses("From");
+    let nil = || "NIL".to_string();
+    format!(
+        "({} {} {} {} {} {} {} {} {} {})",
+        text("Date"),
+        text("Subject"),
+        from.clone().unwrap_or_else(nil),
+        addresses("Sender")
+            .or_else(|| from.clone())
+            .unwrap_or_else(nil),
+        addresses("Reply-To").or(from).unwrap_or_else(nil),
+        addresses("To").unwrap_or_else(nil),
+        addresses("Cc").unwrap_or_else(nil),
+        addresses("Bcc").unwrap_or_else(nil),
+        text("In-Reply-To"),
+        text("Message-ID"),
+    )
+}
+
+fn address(info: &SingleInfo) -> String {
+    let name = info
+        .display_name
+        .as_deref()
+        .map_or("NIL".to_string(), string);
+    let (mailbox, host) = match info.addr.rfind('@') {
+        Some(at) => (&info.addr[..at], &info.addr[at + 1..]),
+        None => (info.addr.as_str(), ""),
+    };
+    let host = if host.is_empty() {
+        "NIL".to_string()
+    } else {
+        string(host)
+    };
+    format!("({} NIL {} {})", name, string(mailbox), host)
+}
+
+// Formats an IMAP string, as a literal if a quoted string can't hold it.
+fn string(s: &str) -> String {
+    if s.bytes().any(|b| b == b'\r' || b == b'\n' || !b.is_ascii()) {
+        format!("{{{}}}\r\n{}", s.len(), s)
+    } else {
+        quote(s)
+    }
+}
+
+fn quote(s: &str) -> String {
+    format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+fn string_arg(arg: Option<&Arg>) -> Option<String> {
+    match arg? {
+        Arg::Atom(s) | Arg::Str(s) => Some(s.clone()),
+        Arg::List(_) => None,
+    }
+}
+
+// Reads a command line, with the literals it contains inlined as quoted
+// strings. Returns `None` at the end of the input.
+fn read_command<R: BufRead, W: Write>(input: &mut R, output: &mut W) -> io::Result<Option<String>> {
+    let mut command = String::new();
+    let mut line = Vec::new();
+    loop {
+        line.clear();
+        if input.by_ref().take(MAX_LINE).read_until(b'\n', &mut line)? == 0 {
+            return Ok(None);
+        }
+        if !line.ends_with(b"\n") {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "line too long"));
+        }
+        let text = String::from_utf8_lossy(&line);
+        let text = text.trim_end_matches(['\r', '\n']);
+        let (start, size, synchronizing) = match literal(text) {
+            Some(literal) => literal,
+            None => {
+                command.push_str(text);
+                return Ok(Some(command));
+            }
+        };
+        if size > MAX_LITERAL || command.len() + size > MAX_LITERAL {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "literal too large",
+            ));
+        }
+        command.push_str(&text[..start]);
+        if synchronizing {
+            output.write_all(b"+ ready for literal data\r\n")?;
+            output.flush()?;
+        }
+        let mut data = vec![0; size];
+        input.read_exact(&mut data)?;
+        command.push_str(&quote(&String::from_utf8_lossy(&data)));
+    }
+}
+
+// Finds the `{n}` or `{n+}` announcing a literal at the end of a line, and
+// returns where it starts, the size, and whether it is synchronizing.
+fn literal(line: &str) -> Option<(usize, usize, bool)> {
+    let rest = line.strip_suffix('}')?;
+    let start = rest.rfind('{')?;
+    let spec = &rest[start + 1..];
+    let (digits, synchronizing) = match spec.strip_suffix('+') {
+        Some(digits) => (digits, false),
+        None => (spec, true),
+    };
+    if digits.is_empty() || !digits.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    Some((start, digits.parse().ok()?, synchronizing))
+}
+
+fn parse_args(s: &str) -> Option<Vec<Arg>> {
+    let mut chars = s.chars().peekable();
+    let mut stack = vec![Vec::new()];
+    while let Some(&c) = chars.peek() {
+        match c {
+            ' ' => {
+                chars.next();
+            }
+            '(' => {
+                chars.next();
+                stack.push(Vec::new());
+            }
+            ')' => {
+                chars.next();
+                let list = stack.pop()?;
+                stack.last_mut()?.push(Arg::List(list));
+            }
+            '"' => {
+                chars.next();
+                let s = parse_quoted(&mut chars)?;
+                stack.last_mut()?.push(Arg::Str(s));
+            }
+            _ => {
+                let atom = parse_atom(&mut chars)?;
+                stack.last_mut()?.push(Arg::Atom(atom));
+            }
+        }
+    }
+    if stack.len() == 1 {
+        stack.pop()
+    } else {
+        None
+    }
+}
+
+fn parse_quoted(chars: &mut Peekable<Chars>) -> Option<String> {
+    let mut s = String::new();
+    loop {
+        match chars.next()? {
+            '"' => return Some(s),
+            '\\' => s.push(chars.next()?),
+            c => s.push(c),
+        }
+    }
+}
+
+// Parses an atom. Fetch items like `BODY[HEADER.FIELDS (From)]<0.100>` are
+// taken as a single atom, brackets and all.
+fn parse_atom(chars: &mut Peekable<Chars>) -> Option<String> {
+    let mut atom = String::new();
+    while let Some(&c) = chars.peek() {
+        match c {
+            ' ' | '(' | ')' => break,
+            '[' => {
+                for c in chars.by_ref() {
+                    atom.push(c);
+                    if c == ']' {
+                        break;
+                    }
+                }
+                if !atom.ends_with(']') {
+                    return None;
+                }
+            }
+            _ => {
+                atom.push(c);
+                chars.next();
+            }
+        }
+    }
+    Some(atom)
+}
+
+// Reads a uidlist file, or returns `None` if there is no such file.
+fn read_uidlist(path: &Path) -> io::Result<Option<UidList>> {
+    let content = match fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    let corrupt = || io::Error::new(io::ErrorKind::InvalidData, "corrupt uidlist file");
+    let mut lines = content.lines();
+    let header = lines.next().ok_or_else(corrupt)?;
+    let numbers = header.strip_prefix(UIDLIST_HEADER).ok_or_else(corrupt)?;
+    let mut numbers = numbers.split_whitespace().map(|n| n.parse::<u32>());
+    let (validity, next) = match (numbers.next(), numbers.next()) {
+        (Some(Ok(validity)), Some(Ok(next))) => (validity, next),
+        _ => return Err(corrupt()),
+    };
+    let mut uids = HashMap::new();
+    for line in lines {
+        let (uid, id) = line.split_once('\t').ok_or_else(corrupt)?;
+        let uid: u32 = uid.parse().map_err(|_| corrupt())?;
+        if uid == 0 || uid >= next {
+            return Err(corrupt());
+        }
+        uids.insert(id.to_string(), uid);
+    }
+    Ok(Some(UidList {
+        validity,
+        next,
+        uids,
+    }))
+}
+
+// Saves a uidlist file atomically, by writing a temporary file first.
+fn write_uidlist(path: &Path, validity: u32, next: u32, messages: &[Message]) -> io::Result<()> {
+    let mut content = format!("{} {} {}\n", UIDLIST_HEADER, validity, next);
+    for message in messages {
+        content.push_str(&format!("{}\t{}\n", message.uid, message.id));
+    }
+    let mut tmp_path = path.to_path_buf().into_os_string();
+    tmp_path.push(".tmp");
+    let tmp_path = PathBuf::from(tmp_path);
+    {
+        let mut file = fs::File::create(&tmp_path)?;
+        file.write_all(content.as_bytes())?;
+        file.sync_all()?;
+    }
+    fs::rename(&tmp_path, path)
+}
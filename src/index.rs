@@ -0,0 +1,399 @@
+//! A persistent full-text index over the messages of a maildir.
+//!
+//! The index maps words found in the headers and the decoded textual
+//! parts of messages to the ids of the messages containing them. It is
+//! stored in a file named `maildir.index` in the maildir base folder, and
+//! is brought up to date incrementally with [`Index::update`]: since the
+//! content of a maildir message never changes, only messages with ids that
+//! are not in the index yet need to be read, and ids that have disappeared
+//! from the maildir are dropped. Everything happens locally.
+//!
+//! Only the `new` and `cur` folders are indexed, not the subfolders. To
+//! search a whole maildir tree, open an index for each subfolder returned by
+//! `Maildir::list_subdirs`; each keeps its own index file.
+//!
+//! This module is only available with the `index` feature.
+//!
+//! ```no_run
+//! use maildir::index::Index;
+//! use maildir::Maildir;
+//!
+//! let maildir = Maildir::from("path/to/maildir");
+//! let mut index = Index::open(&maildir).unwrap();
+//! index.update().unwrap();
+//! index.save().unwrap();
+//! for entry in index.search("quarterly report").unwrap() {
+//!     println!("{}", entry.id());
+//! }
+//! ```
+
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::error;
+use std::fmt;
+use std::fs;
+use std::io::prelude::*;
+use std::io::{BufReader, BufWriter, ErrorKind};
+use std::path::PathBuf;
+
+use mailparse::ParsedMail;
+
+use crate::{MailEntry, Maildir};
+
+/// The name of the index file inside the maildir base folder.
+pub const INDEX_FILE_NAME: &str = "maildir.index";
+
+const FORMAT_HEADER: &str = "maildir-index 1";
+
+// Words longer than this are not indexed; they are almost always encoded
+// data rather than text anyone searches for.
+const MAX_WORD_LEN: usize = 64;
+
+#[derive(Debug)]
+pub enum IndexError {
+    Io(std::io::Error),
+    /// The index file exists but could not be understood.
+    Corrupt(String),
+}
+
+impl fmt::Display for IndexError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            IndexError::Io(ref e) => write!(f, "IO Error: {}", e),
+            IndexError::Corrupt(ref msg) => write!(f, "Corrupt index: {}", msg),
+        }
+    }
+}
+
+impl error::Error for IndexError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            IndexError::Io(ref e) => Some(e),
+            IndexError::Corrupt(_) => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for IndexError {
+    fn from(e: std::io::Error) -> IndexError {
+        IndexError::Io(e)
+    }
+}
+
+/// What changed in an index during `Index::update`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct IndexUpdate {
+    /// The number of messages that were read and added to the index.
+    pub added: usize,
+    /// The number of messages that were dropped from the index because
+    /// they are no longer in the maildir.
+    pub removed: usize,
+}
+
+/// A full-text index over the `new` and `cur` folders of one maildir.
+/// Subfolders have indexes of their own.
+#[derive(Debug)]
+pub struct Index<'m> {
+    maildir: &'m Maildir,
+    // message ids, keyed by the document numbers used in the postings
+    docs: BTreeMap<u32, String>,
+    doc_numbers: HashMap<String, u32>,
+    next_doc: u32,
+    postings: BTreeMap<String, BTreeSet<u32>>,
+}
+
+impl<'m> Index<'m> {
+    /// Loads the index of the maildir from disk, or starts an empty one if
+    /// the maildir has not been indexed before.
+    pub fn open(maildir: &'m Maildir) -> Result<Index<'m>, IndexError> {
+        let mut index = Index {
+            maildir,
+            docs: BTreeMap::new(),
+            doc_numbers: HashMap::new(),
+            next_doc: 0,
+            postings: BTreeMap::new(),
+        };
+        let file = match fs::File::open(index.path()) {
+            Ok(file) => file,
+            Err(ref e) if e.kind() == ErrorKind::NotFound => return Ok(index),
+            Err(e) => return Err(e.into()),
+        };
+        index.load(BufReader::new(file))?;
+        Ok(index)
+    }
+
+    /// Returns the path of the index file.
+    pub fn path(&self) -> PathBuf {
+        self.maildir.path().join(INDEX_FILE_NAME)
+    }
+
+    /// Returns the number of messages in the index.
+    pub fn len(&self) -> usize {
+        self.docs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.docs.is_empty()
+    }
+
+    /// Returns true if the message with the given id is in the index.
+    pub fn contains(&self, id: &str) -> bool {
+        self.doc_numbers.contains_key(id)
+    }
+
+    /// Brings the index up to date with the maildir: messages that are
+    /// not in the index yet are read and indexed, and messages that are no
+    /// longer in the maildir are dropped. Messages that cannot be parsed
+    /// are indexed without any words, so they are not read again. The
+    /// index is not written to disk; call `save` for that.
+    pub fn update(&mut self) -> Result<IndexUpdate, IndexError> {
+        let mut update = IndexUpdate::default();
+        let mut present = HashSet::new();
+        for entry in self.maildir.list_new().chain(self.maildir.list_cur()) {
+            let mut entry = entry?;
+            present.insert(entry.id().to_string());
+            if !self.contains(entry.id()) {
+                self.add(&mut entry)?;
+                update.added += 1;
+            }
+        }
+
+        let removed: HashSet<u32> = self
+            .docs
+            .iter()
+            .filter(|&(_, id)| !present.contains(id))
+            .map(|(&doc, _)| doc)
+            .collect();
+        if !removed.is_empty() {
+            for doc in &removed {
+                let id = self.docs.remove(doc).unwrap();
+                self.doc_numbers.remove(&id);
+            }
+            self.postings.retain(|_, docs| {
+                docs.retain(|doc| !removed.contains(doc));
+                !docs.is_empty()
+            });
+            update.removed = removed.len();
+        }
+        Ok(update)
+    }
+
+    fn add(&mut self, entry: &mut MailEntry) -> Result<(), IndexError> {
+        let doc = self.next_doc;
+        self.next_doc += 1;
+        self.docs.insert(doc, entry.id().to_string());
+        self.doc_numbers.insert(entry.id().to_string(), doc);
+
+        let mut words = HashSet::new();
+        match entry.parsed() {
+            Ok(parsed) => collect_words(&parsed, &mut words),
+            Err(crate::MailEntryError::IOError(e)) => return Err(e.into()),
+            Err(_) => (),
+        }
+        for word in words {
+            self.postings.entry(word).or_default().insert(doc);
+        }
+        Ok(())
+    }
+
+    /// Writes the index to disk. The file is replaced atomically, so an
+    /// interrupted save leaves the previous index intact.
+    pub fn save(&self) -> Result<(), IndexError> {
+        let path = self.path();
+        let tmp_path = path.with_extension("index.tmp");
+        {
+            let mut out = BufWriter::new(fs::File::create(&tmp_path)?);
+            writeln!(out, "{}", FORMAT_HEADER)?;
+            writeln!(out, "{}", self.next_doc)?;
+            for (doc, id) in &self.docs {
+                writeln!(out, "D\t{}\t{}", doc, escape_id(id))?;
+            }
+            for (word, docs) in &self.postings {
+                write!(out, "W\t{}", word)?;
+                for doc in docs {
+                    write!(out, "\t{}", doc)?;
+                }
+                writeln!(out)?;
+            }
+            out.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+        }
+        fs::rename(&tmp_path, &path)?;
+        Ok(())
+    }
+
+    fn load<R: BufRead>(&mut self, reader: R) -> Result<(), IndexError> {
+        let corrupt = |line: &str| IndexError::Corrupt(format!("unexpected line: {}", line));
+        let mut lines = reader.lines();
+        match lines.next() {
+            Some(Ok(ref header)) if header == FORMAT_HEADER => (),
+            Some(Err(e)) => return Err(e.into()),
+            _ => return Err(IndexError::Corrupt("unknown format".to_string())),
+        }
+        self.next_doc = match lines.next() {
+            Some(line) => {
+                let line = line?;
+                line.parse().map_err(|_| corrupt(&line))?
+            }
+            None => return Err(IndexError::Corrupt("truncated".to_string())),
+        };
+        for line in lines {
+            let line = line?;
+            let mut fields = line.split('\t');
+            match (fields.next(), fields.next()) {
+                (Some("D"), Some(doc)) => {
+                    let doc: u32 = doc.parse().map_err(|_| corrupt(&line))?;
+                    let id = fields
+                        .next()
+                        .and_then(unescape_id)
+                        .ok_or_else(|| corrupt(&line))?;
+                    // new messages are numbered from next_doc on, so a
+                    // number past it would be handed out a second time
+                    if doc >= self.next_doc
+                        || self.docs.contains_key(&doc)
+                        || self.doc_numbers.contains_key(&id)
+                    {
+                        return Err(corrupt(&line));
+                    }
+                    self.docs.insert(doc, id.clone());
+                    self.doc_numbers.insert(id, doc);
+                }
+                (Some("W"), Some(word)) => {
+                    let docs = fields
+                        .map(|doc| doc.parse())
+                        .collect::<Result<BTreeSet<u32>, _>>()
+                        .map_err(|_| corrupt(&line))?;
+                    self.postings.insert(word.to_string(), docs);
+                }
+                _ => return Err(corrupt(&line)),
+            }
+        }
+        // searching looks up every posted document
+        for (word, docs) in &self.postings {
+            if let Some(doc) = docs.iter().find(|doc| !self.docs.contains_key(doc)) {
+                return Err(IndexError::Corrupt(format!(
+                    "word {} refers to unknown message {}",
+                    word, doc
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the ids of the indexed messages that contain all the words
+    /// of the query, ignoring case. A word ending in `*` matches every
+    /// word starting with it. An empty query matches nothing.
+    pub fn search_ids(&self, query: &str) -> Vec<String> {
+        let mut result: Option<BTreeSet<u32>> = None;
+        for term in query.split_whitespace() {
+            let docs = match term.strip_suffix('*') {
+                Some(prefix) => {
+                    let mut docs = BTreeSet::new();
+                    for prefix in words(prefix) {
+                        for (_, d) in self
+                            .postings
+                            .range(prefix.clone()..)
+                            .take_while(|(word, _)| word.starts_with(&prefix))
+                        {
+                            docs.extend(d);
+                        }
+                    }
+                    docs
+                }
+                None => {
+                    let mut docs: Option<BTreeSet<u32>> = None;
+                    for word in words(term) {
+                        let d = self.postings.get(&word).cloned().unwrap_or_default();
+                        docs = Some(match docs {
+                            Some(docs) => docs.intersection(&d).copied().collect(),
+                            None => d,
+                        });
+                    }
+                    match docs {
+                        Some(docs) => docs,
+                        // the term has no indexable words in it
+                        None => continue,
+                    }
+                }
+            };
+            result = Some(match result {
+                Some(result) => result.intersection(&docs).copied().collect(),
+                None => docs,
+            });
+        }
+        result
+            .unwrap_or_default()
+            .iter()
+            .map(|doc| self.docs[doc].clone())
+            .collect()
+    }
+
+    /// Like `search_ids`, but returns the matching messages. Messages that
+    /// have disappeared from the maildir since the last `update` are left
+    /// out.
+    pub fn search(&self, query: &str) -> Result<Vec<MailEntry>, IndexError> {
+        let ids: HashSet<String> = self.search_ids(query).into_iter().collect();
+        let mut entries = Vec::new();
+        if ids.is_empty() {
+            return Ok(entries);
+        }
+        for entry in self.maildir.list_new().chain(self.maildir.list_cur()) {
+            let entry = entry?;
+            if ids.contains(entry.id()) {
+                entries.push(entry);
+            }
+        }
+        Ok(entries)
+    }
+}
+
+/// Escapes the backslashes and line and field separators in a message id,
+/// which are allowed in file names but would break the index format.
+fn escape_id(id: &str) -> String {
+    id.replace('\\', "\\\\")
+        .replace('\t', "\\t")
+        .replace('\n', "\\n")
+        .replace('\r', "\\r")
+}
+
+/// Reverses `escape_id`, returning `None` for an invalid escape.
+fn unescape_id(escaped: &str) -> Option<String> {
+    let mut id = String::with_capacity(escaped.len());
+    let mut chars = escaped.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            id.push(c);
+            continue;
+        }
+        id.push(match chars.next()? {
+            '\\' => '\\',
+            't' => '\t',
+            'n' => '\n',
+            'r' => '\r',
+            _ => return None,
+        });
+    }
+    Some(id)
+}
+
+/// Splits text into lowercase words.
+fn words(text: &str) -> impl Iterator<Item = String> + '_ {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|w| !w.is_empty() && w.len() <= MAX_WORD_LEN)
+        .map(str::to_lowercase)
+}
+
+fn collect_words(mail: &ParsedMail, out: &mut HashSet<String>) {
+    for header in &mail.headers {
+        out.extend(words(&header.get_value()));
+    }
+    if mail.subparts.is_empty() {
+        if mail.ctype.mimetype.starts_with("text/") {
+            if let Ok(body) = mail.get_body() {
+                out.extend(words(&body));
+            }
+        }
+    } else {
+        for part in &mail.subparts {
+            collect_words(part, out);
+        }
+    }
+}
@@ -3,6 +3,39 @@
 #[cfg(feature = "mmap")]
 extern crate memmap2;
 
+#[cfg(any(feature = "pop3", feature = "imap"))]
+pub mod auth;
+pub mod backup;
+#[cfg(feature = "compression")]
+pub mod compression;
+mod datetime;
+pub mod delivery;
+pub mod diff;
+#[cfg(feature = "export")]
+pub mod export;
+pub mod fault;
+#[cfg(feature = "fsck")]
+pub mod fsck;
+#[cfg(feature = "imap")]
+pub mod imap;
+#[cfg(feature = "index")]
+pub mod index;
+#[cfg(feature = "lmtp")]
+pub mod lmtp;
+#[cfg(feature = "pop3")]
+pub mod pop3;
+pub mod retention;
+#[cfg(feature = "rules")]
+pub mod rules;
+pub mod search;
+#[cfg(any(feature = "pop3", feature = "imap", feature = "lmtp"))]
+mod server;
+#[cfg(feature = "sieve")]
+pub mod sieve;
+pub mod sync;
+pub mod thread;
+
+use std::collections::BTreeMap;
 use std::error;
 use std::fmt;
 use std::fs;
@@ -10,23 +43,28 @@ use std::io::prelude::*;
 use std::io::ErrorKind;
 use std::ops::Deref;
 #[cfg(unix)]
-use std::os::unix::fs::MetadataExt;
-#[cfg(windows)]
-use std::os::windows::fs::MetadataExt;
+use std::os::unix::fs::{DirBuilderExt, MetadataExt, OpenOptionsExt, PermissionsExt};
 use std::path::{Path, PathBuf};
 use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
 use std::time;
 
 static COUNTER: AtomicUsize = AtomicUsize::new(0);
 
+// How many names are tried for a new message file before giving up, in case
+// a `UniqueNameGenerator` keeps producing names that are already taken.
+const MAX_NAME_ATTEMPTS: usize = 64;
+
 use mailparse::*;
 
+use fault::StoreStep;
+
 #[cfg(unix)]
-const INFORMATIONAL_SUFFIX_SEPARATOR: &str = ":";
+pub(crate) const INFORMATIONAL_SUFFIX_SEPARATOR: &str = ":";
 #[cfg(windows)]
-const INFORMATIONAL_SUFFIX_SEPARATOR: &str = ";";
+pub(crate) const INFORMATIONAL_SUFFIX_SEPARATOR: &str = ";";
 /// List of the Maildir subfolders which are required to exist
-pub const MAILDIR_FOLDER_LIST: &'static [&'static str] = &["cur", "new", "tmp"];
+pub const MAILDIR_FOLDER_LIST: &[&str] = &["cur", "new", "tmp"];
 
 #[derive(Debug)]
 pub enum MailEntryError {
@@ -75,7 +113,8 @@ impl From<&'static str> for MailEntryError {
 
 enum MailData {
     None,
-    #[cfg(not(feature = "mmap"))]
+    // compressed messages are decompressed into memory even with mmap
+    #[cfg(any(not(feature = "mmap"), feature = "compression"))]
     Bytes(Vec<u8>),
     #[cfg(feature = "mmap")]
     File(memmap2::Mmap),
@@ -83,19 +122,16 @@ enum MailData {
 
 impl MailData {
     fn is_none(&self) -> bool {
-        match self {
-            MailData::None => true,
-            _ => false,
-        }
+        matches!(self, MailData::None)
     }
 
     fn as_bytes(&self) -> Option<&[u8]> {
         match self {
             Self::None => None,
-            #[cfg(not(feature = "mmap"))]
-            Self::Bytes(buf) => Some(&buf),
+            #[cfg(any(not(feature = "mmap"), feature = "compression"))]
+            Self::Bytes(buf) => Some(buf),
             #[cfg(feature = "mmap")]
-            Self::File(buf) => Some(&buf),
+            Self::File(buf) => Some(buf),
         }
     }
 }
@@ -103,7 +139,7 @@ impl MailData {
 impl fmt::Debug for MailData {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         // Debug as a string rather than a byte slice
-        let mapped = self.as_bytes().map(|buf| String::from_utf8_lossy(&buf));
+        let mapped = self.as_bytes().map(|buf| String::from_utf8_lossy(buf));
         fmt::Debug::fmt(&mapped, f)
     }
 }
@@ -122,6 +158,16 @@ pub struct MailEntry {
 }
 
 impl MailEntry {
+    // Creates an entry for the message file at `path`, without reading it.
+    pub(crate) fn new(id: String, flags: String, path: PathBuf) -> MailEntry {
+        MailEntry {
+            id,
+            flags,
+            path,
+            data: MailData::None,
+        }
+    }
+
     pub fn id(&self) -> &str {
         &self.id
     }
@@ -142,30 +188,44 @@ impl MailEntry {
                 f.read_to_end(&mut d)?;
                 self.data = MailData::Bytes(d);
             }
+
+            #[cfg(feature = "compression")]
+            {
+                let data = self.data.as_bytes().unwrap_or_default();
+                let decompressed = if self.folder_name() == "new" {
+                    // without flags to go by, a file that merely starts
+                    // like a compressed one is taken as it is
+                    compression::decompress(data).unwrap_or(None)
+                } else if self.flags.contains(compression::COMPRESSED_FLAG) {
+                    compression::decompress(data)?
+                } else {
+                    None
+                };
+                if let Some(d) = decompressed {
+                    self.data = MailData::Bytes(d);
+                }
+            }
         }
         Ok(())
     }
 
-    pub fn parsed(&mut self) -> Result<ParsedMail, MailEntryError> {
+    /// Returns the content of the message file. With the `compression`
+    /// feature, messages in `cur` with the `Z` flag are decompressed, as are
+    /// messages in `new` that are compressed.
+    pub fn data(&mut self) -> std::io::Result<&[u8]> {
         self.read_data()?;
-        match self.data {
-            MailData::None => panic!("read_data should have returned an Err!"),
-            #[cfg(not(feature = "mmap"))]
-            MailData::Bytes(ref b) => parse_mail(b).map_err(MailEntryError::ParseError),
-            #[cfg(feature = "mmap")]
-            MailData::File(ref m) => parse_mail(m).map_err(MailEntryError::ParseError),
-        }
+        Ok(self
+            .data
+            .as_bytes()
+            .expect("read_data should have returned an Err!"))
     }
 
-    pub fn headers(&mut self) -> Result<Vec<MailHeader>, MailEntryError> {
-        self.read_data()?;
-        let headers = match self.data {
-            MailData::None => panic!("read_data should have returned an Err!"),
-            #[cfg(not(feature = "mmap"))]
-            MailData::Bytes(ref b) => parse_headers(b),
-            #[cfg(feature = "mmap")]
-            MailData::File(ref m) => parse_headers(m),
-        };
+    pub fn parsed(&mut self) -> Result<ParsedMail<'_>, MailEntryError> {
+        parse_mail(self.data()?).map_err(MailEntryError::ParseError)
+    }
+
+    pub fn headers(&mut self) -> Result<Vec<MailHeader<'_>>, MailEntryError> {
+        let headers = parse_headers(self.data()?);
         headers.map(|(v, _)| v).map_err(MailEntryError::ParseError)
     }
 
@@ -177,7 +237,7 @@ impl MailEntry {
             Some(v) => v
                 .rsplit(';')
                 .nth(0)
-                .ok_or_else(|| MailEntryError::DateError("Unable to split Received header"))
+                .ok_or(MailEntryError::DateError("Unable to split Received header"))
                 .and_then(|ts| dateparse(ts).map_err(MailEntryError::from)),
             None => Err("No Received header found")?,
         }
@@ -193,6 +253,58 @@ impl MailEntry {
         }
     }
 
+    /// Returns the delivery time of the message, taken from the timestamp
+    /// at the start of its file name, as used by `Maildir::store_new` and
+    /// most other programs delivering to maildirs. Returns `None` if the
+    /// file name doesn't start with a timestamp.
+    pub fn delivery_time(&self) -> Option<i64> {
+        let end = self
+            .id
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(self.id.len());
+        self.id[..end].parse().ok()
+    }
+
+    /// Returns the date of the message, trying the sources in
+    /// `DateSource::DEFAULT_CHAIN` in order until one of them yields a
+    /// date. Unlike `date`, this only fails if none of the sources do.
+    pub fn best_date(&mut self) -> Result<ResolvedDate, MailEntryError> {
+        self.best_date_with(&DateSource::DEFAULT_CHAIN)
+    }
+
+    /// Returns the date of the message, trying the given sources in order
+    /// until one of them yields a date.
+    pub fn best_date_with(&mut self, chain: &[DateSource]) -> Result<ResolvedDate, MailEntryError> {
+        for &source in chain {
+            let timestamp = match source {
+                DateSource::DateHeader => self.date().ok(),
+                DateSource::NewestReceived | DateSource::OldestReceived => {
+                    let mut received = match self.headers() {
+                        Ok(headers) => headers.get_all_values("Received"),
+                        Err(_) => continue,
+                    };
+                    if source == DateSource::OldestReceived {
+                        received.reverse();
+                    }
+                    received
+                        .iter()
+                        .filter_map(|v| v.rsplit_once(';').map(|(_, ts)| ts))
+                        .find_map(|ts| dateparse(ts).ok())
+                }
+                DateSource::Delivery => self.delivery_time(),
+                DateSource::Mtime => fs::metadata(&self.path)
+                    .and_then(|m| m.modified())
+                    .ok()
+                    .and_then(|t| t.duration_since(time::UNIX_EPOCH).ok())
+                    .map(|d| d.as_secs() as i64),
+            };
+            if let Some(timestamp) = timestamp {
+                return Ok(ResolvedDate { timestamp, source });
+            }
+        }
+        Err("No date found in any of the sources")?
+    }
+
     pub fn flags(&self) -> &str {
         &self.flags
     }
@@ -224,6 +336,289 @@ impl MailEntry {
     pub fn path(&self) -> &PathBuf {
         &self.path
     }
+
+    /// Returns the name of the folder the message is in, `new` or `cur`.
+    fn folder_name(&self) -> &'static str {
+        match self.path.parent().and_then(|p| p.file_name()) {
+            Some(name) if name == "new" => "new",
+            _ => "cur",
+        }
+    }
+
+    /// Returns the value of a `,<key>=<value>` attribute in the id, like
+    /// the `,S=<size>` attribute added by `Maildir::store_new`.
+    fn numeric_attribute(&self, key: &str) -> Option<u64> {
+        self.id
+            .split(',')
+            .skip(1)
+            .filter_map(|attr| attr.strip_prefix(key)?.strip_prefix('='))
+            .find_map(|value| value.parse().ok())
+    }
+
+    /// Returns the size of the message in bytes. This is taken from the
+    /// `,S=` attribute in the file name if present, and from the file
+    /// system otherwise, which for a compressed message without the
+    /// attribute is the compressed size.
+    pub fn size(&self) -> std::io::Result<u64> {
+        match self.numeric_attribute("S") {
+            Some(size) => Ok(size),
+            None => Ok(fs::metadata(&self.path)?.len()),
+        }
+    }
+
+    // Returns the size of the message if it is known without decompressing
+    // it: from the `,S=` attribute, or from the file system for a file that
+    // isn't compressed.
+    pub(crate) fn known_size(&self) -> std::io::Result<Option<u64>> {
+        if let Some(size) = self.numeric_attribute("S") {
+            return Ok(Some(size));
+        }
+        #[cfg(feature = "compression")]
+        if self.is_compressed()? {
+            return Ok(None);
+        }
+        Ok(Some(fs::metadata(&self.path)?.len()))
+    }
+
+    // Returns whether the message file is compressed. Files in `cur` are
+    // marked with the `Z` flag; files in `new` have no flags, so their
+    // content has to tell.
+    #[cfg(feature = "compression")]
+    pub(crate) fn is_compressed(&self) -> std::io::Result<bool> {
+        if self.folder_name() == "new" {
+            return compression::is_compressed_file(&self.path);
+        }
+        Ok(self.flags.contains(compression::COMPRESSED_FLAG))
+    }
+
+    // Opens the message file for reading. With the `compression` feature,
+    // a compressed message is decompressed while it is read.
+    pub(crate) fn open(&self) -> std::io::Result<Box<dyn Read>> {
+        let file = fs::File::open(&self.path)?;
+        #[cfg(feature = "compression")]
+        if self.is_compressed()? {
+            return compression::decoder(file);
+        }
+        Ok(Box::new(file))
+    }
+
+    /// Returns the size of the message and its RFC822 size, which is the
+    /// size with all line endings counted as CRLF, as needed for the IMAP
+    /// `RFC822.SIZE`. The sizes are taken from the `,S=` and `,W=`
+    /// attributes in the file name where present, and computed otherwise,
+    /// which for the RFC822 size means reading the message.
+    pub fn sizes(&mut self) -> Result<MailSizes, MailEntryError> {
+        let size = self.size()?;
+        let rfc822_size = match self.numeric_attribute("W") {
+            Some(rfc822_size) => rfc822_size,
+            None => {
+                self.read_data()?;
+                rfc822_size(self.data.as_bytes().unwrap_or_default())
+            }
+        };
+        Ok(MailSizes { size, rfc822_size })
+    }
+
+    /// Returns the metadata of the message in a plain struct, along with
+    /// the first value of each of the given headers that the message has.
+    /// The message is only read if headers are requested.
+    pub fn summary(&mut self, headers: &[&str]) -> Result<MailSummary, MailEntryError> {
+        let mut summary = MailSummary {
+            id: self.id.clone(),
+            folder: self.folder_name().to_string(),
+            flags: self.flags.clone(),
+            path: self.path.clone(),
+            size: self.size()?,
+            delivery_time: self.delivery_time(),
+            headers: BTreeMap::new(),
+        };
+        if !headers.is_empty() {
+            let parsed = self.headers()?;
+            for &name in headers {
+                if let Some(value) = parsed.get_first_value(name) {
+                    summary.headers.insert(name.to_string(), value);
+                }
+            }
+        }
+        Ok(summary)
+    }
+}
+
+/// Serializes the message as its `MailSummary` without any headers. This
+/// needs the size of the message, so it can fail if the size is not in the
+/// file name and the file cannot be accessed.
+#[cfg(feature = "serde")]
+impl serde::Serialize for MailEntry {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let summary = MailSummary {
+            id: self.id.clone(),
+            folder: self.folder_name().to_string(),
+            flags: self.flags.clone(),
+            path: self.path.clone(),
+            size: self.size().map_err(serde::ser::Error::custom)?,
+            delivery_time: self.delivery_time(),
+            headers: BTreeMap::new(),
+        };
+        summary.serialize(serializer)
+    }
+}
+
+/// The metadata of a message, as returned by `MailEntry::summary`. Unlike
+/// `MailEntry`, this is a plain struct that can be stored or, with the
+/// `serde` feature, serialized.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct MailSummary {
+    pub id: String,
+    /// The folder the message is in, `new` or `cur`.
+    pub folder: String,
+    pub flags: String,
+    pub path: PathBuf,
+    /// The size of the message in bytes, see `MailEntry::size`.
+    pub size: u64,
+    /// See `MailEntry::delivery_time`.
+    pub delivery_time: Option<i64>,
+    /// The requested headers that the message has, keyed by the names
+    /// they were requested with.
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, skip_serializing_if = "BTreeMap::is_empty")
+    )]
+    pub headers: BTreeMap<String, String>,
+}
+
+/// A source for the date of a message, see `MailEntry::best_date_with`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum DateSource {
+    /// The `Date` header, set by the sender.
+    DateHeader,
+    /// The topmost `Received` header with a valid date, added by the last
+    /// server that handled the message.
+    NewestReceived,
+    /// The bottommost `Received` header with a valid date, added by the
+    /// first server that handled the message.
+    OldestReceived,
+    /// The delivery timestamp in the file name, see
+    /// `MailEntry::delivery_time`.
+    Delivery,
+    /// The modification time of the message file.
+    Mtime,
+}
+
+impl DateSource {
+    /// The sources tried by `MailEntry::best_date`, in order.
+    pub const DEFAULT_CHAIN: [DateSource; 5] = [
+        DateSource::DateHeader,
+        DateSource::NewestReceived,
+        DateSource::OldestReceived,
+        DateSource::Delivery,
+        DateSource::Mtime,
+    ];
+}
+
+/// A message date along with where it came from, as returned by
+/// `MailEntry::best_date`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ResolvedDate {
+    /// The date, in seconds since the Unix epoch.
+    pub timestamp: i64,
+    pub source: DateSource,
+}
+
+/// A summary of the messages in a maildir, as returned by
+/// `Maildir::stats`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct MaildirStats {
+    /// The number of messages in the `new` folder.
+    pub new: usize,
+    /// The number of messages in the `cur` folder.
+    pub cur: usize,
+    /// The total size of all messages in bytes, see `MailEntry::size`.
+    pub size: u64,
+    /// The number of messages and their total size for each flag that is
+    /// set on at least one message.
+    pub flags: BTreeMap<char, FlagStats>,
+    /// The earliest delivery time of any message, see
+    /// `MailEntry::delivery_time`.
+    pub oldest_delivery: Option<i64>,
+    /// The latest delivery time of any message.
+    pub newest_delivery: Option<i64>,
+}
+
+impl MaildirStats {
+    /// Returns the total number of messages.
+    pub fn count(&self) -> usize {
+        self.new + self.cur
+    }
+
+    /// Adds the statistics of another maildir to these.
+    pub fn merge(&mut self, other: &MaildirStats) {
+        self.new += other.new;
+        self.cur += other.cur;
+        self.size += other.size;
+        for (&flag, stats) in &other.flags {
+            let entry = self.flags.entry(flag).or_default();
+            entry.count += stats.count;
+            entry.size += stats.size;
+        }
+        self.oldest_delivery = match (self.oldest_delivery, other.oldest_delivery) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        };
+        self.newest_delivery = match (self.newest_delivery, other.newest_delivery) {
+            (Some(a), Some(b)) => Some(a.max(b)),
+            (a, b) => a.or(b),
+        };
+    }
+
+    fn add(&mut self, entry: &MailEntry) -> std::io::Result<()> {
+        let size = entry.size()?;
+        self.size += size;
+        for flag in entry.flags().chars() {
+            let stats = self.flags.entry(flag).or_default();
+            stats.count += 1;
+            stats.size += size;
+        }
+        if let Some(delivered) = entry.delivery_time() {
+            self.oldest_delivery =
+                Some(self.oldest_delivery.map_or(delivered, |t| t.min(delivered)));
+            self.newest_delivery =
+                Some(self.newest_delivery.map_or(delivered, |t| t.max(delivered)));
+        }
+        Ok(())
+    }
+}
+
+/// The number of messages with a flag and their total size, see
+/// `MaildirStats`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct FlagStats {
+    pub count: usize,
+    pub size: u64,
+}
+
+/// The sizes of a message, as returned by `MailEntry::sizes`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct MailSizes {
+    /// The size of the message file in bytes.
+    pub size: u64,
+    /// The size of the message with every line ending counted as CRLF.
+    pub rfc822_size: u64,
+}
+
+/// Computes the size of the data if every bare LF were replaced with CRLF.
+fn rfc822_size(data: &[u8]) -> u64 {
+    let bare_lfs = data
+        .iter()
+        .enumerate()
+        .filter(|&(i, &b)| b == b'\n' && (i == 0 || data[i - 1] != b'\r'))
+        .count();
+    (data.len() + bare_lfs) as u64
 }
 
 #[derive(Debug)]
@@ -295,12 +690,11 @@ impl Iterator for MailEntries {
                         "Non-maildir file found in maildir",
                     ));
                 }
-                Ok(Some(MailEntry {
-                    id: String::from(id.unwrap()),
-                    flags: String::from(flags.unwrap()),
-                    path: entry.path(),
-                    data: MailData::None,
-                }))
+                Ok(Some(MailEntry::new(
+                    String::from(id.unwrap()),
+                    String::from(flags.unwrap()),
+                    entry.path(),
+                )))
             });
             return match result {
                 None => None,
@@ -318,6 +712,10 @@ pub enum MaildirError {
     Utf8(std::str::Utf8Error),
     Time(std::time::SystemTimeError),
     InvalidFolderName(std::string::String),
+    MailEntry(MailEntryError),
+    /// The message was stored under the given id, but flushing a directory to disk
+    /// afterwards failed. The message should not be stored again.
+    NotSynced(std::string::String, std::io::Error),
 }
 
 impl fmt::Display for MaildirError {
@@ -329,6 +727,8 @@ impl fmt::Display for MaildirError {
             Utf8(ref e) => write!(f, "UTF8 Encoding Error: {}", e),
             Time(ref e) => write!(f, "Time Error: {}", e),
             InvalidFolderName(ref e) => write!(f, "Invalid Folder Name: {}", e),
+            MailEntry(ref e) => write!(f, "Mail Entry Error: {}", e),
+            NotSynced(ref id, ref e) => write!(f, "Stored {} but failed to sync: {}", id, e),
         }
     }
 }
@@ -342,6 +742,8 @@ impl error::Error for MaildirError {
             Utf8(ref e) => Some(e),
             Time(ref e) => Some(e),
             InvalidFolderName(ref _e) => None,
+            MailEntry(ref e) => Some(e),
+            NotSynced(_, ref e) => Some(e),
         }
     }
 }
@@ -361,6 +763,11 @@ impl From<std::time::SystemTimeError> for MaildirError {
         MaildirError::Time(e)
     }
 }
+impl From<MailEntryError> for MaildirError {
+    fn from(e: MailEntryError) -> MaildirError {
+        MaildirError::MailEntry(e)
+    }
+}
 
 /// An iterator over the maildir subdirectories. This iterator
 /// produces a `std::io::Result<Maildir>`, which can be an
@@ -370,13 +777,15 @@ impl From<std::time::SystemTimeError> for MaildirError {
 #[derive(Debug)]
 pub struct MaildirEntries {
     path: PathBuf,
+    options: MaildirOptions,
     readdir: Option<fs::ReadDir>,
 }
 
 impl MaildirEntries {
-    fn new(path: PathBuf) -> MaildirEntries {
+    fn new(path: PathBuf, options: MaildirOptions) -> MaildirEntries {
         MaildirEntries {
             path,
+            options,
             readdir: None,
         }
     }
@@ -412,6 +821,7 @@ impl Iterator for MaildirEntries {
 
                 Ok(Some(Maildir {
                     path: self.path.join(filename),
+                    options: self.options.clone(),
                 }))
             });
 
@@ -425,13 +835,162 @@ impl Iterator for MaildirEntries {
     }
 }
 
+/// How much effort a `Maildir` puts into making its changes survive a
+/// crash or power loss.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Durability {
+    /// Nothing is explicitly flushed to disk; it is left to the operating
+    /// system to write out the data eventually.
+    None,
+    /// Message files are flushed to disk before they are renamed into
+    /// `new` or `cur`. The renames themselves are not flushed, so after a
+    /// power loss a message may still be missing from its folder on some
+    /// filesystems (e.g. ext4 or xfs). This is the default.
+    #[default]
+    File,
+    /// In addition to flushing message files, the directories affected by
+    /// a rename or a deletion are flushed afterwards. Once a storing,
+    /// moving or deleting operation returns, its result is on disk. When
+    /// messages move between two maildirs, each directory is flushed
+    /// according to the level of the maildir it belongs to. On platforms other than
+    /// Unix directories cannot be flushed, and this behaves like `File`.
+    FileAndDirectory,
+}
+
+/// Settings that affect how a `Maildir` writes to the filesystem. These
+/// are inherited by the `Maildir` instances created for its subfolders.
+#[derive(Clone, Debug, Default)]
+struct MaildirOptions {
+    durability: Durability,
+    #[cfg(unix)]
+    file_mode: Option<u32>,
+    #[cfg(unix)]
+    dir_mode: Option<u32>,
+    #[cfg(unix)]
+    group: Option<u32>,
+    hostname: Option<String>,
+    name_generator: Option<Arc<dyn UniqueNameGenerator>>,
+    no_size_attributes: bool,
+    #[cfg(feature = "compression")]
+    compression: Option<compression::Compression>,
+    #[cfg(feature = "fault-injection")]
+    faults: Option<Arc<fault::FaultInjector>>,
+}
+
 /// The main entry point for this library. This struct can be
 /// instantiated from a path using the `from` implementations.
 /// The path passed in to the `from` should be the root of the
 /// maildir (the folder containing `cur`, `new`, and `tmp`).
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct Maildir {
     path: PathBuf,
+    options: MaildirOptions,
+}
+
+/// A builder for a `Maildir` with non-default settings. The settings
+/// are inherited by the `Maildir` instances created for its subfolders.
+///
+/// ```no_run
+/// use maildir::{Durability, MaildirBuilder};
+///
+/// let maildir = MaildirBuilder::new("path/to/maildir")
+///     .durability(Durability::FileAndDirectory)
+///     .build();
+/// ```
+#[derive(Debug)]
+pub struct MaildirBuilder {
+    path: PathBuf,
+    options: MaildirOptions,
+}
+
+impl MaildirBuilder {
+    /// Starts building a `Maildir` rooted at the given path.
+    pub fn new<P: Into<PathBuf>>(path: P) -> MaildirBuilder {
+        MaildirBuilder {
+            path: path.into(),
+            options: MaildirOptions::default(),
+        }
+    }
+
+    /// Sets the durability level, see `Maildir::set_durability`.
+    pub fn durability(mut self, durability: Durability) -> MaildirBuilder {
+        self.options.durability = durability;
+        self
+    }
+
+    /// Sets the permission bits of message files written to the maildir,
+    /// e.g. `0o600`. The bits are applied exactly, regardless of the umask
+    /// of the process. By default the umask decides.
+    #[cfg(unix)]
+    pub fn file_mode(mut self, mode: u32) -> MaildirBuilder {
+        self.options.file_mode = Some(mode);
+        self
+    }
+
+    /// Sets the permission bits of directories created by `create_dirs`
+    /// and `create_subfolder_dirs`, e.g. `0o700`. The bits are applied
+    /// exactly, regardless of the umask of the process. Directories that
+    /// already exist are left alone.
+    #[cfg(unix)]
+    pub fn dir_mode(mut self, mode: u32) -> MaildirBuilder {
+        self.options.dir_mode = Some(mode);
+        self
+    }
+
+    /// Sets the group owning message files written to the maildir and
+    /// directories created by it. Changing to a group the process is not
+    /// a member of requires sufficient privileges.
+    #[cfg(unix)]
+    pub fn group(mut self, gid: u32) -> MaildirBuilder {
+        self.options.group = Some(gid);
+        self
+    }
+
+    /// Sets the hostname used in the names of message files, instead of
+    /// the hostname of the machine. Characters that are not allowed in a
+    /// maildir file name are escaped.
+    pub fn hostname<S: Into<String>>(mut self, hostname: S) -> MaildirBuilder {
+        self.options.hostname = Some(hostname.into());
+        self
+    }
+
+    /// Sets the generator used to name message files. By default the
+    /// `DefaultNameGenerator` is used.
+    pub fn name_generator<G: UniqueNameGenerator + 'static>(
+        mut self,
+        generator: G,
+    ) -> MaildirBuilder {
+        self.options.name_generator = Some(Arc::new(generator));
+        self
+    }
+
+    /// Sets whether the size of a message is appended to its file name as
+    /// a `,S=<size>` attribute, followed by its RFC822 size (with CRLF line
+    /// endings) as a `,W=<size>` attribute. This is on by default.
+    ///
+    /// With `compression` set, the attributes are written even when this is
+    /// turned off, as the size of a compressed file isn't the size of the
+    /// message.
+    pub fn size_attributes(mut self, enabled: bool) -> MaildirBuilder {
+        self.options.no_size_attributes = !enabled;
+        self
+    }
+
+    /// Sets how stored messages are compressed, see the `compression`
+    /// module. By default messages are stored uncompressed.
+    #[cfg(feature = "compression")]
+    pub fn compression(mut self, compression: compression::Compression) -> MaildirBuilder {
+        self.options.compression = Some(compression);
+        self
+    }
+
+    pub fn build(self) -> Maildir {
+        Maildir {
+            path: self.path,
+            options: self.options,
+        }
+    }
 }
 
 impl Maildir {
@@ -449,7 +1008,31 @@ impl Maildir {
             )));
         }
         let new_path = self.path.join(subfolder);
-        Ok(Maildir { path: new_path })
+        Ok(Maildir {
+            path: new_path,
+            options: self.options.clone(),
+        })
+    }
+
+    /// Returns the durability level used when writing to this maildir.
+    pub fn durability(&self) -> Durability {
+        self.options.durability
+    }
+
+    /// Sets the durability level used by `store_new`, `store_cur_with_flags`,
+    /// `move_new_to_cur`, `move_to`, `copy_to`, `delete` and the flag
+    /// updating functions. Subfolders created from this maildir afterwards inherit
+    /// the setting.
+    pub fn set_durability(&mut self, durability: Durability) {
+        self.options.durability = durability;
+    }
+
+    /// Attaches a fault injector that is consulted before each filesystem
+    /// step of storing a message. Subfolders created from this maildir
+    /// afterwards share the same injector. See the `fault` module.
+    #[cfg(feature = "fault-injection")]
+    pub fn set_fault_injector(&mut self, injector: Arc<fault::FaultInjector>) {
+        self.options.faults = Some(injector);
     }
 
     /// Returns the number of messages found inside the `new`
@@ -464,6 +1047,33 @@ impl Maildir {
         self.list_cur().count()
     }
 
+    /// Returns statistics about the messages in the `new` and `cur`
+    /// maildir folders, gathered in a single pass over both. Sizes are
+    /// taken from the `,S=` attribute in the file names where present, and
+    /// from the file system otherwise; the messages themselves are not read.
+    pub fn stats(&self) -> std::io::Result<MaildirStats> {
+        let mut stats = MaildirStats::default();
+        for entry in self.list_new() {
+            stats.add(&entry?)?;
+            stats.new += 1;
+        }
+        for entry in self.list_cur() {
+            stats.add(&entry?)?;
+            stats.cur += 1;
+        }
+        Ok(stats)
+    }
+
+    /// Like `stats`, but includes the messages of all the subfolders
+    /// returned by `list_subdirs` in the totals.
+    pub fn stats_recursive(&self) -> std::io::Result<MaildirStats> {
+        let mut stats = self.stats()?;
+        for subdir in self.list_subdirs() {
+            stats.merge(&subdir?.stats()?);
+        }
+        Ok(stats)
+    }
+
     /// Returns an iterator over the messages inside the `new`
     /// maildir folder. The order of messages in the iterator
     /// is not specified, and is not guaranteed to be stable
@@ -480,12 +1090,40 @@ impl Maildir {
         MailEntries::new(self.path.clone(), Subfolder::Cur)
     }
 
+    /// Returns an iterator over the messages inside the `new` and `cur`
+    /// maildir folders that match the given query. See the `search`
+    /// module for how to build or parse queries. The order of messages in
+    /// the iterator is not specified.
+    pub fn search<'q>(&self, query: &'q search::Query) -> search::SearchResults<'q> {
+        search::SearchResults::new(query, self.list_new(), self.list_cur())
+    }
+
+    /// Applies a retention policy to the `new` and `cur` maildir folders,
+    /// deleting or archiving old messages. See the `retention` module.
+    pub fn apply_retention(
+        &self,
+        policy: &retention::RetentionPolicy,
+    ) -> Result<retention::RetentionReport, MaildirError> {
+        policy.apply(self)
+    }
+
+    /// Applies a rule set to the `new` and `cur` maildir folders, moving,
+    /// copying, flagging or deleting the messages it matches. See the
+    /// `rules` module.
+    #[cfg(feature = "rules")]
+    pub fn apply_rules(
+        &self,
+        rules: &rules::RuleSet,
+    ) -> Result<rules::RulesReport, rules::RulesError> {
+        rules.apply(self)
+    }
+
     /// Returns an iterator over the maildir subdirectories.
     /// The order of subdirectories in the iterator
     /// is not specified, and is not guaranteed to be stable
     /// over multiple invocations of this method.
     pub fn list_subdirs(&self) -> MaildirEntries {
-        MaildirEntries::new(self.path.clone())
+        MaildirEntries::new(self.path.clone(), self.options.clone())
     }
 
     /// Moves a message from the `new` maildir folder to the
@@ -501,31 +1139,49 @@ impl Maildir {
     /// The possible flags are described e.g. at <https://cr.yp.to/proto/maildir.html> or
     /// <http://www.courier-mta.org/maildir.html>.
     pub fn move_new_to_cur_with_flags(&self, id: &str, flags: &str) -> std::io::Result<()> {
+        self.move_new_to_cur_entry(id, flags).map(|_| ())
+    }
+
+    // Like move_new_to_cur_with_flags, returning the entry of the moved
+    // message.
+    fn move_new_to_cur_entry(&self, id: &str, flags: &str) -> std::io::Result<MailEntry> {
         let src = self.path.join("new").join(id);
+        #[cfg(feature = "compression")]
+        let flags = &if compression::is_compressed_file(&src)? {
+            format!("{}{}", flags, compression::COMPRESSED_FLAG)
+        } else {
+            flags.to_string()
+        };
+        let flags = Self::normalize_flags(flags);
         let dst = self.path.join("cur").join(format!(
             "{}{}2,{}",
-            id,
-            INFORMATIONAL_SUFFIX_SEPARATOR,
-            Self::normalize_flags(flags)
+            id, INFORMATIONAL_SUFFIX_SEPARATOR, flags
         ));
-        fs::rename(src, dst)
+        fs::rename(src, &dst)?;
+        self.sync_dirs(&[&self.path.join("cur"), &self.path.join("new")])?;
+        Ok(MailEntry::new(id.to_string(), flags, dst))
     }
 
     /// Copies a message from the current maildir to the targetted maildir.
+    /// The copy is placed in the `cur` folder of the target; a message in
+    /// `new` gets no flags there, as with `move_new_to_cur`.
     pub fn copy_to(&self, id: &str, target: &Maildir) -> std::io::Result<()> {
         let entry = self.find(id).ok_or_else(|| {
             std::io::Error::new(std::io::ErrorKind::NotFound, "Mail entry not found")
         })?;
-        let filename = entry.path().file_name().ok_or_else(|| {
-            std::io::Error::new(
-                std::io::ErrorKind::InvalidData,
-                "Invalid mail entry file name",
-            )
-        })?;
+        self.copy_entry_to(&entry, target)
+    }
+
+    // Like copy_to, for a message that was already looked up.
+    pub(crate) fn copy_entry_to(&self, entry: &MailEntry, target: &Maildir) -> std::io::Result<()> {
+        let dst_path = target.path().join("cur").join(Self::cur_file_name(entry)?);
+        target.copy_file(entry.path(), &dst_path)
+    }
 
-        let src_path = entry.path();
-        let dst_path = target.path().join("cur").join(filename);
-        if src_path == &dst_path {
+    // Copies the message file at `src_path` to `dst_path` in this maildir,
+    // applying its file permissions and durability.
+    pub(crate) fn copy_file(&self, src_path: &Path, dst_path: &Path) -> std::io::Result<()> {
+        if src_path == dst_path {
             return Err(std::io::Error::new(
                 std::io::ErrorKind::InvalidInput,
                 "Target maildir needs to be different from the source",
@@ -533,22 +1189,65 @@ impl Maildir {
         }
 
         fs::copy(src_path, dst_path)?;
-        Ok(())
+        #[cfg(unix)]
+        self.apply_file_permissions(&fs::File::open(dst_path)?)?;
+        if self.options.durability != Durability::None {
+            fs::File::open(dst_path)?.sync_all()?;
+        }
+        self.sync_dirs(&[dst_path.parent().unwrap_or(&self.path)])
     }
 
     /// Moves a message from the current maildir to the targetted maildir.
+    /// The message is placed in the `cur` folder of the target; a message
+    /// in `new` gets no flags there, as with `move_new_to_cur`.
     pub fn move_to(&self, id: &str, target: &Maildir) -> std::io::Result<()> {
         let entry = self.find(id).ok_or_else(|| {
             std::io::Error::new(std::io::ErrorKind::NotFound, "Mail entry not found")
         })?;
-        let filename = entry.path().file_name().ok_or_else(|| {
+        self.move_entry_to(&entry, target)
+    }
+
+    // Like move_to, for a message that was already looked up.
+    pub(crate) fn move_entry_to(&self, entry: &MailEntry, target: &Maildir) -> std::io::Result<()> {
+        let src_dir = entry.path().parent().unwrap_or(&self.path);
+        let dst_dir = target.path().join("cur");
+        fs::rename(entry.path(), dst_dir.join(Self::cur_file_name(entry)?))?;
+        target.sync_dirs(&[&dst_dir])?;
+        self.sync_dirs(&[src_dir])
+    }
+
+    // Returns the name a message gets in the `cur` folder of the maildir it
+    // is copied or moved to. A file in `cur` without an info part is not a
+    // valid message, so one from `new` gets an empty set of flags.
+    fn cur_file_name(entry: &MailEntry) -> std::io::Result<std::ffi::OsString> {
+        if entry.folder_name() == "cur" {
+            return Self::file_name(entry).map(|name| name.to_os_string());
+        }
+        #[cfg(feature = "compression")]
+        let flags = if entry.is_compressed()? {
+            compression::COMPRESSED_FLAG.to_string()
+        } else {
+            String::new()
+        };
+        #[cfg(not(feature = "compression"))]
+        let flags = "";
+        Ok(format!(
+            "{}{}2,{}",
+            entry.id(),
+            INFORMATIONAL_SUFFIX_SEPARATOR,
+            flags
+        )
+        .into())
+    }
+
+    // Returns the file name of a message, to copy or move it under.
+    fn file_name(entry: &MailEntry) -> std::io::Result<&std::ffi::OsStr> {
+        entry.path().file_name().ok_or_else(|| {
             std::io::Error::new(
                 std::io::ErrorKind::InvalidData,
                 "Invalid mail entry file name",
             )
-        })?;
-        fs::rename(entry.path(), target.path().join("cur").join(filename))?;
-        Ok(())
+        })
     }
 
     /// Tries to find the message with the given id in the
@@ -566,13 +1265,45 @@ impl Maildir {
             .map(|e| e.unwrap())
     }
 
-    fn normalize_flags(flags: &str) -> String {
+    // Checks that the maildir and its `cur` and `new` folders can be read.
+    // Listing treats a missing `cur` or `new` as empty, so a mistyped or
+    // unmounted maildir would otherwise look like one whose messages were
+    // all deleted.
+    pub(crate) fn check_readable(&self) -> std::io::Result<()> {
+        for dir in [
+            self.path.clone(),
+            self.path.join("cur"),
+            self.path.join("new"),
+        ] {
+            if let Err(e) = fs::read_dir(&dir) {
+                return Err(std::io::Error::new(
+                    e.kind(),
+                    format!("cannot read {}: {}", dir.display(), e),
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) fn normalize_flags(flags: &str) -> String {
         let mut flag_chars = flags.chars().collect::<Vec<char>>();
         flag_chars.sort();
         flag_chars.dedup();
         flag_chars.into_iter().collect()
     }
 
+    // Returns true for flags that describe the message file rather than the
+    // message, which `set_flags` and `remove_flags` leave alone.
+    fn is_storage_flag(flag: char) -> bool {
+        #[cfg(feature = "compression")]
+        return flag == compression::COMPRESSED_FLAG;
+        #[cfg(not(feature = "compression"))]
+        {
+            let _ = flag;
+            false
+        }
+    }
+
     fn update_flags<F>(&self, id: &str, flag_op: F) -> std::io::Result<()>
     where
         F: Fn(&str) -> String,
@@ -583,18 +1314,7 @@ impl Maildir {
         };
 
         match self.list_cur().find(&filter).map(|e| e.unwrap()) {
-            Some(m) => {
-                let src = m.path();
-                let mut dst = m.path().clone();
-                dst.pop();
-                dst.push(format!(
-                    "{}{}2,{}",
-                    m.id(),
-                    INFORMATIONAL_SUFFIX_SEPARATOR,
-                    flag_op(m.flags())
-                ));
-                fs::rename(src, dst)
-            }
+            Some(mut m) => self.update_entry_flags(&mut m, flag_op),
             None => Err(std::io::Error::new(
                 std::io::ErrorKind::NotFound,
                 "Mail entry not found",
@@ -602,13 +1322,60 @@ impl Maildir {
         }
     }
 
+    // Renames a message in `cur` to carry the new flags, and updates the
+    // entry to match.
+    fn update_entry_flags<F>(&self, m: &mut MailEntry, flag_op: F) -> std::io::Result<()>
+    where
+        F: Fn(&str) -> String,
+    {
+        let flags = flag_op(m.flags());
+        let mut dst = m.path().clone();
+        dst.pop();
+        dst.push(format!(
+            "{}{}2,{}",
+            m.id(),
+            INFORMATIONAL_SUFFIX_SEPARATOR,
+            flags
+        ));
+        fs::rename(m.path(), &dst)?;
+        m.path = dst;
+        m.flags = flags;
+        self.sync_dirs(&[&self.path.join("cur")])
+    }
+
     /// Updates the flags for the message with the given id in the
     /// maildir. This only searches the `cur` folder, because that's
     /// the folder where messages have flags. Returns an error if the
     /// message was not found. All existing flags are overwritten with
     /// the new flags provided.
     pub fn set_flags(&self, id: &str, flags: &str) -> std::io::Result<()> {
-        self.update_flags(id, |_old_flags| Self::normalize_flags(flags))
+        self.update_flags(id, |old_flags| Self::replace_flags(old_flags, flags))
+    }
+
+    // Sets the flags of a message that was already looked up, like
+    // set_flags, moving it to `cur` first if it is in `new`. The entry is
+    // updated to match.
+    pub(crate) fn set_entry_flags(
+        &self,
+        entry: &mut MailEntry,
+        flags: &str,
+    ) -> std::io::Result<()> {
+        if entry.folder_name() == "new" {
+            *entry = self.move_new_to_cur_entry(entry.id(), flags)?;
+            Ok(())
+        } else {
+            self.update_entry_flags(entry, |old_flags| Self::replace_flags(old_flags, flags))
+        }
+    }
+
+    fn replace_flags(old_flags: &str, flags: &str) -> String {
+        let kept = old_flags.chars().filter(|&c| Self::is_storage_flag(c));
+        let flags: String = flags
+            .chars()
+            .filter(|&c| !Self::is_storage_flag(c))
+            .chain(kept)
+            .collect();
+        Self::normalize_flags(&flags)
     }
 
     /// Adds the given flags to the message with the given id in the maildir.
@@ -620,7 +1387,7 @@ impl Maildir {
             let merged = String::from(old_flags) + flags;
             Self::normalize_flags(&merged)
         };
-        self.update_flags(id, &flag_merge)
+        self.update_flags(id, flag_merge)
     }
 
     /// Removes the given flags to the message with the given id in the maildir.
@@ -629,9 +1396,13 @@ impl Maildir {
     /// If the message doesn't have the flag(s) to be removed, those flags are
     /// ignored.
     pub fn remove_flags(&self, id: &str, flags: &str) -> std::io::Result<()> {
-        let flag_strip =
-            |old_flags: &str| old_flags.chars().filter(|c| !flags.contains(*c)).collect();
-        self.update_flags(id, &flag_strip)
+        let flag_strip = |old_flags: &str| {
+            old_flags
+                .chars()
+                .filter(|&c| !flags.contains(c) || Self::is_storage_flag(c))
+                .collect()
+        };
+        self.update_flags(id, flag_strip)
     }
 
     /// Deletes the message with the given id in the maildir.
@@ -640,7 +1411,7 @@ impl Maildir {
     /// error if no message was found with the given id.
     pub fn delete(&self, id: &str) -> std::io::Result<()> {
         match self.find(id) {
-            Some(m) => fs::remove_file(m.path()),
+            Some(m) => self.delete_entry(&m),
             None => Err(std::io::Error::new(
                 std::io::ErrorKind::NotFound,
                 "Mail entry not found",
@@ -648,16 +1419,16 @@ impl Maildir {
         }
     }
 
+    // Like delete, for a message that was already looked up.
+    pub(crate) fn delete_entry(&self, entry: &MailEntry) -> std::io::Result<()> {
+        fs::remove_file(entry.path())?;
+        self.sync_dirs(&[entry.path().parent().unwrap_or(&self.path)])
+    }
+
     /// Creates all neccessary directories if they don't exist yet. It is the library user's
     /// responsibility to call this before using `store_new`.
     pub fn create_dirs(&self) -> std::io::Result<()> {
-        let mut path = self.path.clone();
-        for d in MAILDIR_FOLDER_LIST {
-            path.push(d);
-            fs::create_dir_all(path.as_path())?;
-            path.pop();
-        }
-        Ok(())
+        self.create_maildir_dirs(&self.path)
     }
 
     /// Creates all neccessary directories for a `subfolder` if they don't exist yet. It is the library user's
@@ -670,19 +1441,59 @@ impl Maildir {
             )));
         }
         let subpath = PathBuf::from(subfolder);
-        let mut path = self.path.clone().join(&subpath);
+        self.create_maildir_dirs(&self.path.join(subpath))?;
+        Ok(())
+    }
+
+    fn create_maildir_dirs(&self, root: &Path) -> std::io::Result<()> {
+        let mut path = root.to_path_buf();
+        let mut created = Vec::new();
+        if !path.is_dir() {
+            created.push(path.clone());
+        }
         for d in MAILDIR_FOLDER_LIST {
             path.push(d);
-            fs::create_dir_all(path.as_path())?;
+            if !path.is_dir() {
+                created.push(path.clone());
+            }
+            let mut builder = fs::DirBuilder::new();
+            builder.recursive(true);
+            #[cfg(unix)]
+            if let Some(mode) = self.options.dir_mode {
+                builder.mode(mode);
+            }
+            builder.create(&path)?;
             path.pop();
         }
+        #[cfg(unix)]
+        for dir in &created {
+            if let Some(mode) = self.options.dir_mode {
+                fs::set_permissions(dir, fs::Permissions::from_mode(mode))?;
+            }
+            if let Some(gid) = self.options.group {
+                std::os::unix::fs::chown(dir, None, Some(gid))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Applies the configured file mode and group to a message file.
+    #[cfg(unix)]
+    pub(crate) fn apply_file_permissions(&self, file: &fs::File) -> std::io::Result<()> {
+        if let Some(mode) = self.options.file_mode {
+            file.set_permissions(fs::Permissions::from_mode(mode))?;
+        }
+        if let Some(gid) = self.options.group {
+            std::os::unix::fs::fchown(file, None, Some(gid))?;
+        }
         Ok(())
     }
 
     /// Stores the given message data as a new message file in the Maildir `new` folder. Does not
     /// create the neccessary directories, so if in doubt call `create_dirs` before using
     /// `store_new`.
-    /// Returns the Id of the inserted message on success.
+    /// Returns the Id of the inserted message on success. If the message was stored but
+    /// flushing its directories failed, the error is `MaildirError::NotSynced` with the Id.
     pub fn store_new(&self, data: &[u8]) -> std::result::Result<String, MaildirError> {
         self.store(Subfolder::New, data, "")
     }
@@ -696,34 +1507,147 @@ impl Maildir {
         data: &[u8],
         flags: &str,
     ) -> std::result::Result<String, MaildirError> {
-        self.store(
-            Subfolder::Cur,
-            data,
-            &format!(
-                "{}2,{}",
-                INFORMATIONAL_SUFFIX_SEPARATOR,
-                Self::normalize_flags(flags)
-            ),
-        )
+        self.store(Subfolder::Cur, data, flags)
     }
 
+    // Stores the message in `new`, or in `cur` with the given flags. With
+    // compression turned on, the message is compressed as it is written,
+    // and gets the `Z` flag in `cur`.
     fn store(
         &self,
         subfolder: Subfolder,
         data: &[u8],
-        info: &str,
+        flags: &str,
     ) -> std::result::Result<String, MaildirError> {
+        let mut staged = self.stage(data)?;
+        let id = staged.id(staged.counter);
+        let info = match subfolder {
+            Subfolder::New => String::new(),
+            Subfolder::Cur => {
+                #[cfg(feature = "compression")]
+                let flags = &match self.options.compression {
+                    Some(_) => format!("{}{}", flags, compression::COMPRESSED_FLAG),
+                    None => flags.to_string(),
+                };
+                format!(
+                    "{}2,{}",
+                    INFORMATIONAL_SUFFIX_SEPARATOR,
+                    Self::normalize_flags(flags)
+                )
+            }
+        };
+
+        let mut newpath = self.path.clone();
+        newpath.push(match subfolder {
+            Subfolder::New => "new",
+            Subfolder::Cur => "cur",
+        });
+        newpath.push(format!("{}{}", id, info));
+
+        self.inject_fault(StoreStep::Rename)?;
+        std::fs::rename(&staged.path, &newpath)?;
+        staged.unlink_guard.path_to_unlink.take();
+
+        newpath.pop();
+        self.sync_dirs(&[&newpath, &self.path.join("tmp")])
+            .map_err(|e| MaildirError::NotSynced(id.clone(), e))?;
+        Ok(id)
+    }
+
+    /// Stores the given message data as a new message file in the `new` folder of each of the
+    /// `targets`. The data is written only once, to the `tmp` folder of the first target, and
+    /// then hard-linked into the `new` folder of every target under a distinct id. Where a
+    /// target is on a different filesystem, so that a hard link cannot be made, the message is
+    /// written to that target with `store_new` instead.
+    ///
+    /// Returns an error if the message could not be written at all. Otherwise returns, for each
+    /// target in order, the Id of the inserted message or the error that prevented delivery to
+    /// that target. If a message was linked but flushing a directory afterwards failed, the
+    /// error is `MaildirError::NotSynced` with the Id of the message.
+    ///
+    /// Hard-linked messages are the very file written to the first target, so they keep its
+    /// file permissions, group, compression and size attributes, and their names are made by
+    /// its `UniqueNameGenerator` and hostname.
+    pub fn store_new_linked(
+        targets: &[&Maildir],
+        data: &[u8],
+    ) -> std::result::Result<Vec<std::result::Result<String, MaildirError>>, MaildirError> {
+        let first = match targets.first() {
+            Some(first) => first,
+            None => return Ok(Vec::new()),
+        };
+        let staged = first.stage(data)?;
+
+        let results = targets
+            .iter()
+            .map(|target| {
+                let newdir = target.path.join("new");
+                target.inject_fault(StoreStep::Rename)?;
+                let mut attempts = 0;
+                let id = loop {
+                    let id = staged.id(COUNTER.fetch_add(1, Ordering::SeqCst));
+                    match fs::hard_link(&staged.path, newdir.join(&id)) {
+                        Ok(()) => break id,
+                        Err(err) if err.kind() == ErrorKind::AlreadyExists => {
+                            attempts += 1;
+                            if attempts == MAX_NAME_ATTEMPTS {
+                                return Err(name_taken_error().into());
+                            }
+                        }
+                        // fall back to copying across filesystems
+                        Err(err) if err.kind() == ErrorKind::CrossesDevices => {
+                            return target.store_new(data)
+                        }
+                        Err(err) => return Err(err.into()),
+                    }
+                };
+                target
+                    .sync_dirs(&[&newdir])
+                    .map_err(|e| MaildirError::NotSynced(id.clone(), e))?;
+                Ok(id)
+            })
+            .collect::<Vec<_>>();
+
+        // the staged file is unlinked when it goes out of scope
+        drop(staged);
+        if let Err(err) = first.sync_dirs(&[&first.path.join("tmp")]) {
+            // the messages are delivered, so keep their ids
+            return Ok(results
+                .into_iter()
+                .map(|result| {
+                    result.and_then(|id| {
+                        let err = std::io::Error::new(err.kind(), err.to_string());
+                        Err(MaildirError::NotSynced(id, err))
+                    })
+                })
+                .collect());
+        }
+        Ok(results)
+    }
+
+    /// Writes the message data to a new file in the `tmp` folder. The file is removed again
+    /// when the returned value is dropped, unless its guard is disarmed.
+    fn stage(&self, data: &[u8]) -> std::result::Result<StagedMessage, MaildirError> {
         // try to get some uniquenes, as described at http://cr.yp.to/proto/maildir.html
         // dovecot and courier IMAP use <timestamp>.M<usec>P<pid>.<hostname> for tmp-files and then
         // move to <timestamp>.M<usec>P<pid>V<dev>I<ino>.<hostname>,S=<size_in_bytes> when moving
         // to new dir. see for example http://www.courier-mta.org/maildir.html.
         let pid = std::process::id();
-        let hostname = gethostname::gethostname()
-            .into_string()
-            // the hostname is always ASCII in order to be a valid DNS
-            // name, so into_string() will always succeed. The error case
-            // here is to satisfy the compiler which doesn't know this.
-            .unwrap_or_else(|_| "localhost".to_string());
+        let hostname = match self.options.hostname {
+            Some(ref hostname) => hostname.clone(),
+            None => gethostname::gethostname()
+                .into_string()
+                // the hostname is always ASCII in order to be a valid DNS
+                // name, so into_string() will always succeed. The error case
+                // here is to satisfy the compiler which doesn't know this.
+                .unwrap_or_else(|_| "localhost".to_string()),
+        };
+        let hostname = escape_hostname(&hostname);
+        let generator = self
+            .options
+            .name_generator
+            .clone()
+            .unwrap_or_else(|| Arc::new(DefaultNameGenerator));
 
         // loop when conflicting filenames occur, as described at
         // http://www.courier-mta.org/maildir.html
@@ -735,6 +1659,7 @@ impl Maildir {
         let mut secs;
         let mut nanos;
         let mut counter;
+        let mut attempts = 0;
 
         loop {
             let ts = time::SystemTime::now().duration_since(time::UNIX_EPOCH)?;
@@ -742,13 +1667,25 @@ impl Maildir {
             nanos = ts.subsec_nanos();
             counter = COUNTER.fetch_add(1, Ordering::SeqCst);
 
-            tmppath.push(format!("{secs}.#{counter:x}M{nanos}P{pid}.{hostname}"));
-
-            match std::fs::OpenOptions::new()
-                .write(true)
-                .create_new(true)
-                .open(&tmppath)
-            {
+            tmppath.push(generator.unique_name(&NameContext {
+                secs,
+                nanos,
+                counter,
+                pid,
+                hostname: &hostname,
+                dev: None,
+                ino: None,
+            }));
+
+            self.inject_fault(StoreStep::Create)?;
+
+            let mut open_options = std::fs::OpenOptions::new();
+            open_options.write(true).create_new(true);
+            #[cfg(unix)]
+            if let Some(mode) = self.options.file_mode {
+                open_options.mode(mode);
+            }
+            match open_options.open(&tmppath) {
                 Ok(f) => {
                     file = f;
                     break;
@@ -757,43 +1694,48 @@ impl Maildir {
                     if err.kind() != ErrorKind::AlreadyExists {
                         return Err(err.into());
                     }
+                    attempts += 1;
+                    if attempts == MAX_NAME_ATTEMPTS {
+                        return Err(name_taken_error().into());
+                    }
                     tmppath.pop();
                 }
             }
         }
 
-        /// At this point, `file` is our new file at `tmppath`.
-        /// If we leave the scope of this function prior to
-        /// successfully writing the file to its final location,
-        /// we need to ensure that we remove the temporary file.
-        /// This struct takes care of that detail.
-        struct UnlinkOnError {
-            path_to_unlink: Option<PathBuf>,
-        }
-
-        impl Drop for UnlinkOnError {
-            fn drop(&mut self) {
-                if let Some(path) = self.path_to_unlink.take() {
-                    // Best effort to remove it
-                    std::fs::remove_file(path).ok();
-                }
-            }
-        }
-
         // Ensure that we remove the temporary file on failure
-        let mut unlink_guard = UnlinkOnError {
+        let unlink_guard = UnlinkOnError {
             path_to_unlink: Some(tmppath.clone()),
         };
 
-        file.write_all(data)?;
-        file.sync_all()?;
+        #[cfg(unix)]
+        self.apply_file_permissions(&file)?;
+
+        #[cfg(feature = "compression")]
+        let compressed = match self.options.compression {
+            Some(compression) => Some(compression::compress(data, compression)?),
+            None => None,
+        };
+        #[cfg(feature = "compression")]
+        let contents = compressed.as_deref().unwrap_or(data);
+        #[cfg(not(feature = "compression"))]
+        let contents = data;
+
+        if let Err(err) = self.inject_fault(StoreStep::Write) {
+            // simulate a short write before failing
+            file.write_all(&contents[..contents.len() / 2])?;
+            return Err(err.into());
+        }
+        file.write_all(contents)?;
+        let rfc822_size = rfc822_size(data);
+        if self.options.durability != Durability::None {
+            self.inject_fault(StoreStep::Sync)?;
+            file.sync_all()?;
+        }
 
+        self.inject_fault(StoreStep::Metadata)?;
+        #[cfg_attr(windows, allow(unused_variables))]
         let meta = file.metadata()?;
-        let mut newpath = self.path.clone();
-        newpath.push(match subfolder {
-            Subfolder::New => "new",
-            Subfolder::Cur => "cur",
-        });
 
         #[cfg(unix)]
         let dev = meta.dev();
@@ -805,23 +1747,222 @@ impl Maildir {
         #[cfg(windows)]
         let ino: u64 = 0;
 
-        #[cfg(unix)]
-        let size = meta.size();
-        #[cfg(windows)]
-        let size = meta.file_size();
+        // the size of the message, which differs from the size of the file
+        // when it is compressed
+        let size = data.len() as u64;
+        // which is why compressed messages always get the size attributes
+        #[cfg(feature = "compression")]
+        let size_attributes = !self.options.no_size_attributes || compressed.is_some();
+        #[cfg(not(feature = "compression"))]
+        let size_attributes = !self.options.no_size_attributes;
+
+        Ok(StagedMessage {
+            path: tmppath,
+            secs,
+            nanos,
+            counter,
+            pid,
+            hostname,
+            dev,
+            ino,
+            size,
+            rfc822_size,
+            generator,
+            size_attributes,
+            unlink_guard,
+        })
+    }
 
-        let id = format!("{secs}.#{counter:x}M{nanos}P{pid}V{dev}I{ino}.{hostname},S={size}");
-        newpath.push(format!("{}{}", id, info));
+    /// Flushes the given directories to disk if the durability level asks
+    /// for it, so that renames into or out of them are persisted.
+    pub(crate) fn sync_dirs(&self, dirs: &[&Path]) -> std::io::Result<()> {
+        if self.options.durability != Durability::FileAndDirectory {
+            return Ok(());
+        }
+        for dir in dirs {
+            self.inject_fault(StoreStep::SyncDir)?;
+            // Only Unix allows opening a directory to flush it
+            #[cfg(unix)]
+            fs::File::open(dir)?.sync_all()?;
+            #[cfg(not(unix))]
+            let _ = dir;
+        }
+        Ok(())
+    }
 
-        std::fs::rename(&tmppath, &newpath)?;
-        unlink_guard.path_to_unlink.take();
-        Ok(id)
+    #[cfg(feature = "fault-injection")]
+    fn inject_fault(&self, step: StoreStep) -> std::io::Result<()> {
+        match self.options.faults {
+            Some(ref faults) => faults.check(step),
+            None => Ok(()),
+        }
+    }
+
+    #[cfg(not(feature = "fault-injection"))]
+    #[inline(always)]
+    fn inject_fault(&self, _step: StoreStep) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// When a message has been written to `tmp`, but we leave
+/// the scope of the storing function prior to successfully
+/// writing the file to its final location, we need to ensure
+/// that we remove the temporary file. This struct takes care
+/// of that detail.
+struct UnlinkOnError {
+    path_to_unlink: Option<PathBuf>,
+}
+
+impl Drop for UnlinkOnError {
+    fn drop(&mut self) {
+        if let Some(path) = self.path_to_unlink.take() {
+            // Best effort to remove it
+            std::fs::remove_file(path).ok();
+        }
+    }
+}
+
+/// A message that has been written to the `tmp` folder, along with the
+/// information needed to give it a unique name in `new` or `cur`.
+struct StagedMessage {
+    path: PathBuf,
+    secs: u64,
+    nanos: u32,
+    counter: usize,
+    pid: u32,
+    hostname: String,
+    dev: u64,
+    ino: u64,
+    size: u64,
+    rfc822_size: u64,
+    generator: Arc<dyn UniqueNameGenerator>,
+    size_attributes: bool,
+    unlink_guard: UnlinkOnError,
+}
+
+impl StagedMessage {
+    fn id(&self, counter: usize) -> String {
+        let mut id = self.generator.unique_name(&NameContext {
+            secs: self.secs,
+            nanos: self.nanos,
+            counter,
+            pid: self.pid,
+            hostname: &self.hostname,
+            dev: Some(self.dev),
+            ino: Some(self.ino),
+        });
+        if self.size_attributes {
+            id.push_str(&format!(",S={},W={}", self.size, self.rfc822_size));
+        }
+        id
+    }
+}
+
+/// The error returned when no unused name was found for a new message file.
+fn name_taken_error() -> std::io::Error {
+    std::io::Error::new(
+        ErrorKind::AlreadyExists,
+        "Could not find an unused name for the message file",
+    )
+}
+
+/// Escapes the characters that may not appear in the hostname part of a
+/// maildir file name, as described at <https://cr.yp.to/proto/maildir.html>.
+/// The comma is escaped too, since it separates the attributes that follow.
+fn escape_hostname(hostname: &str) -> String {
+    hostname
+        .replace('/', "\\057")
+        .replace(':', "\\072")
+        .replace(',', "\\054")
+}
+
+/// The information a `UniqueNameGenerator` can use to name a message file.
+#[derive(Debug)]
+pub struct NameContext<'a> {
+    /// The delivery time, in seconds since the Unix epoch.
+    pub secs: u64,
+    /// The sub-second part of the delivery time, in nanoseconds.
+    pub nanos: u32,
+    /// A number that is different for every name requested by this
+    /// process, including retries after a name turned out to be taken.
+    pub counter: usize,
+    /// The id of the delivering process.
+    pub pid: u32,
+    /// The hostname, with characters that are not allowed in a file name
+    /// already escaped.
+    pub hostname: &'a str,
+    /// The device number of the message file. This is `None` when naming
+    /// the temporary file in `tmp`, before the file exists.
+    pub dev: Option<u64>,
+    /// The inode number of the message file. This is `None` when naming
+    /// the temporary file in `tmp`, before the file exists.
+    pub ino: Option<u64>,
+}
+
+/// Generates the unique part of the names of message files, which becomes
+/// the id of the message. A name is requested for the temporary file in
+/// `tmp` and again for the final file in `new` or `cur`. Names must be
+/// unique across all processes delivering to the maildir, and must not
+/// contain `/`, the informational suffix separator (`:` on Unix, `;` on
+/// Windows) or `,`; the `,S=` and `,W=` size attributes are appended by the
+/// `Maildir`.
+/// If a name turns out to be taken, another one is requested with a
+/// different `counter`.
+pub trait UniqueNameGenerator: fmt::Debug + Send + Sync {
+    fn unique_name(&self, context: &NameContext) -> String;
+}
+
+/// The naming scheme used by default. Names have the form
+/// `<secs>.#<counter>M<nanos>P<pid>V<dev>I<ino>.<hostname>`, with the
+/// device and inode parts left out for temporary files.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DefaultNameGenerator;
+
+impl UniqueNameGenerator for DefaultNameGenerator {
+    fn unique_name(&self, context: &NameContext) -> String {
+        let NameContext {
+            secs,
+            nanos,
+            counter,
+            pid,
+            hostname,
+            ..
+        } = *context;
+        match (context.dev, context.ino) {
+            (Some(dev), Some(ino)) => {
+                format!("{secs}.#{counter:x}M{nanos}P{pid}V{dev}I{ino}.{hostname}")
+            }
+            _ => format!("{secs}.#{counter:x}M{nanos}P{pid}.{hostname}"),
+        }
+    }
+}
+
+/// The naming scheme used by Dovecot, with names of the form
+/// `<secs>.M<usecs>P<pid>Q<counter>.<hostname>`. The name is the same for
+/// the temporary and the final file.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DovecotNameGenerator;
+
+impl UniqueNameGenerator for DovecotNameGenerator {
+    fn unique_name(&self, context: &NameContext) -> String {
+        format!(
+            "{}.M{}P{}Q{}.{}",
+            context.secs,
+            context.nanos / 1000,
+            context.pid,
+            context.counter,
+            context.hostname
+        )
     }
 }
 
 impl From<PathBuf> for Maildir {
     fn from(p: PathBuf) -> Maildir {
-        Maildir { path: p }
+        Maildir {
+            path: p,
+            options: MaildirOptions::default(),
+        }
     }
 }
 
@@ -831,7 +1972,7 @@ impl From<String> for Maildir {
     }
 }
 
-impl<'a> From<&'a str> for Maildir {
+impl From<&str> for Maildir {
     fn from(s: &str) -> Maildir {
         Maildir::from(PathBuf::from(s))
     }
@@ -0,0 +1,319 @@
+//! An LMTP server delivering into maildirs.
+//!
+//! [`LmtpServer`] implements LMTP as described in RFC 2033, with the
+//! `PIPELINING`, `ENHANCEDSTATUSCODES` and `8BITMIME` extensions, so an MTA
+//! like Postfix can hand messages over to it for final delivery, over TCP
+//! or a Unix socket. A [`RecipientResolver`] maps each recipient address to
+//! the maildir it is delivered to, and rejects unknown recipients.
+//!
+//! Each message is stored with `Maildir::store_new` once per recipient,
+//! with `Return-Path` and `Delivered-To` headers added, and with LF line
+//! endings like other messages in a maildir. As LMTP requires, the server
+//! replies with a status for each recipient after the message data, so a
+//! failure to store the message for one recipient doesn't affect the
+//! others. Failures to store a message are reported as temporary, so the
+//! MTA tries again later.
+//!
+//! This module is only available with the `lmtp` feature.
+//!
+//! ```no_run
+//! use std::net::TcpListener;
+//!
+//! use maildir::lmtp::LmtpServer;
+//! use maildir::Maildir;
+//!
+//! let server = LmtpServer::new(|recipient: &str| match recipient {
+//!     "alice@example.org" => Some(Maildir::from("/home/alice/Maildir")),
+//!     _ => None,
+//! });
+//! let listener = TcpListener::bind("127.0.0.1:2424").unwrap();
+//! server.serve(listener).unwrap();
+//! ```
+
+use std::io::prelude::*;
+use std::io::{self, BufReader, BufWriter};
+use std::net::{TcpListener, TcpStream};
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::time::Duration;
+
+use crate::delivery::DeliveryHeaders;
+use crate::server;
+use crate::Maildir;
+
+// RFC 5321 asks clients to wait 5 minutes for a reply, and servers to wait
+// at least as long for a command.
+const TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+// Longer command lines are rejected. RFC 5321 allows up to 512 octets.
+const MAX_LINE: u64 = 1024;
+
+// The default limit on the size of messages.
+const DEFAULT_MAX_MESSAGE_SIZE: usize = 64 * 1024 * 1024;
+
+/// Maps recipient addresses to the maildirs their mail is delivered to.
+///
+/// This is implemented for closures taking the address and returning the
+/// maildir, or `None` if there is no such recipient.
+pub trait RecipientResolver: Send + Sync {
+    /// Returns the maildir of the recipient, or `None` if the recipient
+    /// is unknown and should be rejected. The address is passed as the
+    /// client sent it, without the angle brackets.
+    fn resolve(&self, recipient: &str) -> Option<Maildir>;
+}
+
+impl<F> RecipientResolver for F
+where
+    F: Fn(&str) -> Option<Maildir> + Send + Sync,
+{
+    fn resolve(&self, recipient: &str) -> Option<Maildir> {
+        self(recipient)
+    }
+}
+
+/// An LMTP server, see the module documentation.
+#[derive(Debug)]
+pub struct LmtpServer<R> {
+    resolver: R,
+    hostname: String,
+    max_message_size: usize,
+}
+
+// The state of a mail transaction.
+#[derive(Debug, Default)]
+struct Transaction {
+    greeted: bool,
+    sender: Option<String>,
+    recipients: Vec<(String, Maildir)>,
+}
+
+impl<R: RecipientResolver> LmtpServer<R> {
+    pub fn new(resolver: R) -> LmtpServer<R> {
+        LmtpServer {
+            resolver,
+            hostname: gethostname::gethostname().to_string_lossy().into_owned(),
+            max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
+        }
+    }
+
+    /// Sets the host name the server greets clients with. Defaults to the
+    /// host name of the machine.
+    pub fn hostname(mut self, hostname: &str) -> LmtpServer<R> {
+        self.hostname = hostname.to_string();
+        self
+    }
+
+    /// Sets the size in bytes of the largest message accepted. Larger
+    /// messages are rejected permanently. Defaults to 64 MiB.
+    pub fn max_message_size(mut self, max_message_size: usize) -> LmtpServer<R> {
+        self.max_message_size = max_message_size;
+        self
+    }
+
+    /// Accepts connections on the listener and serves each of them on its
+    /// own thread. This only returns if accepting a connection fails.
+    pub fn serve(&self, listener: TcpListener) -> io::Result<()> {
+        server::serve(listener.incoming(), |stream| self.handle_stream(stream))
+    }
+
+    /// Like `serve`, for a Unix socket.
+    #[cfg(unix)]
+    pub fn serve_unix(&self, listener: UnixListener) -> io::Result<()> {
+        server::serve(listener.incoming(), |stream| {
+            self.handle_unix_stream(stream)
+        })
+    }
+
+    /// Serves a single client connected over TCP.
+    pub fn handle_stream(&self, stream: TcpStream) -> io::Result<()> {
+        stream.set_read_timeout(Some(TIMEOUT))?;
+        let reader = BufReader::new(stream.try_clone()?);
+        self.handle(reader, BufWriter::new(stream))
+    }
+
+    /// Serves a single client connected over a Unix socket.
+    #[cfg(unix)]
+    pub fn handle_unix_stream(&self, stream: UnixStream) -> io::Result<()> {
+        stream.set_read_timeout(Some(TIMEOUT))?;
+        let reader = BufReader::new(stream.try_clone()?);
+        self.handle(reader, BufWriter::new(stream))
+    }
+
+    /// Runs an LMTP session, reading commands from `input` and writing the
+    /// replies to `output`, until the client quits or disconnects.
+    pub fn handle<I: BufRead, W: Write>(&self, mut input: I, mut output: W) -> io::Result<()> {
+        let mut transaction = Transaction::default();
+        write!(output, "220 {} LMTP server ready\r\n", self.hostname)?;
+        output.flush()?;
+        let mut line = Vec::new();
+        loop {
+            line.clear();
+            if input.by_ref().take(MAX_LINE).read_until(b'\n', &mut line)? == 0 {
+                return Ok(());
+            }
+            if !line.ends_with(b"\n") {
+                output.write_all(b"500 5.5.2 line too long\r\n")?;
+                return output.flush();
+            }
+            let line = String::from_utf8_lossy(&line);
+            let line = line.trim_end_matches(['\r', '\n']);
+            let (command, argument) = match line.find(' ') {
+                Some(pos) => (line[..pos].to_ascii_uppercase(), line[pos + 1..].trim()),
+                None => (line.to_ascii_uppercase(), ""),
+            };
+            let quit = command == "QUIT";
+            self.command(
+                &mut transaction,
+                &command,
+                argument,
+                &mut input,
+                &mut output,
+            )?;
+            output.flush()?;
+            if quit {
+                return Ok(());
+            }
+        }
+    }
+
+    fn command<I: BufRead, W: Write>(
+        &self,
+        transaction: &mut Transaction,
+        command: &str,
+        argument: &str,
+        input: &mut I,
+        out: &mut W,
+    ) -> io::Result<()> {
+        match command {
+            "LHLO" if !argument.is_empty() => {
+                *transaction = Transaction {
+                    greeted: true,
+                    ..Transaction::default()
+                };
+                write!(
+                    out,
+                    "250-{}\r\n250-PIPELINING\r\n250-ENHANCEDSTATUSCODES\r\n250 8BITMIME\r\n",
+                    self.hostname
+                )
+            }
+            "LHLO" => out.write_all(b"501 5.5.4 LHLO needs a host name\r\n"),
+            "HELO" | "EHLO" => out.write_all(b"500 5.5.1 this is LMTP, use LHLO\r\n"),
+            "MAIL" if !transaction.greeted => out.write_all(b"503 5.5.1 send LHLO first\r\n"),
+            "MAIL" if transaction.sender.is_some() => {
+                out.write_all(b"503 5.5.1 nested MAIL command\r\n")
+            }
+            "MAIL" => match path(argument, "FROM:") {
+                Some(sender) => {
+                    transaction.sender = Some(sender);
+                    out.write_all(b"250 2.1.0 sender ok\r\n")
+                }
+                None => out.write_all(b"501 5.5.4 syntax: MAIL FROM:<address>\r\n"),
+            },
+            "RCPT" if transaction.sender.is_none() => {
+                out.write_all(b"503 5.5.1 send MAIL first\r\n")
+            }
+            "RCPT" => match path(argument, "TO:").filter(|r| !r.is_empty()) {
+                Some(recipient) => match self.resolver.resolve(&recipient) {
+                    Some(maildir) => {
+                        transaction.recipients.push((recipient, maildir));
+                        out.write_all(b"250 2.1.5 recipient ok\r\n")
+                    }
+                    None => write!(out, "550 5.1.1 <{}> unknown recipient\r\n", recipient),
+                },
+                None => out.write_all(b"501 5.5.4 syntax: RCPT TO:<address>\r\n"),
+            },
+            "DATA" if transaction.recipients.is_empty() => {
+                out.write_all(b"503 5.5.1 no valid recipients\r\n")
+            }
+            "DATA" => {
+                out.write_all(b"354 end data with <CR><LF>.<CR><LF>\r\n")?;
+                out.flush()?;
+                let data = read_data(input, self.max_message_size)?;
+                let sender = transaction.sender.take().unwrap_or_default();
+                for (recipient, maildir) in transaction.recipients.drain(..) {
+                    let data = match data {
+                        Some(ref data) => data,
+                        None => {
+                            write!(out, "552 5.3.4 <{}> message too big\r\n", recipient)?;
+                            continue;
+                        }
+                    };
+                    let message = DeliveryHeaders::new()
+                        .return_path(&sender)
+                        .delivered_to(&recipient)
+                        .prepend_to(data);
+                    match maildir.store_new(&message) {
+                        Ok(id) => write!(out, "250 2.0.0 <{}> delivered as {}\r\n", recipient, id)?,
+                        Err(e) => {
+                            write!(out, "451 4.2.0 <{}> delivery failed: {}\r\n", recipient, e)?
+                        }
+                    }
+                }
+                Ok(())
+            }
+            "RSET" => {
+                transaction.sender = None;
+                transaction.recipients.clear();
+                out.write_all(b"250 2.0.0 ok\r\n")
+            }
+            "NOOP" => out.write_all(b"250 2.0.0 ok\r\n"),
+            "VRFY" => out.write_all(b"252 2.5.2 cannot verify, but will try\r\n"),
+            "QUIT" => write!(out, "221 2.0.0 {} closing connection\r\n", self.hostname),
+            _ => out.write_all(b"500 5.5.1 unknown command\r\n"),
+        }
+    }
+}
+
+// Parses the path of MAIL FROM:<address> or RCPT TO:<address>, ignoring any
+// parameters after it.
+fn path(argument: &str, prefix: &str) -> Option<String> {
+    // get() rather than indexing, as the prefix may end inside a multibyte
+    // character sent by the client
+    if !argument
+        .get(..prefix.len())
+        .is_some_and(|p| p.eq_ignore_ascii_case(prefix))
+    {
+        return None;
+    }
+    let rest = argument[prefix.len()..].trim_start().strip_prefix('<')?;
+    let end = rest.find('>')?;
+    Some(rest[..end].to_string())
+}
+
+// Reads the message data up to the line with a single dot, undoing the dot
+// stuffing and converting line endings to LF. Returns `None` if the message
+// is larger than `max_size`, after reading all of it.
+fn read_data<I: BufRead>(input: &mut I, max_size: usize) -> io::Result<Option<Vec<u8>>> {
+    let mut data = Vec::new();
+    let mut too_big = false;
+    let mut line = Vec::new();
+    loop {
+        line.clear();
+        // the limit keeps a line without end from taking all the memory
+        if input
+            .by_ref()
+            .take(max_size as u64 + 2)
+            .read_until(b'\n', &mut line)?
+            == 0
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "connection closed during DATA",
+            ));
+        }
+        let content = line.strip_suffix(b"\n").unwrap_or(&line);
+        let content = content.strip_suffix(b"\r").unwrap_or(content);
+        if content == b"." {
+            return Ok(if too_big { None } else { Some(data) });
+        }
+        let content = content.strip_prefix(b".").unwrap_or(content);
+        if data.len() + content.len() + 1 > max_size {
+            too_big = true;
+            data = Vec::new();
+        }
+        if !too_big {
+            data.extend_from_slice(content);
+            data.push(b'\n');
+        }
+    }
+}
@@ -0,0 +1,388 @@
+//! A POP3 server backed by maildirs.
+//!
+//! [`Pop3Server`] implements POP3 as described in RFC 1939, including the
+//! optional `UIDL`, `TOP` and `USER`/`PASS` commands, and the `CAPA`
+//! command of RFC 2449. There is no TLS support, so the server is meant to
+//! be run on localhost or on a trusted network. Users are checked by an
+//! [`Authenticator`], which also provides the maildir serving as the
+//! maildrop of each user.
+//!
+//! The maildrop consists of the messages in the `new` and `cur` folders of
+//! the maildir when the user logs in, ordered by id. Messages that arrive
+//! during the session show up in the next one. The maildir id of a message
+//! serves as its unique id for `UIDL`, unless it is too long or contains
+//! characters that POP3 doesn't allow, in which case a hash of it is used.
+//! Messages marked for deletion with `DELE` are deleted from the maildir
+//! when the client ends the session with `QUIT`. A maildir can only be
+//! opened by one session of a server at a time; logging in to a maildrop
+//! that is in use fails.
+//!
+//! This module is only available with the `pop3` feature.
+//!
+//! ```no_run
+//! use std::net::TcpListener;
+//!
+//! use maildir::auth::StaticAuthenticator;
+//! use maildir::pop3::Pop3Server;
+//!
+//! let mut users = StaticAuthenticator::new();
+//! users.add_user("alice", "secret", "/home/alice/Maildir");
+//! let listener = TcpListener::bind("127.0.0.1:1110").unwrap();
+//! Pop3Server::new(users).serve(listener).unwrap();
+//! ```
+
+use std::collections::HashSet;
+use std::io::prelude::*;
+use std::io::{self, BufReader, BufWriter};
+use std::net::{TcpListener, TcpStream};
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::auth::Authenticator;
+use crate::server;
+use crate::{MailEntry, Maildir};
+
+// RFC 1939 asks for an inactivity timer of at least 10 minutes.
+const TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+// Longer command lines are rejected. RFC 2449 allows up to 255 octets.
+const MAX_LINE: u64 = 512;
+
+// The longest unique id allowed by RFC 1939.
+const MAX_UID_LEN: usize = 70;
+
+/// A POP3 server, see the module documentation.
+#[derive(Debug)]
+pub struct Pop3Server<A> {
+    authenticator: A,
+    // the paths of the maildirs that are in use by a session
+    locked: Mutex<HashSet<PathBuf>>,
+}
+
+// A message of the maildrop.
+#[derive(Debug)]
+struct Message {
+    entry: MailEntry,
+    uid: String,
+    size: u64,
+    deleted: bool,
+}
+
+// The lock a session holds on its maildrop, released when it is dropped.
+struct MaildropLock<'s> {
+    locked: &'s Mutex<HashSet<PathBuf>>,
+    path: PathBuf,
+}
+
+impl<'s> MaildropLock<'s> {
+    // Locks the maildir, or returns `None` if another session has it.
+    fn acquire(locked: &'s Mutex<HashSet<PathBuf>>, maildir: &Maildir) -> Option<MaildropLock<'s>> {
+        let path = maildir.path().to_path_buf();
+        let mut paths = locked.lock().unwrap_or_else(|e| e.into_inner());
+        if !paths.insert(path.clone()) {
+            return None;
+        }
+        Some(MaildropLock { locked, path })
+    }
+}
+
+impl Drop for MaildropLock<'_> {
+    fn drop(&mut self) {
+        let mut paths = self.locked.lock().unwrap_or_else(|e| e.into_inner());
+        paths.remove(&self.path);
+    }
+}
+
+// The state of a session. The maildrop is only locked and loaded once the
+// user is authenticated.
+enum State<'s> {
+    Authorization {
+        user: Option<String>,
+    },
+    Transaction {
+        maildir: Maildir,
+        messages: Vec<Message>,
+        _lock: MaildropLock<'s>,
+    },
+}
+
+impl<A: Authenticator> Pop3Server<A> {
+    pub fn new(authenticator: A) -> Pop3Server<A> {
+        Pop3Server {
+            authenticator,
+            locked: Mutex::new(HashSet::new()),
+        }
+    }
+
+    /// Accepts connections on the listener and serves each of them on its
+    /// own thread. This only returns if accepting a connection fails.
+    pub fn serve(&self, listener: TcpListener) -> io::Result<()> {
+        server::serve(listener.incoming(), |stream| self.handle_stream(stream))
+    }
+
+    /// Serves a single client connected over TCP.
+    pub fn handle_stream(&self, stream: TcpStream) -> io::Result<()> {
+        stream.set_read_timeout(Some(TIMEOUT))?;
+        let reader = BufReader::new(stream.try_clone()?);
+        self.handle(reader, BufWriter::new(stream))
+    }
+
+    /// Runs a POP3 session, reading commands from `input` and writing the
+    /// responses to `output`, until the client quits or disconnects.
+    pub fn handle<R: BufRead, W: Write>(&self, mut input: R, mut output: W) -> io::Result<()> {
+        let mut state = State::Authorization { user: None };
+        output.write_all(b"+OK POP3 server ready\r\n")?;
+        output.flush()?;
+        let mut line = Vec::new();
+        loop {
+            line.clear();
+            if input.by_ref().take(MAX_LINE).read_until(b'\n', &mut line)? == 0 {
+                // the client went away without QUIT, so nothing is deleted
+                return Ok(());
+            }
+            if !line.ends_with(b"\n") {
+                output.write_all(b"-ERR line too long\r\n")?;
+                return output.flush();
+            }
+            let line = String::from_utf8_lossy(&line);
+            let mut words = line.trim_end_matches(['\r', '\n']).splitn(2, ' ');
+            let command = words.next().unwrap_or("").to_ascii_uppercase();
+            let argument = words.next().unwrap_or("");
+            let quit = command == "QUIT";
+            self.command(&mut state, &command, argument, &mut output)?;
+            output.flush()?;
+            if quit {
+                return Ok(());
+            }
+        }
+    }
+
+    fn command<'s, W: Write>(
+        &'s self,
+        state: &mut State<'s>,
+        command: &str,
+        argument: &str,
+        out: &mut W,
+    ) -> io::Result<()> {
+        if command == "CAPA" {
+            out.write_all(b"+OK capability list follows\r\nUSER\r\nTOP\r\nUIDL\r\n.\r\n")?;
+            return Ok(());
+        }
+        match *state {
+            State::Authorization { ref mut user } => match command {
+                "USER" if !argument.is_empty() => {
+                    *user = Some(argument.to_string());
+                    out.write_all(b"+OK send PASS\r\n")
+                }
+                "PASS" => {
+                    let authenticated = user
+                        .take()
+                        .and_then(|user| self.authenticator.authenticate(&user, argument));
+                    let maildir = match authenticated {
+                        Some(maildir) => maildir,
+                        None => return out.write_all(b"-ERR invalid user name or password\r\n"),
+                    };
+                    match MaildropLock::acquire(&self.locked, &maildir) {
+                        Some(lock) => {
+                            let messages = load_maildrop(&maildir)?;
+                            let size: u64 = messages.iter().map(|m| m.size).sum();
+                            write!(
+                                out,
+                                "+OK maildrop has {} messages ({} octets)\r\n",
+                                messages.len(),
+                                size
+                            )?;
+                            *state = State::Transaction {
+                                maildir,
+                                messages,
+                                _lock: lock,
+                            };
+                            Ok(())
+                        }
+                        None => out.write_all(b"-ERR maildrop already locked\r\n"),
+                    }
+                }
+                "QUIT" => out.write_all(b"+OK bye\r\n"),
+                _ => out.write_all(b"-ERR unknown command\r\n"),
+            },
+            State::Transaction {
+                ref maildir,
+                ref mut messages,
+                ..
+            } => transaction(maildir, messages, command, argument, out),
+        }
+    }
+}
+
+fn transaction<W: Write>(
+    maildir: &Maildir,
+    messages: &mut [Message],
+    command: &str,
+    argument: &str,
+    out: &mut W,
+) -> io::Result<()> {
+    let mut args = argument.split_whitespace();
+    let number = args.next();
+    // looks up a message by its number, which counts from 1
+    let lookup = |number: &str| -> Option<usize> {
+        let index = number.parse::<usize>().ok()?.checked_sub(1)?;
+        match messages.get(index) {
+            Some(message) if !message.deleted => Some(index),
+            _ => None,
+        }
+    };
+    let no_such_message = b"-ERR no such message\r\n";
+
+    match command {
+        "STAT" => {
+            let live = messages.iter().filter(|m| !m.deleted);
+            let (count, size) = live.fold((0, 0), |(c, s), m| (c + 1, s + m.size));
+            write!(out, "+OK {} {}\r\n", count, size)
+        }
+        "LIST" | "UIDL" => {
+            let field = |index: usize| match command {
+                "LIST" => messages[index].size.to_string(),
+                _ => messages[index].uid.clone(),
+            };
+            match number {
+                Some(number) => match lookup(number) {
+                    Some(index) => write!(out, "+OK {} {}\r\n", index + 1, field(index)),
+                    None => out.write_all(no_such_message),
+                },
+                None => {
+                    out.write_all(b"+OK\r\n")?;
+                    for (index, message) in messages.iter().enumerate() {
+                        if !message.deleted {
+                            write!(out, "{} {}\r\n", index + 1, field(index))?;
+                        }
+                    }
+                    out.write_all(b".\r\n")
+                }
+            }
+        }
+        "RETR" | "TOP" => {
+            let index = match number.and_then(lookup) {
+                Some(index) => index,
+                None => return out.write_all(no_such_message),
+            };
+            let body_lines = if command == "TOP" {
+                match args.next().and_then(|n| n.parse::<usize>().ok()) {
+                    Some(lines) => Some(lines),
+                    None => return out.write_all(b"-ERR invalid number of lines\r\n"),
+                }
+            } else {
+                None
+            };
+            let mut data = Vec::new();
+            match messages[index].entry.open() {
+                Ok(mut file) => file.read_to_end(&mut data)?,
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                    return out.write_all(b"-ERR message is gone\r\n")
+                }
+                Err(e) => return Err(e),
+            };
+            write!(out, "+OK {} octets\r\n", messages[index].size)?;
+            write_message(&data, body_lines, out)
+        }
+        "DELE" => match number.and_then(lookup) {
+            Some(index) => {
+                messages[index].deleted = true;
+                out.write_all(b"+OK message deleted\r\n")
+            }
+            None => out.write_all(no_such_message),
+        },
+        "RSET" => {
+            for message in messages.iter_mut() {
+                message.deleted = false;
+            }
+            out.write_all(b"+OK\r\n")
+        }
+        "NOOP" => out.write_all(b"+OK\r\n"),
+        "QUIT" => {
+            let mut failed = 0;
+            for message in messages.iter().filter(|m| m.deleted) {
+                match maildir.delete_entry(&message.entry) {
+                    Ok(()) => (),
+                    Err(ref e) if e.kind() == io::ErrorKind::NotFound => (),
+                    Err(_) => failed += 1,
+                }
+            }
+            if failed == 0 {
+                out.write_all(b"+OK bye\r\n")
+            } else {
+                write!(out, "-ERR {} messages could not be deleted\r\n", failed)
+            }
+        }
+        _ => out.write_all(b"-ERR unknown command\r\n"),
+    }
+}
+
+fn load_maildrop(maildir: &Maildir) -> io::Result<Vec<Message>> {
+    let mut messages = Vec::new();
+    for entry in maildir.list_new().chain(maildir.list_cur()) {
+        let mut entry = entry?;
+        let size = match entry.sizes() {
+            Ok(sizes) => sizes.rfc822_size,
+            Err(crate::MailEntryError::IOError(e)) => return Err(e),
+            Err(e) => return Err(io::Error::other(e.to_string())),
+        };
+        messages.push(Message {
+            uid: unique_id(entry.id()),
+            entry: MailEntry::new(
+                entry.id().to_string(),
+                entry.flags().to_string(),
+                entry.path().clone(),
+            ),
+            size,
+            deleted: false,
+        });
+    }
+    messages.sort_by(|a, b| a.entry.id().cmp(b.entry.id()));
+    Ok(messages)
+}
+
+// Returns the id if it is a valid POP3 unique id, and a stable hash of it
+// otherwise.
+fn unique_id(id: &str) -> String {
+    if id.len() <= MAX_UID_LEN && id.bytes().all(|b| (0x21..=0x7e).contains(&b)) {
+        return id.to_string();
+    }
+    // 64-bit FNV-1a
+    let hash = id.bytes().fold(0xcbf2_9ce4_8422_2325u64, |hash, b| {
+        (hash ^ u64::from(b)).wrapping_mul(0x0100_0000_01b3)
+    });
+    format!("{:016x}", hash)
+}
+
+// Writes a message as a multi-line response: with CRLF line endings, lines
+// starting with a dot byte-stuffed, and the terminating dot line. If
+// `body_lines` is given, only that many lines of the body are written.
+fn write_message<W: Write>(data: &[u8], body_lines: Option<usize>, out: &mut W) -> io::Result<()> {
+    let mut in_body = false;
+    let mut remaining = body_lines;
+    let mut lines = data.split(|&b| b == b'\n').peekable();
+    while let Some(line) = lines.next() {
+        if line.is_empty() && lines.peek().is_none() {
+            // the final line ending
+            break;
+        }
+        if in_body {
+            match remaining {
+                Some(0) => break,
+                Some(ref mut n) => *n -= 1,
+                None => (),
+            }
+        }
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        if line.starts_with(b".") {
+            out.write_all(b".")?;
+        }
+        out.write_all(line)?;
+        out.write_all(b"\r\n")?;
+        if line.is_empty() {
+            in_body = true;
+        }
+    }
+    out.write_all(b".\r\n")
+}
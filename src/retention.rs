@@ -0,0 +1,337 @@
+//! Retention policies, which delete or archive old messages.
+//!
+//! A [`RetentionPolicy`] is a list of [`RetentionRule`]s. Each rule picks
+//! the messages matching a [`Query`] that are older
+//! than a cutoff, or that exceed a maximum number of messages to keep, and
+//! either deletes them or moves them to a subfolder. Rules are evaluated in
+//! order, and a message is only ever handled by the first rule that selects
+//! it. A policy can be applied as a dry run, which only reports what would
+//! be done.
+//!
+//! ```no_run
+//! use std::time::Duration;
+//!
+//! use maildir::retention::{RetentionAction, RetentionPolicy, RetentionRule};
+//! use maildir::search::Query;
+//! use maildir::Maildir;
+//!
+//! const DAY: u64 = 24 * 60 * 60;
+//!
+//! let maildir = Maildir::from("path/to/maildir");
+//! let policy = RetentionPolicy::new()
+//!     .rule(
+//!         RetentionRule::new(RetentionAction::Delete)
+//!             .matching(Query::trashed())
+//!             .older_than(Duration::from_secs(30 * DAY)),
+//!     )
+//!     .rule(
+//!         RetentionRule::new(RetentionAction::MoveTo(".Archive.{year}".to_string()))
+//!             .matching(Query::seen())
+//!             .older_than(Duration::from_secs(365 * DAY)),
+//!     );
+//! let report = maildir.apply_retention(&policy).unwrap();
+//! for applied in &report.applied {
+//!     println!("{} {:?}", applied.id, applied.action);
+//! }
+//! ```
+
+use std::collections::{HashMap, HashSet};
+use std::time::{self, Duration};
+
+use crate::datetime::{civil_from_days, SECONDS_PER_DAY};
+use crate::search::Query;
+use crate::{DateSource, MailEntry, MailEntryError, Maildir, MaildirError, ResolvedDate};
+
+/// What a retention rule does with the messages it selects.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RetentionAction {
+    /// Deletes the messages with `Maildir::delete`.
+    Delete,
+    /// Moves the messages to the subfolder with the given name, which is
+    /// created if needed, like `Maildir::move_to` does.
+    /// In the name, `{year}` and `{month}` are replaced with the year and
+    /// the two-digit month of the date of each message, so
+    /// `.Archive.{year}` files messages into one folder per year.
+    MoveTo(String),
+}
+
+/// A rule of a retention policy.
+///
+/// A rule selects the messages matching its query that are older than the
+/// `older_than` age, as well as the messages beyond the newest
+/// `keep_at_most` matching ones. A rule with neither limit selects every
+/// matching message.
+#[derive(Clone, Debug)]
+pub struct RetentionRule {
+    query: Query,
+    older_than: Option<Duration>,
+    keep_at_most: Option<usize>,
+    date_sources: Vec<DateSource>,
+    action: RetentionAction,
+}
+
+impl RetentionRule {
+    /// Creates a rule with the given action that matches every message and
+    /// has no limits yet.
+    pub fn new(action: RetentionAction) -> RetentionRule {
+        RetentionRule {
+            query: Query::All,
+            older_than: None,
+            keep_at_most: None,
+            date_sources: vec![DateSource::Delivery, DateSource::Mtime],
+            action,
+        }
+    }
+
+    /// Restricts the rule to the messages matching the query.
+    pub fn matching(mut self, query: Query) -> RetentionRule {
+        self.query = query;
+        self
+    }
+
+    /// Selects the matching messages that are older than the given age.
+    pub fn older_than(mut self, age: Duration) -> RetentionRule {
+        self.older_than = Some(age);
+        self
+    }
+
+    /// Selects the matching messages beyond the newest `count` ones.
+    pub fn keep_at_most(mut self, count: usize) -> RetentionRule {
+        self.keep_at_most = Some(count);
+        self
+    }
+
+    /// Sets where the date of a message is taken from, see
+    /// `MailEntry::best_date_with`. The default is the delivery timestamp
+    /// in the file name, falling back to the modification time of the file.
+    /// Messages for which none of the sources yield a date are left alone.
+    pub fn date_sources(mut self, sources: &[DateSource]) -> RetentionRule {
+        self.date_sources = sources.to_vec();
+        self
+    }
+
+    // Checks whether the message matches the rule, and finds its date if
+    // it does. Fails if the query can't be checked because the message
+    // can't be read or parsed.
+    fn check(&self, entry: &mut MailEntry) -> Result<RuleMatch, MailEntryError> {
+        if !self.query.matches(entry)? {
+            return Ok(RuleMatch::No);
+        }
+        match entry.best_date_with(&self.date_sources) {
+            Ok(date) => Ok(RuleMatch::Dated(date)),
+            Err(_) => Ok(RuleMatch::Undated),
+        }
+    }
+
+    /// Returns the positions in `candidates` of the messages that the rule,
+    /// at the given index in the policy, selects, along with their dates.
+    /// Positions in `claimed` are skipped, and the selected ones are added
+    /// to it.
+    fn select(
+        &self,
+        index: usize,
+        candidates: &[Candidate],
+        claimed: &mut HashSet<usize>,
+        undated: &mut Vec<String>,
+        now: i64,
+    ) -> Vec<(usize, ResolvedDate)> {
+        let mut matching = Vec::new();
+        for (position, candidate) in candidates.iter().enumerate() {
+            if claimed.contains(&position) {
+                continue;
+            }
+            match candidate.matches[index] {
+                RuleMatch::No => {}
+                RuleMatch::Dated(date) => matching.push((position, date)),
+                RuleMatch::Undated => undated.push(candidate.entry.id().to_string()),
+            }
+        }
+        // newest first, so the ones to keep come first
+        let id = |position: usize| candidates[position].entry.id();
+        matching.sort_by(|a, b| {
+            b.1.timestamp
+                .cmp(&a.1.timestamp)
+                .then_with(|| id(a.0).cmp(id(b.0)))
+        });
+
+        let cutoff = self.older_than.map(|age| now - age.as_secs() as i64);
+        let selected: Vec<(usize, ResolvedDate)> = matching
+            .into_iter()
+            .enumerate()
+            .filter(|(rank, (_, date))| {
+                let too_old = cutoff.is_some_and(|cutoff| date.timestamp < cutoff);
+                let too_many = self.keep_at_most.is_some_and(|keep| *rank >= keep);
+                too_old || too_many || (cutoff.is_none() && self.keep_at_most.is_none())
+            })
+            .map(|(_, selected)| selected)
+            .collect();
+        for (position, _) in &selected {
+            claimed.insert(*position);
+        }
+        selected
+    }
+}
+
+// How a message relates to one rule of a policy.
+#[derive(Clone, Copy, Debug)]
+enum RuleMatch {
+    No,
+    Dated(ResolvedDate),
+    Undated,
+}
+
+// What `apply` keeps of each message while listing the maildir: an entry
+// without the message data, so the messages themselves don't have to stay
+// in memory.
+#[derive(Debug)]
+struct Candidate {
+    entry: MailEntry,
+    matches: Vec<RuleMatch>,
+}
+
+/// An ordered list of retention rules, see the module documentation.
+#[derive(Clone, Debug, Default)]
+pub struct RetentionPolicy {
+    rules: Vec<RetentionRule>,
+    dry_run: bool,
+    now: Option<i64>,
+}
+
+impl RetentionPolicy {
+    pub fn new() -> RetentionPolicy {
+        RetentionPolicy::default()
+    }
+
+    /// Adds a rule after the existing ones.
+    pub fn rule(mut self, rule: RetentionRule) -> RetentionPolicy {
+        self.rules.push(rule);
+        self
+    }
+
+    /// When set, `apply` only reports what it would do, without touching
+    /// any messages.
+    pub fn dry_run(mut self, dry_run: bool) -> RetentionPolicy {
+        self.dry_run = dry_run;
+        self
+    }
+
+    /// Sets the current time, as a Unix timestamp, that message ages are
+    /// measured from. Defaults to the system time when the policy is
+    /// applied.
+    pub fn now(mut self, timestamp: i64) -> RetentionPolicy {
+        self.now = Some(timestamp);
+        self
+    }
+
+    /// Applies the policy to the `new` and `cur` folders of the maildir.
+    /// Subfolders are not visited. Fails before touching any message if a
+    /// message can't be read or parsed to check a rule's query. The policy
+    /// stops at the first message that fails to be deleted or moved; the
+    /// messages handled before that stay handled.
+    pub fn apply(&self, maildir: &Maildir) -> Result<RetentionReport, MaildirError> {
+        let now = match self.now {
+            Some(now) => now,
+            None => time::SystemTime::now()
+                .duration_since(time::UNIX_EPOCH)?
+                .as_secs() as i64,
+        };
+        let mut candidates = Vec::new();
+        for entry in maildir.list_new().chain(maildir.list_cur()) {
+            let mut entry = entry?;
+            let matches = self
+                .rules
+                .iter()
+                .map(|rule| rule.check(&mut entry))
+                .collect::<Result<_, _>>()?;
+            candidates.push(Candidate {
+                entry: MailEntry::new(
+                    entry.id().to_string(),
+                    entry.flags().to_string(),
+                    entry.path().clone(),
+                ),
+                matches,
+            });
+        }
+
+        let mut report = RetentionReport {
+            dry_run: self.dry_run,
+            ..RetentionReport::default()
+        };
+        let mut claimed = HashSet::new();
+        let mut selected_entries = Vec::new();
+        for (index, rule) in self.rules.iter().enumerate() {
+            let selected = rule.select(index, &candidates, &mut claimed, &mut report.undated, now);
+            for (position, date) in selected {
+                let entry = &candidates[position].entry;
+                selected_entries.push(entry);
+                let action = match rule.action {
+                    RetentionAction::Delete => RetentionAction::Delete,
+                    RetentionAction::MoveTo(ref template) => {
+                        RetentionAction::MoveTo(expand_folder_name(template, date.timestamp))
+                    }
+                };
+                report.applied.push(AppliedRetention {
+                    id: entry.id().to_string(),
+                    date,
+                    rule: index,
+                    action,
+                });
+            }
+        }
+        report.undated.sort();
+        report.undated.dedup();
+
+        if !self.dry_run {
+            let mut targets: HashMap<&str, Maildir> = HashMap::new();
+            for (applied, entry) in report.applied.iter().zip(selected_entries) {
+                match applied.action {
+                    RetentionAction::Delete => maildir.delete_entry(entry)?,
+                    RetentionAction::MoveTo(ref folder) => {
+                        if !targets.contains_key(folder.as_str()) {
+                            let target = maildir.subfolder(folder)?;
+                            target.create_dirs()?;
+                            targets.insert(folder, target);
+                        }
+                        maildir.move_entry_to(entry, &targets[folder.as_str()])?;
+                    }
+                }
+            }
+        }
+        Ok(report)
+    }
+}
+
+/// A message selected by a retention rule, as listed in a
+/// `RetentionReport`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AppliedRetention {
+    pub id: String,
+    /// The date the rule used for the message.
+    pub date: ResolvedDate,
+    /// The index of the rule in the policy.
+    pub rule: usize,
+    /// What was done with the message. For moves, this holds the name of
+    /// the subfolder the message went to.
+    pub action: RetentionAction,
+}
+
+/// The outcome of `RetentionPolicy::apply`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RetentionReport {
+    /// True if the policy was applied as a dry run, in which case nothing
+    /// in `applied` was actually done.
+    pub dry_run: bool,
+    /// The messages that were deleted or moved, in the order in which this
+    /// happened.
+    pub applied: Vec<AppliedRetention>,
+    /// The ids of the messages that matched a rule but were left alone
+    /// because no date could be found for them.
+    pub undated: Vec<String>,
+}
+
+fn expand_folder_name(template: &str, timestamp: i64) -> String {
+    let (year, month, _) = civil_from_days(timestamp.div_euclid(SECONDS_PER_DAY));
+    template
+        .replace("{year}", &year.to_string())
+        .replace("{month}", &format!("{:02}", month))
+}
@@ -0,0 +1,578 @@
+//! Rules that reorganize the existing messages of a maildir.
+//!
+//! A [`RuleSet`] is a list of [`Rule`]s. Each rule has [`Condition`]s on
+//! the headers, size, age and flags of a message, and [`RuleAction`]s that
+//! change its flags, copy it or move it to a subfolder, or delete it. Rules
+//! are evaluated in order, and a message is only ever handled by the first
+//! rule whose conditions all match. A rule set can be applied as a dry run,
+//! which only reports what would be done.
+//!
+//! Rule sets can be written in a small configuration format, one rule per
+//! block:
+//!
+//! ```text
+//! # mailing list threads nobody looked at in a month
+//! rule archive-lists
+//!     header List-Id rust-users\.example\.org
+//!     older-than 30d
+//!     unflagged F
+//!     add-flags S
+//!     move .Archive.Lists
+//!
+//! rule trash
+//!     flagged T
+//!     older-than 1w
+//!     delete
+//! ```
+//!
+//! A `rule NAME` line starts a rule, and each following line holds one
+//! condition or action; indentation is optional, and lines starting with
+//! `#` are comments. The conditions are:
+//!
+//! * `header NAME REGEX`: a header of the given name has a value matching
+//!   the regular expression, which is the rest of the line. Use `(?i)` to
+//!   ignore case.
+//! * `larger SIZE` and `smaller SIZE`: the message is larger or smaller
+//!   than the given number of bytes, which may end in `K`, `M` or `G`.
+//! * `older-than AGE` and `newer-than AGE`: the message was delivered more
+//!   or less than the given time ago, which ends in `s`, `m`, `h`, `d` or
+//!   `w`.
+//! * `flagged FLAGS` and `unflagged FLAGS`: the message has all or none of
+//!   the given maildir flags.
+//!
+//! The actions, which are carried out in order, are `set-flags FLAGS`,
+//! `add-flags FLAGS`, `remove-flags FLAGS`, `copy FOLDER`, `move FOLDER`
+//! and `delete`. Folders are subfolders of the maildir, like `.Archive`,
+//! and are created if needed. `move` and `delete` can only be the last
+//! action of a rule.
+//!
+//! This module is only available with the `rules` feature.
+//!
+//! ```no_run
+//! use maildir::rules::RuleSet;
+//! use maildir::Maildir;
+//!
+//! let maildir = Maildir::from("path/to/maildir");
+//! let rules: RuleSet = std::fs::read_to_string("rules.conf")
+//!     .unwrap()
+//!     .parse()
+//!     .unwrap();
+//! let report = maildir.apply_rules(&rules.dry_run(true)).unwrap();
+//! for applied in &report.applied {
+//!     println!("{} {:?}", applied.id, applied.actions);
+//! }
+//! ```
+
+use std::collections::HashMap;
+use std::error;
+use std::fmt;
+use std::io;
+use std::str::FromStr;
+use std::time::{self, Duration};
+
+use mailparse::MailHeaderMap;
+use regex::Regex;
+
+use crate::{DateSource, MailEntry, Maildir, MaildirError};
+
+/// A condition of a rule, see the module documentation.
+#[derive(Clone, Debug)]
+pub enum Condition {
+    /// Matches messages with a header of the given name whose decoded
+    /// value matches the regular expression.
+    Header(String, Regex),
+    /// Matches messages larger than the given number of bytes.
+    Larger(u64),
+    /// Matches messages smaller than the given number of bytes.
+    Smaller(u64),
+    /// Matches messages delivered longer ago than the given age, according
+    /// to the delivery timestamp in the file name, or the modification time
+    /// of the file if there is none.
+    OlderThan(Duration),
+    /// Matches messages delivered more recently than the given age.
+    NewerThan(Duration),
+    /// Matches messages that have all the given maildir flags.
+    Flagged(String),
+    /// Matches messages that have none of the given maildir flags.
+    Unflagged(String),
+}
+
+impl Condition {
+    fn matches(&self, entry: &mut MailEntry, now: i64) -> Result<bool, MaildirError> {
+        Ok(match *self {
+            Condition::Header(ref name, ref regex) => entry
+                .headers()?
+                .get_all_values(name)
+                .iter()
+                .any(|value| regex.is_match(value)),
+            Condition::Larger(size) => entry.size()? > size,
+            Condition::Smaller(size) => entry.size()? < size,
+            Condition::OlderThan(age) | Condition::NewerThan(age) => {
+                let date = entry.best_date_with(&[DateSource::Delivery, DateSource::Mtime]);
+                let cutoff = now - age.as_secs() as i64;
+                match (date, self) {
+                    (Ok(date), Condition::OlderThan(_)) => date.timestamp < cutoff,
+                    (Ok(date), _) => date.timestamp > cutoff,
+                    // messages without a date have no age
+                    (Err(_), _) => false,
+                }
+            }
+            Condition::Flagged(ref flags) => flags.chars().all(|f| entry.flags().contains(f)),
+            Condition::Unflagged(ref flags) => !flags.chars().any(|f| entry.flags().contains(f)),
+        })
+    }
+}
+
+/// An action of a rule, see the module documentation.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RuleAction {
+    /// Replaces the flags of the message with the given ones.
+    SetFlags(String),
+    AddFlags(String),
+    RemoveFlags(String),
+    /// Copies the message to the subfolder with the given name, like
+    /// `Maildir::copy_to` does.
+    Copy(String),
+    /// Moves the message to the subfolder with the given name, like
+    /// `Maildir::move_to` does.
+    Move(String),
+    Delete,
+}
+
+impl RuleAction {
+    // Whether the message is gone from the maildir after the action.
+    fn is_final(&self) -> bool {
+        matches!(*self, RuleAction::Move(_) | RuleAction::Delete)
+    }
+}
+
+/// A rule of a rule set, see the module documentation.
+#[derive(Clone, Debug)]
+pub struct Rule {
+    name: String,
+    conditions: Vec<Condition>,
+    actions: Vec<RuleAction>,
+}
+
+impl Rule {
+    /// Creates a rule that matches every message and does nothing yet.
+    pub fn new(name: &str) -> Rule {
+        Rule {
+            name: name.to_string(),
+            conditions: Vec::new(),
+            actions: Vec::new(),
+        }
+    }
+
+    /// Adds a condition, which must match along with the existing ones.
+    pub fn when(mut self, condition: Condition) -> Rule {
+        self.conditions.push(condition);
+        self
+    }
+
+    /// Adds an action after the existing ones. `RuleSet::apply` refuses
+    /// rules with an action after a `Move` or `Delete`.
+    pub fn then(mut self, action: RuleAction) -> Rule {
+        self.actions.push(action);
+        self
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    // Checks that nothing follows an action that takes the message away.
+    fn check_actions(&self) -> Result<(), String> {
+        let mut actions = self.actions.iter();
+        if actions.by_ref().any(RuleAction::is_final) && actions.next().is_some() {
+            return Err(format!(
+                "rule {} has actions after the final one",
+                self.name
+            ));
+        }
+        Ok(())
+    }
+
+    fn matches(&self, entry: &mut MailEntry, now: i64) -> Result<bool, MaildirError> {
+        for condition in &self.conditions {
+            if !condition.matches(entry, now)? {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+}
+
+/// An ordered list of rules, see the module documentation.
+#[derive(Clone, Debug, Default)]
+pub struct RuleSet {
+    rules: Vec<Rule>,
+    dry_run: bool,
+    now: Option<i64>,
+}
+
+impl RuleSet {
+    pub fn new() -> RuleSet {
+        RuleSet::default()
+    }
+
+    /// Parses a rule set in the configuration format described in the
+    /// module documentation.
+    pub fn parse(config: &str) -> Result<RuleSet, RulesParseError> {
+        let mut rules = RuleSet::new();
+        for (index, line) in config.lines().enumerate() {
+            let error = |message: String| RulesParseError {
+                line: index + 1,
+                message,
+            };
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (keyword, argument) = match line.split_once(char::is_whitespace) {
+                Some((keyword, argument)) => (keyword, argument.trim()),
+                None => (line, ""),
+            };
+            if keyword == "rule" {
+                if argument.is_empty() {
+                    return Err(error("a rule needs a name".to_string()));
+                }
+                rules.check_last().map_err(error)?;
+                rules.rules.push(Rule::new(argument));
+                continue;
+            }
+            let rule = match rules.rules.last_mut() {
+                Some(rule) => rule,
+                None => return Err(error(format!("{} outside of a rule", keyword))),
+            };
+            if rule.actions.last().is_some_and(RuleAction::is_final) {
+                return Err(error(format!("{} after the final action", keyword)));
+            }
+            let needs_argument = |argument: &str| {
+                if argument.is_empty() {
+                    Err(error(format!("{} needs an argument", keyword)))
+                } else {
+                    Ok(argument.to_string())
+                }
+            };
+            let is_condition = matches!(
+                keyword,
+                "header"
+                    | "larger"
+                    | "smaller"
+                    | "older-than"
+                    | "newer-than"
+                    | "flagged"
+                    | "unflagged"
+            );
+            if is_condition && !rule.actions.is_empty() {
+                return Err(error(format!("{} after an action", keyword)));
+            }
+            match keyword {
+                "header" => {
+                    let (name, pattern) = match argument.split_once(char::is_whitespace) {
+                        Some((name, pattern)) => (name, pattern.trim()),
+                        None => return Err(error("header needs a name and a regex".to_string())),
+                    };
+                    let regex = Regex::new(pattern).map_err(|e| error(e.to_string()))?;
+                    rule.conditions
+                        .push(Condition::Header(name.to_string(), regex));
+                }
+                "larger" | "smaller" => {
+                    let size = parse_size(argument)
+                        .ok_or_else(|| error(format!("invalid size {}", argument)))?;
+                    rule.conditions.push(if keyword == "larger" {
+                        Condition::Larger(size)
+                    } else {
+                        Condition::Smaller(size)
+                    });
+                }
+                "older-than" | "newer-than" => {
+                    let age = parse_age(argument)
+                        .ok_or_else(|| error(format!("invalid age {}", argument)))?;
+                    rule.conditions.push(if keyword == "older-than" {
+                        Condition::OlderThan(age)
+                    } else {
+                        Condition::NewerThan(age)
+                    });
+                }
+                "flagged" => rule
+                    .conditions
+                    .push(Condition::Flagged(needs_argument(argument)?)),
+                "unflagged" => rule
+                    .conditions
+                    .push(Condition::Unflagged(needs_argument(argument)?)),
+                "set-flags" => rule
+                    .actions
+                    .push(RuleAction::SetFlags(argument.to_string())),
+                "add-flags" => rule
+                    .actions
+                    .push(RuleAction::AddFlags(needs_argument(argument)?)),
+                "remove-flags" => rule
+                    .actions
+                    .push(RuleAction::RemoveFlags(needs_argument(argument)?)),
+                "copy" => rule
+                    .actions
+                    .push(RuleAction::Copy(needs_argument(argument)?)),
+                "move" => rule
+                    .actions
+                    .push(RuleAction::Move(needs_argument(argument)?)),
+                "delete" if argument.is_empty() => rule.actions.push(RuleAction::Delete),
+                "delete" => return Err(error("delete takes no argument".to_string())),
+                _ => return Err(error(format!("unknown keyword {}", keyword))),
+            }
+        }
+        let lines = config.lines().count();
+        rules.check_last().map_err(|message| RulesParseError {
+            line: lines.max(1),
+            message,
+        })?;
+        Ok(rules)
+    }
+
+    // Checks that the last rule parsed does something.
+    fn check_last(&self) -> Result<(), String> {
+        match self.rules.last() {
+            Some(rule) if rule.actions.is_empty() => {
+                Err(format!("rule {} has no actions", rule.name))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Adds a rule after the existing ones.
+    pub fn rule(mut self, rule: Rule) -> RuleSet {
+        self.rules.push(rule);
+        self
+    }
+
+    /// When set, `apply` only reports what it would do, without touching
+    /// any messages.
+    pub fn dry_run(mut self, dry_run: bool) -> RuleSet {
+        self.dry_run = dry_run;
+        self
+    }
+
+    /// Sets the current time, as a Unix timestamp, that message ages are
+    /// measured from. Defaults to the system time when the rules are
+    /// applied.
+    pub fn now(mut self, timestamp: i64) -> RuleSet {
+        self.now = Some(timestamp);
+        self
+    }
+
+    /// Applies the rules to the `new` and `cur` folders of the maildir.
+    /// Subfolders are not visited. Applying stops at the first message
+    /// whose actions fail; the messages handled before that stay handled,
+    /// and the error holds a report of them. A rule with an action after
+    /// its `move` or `delete` fails the whole rule set before any message
+    /// is touched.
+    pub fn apply(&self, maildir: &Maildir) -> Result<RulesReport, RulesError> {
+        let mut report = RulesReport {
+            dry_run: self.dry_run,
+            ..RulesReport::default()
+        };
+        let selected = match self.select(maildir) {
+            Ok(selected) => selected,
+            Err(error) => return Err(RulesError { report, error }),
+        };
+        if self.dry_run {
+            report.applied = selected.into_iter().map(|(applied, _)| applied).collect();
+            return Ok(report);
+        }
+
+        let mut targets = HashMap::new();
+        for (mut applied, mut entry) in selected {
+            let actions = std::mem::take(&mut applied.actions);
+            let mut flags = entry.flags().to_string();
+            for action in actions {
+                let result = apply_action(maildir, &mut entry, &action, &mut flags, &mut targets);
+                if let Err(error) = result {
+                    // report the actions already carried out on the message
+                    if !applied.actions.is_empty() {
+                        report.applied.push(applied);
+                    }
+                    return Err(RulesError { report, error });
+                }
+                applied.actions.push(action);
+            }
+            report.applied.push(applied);
+        }
+        Ok(report)
+    }
+
+    // Finds the messages the rules match, ordered by id, along with the
+    // rule that matched each of them.
+    fn select(&self, maildir: &Maildir) -> Result<Vec<(AppliedRule, MailEntry)>, MaildirError> {
+        for rule in &self.rules {
+            rule.check_actions()
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        }
+        let now = match self.now {
+            Some(now) => now,
+            None => time::SystemTime::now()
+                .duration_since(time::UNIX_EPOCH)?
+                .as_secs() as i64,
+        };
+        let mut entries = maildir
+            .list_new()
+            .chain(maildir.list_cur())
+            .collect::<Result<Vec<_>, _>>()?;
+        entries.sort_by(|a, b| a.id().cmp(b.id()));
+
+        let mut selected = Vec::new();
+        for mut entry in entries {
+            for (index, rule) in self.rules.iter().enumerate() {
+                if rule.matches(&mut entry, now)? {
+                    let applied = AppliedRule {
+                        id: entry.id().to_string(),
+                        rule: index,
+                        actions: rule.actions.clone(),
+                    };
+                    selected.push((applied, entry));
+                    break;
+                }
+            }
+        }
+        Ok(selected)
+    }
+}
+
+// Carries out one action of a rule on a message. `flags` holds the flags
+// the earlier actions left the message with.
+fn apply_action(
+    maildir: &Maildir,
+    entry: &mut MailEntry,
+    action: &RuleAction,
+    flags: &mut String,
+    targets: &mut HashMap<String, Maildir>,
+) -> Result<(), MaildirError> {
+    let new_flags = match *action {
+        RuleAction::SetFlags(ref set) => Maildir::normalize_flags(set),
+        RuleAction::AddFlags(ref added) => Maildir::normalize_flags(&format!("{}{}", flags, added)),
+        RuleAction::RemoveFlags(ref removed) => {
+            flags.chars().filter(|c| !removed.contains(*c)).collect()
+        }
+        RuleAction::Copy(ref folder) | RuleAction::Move(ref folder) => {
+            if !targets.contains_key(folder) {
+                let target = maildir.subfolder(folder)?;
+                target.create_dirs()?;
+                targets.insert(folder.clone(), target);
+            }
+            let target = &targets[folder];
+            if let RuleAction::Copy(_) = *action {
+                maildir.copy_entry_to(entry, target)?;
+            } else {
+                maildir.move_entry_to(entry, target)?;
+            }
+            return Ok(());
+        }
+        RuleAction::Delete => {
+            maildir.delete_entry(entry)?;
+            return Ok(());
+        }
+    };
+    if entry.folder_name() == "new" {
+        // a message in new has no flags yet, and moves to cur to get some
+        if !new_flags.is_empty() {
+            maildir.set_entry_flags(entry, &new_flags)?;
+        }
+    } else if new_flags != *flags {
+        maildir.set_entry_flags(entry, &new_flags)?;
+    }
+    *flags = new_flags;
+    Ok(())
+}
+
+impl FromStr for RuleSet {
+    type Err = RulesParseError;
+
+    fn from_str(s: &str) -> Result<RuleSet, RulesParseError> {
+        RuleSet::parse(s)
+    }
+}
+
+/// A message matched by a rule, as listed in a `RulesReport`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AppliedRule {
+    pub id: String,
+    /// The index of the rule in the rule set.
+    pub rule: usize,
+    /// The actions of the rule, in the order they were carried out.
+    pub actions: Vec<RuleAction>,
+}
+
+/// The outcome of `RuleSet::apply`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct RulesReport {
+    /// True if the rules were applied as a dry run, in which case nothing
+    /// in `applied` was actually done.
+    pub dry_run: bool,
+    /// The messages matched by a rule, ordered by id.
+    pub applied: Vec<AppliedRule>,
+}
+
+/// The error returned by `RuleSet::apply`.
+#[derive(Debug)]
+pub struct RulesError {
+    /// The messages handled before the error. The message whose actions
+    /// failed is included with the actions that were carried out, if any.
+    pub report: RulesReport,
+    pub error: MaildirError,
+}
+
+impl fmt::Display for RulesError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} (after handling {} messages)",
+            self.error,
+            self.report.applied.len()
+        )
+    }
+}
+
+impl error::Error for RulesError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        Some(&self.error)
+    }
+}
+
+/// An error in the configuration of a rule set.
+#[derive(Debug, PartialEq, Eq)]
+pub struct RulesParseError {
+    /// The line of the configuration the error is on, counting from 1.
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for RulesParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl error::Error for RulesParseError {}
+
+fn parse_size(s: &str) -> Option<u64> {
+    let (number, multiplier) = match s.chars().last()?.to_ascii_uppercase() {
+        'K' => (&s[..s.len() - 1], 1 << 10),
+        'M' => (&s[..s.len() - 1], 1 << 20),
+        'G' => (&s[..s.len() - 1], 1 << 30),
+        _ => (s, 1),
+    };
+    number.parse::<u64>().ok()?.checked_mul(multiplier)
+}
+
+fn parse_age(s: &str) -> Option<Duration> {
+    let unit = match s.chars().last()? {
+        's' => 1,
+        'm' => 60,
+        'h' => 60 * 60,
+        'd' => 24 * 60 * 60,
+        'w' => 7 * 24 * 60 * 60,
+        _ => return None,
+    };
+    let number: u64 = s[..s.len() - 1].parse().ok()?;
+    Some(Duration::from_secs(number.checked_mul(unit)?))
+}
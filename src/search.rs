@@ -0,0 +1,524 @@
+//! Structured queries over the messages in a maildir.
+//!
+//! A [`Query`] can be built directly or parsed from the string form of an
+//! IMAP `SEARCH` command (RFC 3501, section 6.4.4), and is evaluated with
+//! `Maildir::search` or `Query::matches`. Predicates on flags are answered
+//! from the file names alone; the message file is only read when the query
+//! needs a header, the body, a date or the size.
+//!
+//! ```no_run
+//! use maildir::search::Query;
+//! use maildir::Maildir;
+//!
+//! let maildir = Maildir::from("path/to/maildir");
+//! let query: Query = "UNSEEN FROM alice SINCE 1-Feb-2020".parse().unwrap();
+//! for entry in maildir.search(&query) {
+//!     println!("{}", entry.unwrap().id());
+//! }
+//! ```
+
+use std::collections::BTreeSet;
+use std::error;
+use std::fmt;
+use std::fs;
+use std::ops;
+use std::str::FromStr;
+use std::time;
+
+use mailparse::{MailHeaderMap, ParsedMail};
+
+use crate::datetime::{parse_imap_date, SECONDS_PER_DAY};
+use crate::{MailEntries, MailEntry, MailEntryError};
+
+/// A predicate on messages.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Query {
+    /// Matches every message.
+    All,
+    /// Matches messages that have the given maildir flag, e.g. `'S'` for
+    /// seen messages.
+    Flag(char),
+    /// Matches messages in the `new` folder, which have not been seen by
+    /// any mail client yet.
+    Recent,
+    /// Matches messages with a header of the given name whose value
+    /// contains the given string, ignoring case. An empty string matches
+    /// every message that has the header.
+    Header(String, String),
+    /// Matches messages whose body contains the given string, ignoring
+    /// case. Only textual parts of the message are searched.
+    Body(String),
+    /// Matches messages whose headers or body contain the given string,
+    /// ignoring case.
+    Text(String),
+    /// Matches messages that were delivered before the given timestamp,
+    /// according to the modification time of the message file.
+    Before(i64),
+    /// Matches messages that were delivered at or after the given
+    /// timestamp, according to the modification time of the message file.
+    Since(i64),
+    /// Matches messages whose `Date` header is before the given timestamp.
+    /// Messages without a valid `Date` header don't match.
+    SentBefore(i64),
+    /// Matches messages whose `Date` header is at or after the given
+    /// timestamp. Messages without a valid `Date` header don't match.
+    SentSince(i64),
+    /// Matches messages whose RFC822 size (see `MailEntry::sizes`) is
+    /// larger than the given number of bytes.
+    Larger(u64),
+    /// Matches messages whose RFC822 size (see `MailEntry::sizes`) is
+    /// smaller than the given number of bytes.
+    Smaller(u64),
+    /// Matches messages whose id is in the set.
+    Ids(BTreeSet<String>),
+    Not(Box<Query>),
+    And(Vec<Query>),
+    Or(Box<Query>, Box<Query>),
+}
+
+impl Query {
+    pub fn seen() -> Query {
+        Query::Flag('S')
+    }
+
+    pub fn flagged() -> Query {
+        Query::Flag('F')
+    }
+
+    pub fn replied() -> Query {
+        Query::Flag('R')
+    }
+
+    pub fn trashed() -> Query {
+        Query::Flag('T')
+    }
+
+    pub fn draft() -> Query {
+        Query::Flag('D')
+    }
+
+    pub fn header(name: &str, value: &str) -> Query {
+        Query::Header(name.to_string(), value.to_string())
+    }
+
+    pub fn or(left: Query, right: Query) -> Query {
+        Query::Or(Box::new(left), Box::new(right))
+    }
+
+    /// Parses the string form of an IMAP `SEARCH` command. The supported
+    /// search keys are `ALL`, `ANSWERED`, `DELETED`, `DRAFT`, `FLAGGED`,
+    /// `SEEN`, `RECENT`, `NEW`, `OLD`, the `UN`-prefixed negations,
+    /// `FROM`, `TO`, `CC`, `BCC`, `SUBJECT`, `HEADER`, `BODY`, `TEXT`,
+    /// `BEFORE`, `ON`, `SINCE`, `SENTBEFORE`, `SENTON`, `SENTSINCE`,
+    /// `LARGER`, `SMALLER`, `NOT`, `OR` and parenthesized lists. Keys are
+    /// case-insensitive, and several keys in a row must all match.
+    pub fn parse(s: &str) -> Result<Query, QueryParseError> {
+        Query::parse_query(s, None)
+    }
+
+    /// Like `parse`, but also accepts the IMAP sequence set and `UID` search
+    /// keys. Sequence sets refer to the message numbers of an IMAP session,
+    /// so they are resolved into `Query::Ids` by the given function, which
+    /// gets the set and whether it is a set of UIDs, and returns `None` if
+    /// the set is invalid.
+    pub fn parse_with_sets<F>(s: &str, mut resolve_set: F) -> Result<Query, QueryParseError>
+    where
+        F: FnMut(&str, bool) -> Option<BTreeSet<String>>,
+    {
+        Query::parse_query(s, Some(&mut resolve_set))
+    }
+
+    fn parse_query(
+        s: &str,
+        resolve_set: Option<&mut SetResolver>,
+    ) -> Result<Query, QueryParseError> {
+        let mut parser = Parser {
+            tokens: tokenize(s)?,
+            pos: 0,
+            resolve_set,
+        };
+        let query = parser.parse_list(false)?;
+        match parser.tokens.get(parser.pos) {
+            None => Ok(query),
+            Some(_) => Err(QueryParseError::UnbalancedParenthesis),
+        }
+    }
+
+    /// Returns true if the query can be answered from the file name of a
+    /// message alone, without reading the message.
+    pub fn is_flag_only(&self) -> bool {
+        match *self {
+            Query::All | Query::Flag(_) | Query::Recent | Query::Ids(_) => true,
+            Query::Not(ref q) => q.is_flag_only(),
+            Query::And(ref qs) => qs.iter().all(Query::is_flag_only),
+            Query::Or(ref a, ref b) => a.is_flag_only() && b.is_flag_only(),
+            _ => false,
+        }
+    }
+
+    /// Evaluates the query against a message.
+    pub fn matches(&self, entry: &mut MailEntry) -> Result<bool, MailEntryError> {
+        Ok(match *self {
+            Query::All => true,
+            Query::Flag(flag) => entry.flags().contains(flag),
+            Query::Recent => is_recent(entry),
+            Query::Header(ref name, ref value) => {
+                let value = value.to_lowercase();
+                entry
+                    .headers()?
+                    .get_all_values(name)
+                    .iter()
+                    .any(|v| v.to_lowercase().contains(&value))
+            }
+            Query::Body(ref needle) => body_contains(&entry.parsed()?, &needle.to_lowercase()),
+            Query::Text(ref needle) => {
+                let needle = needle.to_lowercase();
+                let parsed = entry.parsed()?;
+                parsed.headers.iter().any(|h| {
+                    h.get_key().to_lowercase().contains(&needle)
+                        || h.get_value().to_lowercase().contains(&needle)
+                }) || body_contains(&parsed, &needle)
+            }
+            Query::Before(ts) => internal_date(entry)? < ts,
+            Query::Since(ts) => internal_date(entry)? >= ts,
+            Query::SentBefore(ts) => sent_date(entry)?.is_some_and(|date| date < ts),
+            Query::SentSince(ts) => sent_date(entry)?.is_some_and(|date| date >= ts),
+            Query::Larger(size) => entry.sizes()?.rfc822_size > size,
+            Query::Smaller(size) => entry.sizes()?.rfc822_size < size,
+            Query::Ids(ref ids) => ids.contains(entry.id()),
+            Query::Not(ref q) => !q.matches(entry)?,
+            Query::And(ref qs) => {
+                // answer what we can from the file name before reading the message
+                for q in qs.iter().filter(|q| q.is_flag_only()) {
+                    if !q.matches(entry)? {
+                        return Ok(false);
+                    }
+                }
+                for q in qs.iter().filter(|q| !q.is_flag_only()) {
+                    if !q.matches(entry)? {
+                        return Ok(false);
+                    }
+                }
+                true
+            }
+            Query::Or(ref a, ref b) => {
+                let (first, second) = if !a.is_flag_only() && b.is_flag_only() {
+                    (b, a)
+                } else {
+                    (a, b)
+                };
+                first.matches(entry)? || second.matches(entry)?
+            }
+        })
+    }
+}
+
+impl ops::Not for Query {
+    type Output = Query;
+
+    fn not(self) -> Query {
+        Query::Not(Box::new(self))
+    }
+}
+
+impl FromStr for Query {
+    type Err = QueryParseError;
+
+    fn from_str(s: &str) -> Result<Query, QueryParseError> {
+        Query::parse(s)
+    }
+}
+
+fn is_recent(entry: &MailEntry) -> bool {
+    entry
+        .path()
+        .parent()
+        .and_then(|p| p.file_name())
+        .map(|name| name == "new")
+        .unwrap_or(false)
+}
+
+fn internal_date(entry: &MailEntry) -> Result<i64, MailEntryError> {
+    let modified = fs::metadata(entry.path())?.modified()?;
+    Ok(match modified.duration_since(time::UNIX_EPOCH) {
+        Ok(d) => d.as_secs() as i64,
+        Err(e) => -(e.duration().as_secs() as i64),
+    })
+}
+
+// The date from the Date header, or None if it is missing or invalid.
+fn sent_date(entry: &mut MailEntry) -> Result<Option<i64>, MailEntryError> {
+    match entry.date() {
+        Ok(date) => Ok(Some(date)),
+        Err(MailEntryError::DateError(_)) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+fn body_contains(mail: &ParsedMail, needle: &str) -> bool {
+    if mail.subparts.is_empty() {
+        mail.ctype.mimetype.starts_with("text/")
+            && mail
+                .get_body()
+                .map(|body| body.to_lowercase().contains(needle))
+                .unwrap_or(false)
+    } else {
+        mail.subparts.iter().any(|part| body_contains(part, needle))
+    }
+}
+
+/// An iterator over the messages of a maildir that match a query, as
+/// returned by `Maildir::search`. Messages in `new` are produced before
+/// those in `cur`. An `Err` is produced for messages that could not be
+/// listed, read or evaluated.
+#[derive(Debug)]
+pub struct SearchResults<'q> {
+    query: &'q Query,
+    entries: std::iter::Chain<MailEntries, MailEntries>,
+}
+
+impl<'q> SearchResults<'q> {
+    pub(crate) fn new(query: &'q Query, new: MailEntries, cur: MailEntries) -> SearchResults<'q> {
+        SearchResults {
+            query,
+            entries: new.chain(cur),
+        }
+    }
+}
+
+impl<'q> Iterator for SearchResults<'q> {
+    type Item = Result<MailEntry, MailEntryError>;
+
+    fn next(&mut self) -> Option<Result<MailEntry, MailEntryError>> {
+        for entry in &mut self.entries {
+            let mut entry = match entry {
+                Ok(entry) => entry,
+                Err(e) => return Some(Err(e.into())),
+            };
+            match self.query.matches(&mut entry) {
+                Ok(true) => return Some(Ok(entry)),
+                Ok(false) => continue,
+                Err(e) => return Some(Err(e)),
+            }
+        }
+        None
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum QueryParseError {
+    /// The query ended where an argument or search key was expected.
+    UnexpectedEnd,
+    UnknownKey(String),
+    InvalidArgument(String),
+    UnbalancedParenthesis,
+    UnterminatedString,
+}
+
+impl fmt::Display for QueryParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use QueryParseError::*;
+
+        match *self {
+            UnexpectedEnd => write!(f, "Unexpected end of query"),
+            UnknownKey(ref key) => write!(f, "Unknown search key: {}", key),
+            InvalidArgument(ref arg) => write!(f, "Invalid argument: {}", arg),
+            UnbalancedParenthesis => write!(f, "Unbalanced parenthesis"),
+            UnterminatedString => write!(f, "Unterminated quoted string"),
+        }
+    }
+}
+
+impl error::Error for QueryParseError {}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Token {
+    Open,
+    Close,
+    // an atom, which may be a search key or an argument
+    Atom(String),
+    // a quoted string, which can only be an argument
+    Quoted(String),
+}
+
+fn tokenize(s: &str) -> Result<Vec<Token>, QueryParseError> {
+    let mut tokens = Vec::new();
+    let mut chars = s.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        match c {
+            c if c.is_whitespace() => {
+                chars.next();
+            }
+            '(' => {
+                chars.next();
+                tokens.push(Token::Open);
+            }
+            ')' => {
+                chars.next();
+                tokens.push(Token::Close);
+            }
+            '"' => {
+                chars.next();
+                let mut value = String::new();
+                loop {
+                    match chars.next() {
+                        None => return Err(QueryParseError::UnterminatedString),
+                        Some('"') => break,
+                        Some('\\') => match chars.next() {
+                            Some(escaped) => value.push(escaped),
+                            None => return Err(QueryParseError::UnterminatedString),
+                        },
+                        Some(c) => value.push(c),
+                    }
+                }
+                tokens.push(Token::Quoted(value));
+            }
+            _ => {
+                let mut value = String::new();
+                while let Some(&c) = chars.peek() {
+                    if c.is_whitespace() || c == '(' || c == ')' || c == '"' {
+                        break;
+                    }
+                    value.push(c);
+                    chars.next();
+                }
+                tokens.push(Token::Atom(value));
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+type SetResolver<'a> = dyn FnMut(&str, bool) -> Option<BTreeSet<String>> + 'a;
+
+struct Parser<'r, 'a> {
+    tokens: Vec<Token>,
+    pos: usize,
+    resolve_set: Option<&'r mut SetResolver<'a>>,
+}
+
+impl<'r, 'a> Parser<'r, 'a> {
+    fn next(&mut self) -> Option<&Token> {
+        let token = self.tokens.get(self.pos);
+        self.pos += 1;
+        token
+    }
+
+    // Parses keys up to the end of input, or up to the closing parenthesis
+    // if `nested` is set, and combines them with AND.
+    fn parse_list(&mut self, nested: bool) -> Result<Query, QueryParseError> {
+        let mut queries = Vec::new();
+        loop {
+            match self.tokens.get(self.pos) {
+                None if nested => return Err(QueryParseError::UnbalancedParenthesis),
+                None => break,
+                Some(Token::Close) if nested => {
+                    self.pos += 1;
+                    break;
+                }
+                Some(Token::Close) => return Err(QueryParseError::UnbalancedParenthesis),
+                Some(_) => queries.push(self.parse_key()?),
+            }
+        }
+        match queries.len() {
+            0 if nested => Err(QueryParseError::UnexpectedEnd),
+            0 => Ok(Query::All),
+            1 => Ok(queries.pop().unwrap()),
+            _ => Ok(Query::And(queries)),
+        }
+    }
+
+    fn string(&mut self) -> Result<String, QueryParseError> {
+        match self.next() {
+            Some(Token::Atom(s)) | Some(Token::Quoted(s)) => Ok(s.clone()),
+            Some(Token::Open) | Some(Token::Close) => {
+                Err(QueryParseError::InvalidArgument("parenthesis".to_string()))
+            }
+            None => Err(QueryParseError::UnexpectedEnd),
+        }
+    }
+
+    fn date(&mut self) -> Result<i64, QueryParseError> {
+        let s = self.string()?;
+        parse_imap_date(&s).ok_or(QueryParseError::InvalidArgument(s))
+    }
+
+    fn number(&mut self) -> Result<u64, QueryParseError> {
+        let s = self.string()?;
+        s.parse().map_err(|_| QueryParseError::InvalidArgument(s))
+    }
+
+    fn parse_key(&mut self) -> Result<Query, QueryParseError> {
+        let key = match self.next() {
+            None => return Err(QueryParseError::UnexpectedEnd),
+            Some(Token::Open) => return self.parse_list(true),
+            Some(Token::Close) => return Err(QueryParseError::UnbalancedParenthesis),
+            Some(Token::Quoted(s)) => return Err(QueryParseError::UnknownKey(s.clone())),
+            Some(Token::Atom(s)) => s.to_ascii_uppercase(),
+        };
+        if self.resolve_set.is_some() {
+            let set = match key.as_str() {
+                "UID" => Some((self.string()?, true)),
+                _ if key.starts_with(|c: char| c.is_ascii_digit() || c == '*') => {
+                    Some((key.clone(), false))
+                }
+                _ => None,
+            };
+            if let Some((set, uid)) = set {
+                let resolve_set = self.resolve_set.as_mut().unwrap();
+                return match resolve_set(&set, uid) {
+                    Some(ids) => Ok(Query::Ids(ids)),
+                    None => Err(QueryParseError::InvalidArgument(set)),
+                };
+            }
+        }
+        let flag = |c| Query::Flag(c);
+        let unflag = |c| !Query::Flag(c);
+        Ok(match key.as_str() {
+            "ALL" => Query::All,
+            "ANSWERED" => flag('R'),
+            "DELETED" => flag('T'),
+            "DRAFT" => flag('D'),
+            "FLAGGED" => flag('F'),
+            "SEEN" => flag('S'),
+            "UNANSWERED" => unflag('R'),
+            "UNDELETED" => unflag('T'),
+            "UNDRAFT" => unflag('D'),
+            "UNFLAGGED" => unflag('F'),
+            "UNSEEN" => unflag('S'),
+            "RECENT" => Query::Recent,
+            "OLD" => !Query::Recent,
+            "NEW" => Query::And(vec![Query::Recent, unflag('S')]),
+            "FROM" | "TO" | "CC" | "BCC" | "SUBJECT" => Query::Header(key, self.string()?),
+            "HEADER" => Query::Header(self.string()?, self.string()?),
+            "BODY" => Query::Body(self.string()?),
+            "TEXT" => Query::Text(self.string()?),
+            "BEFORE" => Query::Before(self.date()?),
+            "SINCE" => Query::Since(self.date()?),
+            "ON" => {
+                let day = self.date()?;
+                Query::And(vec![
+                    Query::Since(day),
+                    Query::Before(day + SECONDS_PER_DAY),
+                ])
+            }
+            "SENTBEFORE" => Query::SentBefore(self.date()?),
+            "SENTSINCE" => Query::SentSince(self.date()?),
+            "SENTON" => {
+                let day = self.date()?;
+                Query::And(vec![
+                    Query::SentSince(day),
+                    Query::SentBefore(day + SECONDS_PER_DAY),
+                ])
+            }
+            "LARGER" => Query::Larger(self.number()?),
+            "SMALLER" => Query::Smaller(self.number()?),
+            "NOT" => !self.parse_key()?,
+            "OR" => {
+                let left = self.parse_key()?;
+                Query::or(left, self.parse_key()?)
+            }
+            _ => return Err(QueryParseError::UnknownKey(key)),
+        })
+    }
+}
@@ -0,0 +1,27 @@
+//! What the POP3, IMAP and LMTP servers have in common: accepting
+//! connections and serving each of them on its own thread.
+
+use std::io;
+use std::thread;
+
+/// Takes connections from `incoming` and serves each of them on its own
+/// thread with `handle`. This only returns if accepting a connection
+/// fails, after the connections accepted so far have been served.
+pub(crate) fn serve<S, I, H>(incoming: I, handle: H) -> io::Result<()>
+where
+    S: Send,
+    I: Iterator<Item = io::Result<S>>,
+    H: Fn(S) -> io::Result<()> + Sync,
+{
+    let handle = &handle;
+    thread::scope(|scope| {
+        for stream in incoming {
+            let stream = stream?;
+            scope.spawn(move || {
+                // a failed connection has nobody left to report to
+                let _ = handle(stream);
+            });
+        }
+        Ok(())
+    })
+}
@@ -0,0 +1,1672 @@
+//! Sieve filtering of messages at delivery time.
+//!
+//! A [`Script`] is a Sieve script as described in RFC 5228, supporting
+//! the core language and the `fileinto`, `envelope`, `copy`, `body`
+//! (RFC 5173), `imap4flags` (RFC 5232) and `vacation` (RFC 5230)
+//! extensions, with the `i;octet` and `i;ascii-casemap` comparators. A
+//! script is checked completely when it is parsed, so mistakes show up when
+//! a user uploads it rather than when mail arrives.
+//!
+//! `Script::evaluate` runs a script against a message and returns the
+//! [`Action`]s to take. [`SieveFilter`] also carries them out: it stores
+//! the message into the maildir, or into the subfolders named by
+//! `fileinto`, with the flags set by `imap4flags`, and writes vacation
+//! responses into a local outbox maildir, from which another program is
+//! expected to send them. Redirects are only reported, since sending mail
+//! is up to the MTA.
+//!
+//! Mailbox names use `.` as the hierarchy delimiter, and `INBOX` is the
+//! maildir itself, so `fileinto "Lists.rust"` stores into `.Lists.rust`.
+//!
+//! This module is only available with the `sieve` feature.
+//!
+//! ```no_run
+//! use maildir::sieve::{Envelope, Script, SieveFilter};
+//! use maildir::Maildir;
+//!
+//! let script: Script = r#"
+//!     require ["fileinto", "imap4flags"];
+//!     if header :contains "List-Id" "rust-users" {
+//!         fileinto :flags "\\Seen" "Lists.rust";
+//!     }
+//! "#
+//! .parse()
+//! .unwrap();
+//! let maildir = Maildir::from("/home/alice/Maildir");
+//! let envelope = Envelope::new("bob@example.org", "alice@example.org");
+//! let message = b"List-Id: <rust-users.example.org>\n\nhello\n";
+//! SieveFilter::new(&script)
+//!     .deliver(&maildir, message, &envelope)
+//!     .unwrap();
+//! ```
+
+use std::collections::HashSet;
+use std::error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::str::FromStr;
+use std::sync::atomic::Ordering;
+use std::time;
+
+use mailparse::{MailAddr, MailHeader, MailHeaderMap, ParsedMail};
+
+use crate::datetime::{format_rfc2822, SECONDS_PER_DAY};
+use crate::delivery::DeliveryHeaders;
+use crate::{Maildir, MaildirError, COUNTER};
+
+/// The name of the file in the outbox that records which senders got a
+/// vacation response, and until when no other one is sent to them.
+pub const VACATION_FILE_NAME: &str = "maildir.vacation";
+
+/// The name of the file in the outbox that is locked while the vacation
+/// records are updated, so concurrent deliveries don't lose each other's
+/// records. Locking is only done on Unix.
+pub const VACATION_LOCK_FILE_NAME: &str = "maildir.vacation.lock";
+
+// The extensions a script can require.
+const EXTENSIONS: &[&str] = &[
+    "fileinto",
+    "envelope",
+    "copy",
+    "body",
+    "imap4flags",
+    "vacation",
+    "comparator-i;octet",
+    "comparator-i;ascii-casemap",
+];
+
+// The tags that are followed by a value.
+const VALUE_TAGS: &[&str] = &[
+    "comparator",
+    "days",
+    "subject",
+    "from",
+    "addresses",
+    "handle",
+    "flags",
+    "content",
+];
+
+// The IMAP system flags and the maildir flags they map to.
+const FLAGS: [(&str, char); 5] = [
+    ("\\answered", 'R'),
+    ("\\flagged", 'F'),
+    ("\\deleted", 'T'),
+    ("\\seen", 'S'),
+    ("\\draft", 'D'),
+];
+
+/// An error in a Sieve script.
+#[derive(Debug, PartialEq, Eq)]
+pub struct SieveParseError {
+    /// The line of the script the error is on, counting from 1.
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for SieveParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl error::Error for SieveParseError {}
+
+/// The envelope of a message, which the `envelope` test and vacation
+/// responses look at.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Envelope {
+    /// The envelope sender, empty for bounces.
+    pub from: String,
+    /// The envelope recipient.
+    pub to: String,
+}
+
+impl Envelope {
+    pub fn new(from: &str, to: &str) -> Envelope {
+        Envelope {
+            from: from.to_string(),
+            to: to.to_string(),
+        }
+    }
+}
+
+/// The parameters of a vacation response, see RFC 5230.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Vacation {
+    /// How many days to wait before responding to the same sender again.
+    pub days: u64,
+    pub subject: Option<String>,
+    pub from: Option<String>,
+    /// Other addresses of the user, besides the envelope recipient.
+    pub addresses: Vec<String>,
+    /// Whether the reason is a MIME entity rather than plain text.
+    pub mime: bool,
+    pub handle: Option<String>,
+    pub reason: String,
+}
+
+/// What a script decided to do with a message.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Action {
+    /// Stores the message into the mailbox, `INBOX` for `keep`, with the
+    /// given IMAP flags.
+    Store { mailbox: String, flags: Vec<String> },
+    /// Forwards the message to the address.
+    Redirect(String),
+    /// Sends a vacation response to the sender.
+    Vacation(Vacation),
+}
+
+/// A parsed Sieve script, see the module documentation.
+#[derive(Clone, Debug)]
+pub struct Script {
+    commands: Vec<Command>,
+}
+
+#[derive(Clone, Debug)]
+enum Command {
+    If(Vec<(Test, Vec<Command>)>, Vec<Command>),
+    Stop,
+    Keep(Option<Vec<String>>),
+    Discard,
+    FileInto {
+        mailbox: String,
+        flags: Option<Vec<String>>,
+        copy: bool,
+    },
+    Redirect {
+        address: String,
+        copy: bool,
+    },
+    SetFlag(Vec<String>),
+    AddFlag(Vec<String>),
+    RemoveFlag(Vec<String>),
+    Vacation(Vacation),
+}
+
+#[derive(Clone, Debug)]
+enum Test {
+    True,
+    False,
+    Not(Box<Test>),
+    AllOf(Vec<Test>),
+    AnyOf(Vec<Test>),
+    Exists(Vec<String>),
+    Size { over: bool, limit: u64 },
+    Header(Matcher, Vec<String>, Vec<String>),
+    Address(Matcher, AddressPart, Vec<String>, Vec<String>),
+    Envelope(Matcher, AddressPart, Vec<String>, Vec<String>),
+    Body(Matcher, BodyTransform, Vec<String>),
+    HasFlag(Matcher, Vec<String>),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum MatchType {
+    Is,
+    Contains,
+    Matches,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Matcher {
+    match_type: MatchType,
+    // true for i;ascii-casemap, false for i;octet
+    ignore_case: bool,
+}
+
+#[derive(Clone, Copy, Debug)]
+enum AddressPart {
+    All,
+    LocalPart,
+    Domain,
+}
+
+#[derive(Clone, Debug)]
+enum BodyTransform {
+    Raw,
+    Content(Vec<String>),
+    Text,
+}
+
+impl FromStr for Script {
+    type Err = SieveParseError;
+
+    fn from_str(s: &str) -> Result<Script, SieveParseError> {
+        Script::parse(s)
+    }
+}
+
+impl Script {
+    /// Parses and checks a script.
+    pub fn parse(source: &str) -> Result<Script, SieveParseError> {
+        let mut parser = Parser {
+            tokens: tokenize(source)?,
+            pos: 0,
+        };
+        let raw = parser.commands(false)?;
+        let mut compiler = Compiler {
+            extensions: HashSet::new(),
+        };
+        Ok(Script {
+            commands: compiler.commands(raw, true)?,
+        })
+    }
+
+    /// Runs the script against a message and returns the actions to take,
+    /// including the implicit keep. An empty list means the message is
+    /// discarded.
+    pub fn evaluate(&self, message: &[u8], envelope: &Envelope) -> Vec<Action> {
+        let mut context = Context {
+            message,
+            parsed: mailparse::parse_mail(message).ok(),
+            envelope,
+            flags: Vec::new(),
+            actions: Vec::new(),
+            implicit_keep: true,
+        };
+        context.run(&self.commands);
+        if context.implicit_keep {
+            let flags = context.flags.clone();
+            context.store("INBOX", flags);
+        }
+        context.actions
+    }
+}
+
+/// Delivers messages as a script says, see the module documentation.
+#[derive(Debug)]
+pub struct SieveFilter<'a> {
+    script: &'a Script,
+    outbox: Option<&'a Maildir>,
+    now: Option<i64>,
+}
+
+/// The result of `SieveFilter::deliver`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct FilterReport {
+    /// The mailboxes the message was stored in, and its id in each.
+    pub stored: Vec<(String, String)>,
+    /// The addresses the message should be redirected to.
+    pub redirects: Vec<String>,
+    /// The id of the vacation response stored in the outbox, if one was.
+    pub vacation: Option<String>,
+    /// Why the vacation response couldn't be generated or stored, if that
+    /// failed. This doesn't fail the delivery of the message.
+    pub vacation_error: Option<String>,
+}
+
+impl<'a> SieveFilter<'a> {
+    pub fn new(script: &'a Script) -> SieveFilter<'a> {
+        SieveFilter {
+            script,
+            outbox: None,
+            now: None,
+        }
+    }
+
+    /// Sets the maildir vacation responses are stored in, to be sent by
+    /// another program. Without an outbox, no vacation responses are
+    /// generated.
+    pub fn outbox(mut self, outbox: &'a Maildir) -> SieveFilter<'a> {
+        self.outbox = Some(outbox);
+        self
+    }
+
+    /// Sets the current time as a Unix timestamp, for the dates of
+    /// vacation responses and for tracking whom they were sent to. Defaults
+    /// to the system time.
+    pub fn now(mut self, timestamp: i64) -> SieveFilter<'a> {
+        self.now = Some(timestamp);
+        self
+    }
+
+    /// Evaluates the script against the message, stores it where the
+    /// script says, and generates a vacation response if it asks for one.
+    /// If the message can't be stored into a mailbox named by `fileinto`,
+    /// it is stored into the maildir itself instead, so no mail is lost.
+    /// Only a failure to store the message fails the delivery.
+    pub fn deliver(
+        &self,
+        maildir: &Maildir,
+        message: &[u8],
+        envelope: &Envelope,
+    ) -> Result<FilterReport, MaildirError> {
+        let mut report = FilterReport::default();
+        let actions = self.script.evaluate(message, envelope);
+        // whether the message is stored into the maildir itself anyway
+        let mut kept = actions.iter().any(|action| match *action {
+            Action::Store { ref mailbox, .. } => mailbox.eq_ignore_ascii_case("INBOX"),
+            _ => false,
+        });
+        for action in actions {
+            match action {
+                Action::Store { mailbox, flags } => {
+                    let flags = maildir_flags(&flags);
+                    match store(maildir, &mailbox, message, &flags) {
+                        Ok(id) => report.stored.push((mailbox, id)),
+                        Err(e) if mailbox.eq_ignore_ascii_case("INBOX") => return Err(e),
+                        Err(_) if kept => (),
+                        Err(_) => {
+                            let id = store(maildir, "INBOX", message, &flags)?;
+                            report.stored.push(("INBOX".to_string(), id));
+                            kept = true;
+                        }
+                    }
+                }
+                Action::Redirect(address) => report.redirects.push(address),
+                Action::Vacation(vacation) => {
+                    if let Some(outbox) = self.outbox {
+                        match self.vacation(outbox, &vacation, message, envelope) {
+                            Ok(id) => report.vacation = id,
+                            Err(e) => report.vacation_error = Some(e.to_string()),
+                        }
+                    }
+                }
+            }
+        }
+        Ok(report)
+    }
+
+    fn vacation(
+        &self,
+        outbox: &Maildir,
+        vacation: &Vacation,
+        message: &[u8],
+        envelope: &Envelope,
+    ) -> Result<Option<String>, MaildirError> {
+        let headers = match mailparse::parse_headers(message) {
+            Ok((headers, _)) => headers,
+            Err(_) => return Ok(None),
+        };
+        if !should_respond(&headers, vacation, envelope) {
+            return Ok(None);
+        }
+        let now = self.now.unwrap_or_else(|| {
+            time::SystemTime::now()
+                .duration_since(time::UNIX_EPOCH)
+                .map_or(0, |d| d.as_secs() as i64)
+        });
+        let handle = match vacation.handle {
+            Some(ref handle) => handle.clone(),
+            None => default_handle(vacation),
+        };
+        let sender = envelope.from.to_lowercase();
+        outbox.create_dirs()?;
+        if !record_response(outbox, &handle, &sender, now, vacation.days)? {
+            return Ok(None);
+        }
+
+        let subject = match vacation.subject {
+            Some(ref subject) => subject.clone(),
+            None => format!(
+                "Auto: {}",
+                headers
+                    .get_first_value("Subject")
+                    .unwrap_or_default()
+                    .trim()
+            ),
+        };
+        let from = vacation.from.clone().unwrap_or_else(|| envelope.to.clone());
+        let mut response = String::new();
+        response.push_str(&format!("From: {}\n", from));
+        response.push_str(&format!("To: <{}>\n", envelope.from));
+        response.push_str(&format!(
+            "Subject: {}\n",
+            subject.replace(['\r', '\n'], " ")
+        ));
+        response.push_str(&format!("Date: {}\n", format_rfc2822(now)));
+        if let Some(message_id) = headers.get_first_value("Message-ID") {
+            let message_id = message_id.trim();
+            response.push_str(&format!("In-Reply-To: {}\n", message_id));
+            match headers.get_first_value("References") {
+                Some(references) => response.push_str(&format!(
+                    "References: {} {}\n",
+                    references.trim(),
+                    message_id
+                )),
+                None => response.push_str(&format!("References: {}\n", message_id)),
+            }
+        }
+        response.push_str("Auto-Submitted: auto-replied (vacation)\n");
+        response.push_str("MIME-Version: 1.0\n");
+        if vacation.mime {
+            response.push_str(vacation.reason.trim_start_matches(['\r', '\n']));
+        } else {
+            response.push_str("Content-Type: text/plain; charset=utf-8\n");
+            response.push_str("Content-Transfer-Encoding: 8bit\n\n");
+            response.push_str(&vacation.reason);
+        }
+        if !response.ends_with('\n') {
+            response.push('\n');
+        }
+        // responses go out with an empty envelope sender, so they can't
+        // bounce back and forth
+        let response = DeliveryHeaders::new()
+            .return_path("")
+            .prepend_to(response.as_bytes());
+        outbox.store_new(&response).map(Some)
+    }
+}
+
+// Stores a message into a mailbox of the maildir, creating the mailbox if
+// needed.
+fn store(
+    maildir: &Maildir,
+    mailbox: &str,
+    message: &[u8],
+    flags: &str,
+) -> Result<String, MaildirError> {
+    let target = if mailbox.eq_ignore_ascii_case("INBOX") {
+        maildir.clone()
+    } else {
+        let name = match mailbox.get(..6) {
+            Some(prefix) if prefix.eq_ignore_ascii_case("INBOX.") => &mailbox[6..],
+            _ => mailbox,
+        };
+        if name.is_empty() || name.contains(['/', '\\']) || name.contains("..") {
+            return Err(MaildirError::InvalidFolderName(mailbox.to_string()));
+        }
+        let name = format!(".{}", name.trim_start_matches('.'));
+        maildir.create_subfolder_dirs(&name)?;
+        maildir.subfolder(&name)?
+    };
+    if flags.is_empty() {
+        target.store_new(message)
+    } else {
+        target.store_cur_with_flags(message, flags)
+    }
+}
+
+// Maps IMAP flags to maildir flags. Keywords have no maildir flag and are
+// dropped.
+fn maildir_flags(flags: &[String]) -> String {
+    let mut result: Vec<char> = flags
+        .iter()
+        .filter_map(|flag| {
+            FLAGS
+                .iter()
+                .find(|&&(name, _)| name.eq_ignore_ascii_case(flag))
+                .map(|&(_, c)| c)
+        })
+        .collect();
+    result.sort_unstable();
+    result.dedup();
+    result.into_iter().collect()
+}
+
+// Applies the rules of RFC 5230 about whom not to respond to.
+fn should_respond(headers: &[MailHeader], vacation: &Vacation, envelope: &Envelope) -> bool {
+    let sender = envelope.from.to_lowercase();
+    let local = sender
+        .rsplit_once('@')
+        .map_or(sender.as_str(), |(local, _)| local);
+    if sender.is_empty()
+        || local == "mailer-daemon"
+        || local == "listserv"
+        || local == "majordomo"
+        || local.starts_with("owner-")
+        || local.ends_with("-request")
+    {
+        return false;
+    }
+    if let Some(auto) = headers.get_first_value("Auto-Submitted") {
+        if !auto.trim().eq_ignore_ascii_case("no") {
+            return false;
+        }
+    }
+    if headers
+        .iter()
+        .any(|h| h.get_key().to_lowercase().starts_with("list-"))
+    {
+        return false;
+    }
+    if let Some(precedence) = headers.get_first_value("Precedence") {
+        let precedence = precedence.trim().to_lowercase();
+        if precedence == "bulk" || precedence == "list" || precedence == "junk" {
+            return false;
+        }
+    }
+    // only messages sent to the user directly get a response
+    let mut own: Vec<String> = vacation
+        .addresses
+        .iter()
+        .map(|a| a.to_lowercase())
+        .collect();
+    own.push(envelope.to.to_lowercase());
+    ["To", "Cc", "Bcc", "Resent-To", "Resent-Cc", "Resent-Bcc"]
+        .iter()
+        .flat_map(|name| headers.get_all_headers(name))
+        .flat_map(|header| addresses(header))
+        .any(|address| own.contains(&address.to_lowercase()))
+}
+
+// Derives the handle of a vacation response that doesn't name one from its
+// parameters, as RFC 5230 suggests. The handles are stored, so this uses
+// 64-bit FNV-1a rather than the standard library's hashers, whose output
+// may change between Rust releases.
+fn default_handle(vacation: &Vacation) -> String {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    let mut feed = |bytes: &[u8]| {
+        for &byte in bytes {
+            hash ^= u64::from(byte);
+            hash = hash.wrapping_mul(0x0100_0000_01b3);
+        }
+    };
+    let fields = [
+        vacation.subject.as_deref(),
+        vacation.from.as_deref(),
+        Some(vacation.reason.as_str()),
+    ];
+    for field in fields {
+        // the lengths keep the fields apart
+        match field {
+            Some(field) => {
+                feed(&[1]);
+                feed(&(field.len() as u64).to_le_bytes());
+                feed(field.as_bytes());
+            }
+            None => feed(&[0]),
+        }
+    }
+    feed(&[u8::from(vacation.mime)]);
+    format!("{:016x}", hash)
+}
+
+// Checks whether a response with the handle may be sent to the sender, and
+// records it if so.
+fn record_response(
+    outbox: &Maildir,
+    handle: &str,
+    sender: &str,
+    now: i64,
+    days: u64,
+) -> io::Result<bool> {
+    // held until the records are replaced
+    let _lock = lock_records(outbox)?;
+    let path = outbox.path().join(VACATION_FILE_NAME);
+    let content = match fs::read_to_string(&path) {
+        Ok(content) => content,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(e),
+    };
+    let handle = handle.replace(['\t', '\n', '\r'], " ");
+    let sender = sender.replace(['\t', '\n', '\r'], " ");
+    let mut records = String::new();
+    for line in content.lines() {
+        let mut fields = line.splitn(3, '\t');
+        let expiry: i64 = match fields.next().and_then(|e| e.parse().ok()) {
+            Some(expiry) => expiry,
+            None => continue,
+        };
+        if expiry <= now {
+            // forget expired records
+            continue;
+        }
+        if fields.next() == Some(handle.as_str()) && fields.next() == Some(sender.as_str()) {
+            return Ok(false);
+        }
+        records.push_str(line);
+        records.push('\n');
+    }
+    let expiry = now + days.max(1) as i64 * SECONDS_PER_DAY;
+    records.push_str(&format!("{}\t{}\t{}\n", expiry, handle, sender));
+    let tmp_path = outbox.path().join(format!(
+        "{}.{}.{}.tmp",
+        VACATION_FILE_NAME,
+        std::process::id(),
+        COUNTER.fetch_add(1, Ordering::SeqCst)
+    ));
+    if let Err(e) = fs::write(&tmp_path, records).and_then(|()| fs::rename(&tmp_path, &path)) {
+        let _ = fs::remove_file(&tmp_path);
+        return Err(e);
+    }
+    Ok(true)
+}
+
+// Takes an exclusive lock on the vacation lock file of the outbox, which is
+// released when the returned file is closed.
+fn lock_records(outbox: &Maildir) -> io::Result<fs::File> {
+    let file = fs::OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(outbox.path().join(VACATION_LOCK_FILE_NAME))?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::io::AsRawFd;
+
+        // SAFETY: the descriptor belongs to `file`, which is open
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(file)
+}
+
+// Returns the addresses in an address header, with group members.
+fn addresses(header: &MailHeader) -> Vec<String> {
+    let list = match mailparse::addrparse_header(header) {
+        Ok(list) => list,
+        Err(_) => return Vec::new(),
+    };
+    let mut result = Vec::new();
+    for addr in list.iter() {
+        match *addr {
+            MailAddr::Single(ref info) => result.push(info.addr.clone()),
+            MailAddr::Group(ref group) => {
+                result.extend(group.addrs.iter().map(|info| info.addr.clone()))
+            }
+        }
+    }
+    result
+}
+
+// The state of an evaluation.
+struct Context<'m> {
+    message: &'m [u8],
+    parsed: Option<ParsedMail<'m>>,
+    envelope: &'m Envelope,
+    // the internal variable of imap4flags
+    flags: Vec<String>,
+    actions: Vec<Action>,
+    implicit_keep: bool,
+}
+
+impl<'m> Context<'m> {
+    // Runs commands, and returns false once `stop` was run.
+    fn run(&mut self, commands: &[Command]) -> bool {
+        for command in commands {
+            match *command {
+                Command::If(ref branches, ref otherwise) => {
+                    let block = branches
+                        .iter()
+                        .find(|(test, _)| self.test(test))
+                        .map_or(otherwise, |(_, block)| block);
+                    if !self.run(block) {
+                        return false;
+                    }
+                }
+                Command::Stop => return false,
+                Command::Keep(ref flags) => {
+                    let flags = flags.clone().unwrap_or_else(|| self.flags.clone());
+                    self.store("INBOX", flags);
+                    self.implicit_keep = false;
+                }
+                Command::Discard => self.implicit_keep = false,
+                Command::FileInto {
+                    ref mailbox,
+                    ref flags,
+                    copy,
+                } => {
+                    let flags = flags.clone().unwrap_or_else(|| self.flags.clone());
+                    self.store(mailbox, flags);
+                    if !copy {
+                        self.implicit_keep = false;
+                    }
+                }
+                Command::Redirect { ref address, copy } => {
+                    let action = Action::Redirect(address.clone());
+                    if !self.actions.contains(&action) {
+                        self.actions.push(action);
+                    }
+                    if !copy {
+                        self.implicit_keep = false;
+                    }
+                }
+                Command::SetFlag(ref flags) => {
+                    self.flags.clear();
+                    add_flags(&mut self.flags, flags);
+                }
+                Command::AddFlag(ref flags) => add_flags(&mut self.flags, flags),
+                Command::RemoveFlag(ref flags) => {
+                    let mut removed = Vec::new();
+                    add_flags(&mut removed, flags);
+                    self.flags
+                        .retain(|flag| !removed.iter().any(|r| r.eq_ignore_ascii_case(flag)));
+                }
+                Command::Vacation(ref vacation) => {
+                    if !self
+                        .actions
+                        .iter()
+                        .any(|a| matches!(*a, Action::Vacation(_)))
+                    {
+                        self.actions.push(Action::Vacation(vacation.clone()));
+                    }
+                }
+            }
+        }
+        true
+    }
+
+    fn headers(&self) -> &[MailHeader<'m>] {
+        self.parsed.as_ref().map_or(&[], |parsed| &parsed.headers)
+    }
+
+    // Adds a store action, unless the message is already stored into the
+    // same mailbox.
+    fn store(&mut self, mailbox: &str, flags: Vec<String>) {
+        let exists = self.actions.iter().any(|action| match *action {
+            Action::Store { mailbox: ref m, .. } => m.eq_ignore_ascii_case(mailbox),
+            _ => false,
+        });
+        if !exists {
+            self.actions.push(Action::Store {
+                mailbox: mailbox.to_string(),
+                flags,
+            });
+        }
+    }
+
+    fn test(&self, test: &Test) -> bool {
+        match *test {
+            Test::True => true,
+            Test::False => false,
+            Test::Not(ref test) => !self.test(test),
+            Test::AllOf(ref tests) => tests.iter().all(|t| self.test(t)),
+            Test::AnyOf(ref tests) => tests.iter().any(|t| self.test(t)),
+            Test::Exists(ref names) => names
+                .iter()
+                .all(|name| self.headers().get_first_header(name).is_some()),
+            Test::Size { over, limit } => {
+                let size = self.message.len() as u64;
+                if over {
+                    size > limit
+                } else {
+                    size < limit
+                }
+            }
+            Test::Header(matcher, ref names, ref keys) => names.iter().any(|name| {
+                self.headers()
+                    .get_all_values(name)
+                    .iter()
+                    .any(|value| matcher.any(value.trim(), keys))
+            }),
+            Test::Address(matcher, part, ref names, ref keys) => names.iter().any(|name| {
+                self.headers()
+                    .get_all_headers(name)
+                    .iter()
+                    .flat_map(|header| addresses(header))
+                    .any(|address| matcher.any(address_part(&address, part), keys))
+            }),
+            Test::Envelope(matcher, part, ref names, ref keys) => names.iter().any(|name| {
+                let address = match name.to_lowercase().as_str() {
+                    "from" => &self.envelope.from,
+                    "to" => &self.envelope.to,
+                    _ => return false,
+                };
+                matcher.any(address_part(address, part), keys)
+            }),
+            Test::Body(matcher, ref transform, ref keys) => self
+                .bodies(transform)
+                .iter()
+                .any(|body| matcher.any(body, keys)),
+            Test::HasFlag(matcher, ref keys) => {
+                self.flags.iter().any(|flag| matcher.any(flag, keys))
+            }
+        }
+    }
+
+    // Returns the parts of the body the body test looks at.
+    fn bodies(&self, transform: &BodyTransform) -> Vec<String> {
+        let parsed = match self.parsed {
+            Some(ref parsed) => parsed,
+            None => return Vec::new(),
+        };
+        if let BodyTransform::Raw = *transform {
+            let body = match self.message.windows(2).position(|w| w == b"\n\n") {
+                Some(pos) => &self.message[pos + 2..],
+                None => match self.message.windows(4).position(|w| w == b"\r\n\r\n") {
+                    Some(pos) => &self.message[pos + 4..],
+                    None => &[],
+                },
+            };
+            return vec![String::from_utf8_lossy(body).into_owned()];
+        }
+        let mut result = Vec::new();
+        let mut parts = vec![parsed];
+        while let Some(part) = parts.pop() {
+            if !part.subparts.is_empty() {
+                parts.extend(part.subparts.iter().rev());
+                continue;
+            }
+            let mimetype = part.ctype.mimetype.to_lowercase();
+            let wanted = match *transform {
+                BodyTransform::Content(ref types) => types.iter().any(|t| {
+                    let t = t.to_lowercase();
+                    t.is_empty() || t == mimetype || mimetype.split('/').next() == Some(&t)
+                }),
+                _ => mimetype.starts_with("text/"),
+            };
+            if wanted {
+                if let Ok(body) = part.get_body() {
+                    result.push(body);
+                }
+            }
+        }
+        result
+    }
+}
+
+fn add_flags(flags: &mut Vec<String>, added: &[String]) {
+    for flag in added.iter().flat_map(|f| f.split_whitespace()) {
+        if !flags.iter().any(|f| f.eq_ignore_ascii_case(flag)) {
+            flags.push(flag.to_string());
+        }
+    }
+}
+
+fn address_part(address: &str, part: AddressPart) -> &str {
+    match part {
+        AddressPart::All => address,
+        AddressPart::LocalPart => address.rsplit_once('@').map_or(address, |(l, _)| l),
+        AddressPart::Domain => address.rsplit_once('@').map_or("", |(_, d)| d),
+    }
+}
+
+impl Matcher {
+    fn any(&self, value: &str, keys: &[String]) -> bool {
+        keys.iter().any(|key| self.matches(value, key))
+    }
+
+    fn matches(&self, value: &str, key: &str) -> bool {
+        let (value, key) = if self.ignore_case {
+            (value.to_ascii_lowercase(), key.to_ascii_lowercase())
+        } else {
+            (value.to_string(), key.to_string())
+        };
+        match self.match_type {
+            MatchType::Is => value == key,
+            MatchType::Contains => value.contains(&key),
+            MatchType::Matches => wildcard(&key, &value),
+        }
+    }
+}
+
+// A character of a `:matches` pattern.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Wildcard {
+    Any,
+    One,
+    Char(char),
+}
+
+// Matches a value against a pattern where `*` matches any sequence, `?`
+// any character, and a backslash escapes the next character.
+fn wildcard(pattern: &str, value: &str) -> bool {
+    let mut pat = Vec::new();
+    let mut chars = pattern.chars();
+    while let Some(c) = chars.next() {
+        pat.push(match c {
+            '*' => Wildcard::Any,
+            '?' => Wildcard::One,
+            '\\' => Wildcard::Char(chars.next().unwrap_or('\\')),
+            c => Wildcard::Char(c),
+        });
+    }
+    let text: Vec<char> = value.chars().collect();
+    let (mut p, mut t) = (0, 0);
+    let mut backtrack = None;
+    while t < text.len() {
+        match pat.get(p) {
+            Some(&Wildcard::Any) => {
+                backtrack = Some((p, t));
+                p += 1;
+            }
+            Some(&Wildcard::One) => {
+                p += 1;
+                t += 1;
+            }
+            Some(&Wildcard::Char(c)) if c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match backtrack {
+                // let the last `*` match one more character
+                Some((star, start)) => {
+                    p = star + 1;
+                    t = start + 1;
+                    backtrack = Some((star, start + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pat[p..].iter().all(|&w| w == Wildcard::Any)
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Token {
+    Identifier(String),
+    Tag(String),
+    Number(u64),
+    Str(String),
+    LeftBracket,
+    RightBracket,
+    LeftBrace,
+    RightBrace,
+    LeftParen,
+    RightParen,
+    Comma,
+    Semicolon,
+}
+
+fn error<T>(line: usize, message: &str) -> Result<T, SieveParseError> {
+    Err(SieveParseError {
+        line,
+        message: message.to_string(),
+    })
+}
+
+fn tokenize(source: &str) -> Result<Vec<(Token, usize)>, SieveParseError> {
+    let mut tokens = Vec::new();
+    let mut line = 1;
+    let mut chars = source.chars().peekable();
+    while let Some(c) = chars.next() {
+        let start = line;
+        let token = match c {
+            '\n' => {
+                line += 1;
+                continue;
+            }
+            c if c.is_whitespace() => continue,
+            '#' => {
+                for c in chars.by_ref() {
+                    if c == '\n' {
+                        line += 1;
+                        break;
+                    }
+                }
+                continue;
+            }
+            '/' if chars.peek() == Some(&'*') => {
+                chars.next();
+                let mut last = ' ';
+                loop {
+                    match chars.next() {
+                        Some('/') if last == '*' => break,
+                        Some(c) => {
+                            if c == '\n' {
+                                line += 1;
+                            }
+                            last = c;
+                        }
+                        None => return error(start, "unterminated comment"),
+                    }
+                }
+                continue;
+            }
+            '[' => Token::LeftBracket,
+            ']' => Token::RightBracket,
+            '{' => Token::LeftBrace,
+            '}' => Token::RightBrace,
+            '(' => Token::LeftParen,
+            ')' => Token::RightParen,
+            ',' => Token::Comma,
+            ';' => Token::Semicolon,
+            '"' => {
+                let mut value = String::new();
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        // an undefined escape is the character itself
+                        Some('\\') => match chars.next() {
+                            Some(c) => value.push(c),
+                            None => return error(start, "unterminated string"),
+                        },
+                        Some(c) => {
+                            if c == '\n' {
+                                line += 1;
+                            }
+                            value.push(c);
+                        }
+                        None => return error(start, "unterminated string"),
+                    }
+                }
+                Token::Str(value)
+            }
+            ':' => {
+                let name = identifier(&mut chars, None);
+                if name.is_empty() {
+                    return error(line, "expected a tag name after ':'");
+                }
+                Token::Tag(name)
+            }
+            c if c.is_ascii_digit() => {
+                let mut number = u64::from(c as u8 - b'0');
+                while let Some(d) = chars.peek().and_then(|c| c.to_digit(10)) {
+                    chars.next();
+                    number = number.saturating_mul(10).saturating_add(u64::from(d));
+                }
+                let multiplier = match chars.peek().map(|c| c.to_ascii_uppercase()) {
+                    Some('K') => 1 << 10,
+                    Some('M') => 1 << 20,
+                    Some('G') => 1 << 30,
+                    _ => 1,
+                };
+                if multiplier > 1 {
+                    chars.next();
+                }
+                Token::Number(number.saturating_mul(multiplier))
+            }
+            c if c.is_ascii_alphabetic() || c == '_' => {
+                let name = identifier(&mut chars, Some(c));
+                if name == "text" && chars.peek() == Some(&':') {
+                    chars.next();
+                    // the rest of the line may only hold a comment
+                    for c in chars.by_ref() {
+                        if c == '\n' {
+                            break;
+                        }
+                    }
+                    line += 1;
+                    let mut value = String::new();
+                    loop {
+                        let mut text_line = String::new();
+                        let mut ended = false;
+                        for c in chars.by_ref() {
+                            if c == '\n' {
+                                ended = true;
+                                break;
+                            }
+                            text_line.push(c);
+                        }
+                        if !ended && text_line.is_empty() {
+                            return error(start, "unterminated multi-line string");
+                        }
+                        line += 1;
+                        let text_line = text_line.strip_suffix('\r').unwrap_or(&text_line);
+                        if text_line == "." {
+                            break;
+                        }
+                        value.push_str(text_line.strip_prefix('.').unwrap_or(text_line));
+                        value.push('\n');
+                    }
+                    Token::Str(value)
+                } else {
+                    Token::Identifier(name)
+                }
+            }
+            c => return error(line, &format!("unexpected character '{}'", c)),
+        };
+        tokens.push((token, start));
+    }
+    Ok(tokens)
+}
+
+fn identifier(chars: &mut std::iter::Peekable<std::str::Chars>, first: Option<char>) -> String {
+    let mut name: String = first.into_iter().collect();
+    while let Some(&c) = chars.peek() {
+        if !(c.is_ascii_alphanumeric() || c == '_') {
+            break;
+        }
+        name.push(c);
+        chars.next();
+    }
+    name.to_ascii_lowercase()
+}
+
+// The syntax tree of RFC 5228, before the commands and tests are checked.
+#[derive(Debug)]
+enum RawArgument {
+    Tag(String),
+    Number(u64),
+    Strings(Vec<String>),
+}
+
+#[derive(Debug)]
+struct RawTest {
+    name: String,
+    line: usize,
+    arguments: Vec<RawArgument>,
+    tests: Vec<RawTest>,
+}
+
+#[derive(Debug)]
+struct RawCommand {
+    test: RawTest,
+    block: Option<Vec<RawCommand>>,
+}
+
+struct Parser {
+    tokens: Vec<(Token, usize)>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos).map(|(token, _)| token)
+    }
+
+    fn line(&self) -> usize {
+        match self.tokens.get(self.pos) {
+            Some(&(_, line)) => line,
+            None => self.tokens.last().map_or(1, |&(_, line)| line),
+        }
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).map(|(token, _)| token.clone());
+        self.pos += 1;
+        token
+    }
+
+    fn commands(&mut self, in_block: bool) -> Result<Vec<RawCommand>, SieveParseError> {
+        let mut commands = Vec::new();
+        loop {
+            match self.peek() {
+                None if in_block => return error(self.line(), "missing '}'"),
+                None => return Ok(commands),
+                Some(Token::RightBrace) if in_block => {
+                    self.next();
+                    return Ok(commands);
+                }
+                Some(Token::Identifier(_)) => {
+                    let test = self.test()?;
+                    let block = match self.next() {
+                        Some(Token::Semicolon) => None,
+                        Some(Token::LeftBrace) => Some(self.commands(true)?),
+                        _ => return error(test.line, "expected ';' or '{' after a command"),
+                    };
+                    commands.push(RawCommand { test, block });
+                }
+                Some(_) => return error(self.line(), "expected a command"),
+            }
+        }
+    }
+
+    // Parses an identifier with its arguments, which is the shape of both
+    // commands and tests.
+    fn test(&mut self) -> Result<RawTest, SieveParseError> {
+        let line = self.line();
+        let name = match self.next() {
+            Some(Token::Identifier(name)) => name,
+            _ => return error(line, "expected a test"),
+        };
+        let mut arguments = Vec::new();
+        let mut tests = Vec::new();
+        loop {
+            match self.peek() {
+                Some(Token::Tag(_)) | Some(Token::Number(_)) | Some(Token::Str(_)) => {
+                    match self.next() {
+                        Some(Token::Tag(tag)) => arguments.push(RawArgument::Tag(tag)),
+                        Some(Token::Number(n)) => arguments.push(RawArgument::Number(n)),
+                        Some(Token::Str(s)) => arguments.push(RawArgument::Strings(vec![s])),
+                        _ => unreachable!("the token was peeked"),
+                    }
+                }
+                Some(Token::LeftBracket) => {
+                    self.next();
+                    let mut strings = Vec::new();
+                    loop {
+                        match self.next() {
+                            Some(Token::Str(s)) => strings.push(s),
+                            _ => return error(self.line(), "expected a string in a list"),
+                        }
+                        match self.next() {
+                            Some(Token::Comma) => continue,
+                            Some(Token::RightBracket) => break,
+                            _ => return error(self.line(), "expected ',' or ']'"),
+                        }
+                    }
+                    arguments.push(RawArgument::Strings(strings));
+                }
+                Some(Token::Identifier(_)) => {
+                    tests.push(self.test()?);
+                    return Ok(RawTest {
+                        name,
+                        line,
+                        arguments,
+                        tests,
+                    });
+                }
+                Some(Token::LeftParen) => {
+                    self.next();
+                    loop {
+                        tests.push(self.test()?);
+                        match self.next() {
+                            Some(Token::Comma) => continue,
+                            Some(Token::RightParen) => break,
+                            _ => return error(self.line(), "expected ',' or ')'"),
+                        }
+                    }
+                    return Ok(RawTest {
+                        name,
+                        line,
+                        arguments,
+                        tests,
+                    });
+                }
+                _ => {
+                    return Ok(RawTest {
+                        name,
+                        line,
+                        arguments,
+                        tests,
+                    })
+                }
+            }
+        }
+    }
+}
+
+// The arguments of a command or test, with the tags separated out.
+struct Arguments {
+    line: usize,
+    tags: Vec<(String, Option<RawArgument>)>,
+    positional: Vec<RawArgument>,
+}
+
+impl Arguments {
+    fn new(raw: Vec<RawArgument>, line: usize) -> Result<Arguments, SieveParseError> {
+        let mut tags = Vec::new();
+        let mut positional = Vec::new();
+        let mut raw = raw.into_iter();
+        while let Some(argument) = raw.next() {
+            match argument {
+                RawArgument::Tag(tag) => {
+                    if tags.iter().any(|(t, _)| *t == tag) {
+                        return error(line, &format!("duplicate tag :{}", tag));
+                    }
+                    let value = if VALUE_TAGS.contains(&tag.as_str()) {
+                        match raw.next() {
+                            Some(value) => Some(value),
+                            None => return error(line, &format!(":{} needs a value", tag)),
+                        }
+                    } else {
+                        None
+                    };
+                    tags.push((tag, value));
+                }
+                argument => positional.push(argument),
+            }
+        }
+        Ok(Arguments {
+            line,
+            tags,
+            positional,
+        })
+    }
+
+    fn flag(&mut self, name: &str) -> bool {
+        match self.tags.iter().position(|(tag, _)| tag == name) {
+            Some(index) => {
+                self.tags.remove(index);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn value(&mut self, name: &str) -> Option<RawArgument> {
+        let index = self.tags.iter().position(|(tag, _)| tag == name)?;
+        self.tags.remove(index).1
+    }
+
+    fn string_value(&mut self, name: &str) -> Result<Option<String>, SieveParseError> {
+        match self.value(name) {
+            None => Ok(None),
+            Some(RawArgument::Strings(mut list)) if list.len() == 1 => Ok(list.pop()),
+            Some(_) => error(self.line, &format!(":{} needs a string", name)),
+        }
+    }
+
+    fn list_value(&mut self, name: &str) -> Result<Option<Vec<String>>, SieveParseError> {
+        match self.value(name) {
+            None => Ok(None),
+            Some(RawArgument::Strings(list)) => Ok(Some(list)),
+            Some(_) => error(self.line, &format!(":{} needs a string list", name)),
+        }
+    }
+
+    fn number_value(&mut self, name: &str) -> Result<Option<u64>, SieveParseError> {
+        match self.value(name) {
+            None => Ok(None),
+            Some(RawArgument::Number(n)) => Ok(Some(n)),
+            Some(_) => error(self.line, &format!(":{} needs a number", name)),
+        }
+    }
+
+    // Takes the next positional string list.
+    fn strings(&mut self) -> Result<Vec<String>, SieveParseError> {
+        if self.positional.is_empty() {
+            return error(self.line, "missing argument");
+        }
+        match self.positional.remove(0) {
+            RawArgument::Strings(list) => Ok(list),
+            _ => error(self.line, "expected a string or string list"),
+        }
+    }
+
+    fn string(&mut self) -> Result<String, SieveParseError> {
+        let mut list = self.strings()?;
+        match list.len() {
+            1 => Ok(list.remove(0)),
+            _ => error(self.line, "expected a single string"),
+        }
+    }
+
+    fn number(&mut self) -> Result<u64, SieveParseError> {
+        if self.positional.is_empty() {
+            return error(self.line, "missing argument");
+        }
+        match self.positional.remove(0) {
+            RawArgument::Number(n) => Ok(n),
+            _ => error(self.line, "expected a number"),
+        }
+    }
+
+    // Checks that all arguments were used.
+    fn finish(self) -> Result<(), SieveParseError> {
+        if let Some((tag, _)) = self.tags.first() {
+            return error(self.line, &format!("unexpected tag :{}", tag));
+        }
+        if !self.positional.is_empty() {
+            return error(self.line, "too many arguments");
+        }
+        Ok(())
+    }
+
+    // Takes the match type and comparator. Both comparators supported here
+    // are always available, so they need no require.
+    fn matcher(&mut self) -> Result<Matcher, SieveParseError> {
+        let mut match_type = None;
+        for (tag, kind) in [
+            ("is", MatchType::Is),
+            ("contains", MatchType::Contains),
+            ("matches", MatchType::Matches),
+        ] {
+            if self.flag(tag) {
+                if match_type.is_some() {
+                    return error(self.line, "only one match type is allowed");
+                }
+                match_type = Some(kind);
+            }
+        }
+        let ignore_case = match self.string_value("comparator")?.as_deref() {
+            None | Some("i;ascii-casemap") => true,
+            Some("i;octet") => false,
+            Some(other) => return error(self.line, &format!("unknown comparator {}", other)),
+        };
+        Ok(Matcher {
+            match_type: match_type.unwrap_or(MatchType::Is),
+            ignore_case,
+        })
+    }
+
+    fn address_part(&mut self) -> Result<AddressPart, SieveParseError> {
+        let mut part = None;
+        for (tag, kind) in [
+            ("all", AddressPart::All),
+            ("localpart", AddressPart::LocalPart),
+            ("domain", AddressPart::Domain),
+        ] {
+            if self.flag(tag) {
+                if part.is_some() {
+                    return error(self.line, "only one address part is allowed");
+                }
+                part = Some(kind);
+            }
+        }
+        Ok(part.unwrap_or(AddressPart::All))
+    }
+}
+
+// Turns the syntax tree into commands and tests, checking arguments and
+// required extensions.
+struct Compiler {
+    extensions: HashSet<String>,
+}
+
+impl Compiler {
+    fn require(&self, extension: &str, line: usize) -> Result<(), SieveParseError> {
+        if self.extensions.contains(extension) {
+            Ok(())
+        } else {
+            error(line, &format!("missing require \"{}\"", extension))
+        }
+    }
+
+    fn commands(
+        &mut self,
+        raw: Vec<RawCommand>,
+        top_level: bool,
+    ) -> Result<Vec<Command>, SieveParseError> {
+        let mut commands: Vec<Command> = Vec::new();
+        let mut requires_allowed = top_level;
+        let mut raw = raw.into_iter().peekable();
+        while let Some(command) = raw.next() {
+            let RawCommand { test, block } = command;
+            let line = test.line;
+            let name = test.name.clone();
+            if name == "require" {
+                if !requires_allowed {
+                    return error(line, "require must come before other commands");
+                }
+                let mut args = Arguments::new(test.arguments, line)?;
+                for extension in args.strings()? {
+                    if !EXTENSIONS.contains(&extension.as_str()) {
+                        return error(line, &format!("unsupported extension \"{}\"", extension));
+                    }
+                    self.extensions.insert(extension);
+                }
+                args.finish()?;
+                continue;
+            }
+            requires_allowed = false;
+
+            if name == "if" {
+                let mut branches = vec![self.branch(test, block)?];
+                let mut otherwise = Vec::new();
+                while let Some(next) = raw.peek() {
+                    match next.test.name.as_str() {
+                        "elsif" => {
+                            let next = raw.next().unwrap();
+                            branches.push(self.branch(next.test, next.block)?);
+                        }
+                        "else" => {
+                            let next = raw.next().unwrap();
+                            if !next.test.arguments.is_empty() || !next.test.tests.is_empty() {
+                                return error(next.test.line, "else takes no arguments");
+                            }
+                            otherwise = match next.block {
+                                Some(block) => self.commands(block, false)?,
+                                None => return error(next.test.line, "else needs a block"),
+                            };
+                            break;
+                        }
+                        _ => break,
+                    }
+                }
+                commands.push(Command::If(branches, otherwise));
+                continue;
+            }
+            if name == "elsif" || name == "else" {
+                return error(line, &format!("{} without if", name));
+            }
+            if block.is_some() || !test.tests.is_empty() {
+                return error(line, &format!("{} takes no block or test", name));
+            }
+            let mut args = Arguments::new(test.arguments, line)?;
+            let command = match name.as_str() {
+                "stop" => Command::Stop,
+                "keep" => Command::Keep(self.flags_tag(&mut args)?),
+                "discard" => Command::Discard,
+                "fileinto" => {
+                    self.require("fileinto", line)?;
+                    let flags = self.flags_tag(&mut args)?;
+                    let copy = self.copy_tag(&mut args)?;
+                    Command::FileInto {
+                        mailbox: args.string()?,
+                        flags,
+                        copy,
+                    }
+                }
+                "redirect" => {
+                    let copy = self.copy_tag(&mut args)?;
+                    Command::Redirect {
+                        address: args.string()?,
+                        copy,
+                    }
+                }
+                "setflag" | "addflag" | "removeflag" => {
+                    self.require("imap4flags", line)?;
+                    let flags = args.strings()?;
+                    match name.as_str() {
+                        "setflag" => Command::SetFlag(flags),
+                        "addflag" => Command::AddFlag(flags),
+                        _ => Command::RemoveFlag(flags),
+                    }
+                }
+                "vacation" => {
+                    self.require("vacation", line)?;
+                    Command::Vacation(Vacation {
+                        days: args.number_value("days")?.unwrap_or(7).max(1),
+                        subject: args.string_value("subject")?,
+                        from: args.string_value("from")?,
+                        addresses: args.list_value("addresses")?.unwrap_or_default(),
+                        mime: args.flag("mime"),
+                        handle: args.string_value("handle")?,
+                        reason: args.string()?,
+                    })
+                }
+                _ => return error(line, &format!("unknown command {}", name)),
+            };
+            args.finish()?;
+            commands.push(command);
+        }
+        Ok(commands)
+    }
+
+    fn branch(
+        &mut self,
+        mut test: RawTest,
+        block: Option<Vec<RawCommand>>,
+    ) -> Result<(Test, Vec<Command>), SieveParseError> {
+        let line = test.line;
+        if !test.arguments.is_empty() || test.tests.len() != 1 {
+            return error(line, &format!("{} needs a single test", test.name));
+        }
+        let condition = self.test(test.tests.remove(0))?;
+        match block {
+            Some(block) => Ok((condition, self.commands(block, false)?)),
+            None => error(line, &format!("{} needs a block", test.name)),
+        }
+    }
+
+    fn flags_tag(&self, args: &mut Arguments) -> Result<Option<Vec<String>>, SieveParseError> {
+        let flags = args.list_value("flags")?;
+        if flags.is_some() {
+            self.require("imap4flags", args.line)?;
+        }
+        Ok(flags)
+    }
+
+    fn copy_tag(&self, args: &mut Arguments) -> Result<bool, SieveParseError> {
+        let copy = args.flag("copy");
+        if copy {
+            self.require("copy", args.line)?;
+        }
+        Ok(copy)
+    }
+
+    fn test(&self, raw: RawTest) -> Result<Test, SieveParseError> {
+        let line = raw.line;
+        let RawTest {
+            name,
+            arguments,
+            tests,
+            ..
+        } = raw;
+        match name.as_str() {
+            "allof" | "anyof" if arguments.is_empty() && !tests.is_empty() => {
+                let tests = tests
+                    .into_iter()
+                    .map(|t| self.test(t))
+                    .collect::<Result<Vec<_>, _>>()?;
+                return Ok(if name == "allof" {
+                    Test::AllOf(tests)
+                } else {
+                    Test::AnyOf(tests)
+                });
+            }
+            "not" if arguments.is_empty() && tests.len() == 1 => {
+                let test = tests.into_iter().next().unwrap();
+                return Ok(Test::Not(Box::new(self.test(test)?)));
+            }
+            "allof" | "anyof" | "not" => {
+                return error(line, &format!("{} needs tests", name));
+            }
+            _ if !tests.is_empty() => {
+                return error(line, &format!("{} takes no tests", name));
+            }
+            _ => (),
+        }
+        let mut args = Arguments::new(arguments, line)?;
+        let test = match name.as_str() {
+            "true" => Test::True,
+            "false" => Test::False,
+            "exists" => Test::Exists(args.strings()?),
+            "size" => {
+                let over = args.flag("over");
+                let under = args.flag("under");
+                if over == under {
+                    return error(line, "size needs one of :over and :under");
+                }
+                Test::Size {
+                    over,
+                    limit: args.number()?,
+                }
+            }
+            "header" => {
+                let matcher = args.matcher()?;
+                Test::Header(matcher, args.strings()?, args.strings()?)
+            }
+            "address" => {
+                let matcher = args.matcher()?;
+                let part = args.address_part()?;
+                Test::Address(matcher, part, args.strings()?, args.strings()?)
+            }
+            "envelope" => {
+                self.require("envelope", line)?;
+                let matcher = args.matcher()?;
+                let part = args.address_part()?;
+                Test::Envelope(matcher, part, args.strings()?, args.strings()?)
+            }
+            "body" => {
+                self.require("body", line)?;
+                let matcher = args.matcher()?;
+                let mut transform = None;
+                if args.flag("raw") {
+                    transform = Some(BodyTransform::Raw);
+                }
+                if let Some(types) = args.list_value("content")? {
+                    if transform.is_some() {
+                        return error(line, "only one body transform is allowed");
+                    }
+                    transform = Some(BodyTransform::Content(types));
+                }
+                if args.flag("text") {
+                    if transform.is_some() {
+                        return error(line, "only one body transform is allowed");
+                    }
+                    transform = Some(BodyTransform::Text);
+                }
+                Test::Body(
+                    matcher,
+                    transform.unwrap_or(BodyTransform::Text),
+                    args.strings()?,
+                )
+            }
+            "hasflag" => {
+                self.require("imap4flags", line)?;
+                let matcher = args.matcher()?;
+                Test::HasFlag(matcher, args.strings()?)
+            }
+            _ => return error(line, &format!("unknown test {}", name)),
+        };
+        args.finish()?;
+        Ok(test)
+    }
+}
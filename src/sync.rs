@@ -0,0 +1,387 @@
+//! Two-way synchronization between two maildirs.
+//!
+//! A [`Synchronizer`] reconciles a local and a remote maildir, for example
+//! one on a laptop and one on a mounted network share, in the manner of
+//! offlineimap or mbsync. It keeps a state file recording which messages,
+//! with which flags, were present on both sides after the last run. By
+//! comparing each side against that state it can tell apart messages that
+//! are new on one side from messages that were deleted on the other, and
+//! flag changes from stale flags:
+//!
+//! - a message that is on one side only and not in the state is new, and
+//!   is copied to the other side;
+//! - a message that is on one side only but is in the state was deleted on
+//!   the other side, and is deleted, even if its flags were changed;
+//! - a message whose flags differ between the sides gets the flags of the
+//!   side where they changed since the last run. If they changed on both
+//!   sides, the [`ConflictPolicy`] decides.
+//!
+//! Messages are matched by id, so they keep their names when copied.
+//! Subfolders are synchronized too, unless disabled. A subfolder that
+//! exists on one side only is created on the other, and all its messages
+//! are copied over; deleting a whole subfolder on one side does not delete
+//! it on the other. Both maildirs themselves must exist, though: a missing
+//! or unreadable one, like a share that is not mounted, fails the
+//! synchronization instead of being recreated empty.
+//!
+//! ```no_run
+//! use maildir::sync::{ConflictPolicy, Synchronizer};
+//! use maildir::Maildir;
+//!
+//! let local = Maildir::from("/home/me/Mail");
+//! let remote = Maildir::from("/mnt/nas/Mail");
+//! let report = Synchronizer::new(&local, &remote, "/home/me/.mail-sync-state")
+//!     .conflict_policy(ConflictPolicy::PreferLocal)
+//!     .sync()
+//!     .unwrap();
+//! println!("{:?}", report);
+//! ```
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::error;
+use std::fmt;
+use std::fs;
+use std::io::prelude::*;
+use std::io::{BufReader, BufWriter, ErrorKind};
+use std::ops::Deref;
+use std::path::{Path, PathBuf};
+
+use crate::{MailEntry, Maildir};
+
+const FORMAT_HEADER: &str = "maildir-sync 1";
+
+#[derive(Debug)]
+pub enum SyncError {
+    Io(std::io::Error),
+    /// The state file exists but could not be understood.
+    Corrupt(String),
+}
+
+impl fmt::Display for SyncError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SyncError::Io(ref e) => write!(f, "IO Error: {}", e),
+            SyncError::Corrupt(ref msg) => write!(f, "Corrupt sync state: {}", msg),
+        }
+    }
+}
+
+impl error::Error for SyncError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            SyncError::Io(ref e) => Some(e),
+            SyncError::Corrupt(_) => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for SyncError {
+    fn from(e: std::io::Error) -> SyncError {
+        SyncError::Io(e)
+    }
+}
+
+/// How to resolve a message whose flags changed on both sides since the
+/// last synchronization.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ConflictPolicy {
+    /// Use the flags of the local message.
+    PreferLocal,
+    /// Use the flags of the remote message.
+    PreferRemote,
+    /// Use every flag set on either side. A flag removed on one side but
+    /// still set on the other is kept.
+    #[default]
+    UnionFlags,
+}
+
+/// The changes made to one side during a synchronization.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SyncChanges {
+    /// The number of messages copied from the other side.
+    pub copied: usize,
+    /// The number of messages deleted because they were deleted on the
+    /// other side.
+    pub deleted: usize,
+    /// The number of messages whose flags were updated.
+    pub flags_updated: usize,
+}
+
+/// What `Synchronizer::sync` did.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SyncReport {
+    pub local: SyncChanges,
+    pub remote: SyncChanges,
+    /// The number of messages whose flags changed on both sides and were
+    /// resolved with the conflict policy.
+    pub conflicts: usize,
+}
+
+// The messages known to be on both sides after the last run, with their
+// flags, keyed by folder name ("" for the maildir itself) and id.
+type State = BTreeMap<String, BTreeMap<String, String>>;
+
+// A message on one side, with its normalized flags.
+#[derive(Debug)]
+struct Present {
+    flags: String,
+    entry: MailEntry,
+}
+
+/// Synchronizes two maildirs, see the module documentation.
+#[derive(Debug)]
+pub struct Synchronizer<'a> {
+    local: &'a Maildir,
+    remote: &'a Maildir,
+    state_path: PathBuf,
+    policy: ConflictPolicy,
+    recursive: bool,
+}
+
+impl<'a> Synchronizer<'a> {
+    /// Creates a synchronizer between the two maildirs that keeps its state
+    /// in the file at `state_path`. The file is created on the first run.
+    /// It should not be inside either maildir, and must not be shared with
+    /// a different pair of maildirs.
+    pub fn new<P: AsRef<Path>>(
+        local: &'a Maildir,
+        remote: &'a Maildir,
+        state_path: P,
+    ) -> Synchronizer<'a> {
+        Synchronizer {
+            local,
+            remote,
+            state_path: state_path.as_ref().to_path_buf(),
+            policy: ConflictPolicy::default(),
+            recursive: true,
+        }
+    }
+
+    pub fn conflict_policy(mut self, policy: ConflictPolicy) -> Synchronizer<'a> {
+        self.policy = policy;
+        self
+    }
+
+    /// Sets whether the subfolders of the maildirs are synchronized too.
+    pub fn recursive(mut self, recursive: bool) -> Synchronizer<'a> {
+        self.recursive = recursive;
+        self
+    }
+
+    /// Runs the synchronization. The state file is updated even if an
+    /// error occurs part way through, so the changes made until then are
+    /// not mistaken for changes by the user on the next run.
+    pub fn sync(&self) -> Result<SyncReport, SyncError> {
+        let mut state = self.load_state()?;
+        let mut report = SyncReport::default();
+        let result = self.sync_folders(&mut state, &mut report);
+        self.save_state(&state)?;
+        result.map(|()| report)
+    }
+
+    fn sync_folders(&self, state: &mut State, report: &mut SyncReport) -> Result<(), SyncError> {
+        // a missing or unreadable maildir, like an unmounted share, must
+        // not be recreated empty, or its messages would be deleted on the
+        // other side once it is back
+        self.local.check_readable()?;
+        self.remote.check_readable()?;
+        let mut folders = BTreeSet::new();
+        folders.insert(String::new());
+        if self.recursive {
+            folders.extend(subfolder_names(self.local)?);
+            folders.extend(subfolder_names(self.remote)?);
+        }
+        // folders that are gone on both sides
+        state.retain(|folder, _| folders.contains(folder));
+
+        for folder in &folders {
+            let subfolders;
+            let (local, remote) = if folder.is_empty() {
+                (self.local, self.remote)
+            } else {
+                let subfolder = |maildir: &Maildir| {
+                    maildir
+                        .subfolder(folder)
+                        .map_err(|e| SyncError::Corrupt(e.to_string()))
+                };
+                subfolders = (subfolder(self.local)?, subfolder(self.remote)?);
+                (&subfolders.0, &subfolders.1)
+            };
+            // a subfolder missing on one side is recreated, and starts over
+            // without any state
+            for maildir in &[local, remote] {
+                if !folder.is_empty() && !maildir.path().exists() {
+                    maildir.create_dirs()?;
+                    state.remove(folder);
+                }
+            }
+            let folder_state = state.entry(folder.clone()).or_default();
+            self.sync_folder(local, remote, folder_state, report)?;
+        }
+        Ok(())
+    }
+
+    fn sync_folder(
+        &self,
+        local: &Maildir,
+        remote: &Maildir,
+        state: &mut BTreeMap<String, String>,
+        report: &mut SyncReport,
+    ) -> Result<(), SyncError> {
+        let mut local_messages = list(local)?;
+        let mut remote_messages = list(remote)?;
+        let mut ids: BTreeSet<String> = local_messages.keys().cloned().collect();
+        ids.extend(remote_messages.keys().cloned());
+        ids.extend(state.keys().cloned());
+
+        for id in &ids {
+            let synced = state.get(id).cloned();
+            match (
+                local_messages.get_mut(id),
+                remote_messages.get_mut(id),
+                synced,
+            ) {
+                (Some(l), Some(r), synced) => {
+                    let flags = if l.flags == r.flags {
+                        l.flags.clone()
+                    } else {
+                        let local_changed = synced.as_ref() != Some(&l.flags);
+                        let remote_changed = synced.as_ref() != Some(&r.flags);
+                        let flags = match (local_changed, remote_changed, self.policy) {
+                            (true, false, _) => l.flags.clone(),
+                            (false, true, _) => r.flags.clone(),
+                            (_, _, ConflictPolicy::PreferLocal) => l.flags.clone(),
+                            (_, _, ConflictPolicy::PreferRemote) => r.flags.clone(),
+                            (_, _, ConflictPolicy::UnionFlags) => {
+                                Maildir::normalize_flags(&format!("{}{}", l.flags, r.flags))
+                            }
+                        };
+                        if local_changed && remote_changed {
+                            report.conflicts += 1;
+                        }
+                        if flags != l.flags {
+                            local.set_entry_flags(&mut l.entry, &flags)?;
+                            report.local.flags_updated += 1;
+                        }
+                        if flags != r.flags {
+                            remote.set_entry_flags(&mut r.entry, &flags)?;
+                            report.remote.flags_updated += 1;
+                        }
+                        flags
+                    };
+                    state.insert(id.clone(), flags);
+                }
+                (Some(l), None, None) => {
+                    copy(&l.entry, remote)?;
+                    report.remote.copied += 1;
+                    state.insert(id.clone(), l.flags.clone());
+                }
+                (None, Some(r), None) => {
+                    copy(&r.entry, local)?;
+                    report.local.copied += 1;
+                    state.insert(id.clone(), r.flags.clone());
+                }
+                (Some(l), None, Some(_)) => {
+                    local.delete_entry(&l.entry)?;
+                    report.local.deleted += 1;
+                    state.remove(id);
+                }
+                (None, Some(r), Some(_)) => {
+                    remote.delete_entry(&r.entry)?;
+                    report.remote.deleted += 1;
+                    state.remove(id);
+                }
+                (None, None, _) => {
+                    state.remove(id);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn load_state(&self) -> Result<State, SyncError> {
+        let mut state = State::new();
+        let file = match fs::File::open(&self.state_path) {
+            Ok(file) => file,
+            Err(ref e) if e.kind() == ErrorKind::NotFound => return Ok(state),
+            Err(e) => return Err(e.into()),
+        };
+        let mut lines = BufReader::new(file).lines();
+        match lines.next() {
+            Some(Ok(ref header)) if header == FORMAT_HEADER => (),
+            Some(Err(e)) => return Err(e.into()),
+            _ => return Err(SyncError::Corrupt("unknown format".to_string())),
+        }
+        for line in lines {
+            let line = line?;
+            let mut fields = line.split('\t');
+            match (fields.next(), fields.next(), fields.next(), fields.next()) {
+                (Some(folder), Some(id), Some(flags), None) if !id.is_empty() => {
+                    state
+                        .entry(folder.to_string())
+                        .or_default()
+                        .insert(id.to_string(), flags.to_string());
+                }
+                _ => return Err(SyncError::Corrupt(format!("unexpected line: {}", line))),
+            }
+        }
+        Ok(state)
+    }
+
+    /// Writes the state file, replacing it atomically.
+    fn save_state(&self, state: &State) -> Result<(), SyncError> {
+        let mut tmp_path = self.state_path.clone().into_os_string();
+        tmp_path.push(".tmp");
+        let tmp_path = PathBuf::from(tmp_path);
+        {
+            let mut out = BufWriter::new(fs::File::create(&tmp_path)?);
+            writeln!(out, "{}", FORMAT_HEADER)?;
+            for (folder, messages) in state {
+                for (id, flags) in messages {
+                    writeln!(out, "{}\t{}\t{}", folder, id, flags)?;
+                }
+            }
+            out.into_inner().map_err(|e| e.into_error())?.sync_all()?;
+        }
+        fs::rename(&tmp_path, &self.state_path)?;
+        Ok(())
+    }
+}
+
+fn subfolder_names(maildir: &Maildir) -> std::io::Result<Vec<String>> {
+    let mut names = Vec::new();
+    for subdir in maildir.list_subdirs() {
+        if let Some(name) = subdir?.path().file_name() {
+            names.push(name.to_string_lossy().deref().to_string());
+        }
+    }
+    Ok(names)
+}
+
+// Lists the messages of a maildir. Messages with ids that cannot be stored
+// in the state file are left out, and so never synchronized.
+fn list(maildir: &Maildir) -> std::io::Result<HashMap<String, Present>> {
+    // an unreadable folder must not look empty, or its messages would be
+    // deleted on the other side
+    maildir.check_readable()?;
+    let mut messages = HashMap::new();
+    for entry in maildir.list_new().chain(maildir.list_cur()) {
+        let entry = entry?;
+        if entry.id().contains(['\t', '\n', '\r']) {
+            continue;
+        }
+        let present = Present {
+            flags: Maildir::normalize_flags(entry.flags()),
+            entry,
+        };
+        messages.insert(present.entry.id().to_string(), present);
+    }
+    Ok(messages)
+}
+
+// Copies a message to the same folder, `new` or `cur`, of the other side,
+// keeping its name.
+fn copy(entry: &MailEntry, to: &Maildir) -> std::io::Result<()> {
+    let dst_dir = to.path().join(entry.folder_name());
+    to.copy_file(entry.path(), &dst_dir.join(Maildir::file_name(entry)?))
+}
@@ -0,0 +1,422 @@
+//! Conversation threading of messages.
+//!
+//! This implements the threading algorithm described by Jamie Zawinski at
+//! <https://www.jwz.org/doc/threading.html>: messages are linked into trees
+//! using their `Message-ID`, `In-Reply-To` and `References` headers, and
+//! threads whose roots share the same subject (ignoring prefixes like
+//! `Re:`) are grouped together afterwards.
+//!
+//! ```no_run
+//! use maildir::thread::thread_maildirs;
+//! use maildir::Maildir;
+//!
+//! let inbox = Maildir::from("path/to/maildir");
+//! let sent = inbox.subfolder(".Sent").unwrap();
+//! for thread in thread_maildirs(&[&inbox, &sent]).unwrap() {
+//!     println!("{:?}", thread);
+//! }
+//! ```
+
+use std::collections::HashMap;
+
+use mailparse::MailHeaderMap;
+
+use crate::{MailEntry, MailEntryError, Maildir};
+
+/// A message in a conversation thread, along with the replies to it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ThreadNode {
+    /// The id of the message in its maildir. This is `None` for messages
+    /// that are referenced by other messages but are not in any of the
+    /// threaded maildirs, and for the placeholders that group threads with
+    /// the same subject.
+    pub id: Option<String>,
+    /// The `Message-ID` of the message, without the angle brackets. This
+    /// is `None` for placeholders that group threads with the same subject.
+    pub message_id: Option<String>,
+    pub children: Vec<ThreadNode>,
+}
+
+impl ThreadNode {
+    /// Returns the ids of all the messages in this thread, in depth-first
+    /// order.
+    pub fn ids(&self) -> Vec<&str> {
+        let mut ids = Vec::new();
+        self.collect_ids(&mut ids);
+        ids
+    }
+
+    fn collect_ids<'a>(&'a self, out: &mut Vec<&'a str>) {
+        if let Some(ref id) = self.id {
+            out.push(id);
+        }
+        for child in &self.children {
+            child.collect_ids(out);
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Message {
+    id: String,
+    // the subject with prefixes like "Re:" removed
+    base_subject: String,
+    is_reply: bool,
+}
+
+#[derive(Debug)]
+struct Container {
+    message: Option<Message>,
+    message_id: Option<String>,
+    parent: Option<usize>,
+    children: Vec<usize>,
+}
+
+/// Collects messages and threads them. Use `thread_maildirs` to thread
+/// the messages of whole maildirs.
+#[derive(Debug, Default)]
+pub struct Threader {
+    containers: Vec<Container>,
+    by_message_id: HashMap<String, usize>,
+}
+
+impl Threader {
+    pub fn new() -> Threader {
+        Threader::default()
+    }
+
+    /// Adds all the messages in the `new` and `cur` folders of a maildir.
+    pub fn add_maildir(&mut self, maildir: &Maildir) -> Result<(), MailEntryError> {
+        for entry in maildir.list_new().chain(maildir.list_cur()) {
+            self.add_entry(&mut entry?)?;
+        }
+        Ok(())
+    }
+
+    /// Adds a message, reading its threading headers.
+    pub fn add_entry(&mut self, entry: &mut MailEntry) -> Result<(), MailEntryError> {
+        let id = entry.id().to_string();
+        let headers = entry.headers()?;
+        let message_id = headers
+            .get_first_value("Message-ID")
+            .and_then(|v| message_ids(&v).into_iter().next());
+        let mut references = headers
+            .get_first_value("References")
+            .map(|v| message_ids(&v))
+            .unwrap_or_default();
+        // In-Reply-To may name a parent that References doesn't
+        if let Some(parent) = headers
+            .get_first_value("In-Reply-To")
+            .and_then(|v| message_ids(&v).into_iter().next())
+        {
+            if references.last() != Some(&parent) {
+                references.push(parent);
+            }
+        }
+        let subject = headers.get_first_value("Subject").unwrap_or_default();
+        self.add_message(&id, message_id.as_deref(), &references, &subject);
+        Ok(())
+    }
+
+    /// Adds a message given its id, `Message-ID` (without angle brackets),
+    /// the message ids it references from oldest to newest ancestor, and
+    /// its subject.
+    pub fn add_message(
+        &mut self,
+        id: &str,
+        message_id: Option<&str>,
+        references: &[String],
+        subject: &str,
+    ) {
+        let (base_subject, is_reply) = base_subject(subject);
+        let message = Message {
+            id: id.to_string(),
+            base_subject,
+            is_reply,
+        };
+
+        // find or create the container for this message; a duplicate or
+        // missing message id gets a container of its own
+        let this = match message_id.and_then(|mid| self.by_message_id.get(mid).copied()) {
+            Some(c) if self.containers[c].message.is_none() => c,
+            _ => {
+                let c = self.new_container(None);
+                if let Some(mid) = message_id {
+                    self.containers[c].message_id = Some(mid.to_string());
+                    self.by_message_id.entry(mid.to_string()).or_insert(c);
+                }
+                c
+            }
+        };
+        self.containers[this].message = Some(message);
+
+        // link the references together, oldest first, without breaking
+        // links that earlier messages already established
+        let mut prev: Option<usize> = None;
+        for reference in references {
+            let c = self.container_for(reference);
+            if let Some(p) = prev {
+                if self.containers[c].parent.is_none() && c != p && !self.is_ancestor(c, p) {
+                    self.set_parent(c, Some(p));
+                }
+            }
+            prev = Some(c);
+        }
+
+        // the last reference is the parent of this message, overriding
+        // whatever parent was guessed for it before
+        match prev {
+            Some(p) if p != this && !self.is_ancestor(this, p) => self.set_parent(this, Some(p)),
+            Some(_) => (),
+            None => self.set_parent(this, None),
+        }
+    }
+
+    fn new_container(&mut self, message_id: Option<&str>) -> usize {
+        self.containers.push(Container {
+            message: None,
+            message_id: message_id.map(str::to_string),
+            parent: None,
+            children: Vec::new(),
+        });
+        self.containers.len() - 1
+    }
+
+    fn container_for(&mut self, message_id: &str) -> usize {
+        match self.by_message_id.get(message_id) {
+            Some(&c) => c,
+            None => {
+                let c = self.new_container(Some(message_id));
+                self.by_message_id.insert(message_id.to_string(), c);
+                c
+            }
+        }
+    }
+
+    // Returns true if `ancestor` is `c` or one of its ancestors.
+    fn is_ancestor(&self, ancestor: usize, c: usize) -> bool {
+        let mut current = Some(c);
+        while let Some(x) = current {
+            if x == ancestor {
+                return true;
+            }
+            current = self.containers[x].parent;
+        }
+        false
+    }
+
+    fn set_parent(&mut self, c: usize, parent: Option<usize>) {
+        if let Some(old) = self.containers[c].parent.take() {
+            self.containers[old].children.retain(|&x| x != c);
+        }
+        if let Some(p) = parent {
+            self.containers[p].children.push(c);
+            self.containers[c].parent = Some(p);
+        }
+    }
+
+    /// Threads the messages added so far.
+    pub fn build(mut self) -> Vec<ThreadNode> {
+        let roots: Vec<usize> = (0..self.containers.len())
+            .filter(|&c| self.containers[c].parent.is_none())
+            .collect();
+        let roots = self.prune(roots, true);
+        let roots = self.group_by_subject(roots);
+        roots.into_iter().map(|c| self.to_node(c)).collect()
+    }
+
+    // Removes empty containers from the given siblings, promoting their
+    // children, and returns the new list of siblings. At the root level an
+    // empty container with several children is kept, since it ties those
+    // children together.
+    fn prune(&mut self, siblings: Vec<usize>, is_root: bool) -> Vec<usize> {
+        let mut result = Vec::new();
+        for c in siblings {
+            let children = std::mem::take(&mut self.containers[c].children);
+            let children = self.prune(children, false);
+            self.containers[c].children = children.clone();
+            if self.containers[c].message.is_some() || (is_root && children.len() > 1) {
+                result.push(c);
+                continue;
+            }
+            let parent = self.containers[c].parent;
+            for &child in &children {
+                self.containers[child].parent = parent;
+            }
+            self.containers[c].children.clear();
+            result.extend(children);
+        }
+        result
+    }
+
+    fn subject_of(&self, c: usize) -> Option<(&str, bool)> {
+        let container = &self.containers[c];
+        let message = match container.message {
+            Some(ref m) => m,
+            None => self.containers[*container.children.first()?]
+                .message
+                .as_ref()?,
+        };
+        if message.base_subject.is_empty() {
+            None
+        } else {
+            Some((&message.base_subject, message.is_reply))
+        }
+    }
+
+    fn group_by_subject(&mut self, roots: Vec<usize>) -> Vec<usize> {
+        // pick the container that represents each subject, ignoring case,
+        // preferring empty containers, then messages that are not replies
+        let mut table: HashMap<String, usize> = HashMap::new();
+        for &c in &roots {
+            let (subject, is_reply) = match self.subject_of(c) {
+                Some((s, r)) => (s.to_lowercase(), r),
+                None => continue,
+            };
+            match table.get(&subject).copied() {
+                None => {
+                    table.insert(subject, c);
+                }
+                Some(old) => {
+                    let old_is_reply = self.subject_of(old).map(|(_, r)| r).unwrap_or(false);
+                    let this_empty = self.containers[c].message.is_none();
+                    let old_empty = self.containers[old].message.is_none();
+                    if (this_empty && !old_empty) || (!old_empty && old_is_reply && !is_reply) {
+                        table.insert(subject, c);
+                    }
+                }
+            }
+        }
+
+        let mut result: Vec<usize> = Vec::new();
+        // maps the containers in the table to their replacement, when they
+        // get merged under a new placeholder
+        let mut replaced: HashMap<usize, usize> = HashMap::new();
+        for &c in &roots {
+            if self.containers[c].parent.is_some() {
+                // already merged into a placeholder
+                continue;
+            }
+            let subject = match self.subject_of(c) {
+                Some((s, _)) => s.to_lowercase(),
+                None => {
+                    result.push(c);
+                    continue;
+                }
+            };
+            let first = table[&subject];
+            if first == c {
+                result.push(c);
+                continue;
+            }
+            let target = replaced.get(&first).copied().unwrap_or(first);
+            let this_is_reply = self.subject_of(c).map(|(_, r)| r).unwrap_or(false);
+            let target_empty = self.containers[target].message.is_none();
+            let this_empty = self.containers[c].message.is_none();
+            let target_is_reply = self.subject_of(target).map(|(_, r)| r).unwrap_or(false);
+            if target_empty && this_empty {
+                let children = std::mem::take(&mut self.containers[c].children);
+                for child in children {
+                    self.containers[child].parent = None;
+                    self.set_parent(child, Some(target));
+                }
+            } else if target_empty || (!target_is_reply && this_is_reply) {
+                self.set_parent(c, Some(target));
+            } else {
+                let group = self.new_container(None);
+                let pos = result.iter().position(|&x| x == target);
+                self.set_parent(target, Some(group));
+                self.set_parent(c, Some(group));
+                match pos {
+                    Some(pos) => result[pos] = group,
+                    None => result.push(group),
+                }
+                replaced.insert(first, group);
+            }
+        }
+        result
+    }
+
+    fn to_node(&self, c: usize) -> ThreadNode {
+        let container = &self.containers[c];
+        ThreadNode {
+            id: container.message.as_ref().map(|m| m.id.clone()),
+            message_id: container.message_id.clone(),
+            children: container
+                .children
+                .iter()
+                .map(|&child| self.to_node(child))
+                .collect(),
+        }
+    }
+}
+
+/// Threads the messages in the `new` and `cur` folders of the given
+/// maildirs together.
+pub fn thread_maildirs(maildirs: &[&Maildir]) -> Result<Vec<ThreadNode>, MailEntryError> {
+    let mut threader = Threader::new();
+    for maildir in maildirs {
+        threader.add_maildir(maildir)?;
+    }
+    Ok(threader.build())
+}
+
+/// Extracts the message ids in angle brackets from a header value. A value
+/// without angle brackets is taken as a single message id.
+fn message_ids(value: &str) -> Vec<String> {
+    let mut ids = Vec::new();
+    let mut rest = value;
+    while let Some(start) = rest.find('<') {
+        match rest[start..].find('>') {
+            Some(end) => {
+                let id = rest[start + 1..start + end].trim();
+                if !id.is_empty() {
+                    ids.push(id.to_string());
+                }
+                rest = &rest[start + end + 1..];
+            }
+            None => break,
+        }
+    }
+    if ids.is_empty() && !value.trim().is_empty() && !value.contains('<') {
+        ids.push(value.trim().to_string());
+    }
+    ids
+}
+
+/// Removes prefixes like `Re:`, `Fwd:` and `Re[2]:` from a subject, and
+/// returns what is left along with whether any prefix was removed.
+fn base_subject(subject: &str) -> (String, bool) {
+    let mut rest = subject.trim();
+    let mut is_reply = false;
+    loop {
+        let lower = rest.to_ascii_lowercase();
+        let prefix_len = ["re", "fwd", "fw"]
+            .iter()
+            .find(|p| lower.starts_with(*p))
+            .map(|p| p.len());
+        let after = match prefix_len {
+            Some(len) => {
+                let mut after = &rest[len..];
+                // skip a counter like "[2]"
+                if after.starts_with('[') {
+                    if let Some(end) = after.find(']') {
+                        if after[1..end].chars().all(|c| c.is_ascii_digit()) {
+                            after = &after[end + 1..];
+                        }
+                    }
+                }
+                after.strip_prefix(':')
+            }
+            None => None,
+        };
+        match after {
+            Some(after) => {
+                rest = after.trim_start();
+                is_reply = true;
+            }
+            None => break,
+        }
+    }
+    (rest.to_string(), is_reply)
+}
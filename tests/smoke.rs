@@ -1,3 +1,5 @@
+#![allow(clippy::bool_assert_comparison)]
+
 use maildir::*;
 
 #[cfg(unix)]
@@ -61,7 +63,7 @@ where
         let decoded = OsString::from_wide(decoded_bytes.as_slice());
 
         if entry.path().is_dir() {
-            fs::create_dir(tmp_path.join(&decoded)).expect("could not create directory");
+            fs::create_dir(tmp_path.join(decoded)).expect("could not create directory");
         } else {
             fs::copy(entry.path(), tmp_path.join(decoded)).expect("could not copy test data");
         }
@@ -161,17 +163,17 @@ fn maildir_subdir() {
 
         // test subfolder api
         let sf = maildir.subfolder(".folder.with.subs").unwrap();
-        let store_res = sf.store_new(&TEST_MAIL_BODY).unwrap();
+        let store_res = sf.store_new(TEST_MAIL_BODY).unwrap();
         sf.move_new_to_cur(&store_res).unwrap();
         assert!(sf.find(&store_res).is_some());
 
         // test subfolder api with newly created folder
         let sf = maildir.subfolder(".notyes").unwrap();
-        sf.store_new(&TEST_MAIL_BODY)
+        sf.store_new(TEST_MAIL_BODY)
             .expect_err("should not store new message when folder is missing");
         sf.create_dirs().expect("should create folders");
         let store_res = sf
-            .store_new(&TEST_MAIL_BODY)
+            .store_new(TEST_MAIL_BODY)
             .expect("message should be stored");
         sf.move_new_to_cur(&store_res).unwrap();
         assert!(sf.find(&store_res).is_some());
@@ -256,6 +258,29 @@ fn check_copy_and_move() {
     })
 }
 
+#[test]
+fn check_copy_and_move_from_new() {
+    with_maildir_empty("maildir2", |maildir| {
+        maildir.create_dirs().unwrap();
+        maildir.create_subfolder_dirs(".Sub").unwrap();
+        let sub = maildir.subfolder(".Sub").unwrap();
+
+        let id = maildir.store_new(TEST_MAIL_BODY).unwrap();
+        maildir.copy_to(&id, &sub).unwrap();
+        assert_eq!(sub.list_cur().next().unwrap().unwrap().id(), id);
+        sub.delete(&id).unwrap();
+        maildir.move_to(&id, &sub).unwrap();
+
+        // the message lands in "cur" with no flags, and can be listed there
+        assert_eq!(maildir.count_new(), 0);
+        assert_eq!(sub.count_new(), 0);
+        let entries: Vec<_> = sub.list_cur().map(|e| e.unwrap()).collect();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].id(), id);
+        assert_eq!(entries[0].flags(), "");
+    })
+}
+
 #[test]
 fn mark_read() {
     with_maildir(MAILDIR_NAME, |maildir| {
@@ -367,3 +392,2186 @@ fn check_flag_fiddling() {
         assert_eq!(maildir.find(&id).unwrap().flags(), "FS");
     });
 }
+
+#[cfg(feature = "fault-injection")]
+#[test]
+fn check_store_fault_injection() {
+    use maildir::fault::{leaked_tmp_files, FaultInjector, StoreStep};
+    use std::sync::Arc;
+
+    with_maildir_empty("maildir2", |mut maildir| {
+        maildir.create_dirs().unwrap();
+        let faults = Arc::new(FaultInjector::new());
+        maildir.set_fault_injector(faults.clone());
+
+        for step in StoreStep::ALL.iter() {
+            faults.fail_next(*step);
+            maildir
+                .store_new(TEST_MAIL_BODY)
+                .expect_err("store_new should fail");
+            faults.fail_next(*step);
+            maildir
+                .store_cur_with_flags(TEST_MAIL_BODY, "S")
+                .expect_err("store_cur_with_flags should fail");
+
+            assert_eq!(maildir.count_new(), 0, "partial message after {:?}", step);
+            assert_eq!(maildir.count_cur(), 0, "partial message after {:?}", step);
+            assert!(leaked_tmp_files(&maildir).unwrap().is_empty());
+        }
+
+        // the injector only fails the requested occurrence
+        faults.fail_nth(StoreStep::Rename, 1);
+        maildir.store_new(TEST_MAIL_BODY).unwrap();
+        maildir.store_new(TEST_MAIL_BODY).unwrap_err();
+        maildir.store_new(TEST_MAIL_BODY).unwrap();
+        assert_eq!(maildir.count_new(), 2);
+        assert!(leaked_tmp_files(&maildir).unwrap().is_empty());
+
+        // subfolders share the injector of their parent
+        maildir.create_subfolder_dirs(".Sub").unwrap();
+        let sub = maildir.subfolder(".Sub").unwrap();
+        let hits = faults.hits(StoreStep::Create);
+        faults.fail_next(StoreStep::Sync);
+        sub.store_new(TEST_MAIL_BODY).unwrap_err();
+        assert_eq!(faults.hits(StoreStep::Create), hits + 1);
+        assert_eq!(sub.count_new(), 0);
+        assert!(leaked_tmp_files(&sub).unwrap().is_empty());
+
+        // linking into a target counts as its rename step
+        faults.fail_next(StoreStep::Rename);
+        let results = Maildir::store_new_linked(&[&maildir, &sub], TEST_MAIL_BODY).unwrap();
+        assert!(results[0].is_err());
+        assert!(results[1].is_ok());
+        assert_eq!(maildir.count_new(), 2);
+        assert!(leaked_tmp_files(&maildir).unwrap().is_empty());
+    });
+}
+
+#[test]
+fn check_durability() {
+    with_maildir_empty("maildir2", |mut maildir| {
+        assert_eq!(maildir.durability(), Durability::File);
+        maildir.set_durability(Durability::FileAndDirectory);
+        maildir.create_dirs().unwrap();
+        maildir.create_subfolder_dirs(".Sub").unwrap();
+        let sub = maildir.subfolder(".Sub").unwrap();
+        assert_eq!(sub.durability(), Durability::FileAndDirectory);
+
+        let id = maildir.store_new(TEST_MAIL_BODY).unwrap();
+        maildir.move_new_to_cur(&id).unwrap();
+        maildir.add_flags(&id, "S").unwrap();
+        maildir.copy_to(&id, &sub).unwrap();
+        maildir.delete(&id).unwrap();
+        assert_eq!(sub.find(&id).unwrap().flags(), "S");
+
+        maildir.set_durability(Durability::None);
+        let id = maildir.store_cur_with_flags(TEST_MAIL_BODY, "F").unwrap();
+        maildir.move_to(&id, &sub).unwrap();
+        assert_eq!(sub.count_cur(), 2);
+    });
+}
+
+#[cfg(feature = "fault-injection")]
+#[test]
+fn check_durability_syncs_dirs() {
+    use maildir::fault::{FaultInjector, StoreStep};
+    use std::sync::Arc;
+
+    with_maildir_empty("maildir2", |mut maildir| {
+        let faults = Arc::new(FaultInjector::new());
+        maildir.set_fault_injector(faults.clone());
+        maildir.create_dirs().unwrap();
+        maildir.create_subfolder_dirs(".Sub").unwrap();
+        let mut sub = maildir.subfolder(".Sub").unwrap();
+        sub.set_durability(Durability::FileAndDirectory);
+
+        // the source only flushes files, so only the target's directory
+        // is flushed
+        let id = maildir.store_cur_with_flags(TEST_MAIL_BODY, "").unwrap();
+        assert_eq!(faults.hits(StoreStep::SyncDir), 0);
+        maildir.move_to(&id, &sub).unwrap();
+        assert_eq!(faults.hits(StoreStep::SyncDir), 1);
+
+        sub.delete(&id).unwrap();
+        assert_eq!(faults.hits(StoreStep::SyncDir), 2);
+
+        // storing flushes both `tmp` and `new`
+        let id = sub.store_new(TEST_MAIL_BODY).unwrap();
+        assert_eq!(faults.hits(StoreStep::SyncDir), 4);
+        faults.fail_next(StoreStep::SyncDir);
+        sub.delete(&id).expect_err("the directory sync should fail");
+
+        // a message that was linked is reported even if the sync fails
+        faults.fail_next(StoreStep::SyncDir);
+        let results = Maildir::store_new_linked(&[&sub], TEST_MAIL_BODY).unwrap();
+        match results[0] {
+            Err(MaildirError::NotSynced(ref id, _)) => assert!(sub.find(id).is_some()),
+            ref other => panic!("unexpected result {:?}", other),
+        }
+    });
+}
+
+#[cfg(unix)]
+#[test]
+fn check_builder_permissions() {
+    use std::os::unix::fs::{MetadataExt, PermissionsExt};
+
+    let tmp_dir = tempdir().expect("could not create temporary directory");
+    let gid = fs::metadata(tmp_dir.path()).unwrap().gid();
+    let maildir = MaildirBuilder::new(tmp_dir.path().join("maildir2"))
+        .file_mode(0o600)
+        .dir_mode(0o700)
+        .group(gid)
+        .build();
+    maildir.create_dirs().unwrap();
+    maildir.create_subfolder_dirs(".Sub").unwrap();
+    for dir in &["", "cur", "new", "tmp", ".Sub", ".Sub/new"] {
+        let meta = fs::metadata(maildir.path().join(dir)).unwrap();
+        assert_eq!(meta.permissions().mode() & 0o777, 0o700, "{}", dir);
+        assert_eq!(meta.gid(), gid);
+    }
+
+    let id = maildir.store_new(TEST_MAIL_BODY).unwrap();
+    let meta = fs::metadata(maildir.find(&id).unwrap().path()).unwrap();
+    assert_eq!(meta.permissions().mode() & 0o777, 0o600);
+    assert_eq!(meta.gid(), gid);
+
+    let sub = maildir.subfolder(".Sub").unwrap();
+    let id = sub.store_cur_with_flags(TEST_MAIL_BODY, "S").unwrap();
+    let path = sub.find(&id).unwrap().path().clone();
+    assert_eq!(
+        fs::metadata(&path).unwrap().permissions().mode() & 0o777,
+        0o600
+    );
+
+    // copies get the permissions of the target maildir
+    fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+    sub.copy_to(&id, &maildir).unwrap();
+    let copied = maildir.find(&id).unwrap();
+    assert_eq!(
+        fs::metadata(copied.path()).unwrap().permissions().mode() & 0o777,
+        0o600
+    );
+}
+
+#[test]
+fn check_store_new_linked() {
+    with_maildir_empty("maildir2", |maildir| {
+        maildir.create_dirs().unwrap();
+        maildir.create_subfolder_dirs(".A").unwrap();
+        maildir.create_subfolder_dirs(".B").unwrap();
+        let a = maildir.subfolder(".A").unwrap();
+        let b = maildir.subfolder(".B").unwrap();
+        let missing = maildir.subfolder(".Missing").unwrap();
+
+        let results = Maildir::store_new_linked(&[&maildir, &a, &missing, &b], TEST_MAIL_BODY)
+            .expect("message should be staged");
+        assert_eq!(results.len(), 4);
+        assert!(results[2].is_err());
+
+        let ids: Vec<_> = vec![&results[0], &results[1], &results[3]]
+            .into_iter()
+            .map(|r| r.as_ref().unwrap().clone())
+            .collect();
+        assert_ne!(ids[0], ids[1]);
+        assert_ne!(ids[1], ids[2]);
+        for (target, id) in [&maildir, &a, &b].iter().zip(ids.iter()) {
+            assert_eq!(target.count_new(), 1);
+            let mut entry = target.find(id).unwrap();
+            assert_eq!(
+                entry.parsed().unwrap().get_body_raw().unwrap(),
+                b"Today is Boomtime, the 59th day of Discord in the YOLD 3183".as_ref()
+            );
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::MetadataExt;
+                assert_eq!(fs::metadata(entry.path()).unwrap().nlink(), 3);
+            }
+        }
+        assert_eq!(fs::read_dir(maildir.path().join("tmp")).unwrap().count(), 0);
+
+        assert!(Maildir::store_new_linked(&[], TEST_MAIL_BODY)
+            .unwrap()
+            .is_empty());
+    });
+}
+
+#[derive(Debug, Default)]
+struct SequentialNames(std::sync::atomic::AtomicUsize);
+
+impl UniqueNameGenerator for SequentialNames {
+    fn unique_name(&self, context: &NameContext) -> String {
+        let n = self.0.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+        format!("{}.test{}", n, context.hostname)
+    }
+}
+
+#[test]
+fn check_builder_naming() {
+    let tmp_dir = tempdir().expect("could not create temporary directory");
+    let maildir = MaildirBuilder::new(tmp_dir.path().join("maildir2"))
+        .hostname("mx/1")
+        .name_generator(SequentialNames::default())
+        .size_attributes(false)
+        .build();
+    maildir.create_dirs().unwrap();
+    // the first name is used for the temporary file
+    assert_eq!(maildir.store_new(TEST_MAIL_BODY).unwrap(), "1.testmx\\0571");
+    assert_eq!(
+        maildir.store_cur_with_flags(TEST_MAIL_BODY, "S").unwrap(),
+        "3.testmx\\0571"
+    );
+    maildir.create_subfolder_dirs(".Sub").unwrap();
+    let sub = maildir.subfolder(".Sub").unwrap();
+    assert_eq!(sub.store_new(TEST_MAIL_BODY).unwrap(), "5.testmx\\0571");
+
+    let maildir = MaildirBuilder::new(tmp_dir.path().join("maildir3"))
+        .hostname("example")
+        .name_generator(DovecotNameGenerator)
+        .build();
+    maildir.create_dirs().unwrap();
+    let id = maildir.store_new(TEST_MAIL_BODY).unwrap();
+    let pid = format!("P{}Q", std::process::id());
+    assert!(id.contains(".M"));
+    assert!(id.contains(&pid));
+    assert!(id.contains(&format!(".example,S={},W=", TEST_MAIL_BODY.len())));
+    assert!(maildir.find(&id).is_some());
+}
+
+#[derive(Debug)]
+struct FixedName;
+
+impl UniqueNameGenerator for FixedName {
+    fn unique_name(&self, _context: &NameContext) -> String {
+        "fixed".to_string()
+    }
+}
+
+#[test]
+fn check_builder_naming_collisions() {
+    let tmp_dir = tempdir().expect("could not create temporary directory");
+    let maildir = MaildirBuilder::new(tmp_dir.path().join("maildir2"))
+        .name_generator(FixedName)
+        .size_attributes(false)
+        .build();
+    maildir.create_dirs().unwrap();
+
+    // a generator that ignores the counter eventually makes delivery fail
+    let results = Maildir::store_new_linked(&[&maildir], TEST_MAIL_BODY).unwrap();
+    assert_eq!(results[0].as_ref().unwrap(), "fixed");
+    let results = Maildir::store_new_linked(&[&maildir], TEST_MAIL_BODY).unwrap();
+    assert!(results[0].is_err());
+    fs::write(maildir.path().join("tmp").join("fixed"), TEST_MAIL_BODY).unwrap();
+    assert!(maildir.store_new(TEST_MAIL_BODY).is_err());
+    assert_eq!(maildir.count_new(), 1);
+}
+
+#[test]
+fn check_sizes() {
+    with_maildir_empty("maildir2", |maildir| {
+        maildir.create_dirs().unwrap();
+        let id = maildir.store_new(TEST_MAIL_BODY).unwrap();
+        let lines = TEST_MAIL_BODY.iter().filter(|&&b| b == b'\n').count();
+        let size = TEST_MAIL_BODY.len();
+        assert!(id.ends_with(&format!(",S={},W={}", size, size + lines)));
+
+        let mut entry = maildir.find(&id).unwrap();
+        assert_eq!(entry.size().unwrap(), size as u64);
+        assert_eq!(
+            entry.sizes().unwrap(),
+            MailSizes {
+                size: size as u64,
+                rfc822_size: (size + lines) as u64,
+            }
+        );
+    });
+
+    with_maildir(MAILDIR_NAME, |maildir| {
+        // no size attributes in these file names, so they are computed
+        let mut entry = maildir.list_cur().next().unwrap().unwrap();
+        let data = fs::read(entry.path()).unwrap();
+        let bare_lfs = data
+            .windows(2)
+            .filter(|w| w[1] == b'\n' && w[0] != b'\r')
+            .count();
+        let sizes = entry.sizes().unwrap();
+        assert_eq!(sizes.size, data.len() as u64);
+        assert_eq!(sizes.rfc822_size, (data.len() + bare_lfs) as u64);
+    });
+}
+
+#[test]
+fn check_search() {
+    use maildir::search::{Query, QueryParseError};
+
+    with_maildir(MAILDIR_NAME, |maildir| {
+        let ids = |query: &str| {
+            let query: Query = query.parse().unwrap();
+            let mut ids: Vec<_> = maildir
+                .search(&query)
+                .map(|e| e.unwrap().id().to_string())
+                .collect();
+            ids.sort();
+            ids
+        };
+        let new = "1463941010.5f7fa6dd4922c183dc457d033deee9d7".to_string();
+        let cur = "1463868505.38518452d49213cb409aa1db32f53184".to_string();
+
+        assert_eq!(ids("ALL"), vec![cur.clone(), new.clone()]);
+        assert_eq!(ids("seen"), vec![cur.clone()]);
+        assert_eq!(ids("UNSEEN"), vec![new.clone()]);
+        assert_eq!(ids("NEW"), vec![new.clone()]);
+        assert_eq!(ids("OLD FLAGGED"), Vec::<String>::new());
+        assert_eq!(ids("OR SEEN RECENT"), vec![cur.clone(), new.clone()]);
+        assert_eq!(ids("SUBJECT test NOT (SEEN)"), vec![new.clone()]);
+        assert_eq!(ids("HEADER Subject \"te\""), vec![cur.clone(), new.clone()]);
+        assert_eq!(ids("HEADER X-Nonexistent \"\""), Vec::<String>::new());
+        assert_eq!(ids("SENTSINCE 1-Jan-2016 SENTBEFORE 1-Jan-2017").len(), 2);
+        assert_eq!(ids("SENTBEFORE 1-Jan-2016"), Vec::<String>::new());
+        assert_eq!(ids("LARGER 10 SMALLER 100000").len(), 2);
+        assert_eq!(ids("LARGER 100000"), Vec::<String>::new());
+    });
+
+    with_maildir_empty("maildir2", |maildir| {
+        maildir.create_dirs().unwrap();
+        let id = maildir.store_cur_with_flags(TEST_MAIL_BODY, "F").unwrap();
+        maildir.store_new(TEST_MAIL_BODY).unwrap();
+        let query = Query::And(vec![
+            Query::flagged(),
+            Query::header("From", "johannes"),
+            Query::Body("boomtime".to_string()),
+        ]);
+        let found: Vec<_> = maildir.search(&query).map(|e| e.unwrap()).collect();
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].id(), id);
+        let query = Query::parse("TEXT \"of82ecuq@cip\" BODY discord").unwrap();
+        assert_eq!(maildir.search(&query).count(), 2);
+
+        // a message without a Date header matches no SENT* criteria
+        maildir.store_new(b"Subject: undated\n\nhi\n").unwrap();
+        let query = Query::parse("OR SENTBEFORE 1-Jan-2100 SENTSINCE 1-Jan-1970").unwrap();
+        let found: Vec<_> = maildir.search(&query).map(|e| e.unwrap()).collect();
+        assert_eq!(found.len(), 2);
+    });
+
+    assert!(Query::parse("SEEN OR FLAGGED DRAFT")
+        .unwrap()
+        .is_flag_only());
+    assert!(!Query::parse("SEEN FROM x").unwrap().is_flag_only());
+    assert_eq!(Query::parse(""), Ok(Query::All));
+    assert_eq!(
+        Query::parse("BOGUS"),
+        Err(QueryParseError::UnknownKey("BOGUS".into()))
+    );
+    assert_eq!(
+        Query::parse("(SEEN"),
+        Err(QueryParseError::UnbalancedParenthesis)
+    );
+    assert_eq!(Query::parse("FROM"), Err(QueryParseError::UnexpectedEnd));
+    assert!(Query::parse("SINCE 31-Foo-2020").is_err());
+}
+
+#[cfg(feature = "index")]
+#[test]
+fn check_index() {
+    use maildir::index::{Index, IndexUpdate, INDEX_FILE_NAME};
+
+    with_maildir(MAILDIR_NAME, |maildir| {
+        maildir.create_dirs().unwrap();
+        let id = maildir.store_new(TEST_MAIL_BODY).unwrap();
+        {
+            let mut index = Index::open(&maildir).unwrap();
+            assert!(index.is_empty());
+            assert_eq!(
+                index.update().unwrap(),
+                IndexUpdate {
+                    added: 3,
+                    removed: 0
+                }
+            );
+            index.save().unwrap();
+        }
+        assert!(maildir.path().join(INDEX_FILE_NAME).exists());
+
+        let mut index = Index::open(&maildir).unwrap();
+        assert_eq!(index.len(), 3);
+        assert_eq!(index.search_ids("BOOMTIME discord"), vec![id.clone()]);
+        assert_eq!(index.search_ids("boom*"), vec![id.clone()]);
+        assert_eq!(
+            index.search_ids("boomtime nonexistent"),
+            Vec::<String>::new()
+        );
+        assert_eq!(index.search_ids("test").len(), 3);
+        assert_eq!(index.search_ids(""), Vec::<String>::new());
+        let found = index.search("Johannes Schilling").unwrap();
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].id(), id);
+
+        maildir.delete(&id).unwrap();
+        let id2 = maildir.store_cur_with_flags(TEST_MAIL_BODY, "S").unwrap();
+        assert_eq!(
+            index.update().unwrap(),
+            IndexUpdate {
+                added: 1,
+                removed: 1
+            }
+        );
+        assert_eq!(index.search_ids("boomtime"), vec![id2]);
+        assert_eq!(index.update().unwrap(), IndexUpdate::default());
+
+        // a word posted for a message the index doesn't know is corrupt
+        fs::write(
+            maildir.path().join(INDEX_FILE_NAME),
+            "maildir-index 1\n8\nD\t0\tsome.id\nW\tword\t0\t7\n",
+        )
+        .unwrap();
+        assert!(Index::open(&maildir).is_err());
+
+        // as is a message numbered at or past the next free number
+        fs::write(
+            maildir.path().join(INDEX_FILE_NAME),
+            "maildir-index 1\n1\nD\t0\tsome.id\nD\t1\tother.id\n",
+        )
+        .unwrap();
+        assert!(Index::open(&maildir).is_err());
+
+        // ids with separators in them survive a save
+        fs::remove_file(maildir.path().join(INDEX_FILE_NAME)).unwrap();
+        let odd = "1463868505.odd\tid\\";
+        fs::write(
+            maildir.path().join("cur").join(format!("{}:2,S", odd)),
+            TEST_MAIL_BODY,
+        )
+        .unwrap();
+        let mut index = Index::open(&maildir).unwrap();
+        index.update().unwrap();
+        index.save().unwrap();
+        let index = Index::open(&maildir).unwrap();
+        assert!(index.contains(odd));
+        assert_eq!(index.len(), 4);
+    });
+}
+
+#[test]
+fn check_threading() {
+    use maildir::thread::{thread_maildirs, ThreadNode, Threader};
+
+    let msg = |id: &str, refs: &str, subject: &str| {
+        format!(
+            "Message-ID: <{}>\r\n{}Subject: {}\r\n\r\nbody\r\n",
+            id, refs, subject
+        )
+    };
+    with_maildir_empty("maildir2", |maildir| {
+        maildir.create_dirs().unwrap();
+        maildir.create_subfolder_dirs(".Sent").unwrap();
+        let sent = maildir.subfolder(".Sent").unwrap();
+
+        let a = maildir
+            .store_new(msg("a@x", "", "Plans").as_bytes())
+            .unwrap();
+        let b = sent
+            .store_new(msg("b@x", "In-Reply-To: <a@x>\r\n", "Re: Plans").as_bytes())
+            .unwrap();
+        // references a message we don't have
+        let c = maildir
+            .store_cur_with_flags(
+                msg("c@x", "References: <a@x> <missing@x>\r\n", "Re: Plans").as_bytes(),
+                "S",
+            )
+            .unwrap();
+        let d = maildir
+            .store_new(msg("d@x", "", "Other").as_bytes())
+            .unwrap();
+        // same subject as "d" but no references
+        let e = maildir
+            .store_new(msg("e@x", "", "Re: Other").as_bytes())
+            .unwrap();
+
+        let mut threads = thread_maildirs(&[&maildir, &sent]).unwrap();
+        threads.sort_by_key(|t| t.ids().len());
+        assert_eq!(threads.len(), 2);
+
+        assert_eq!(threads[0].id, Some(d));
+        assert_eq!(threads[0].children.len(), 1);
+        assert_eq!(threads[0].children[0].id, Some(e));
+
+        let plans = &threads[1];
+        assert_eq!(plans.id, Some(a));
+        assert_eq!(plans.message_id.as_deref(), Some("a@x"));
+        let mut children: Vec<_> = plans.children.iter().map(|c| c.id.clone()).collect();
+        children.sort();
+        // the placeholder for the missing message is pruned
+        let mut expected = vec![Some(b), Some(c)];
+        expected.sort();
+        assert_eq!(children, expected);
+    });
+
+    // two unrelated non-replies with the same subject get a placeholder
+    let mut threader = Threader::new();
+    threader.add_message("1", Some("1@x"), &[], "Hello");
+    threader.add_message("2", Some("2@x"), &[], "hello");
+    threader.add_message("3", Some("3@x"), &[], "Hello");
+    let threads = threader.build();
+    assert_eq!(threads.len(), 1);
+    assert_eq!(threads[0].id, None);
+    assert_eq!(threads[0].ids(), vec!["1", "2", "3"]);
+    let threads: Vec<ThreadNode> = {
+        let mut threader = Threader::new();
+        threader.add_message("x", Some("x@x"), &["y@x".to_string()], "Re: loop");
+        threader.add_message("y", Some("y@x"), &["x@x".to_string()], "loop");
+        threader.build()
+    };
+    assert_eq!(threads.len(), 1);
+    assert_eq!(threads[0].ids().len(), 2);
+}
+
+#[test]
+fn check_best_date() {
+    with_maildir_empty("maildir2", |maildir| {
+        maildir.create_dirs().unwrap();
+        let no_date = b"Received: from a by b; Fri, 12 May 2017 10:09:45 +0000\r\n\
+Received: from c by a; Fri, 12 May 2017 10:08:00 +0000\r\n\
+Received: garbage\r\n\
+Subject: no date\r\n\r\nbody\r\n";
+        let id = maildir.store_new(no_date).unwrap();
+        let mut entry = maildir.find(&id).unwrap();
+        assert!(entry.date().is_err());
+        assert_eq!(
+            entry.best_date().unwrap(),
+            ResolvedDate {
+                timestamp: 1_494_583_785,
+                source: DateSource::NewestReceived
+            }
+        );
+        assert_eq!(
+            entry
+                .best_date_with(&[DateSource::OldestReceived])
+                .unwrap()
+                .timestamp,
+            1_494_583_680
+        );
+        let delivered = entry.delivery_time().unwrap();
+        assert_eq!(
+            entry.best_date_with(&[DateSource::Delivery]).unwrap(),
+            ResolvedDate {
+                timestamp: delivered,
+                source: DateSource::Delivery
+            }
+        );
+
+        let id = maildir.store_new(TEST_MAIL_BODY).unwrap();
+        let mut entry = maildir.find(&id).unwrap();
+        assert_eq!(entry.best_date().unwrap().source, DateSource::DateHeader);
+
+        // no headers and no timestamp in the file name
+        fs::write(maildir.path().join("cur").join("nodate:2,S"), b"\r\nbody").unwrap();
+        let mut entry = maildir.find("nodate").unwrap();
+        assert_eq!(entry.delivery_time(), None);
+        assert_eq!(entry.best_date().unwrap().source, DateSource::Mtime);
+        assert!(entry
+            .best_date_with(&[DateSource::DateHeader, DateSource::Delivery])
+            .is_err());
+    });
+}
+
+#[test]
+fn check_retention() {
+    use maildir::retention::{RetentionAction, RetentionPolicy, RetentionRule};
+    use maildir::search::Query;
+    use std::time::Duration;
+
+    const DAY: u64 = 24 * 60 * 60;
+
+    with_maildir_empty("maildir2", |maildir| {
+        maildir.create_dirs().unwrap();
+        for name in &[
+            "cur/1559347200.a:2,ST",
+            "cur/1640900000.b:2,T",
+            "cur/1583020800.c:2,S",
+            "cur/1610236800.d:2,S",
+            "new/1559347200.e",
+        ] {
+            fs::write(maildir.path().join(name), TEST_MAIL_BODY).unwrap();
+        }
+
+        let policy = RetentionPolicy::new()
+            .now(1_640_995_200)
+            .rule(
+                RetentionRule::new(RetentionAction::Delete)
+                    .matching(Query::trashed())
+                    .older_than(Duration::from_secs(30 * DAY)),
+            )
+            .rule(
+                RetentionRule::new(RetentionAction::MoveTo(".Archive.{year}".to_string()))
+                    .matching(Query::seen())
+                    .older_than(Duration::from_secs(365 * DAY)),
+            );
+
+        let report = maildir
+            .apply_retention(&policy.clone().dry_run(true))
+            .unwrap();
+        assert!(report.dry_run);
+        let applied: Vec<_> = report
+            .applied
+            .iter()
+            .map(|a| (a.id.as_str(), a.rule, a.action.clone()))
+            .collect();
+        assert_eq!(
+            applied,
+            vec![
+                ("1559347200.a", 0, RetentionAction::Delete),
+                (
+                    "1583020800.c",
+                    1,
+                    RetentionAction::MoveTo(".Archive.2020".to_string())
+                ),
+            ]
+        );
+        assert_eq!(report.applied[0].date.source, DateSource::Delivery);
+        assert_eq!(maildir.count_cur(), 4);
+        assert!(!maildir.path().join(".Archive.2020").exists());
+
+        let report = maildir.apply_retention(&policy).unwrap();
+        assert!(!report.dry_run);
+        assert_eq!(report.applied.len(), 2);
+        assert!(maildir.find("1559347200.a").is_none());
+        assert!(maildir.find("1583020800.c").is_none());
+        let archive = maildir.subfolder(".Archive.2020").unwrap();
+        assert_eq!(archive.count_cur(), 1);
+        assert!(archive.find("1583020800.c").unwrap().is_seen());
+
+        // only the most recently delivered message is kept
+        let policy = RetentionPolicy::new()
+            .rule(RetentionRule::new(RetentionAction::Delete).keep_at_most(1));
+        let report = maildir.apply_retention(&policy).unwrap();
+        assert_eq!(report.applied.len(), 2);
+        assert_eq!(maildir.count_new(), 0);
+        assert_eq!(maildir.count_cur(), 1);
+        assert!(maildir.find("1640900000.b").is_some());
+
+        // a message that can't be read fails the policy before anything
+        // is done
+        fs::create_dir(maildir.path().join("cur/1500000000.z:2,")).unwrap();
+        let policy = RetentionPolicy::new().rule(
+            RetentionRule::new(RetentionAction::Delete).matching(Query::header("Subject", "x")),
+        );
+        assert!(maildir.apply_retention(&policy).is_err());
+        assert_eq!(maildir.count_cur(), 2);
+    });
+}
+
+#[test]
+fn check_stats() {
+    with_maildir(MAILDIR_NAME, |maildir| {
+        let stats = maildir.stats().unwrap();
+        assert_eq!(stats.new, maildir.count_new());
+        assert_eq!(stats.cur, maildir.count_cur());
+        assert_eq!(stats.count(), stats.new + stats.cur);
+        let total: u64 = maildir
+            .list_new()
+            .chain(maildir.list_cur())
+            .map(|e| e.unwrap().size().unwrap())
+            .sum();
+        assert_eq!(stats.size, total);
+        let seen = maildir
+            .list_cur()
+            .filter(|e| e.as_ref().unwrap().is_seen())
+            .count();
+        assert_eq!(stats.flags.get(&'S').map_or(0, |f| f.count), seen);
+    });
+
+    with_maildir_empty("maildir2", |maildir| {
+        maildir.create_dirs().unwrap();
+        assert_eq!(maildir.stats().unwrap(), MaildirStats::default());
+        fs::write(maildir.path().join("new/1000.a,S=10"), b"").unwrap();
+        fs::write(maildir.path().join("cur/3000.b,S=5:2,FS"), b"").unwrap();
+        fs::write(maildir.path().join("cur/2000.c:2,S"), b"abc").unwrap();
+        let stats = maildir.stats().unwrap();
+        assert_eq!((stats.new, stats.cur, stats.size), (1, 2, 18));
+        assert_eq!(stats.flags[&'S'], FlagStats { count: 2, size: 8 });
+        assert_eq!(stats.flags[&'F'], FlagStats { count: 1, size: 5 });
+        assert_eq!(stats.flags.len(), 2);
+        assert_eq!(stats.oldest_delivery, Some(1000));
+        assert_eq!(stats.newest_delivery, Some(3000));
+
+        let archive = maildir.subfolder(".Archive").unwrap();
+        archive.create_dirs().unwrap();
+        fs::write(archive.path().join("cur/500.d,S=7:2,S"), b"").unwrap();
+        let recursive = maildir.stats_recursive().unwrap();
+        let mut expected = stats.clone();
+        expected.merge(&archive.stats().unwrap());
+        assert_eq!(recursive, expected);
+        assert_eq!((recursive.cur, recursive.size), (3, 25));
+        assert_eq!(recursive.flags[&'S'], FlagStats { count: 3, size: 15 });
+        assert_eq!(recursive.oldest_delivery, Some(500));
+        assert_eq!(recursive.newest_delivery, Some(3000));
+    });
+}
+
+#[test]
+fn check_summary() {
+    with_maildir_empty("maildir2", |maildir| {
+        maildir.create_dirs().unwrap();
+        let id = maildir.store_new(TEST_MAIL_BODY).unwrap();
+        let mut entry = maildir.find(&id).unwrap();
+        let summary = entry.summary(&["Subject", "X-Missing"]).unwrap();
+        assert_eq!(summary.id, id);
+        assert_eq!(summary.folder, "new");
+        assert_eq!(summary.flags, "");
+        assert_eq!(&summary.path, entry.path());
+        assert_eq!(summary.size, TEST_MAIL_BODY.len() as u64);
+        assert_eq!(summary.delivery_time, entry.delivery_time());
+        assert_eq!(summary.headers.len(), 1);
+        assert_eq!(summary.headers["Subject"], "maildir delivery test mail");
+        assert!(entry.summary(&[]).unwrap().headers.is_empty());
+    });
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn check_serde() {
+    with_maildir_empty("maildir2", |maildir| {
+        maildir.create_dirs().unwrap();
+        let id = maildir.store_cur_with_flags(TEST_MAIL_BODY, "S").unwrap();
+        let mut entry = maildir.find(&id).unwrap();
+
+        let json = serde_json::to_value(&entry).unwrap();
+        assert_eq!(json["id"], id.as_str());
+        assert_eq!(json["folder"], "cur");
+        assert_eq!(json["flags"], "S");
+        assert_eq!(json["size"], TEST_MAIL_BODY.len() as u64);
+        assert!(json.get("headers").is_none());
+
+        let summary = entry.summary(&["Subject"]).unwrap();
+        let json = serde_json::to_string(&summary).unwrap();
+        let back: MailSummary = serde_json::from_str(&json).unwrap();
+        assert_eq!(back, summary);
+
+        let stats = maildir.stats().unwrap();
+        let json = serde_json::to_string(&stats).unwrap();
+        assert_eq!(serde_json::from_str::<MaildirStats>(&json).unwrap(), stats);
+        let json = serde_json::to_value(&stats).unwrap();
+        assert_eq!(json["flags"]["S"]["count"], 1);
+    });
+}
+
+#[cfg(feature = "export")]
+#[test]
+fn check_export() {
+    use maildir::export::{ExportRecord, Exporter};
+
+    with_maildir_empty("maildir2", |maildir| {
+        maildir.create_dirs().unwrap();
+        let id = maildir.store_new(TEST_MAIL_BODY).unwrap();
+        let archive = maildir.subfolder(".Archive").unwrap();
+        archive.create_dirs().unwrap();
+        let multipart = b"From: a@example.com\r\n\
+Subject: report\r\n\
+Message-ID: <m1@example.com>\r\n\
+Content-Type: multipart/mixed; boundary=b\r\n\r\n\
+--b\r\nContent-Type: text/plain; charset=utf-8\r\n\r\nhello\r\n\
+--b\r\nContent-Type: application/pdf\r\n\
+Content-Disposition: attachment; filename=report.pdf\r\n\r\nPDF\r\n\
+--b--\r\n";
+        let archived = archive.store_cur_with_flags(multipart, "S").unwrap();
+
+        let mut exporter = Exporter::new(Vec::new());
+        assert_eq!(exporter.export(&maildir).unwrap(), 2);
+        let out = String::from_utf8(exporter.into_inner()).unwrap();
+        let records: Vec<ExportRecord> = out
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(records.len(), 2);
+
+        let record = &records[0];
+        assert_eq!((record.mailbox.as_str(), record.id.as_str()), ("", &*id));
+        assert_eq!(record.folder, "new");
+        assert_eq!(record.size, TEST_MAIL_BODY.len() as u64);
+        assert_eq!(
+            record.subject.as_deref(),
+            Some("maildir delivery test mail")
+        );
+        assert_eq!(record.mime.as_ref().unwrap().content_type, "text/plain");
+        assert!(record.error.is_none());
+
+        let record = &records[1];
+        assert_eq!(record.mailbox, ".Archive");
+        assert_eq!(record.id, archived);
+        assert_eq!(
+            (record.folder.as_str(), record.flags.as_str()),
+            ("cur", "S")
+        );
+        assert_eq!(record.from.as_deref(), Some("a@example.com"));
+        assert_eq!(record.message_id.as_deref(), Some("<m1@example.com>"));
+        assert_eq!(record.to, None);
+        let mime = record.mime.as_ref().unwrap();
+        assert_eq!(mime.content_type, "multipart/mixed");
+        assert_eq!(mime.parts.len(), 2);
+        assert_eq!(mime.parts[0].charset, "utf-8");
+        assert!(!mime.parts[0].attachment);
+        assert!(mime.parts[1].attachment);
+        assert_eq!(mime.parts[1].filename.as_deref(), Some("report.pdf"));
+
+        let mut exporter = Exporter::new(Vec::new()).recursive(false);
+        assert_eq!(exporter.export(&maildir).unwrap(), 1);
+    });
+}
+
+#[test]
+fn check_sync() {
+    use maildir::sync::{ConflictPolicy, SyncChanges, SyncReport, Synchronizer};
+
+    let tmp_dir = tempdir().unwrap();
+    let local = Maildir::from(tmp_dir.path().join("local"));
+    let remote = Maildir::from(tmp_dir.path().join("remote"));
+    let state = tmp_dir.path().join("sync-state");
+    local.create_dirs().unwrap();
+    remote.create_dirs().unwrap();
+
+    let a = local.store_new(TEST_MAIL_BODY).unwrap();
+    let b = remote.store_cur_with_flags(TEST_MAIL_BODY, "S").unwrap();
+    let remote_sub = remote.subfolder(".Sub").unwrap();
+    remote_sub.create_dirs().unwrap();
+    let c = remote_sub.store_new(TEST_MAIL_BODY).unwrap();
+
+    let synchronizer = Synchronizer::new(&local, &remote, &state);
+    let report = synchronizer.sync().unwrap();
+    assert_eq!(report.remote.copied, 1);
+    assert_eq!(report.local.copied, 2);
+    assert!(remote.find(&a).is_some());
+    assert!(local.find(&b).unwrap().is_seen());
+    assert!(local.subfolder(".Sub").unwrap().find(&c).is_some());
+    assert_eq!(synchronizer.sync().unwrap(), SyncReport::default());
+
+    // a deletion on one side and a flag change on the other
+    remote.delete(&a).unwrap();
+    local.add_flags(&b, "F").unwrap();
+    let report = synchronizer.sync().unwrap();
+    assert_eq!(
+        report.local,
+        SyncChanges {
+            deleted: 1,
+            ..SyncChanges::default()
+        }
+    );
+    assert_eq!(report.remote.flags_updated, 1);
+    assert!(local.find(&a).is_none());
+    assert_eq!(remote.find(&b).unwrap().flags(), "FS");
+
+    // a conflicting flag change
+    local.remove_flags(&b, "S").unwrap();
+    remote.add_flags(&b, "R").unwrap();
+    let report = synchronizer.sync().unwrap();
+    assert_eq!(report.conflicts, 1);
+    assert_eq!(local.find(&b).unwrap().flags(), "FRS");
+    assert_eq!(remote.find(&b).unwrap().flags(), "FRS");
+
+    local.set_flags(&b, "").unwrap();
+    remote.set_flags(&b, "T").unwrap();
+    let report = synchronizer
+        .conflict_policy(ConflictPolicy::PreferRemote)
+        .sync()
+        .unwrap();
+    assert_eq!(report.conflicts, 1);
+    assert_eq!(local.find(&b).unwrap().flags(), "T");
+
+    // the flags of a message in new are set by moving it to cur
+    remote_sub.move_new_to_cur_with_flags(&c, "S").unwrap();
+    let local_sub = local.subfolder(".Sub").unwrap();
+    Synchronizer::new(&local, &remote, &state).sync().unwrap();
+    assert_eq!(local_sub.count_new(), 0);
+    assert!(local_sub.find(&c).unwrap().is_seen());
+
+    // an unreadable cur aborts instead of deleting the other side
+    fs::rename(remote.path().join("cur"), tmp_dir.path().join("cur")).unwrap();
+    assert!(Synchronizer::new(&local, &remote, &state).sync().is_err());
+    assert!(local.find(&b).is_some());
+    fs::rename(tmp_dir.path().join("cur"), remote.path().join("cur")).unwrap();
+
+    // so does a missing remote, which is not recreated
+    let unmounted = Maildir::from(tmp_dir.path().join("unmounted"));
+    assert!(Synchronizer::new(&local, &unmounted, &state)
+        .sync()
+        .is_err());
+    assert!(!unmounted.path().exists());
+    assert!(local.find(&b).is_some());
+
+    fs::write(&state, "something else\n").unwrap();
+    assert!(Synchronizer::new(&local, &remote, &state).sync().is_err());
+}
+
+#[test]
+fn check_backup() {
+    use maildir::backup::{Backup, BackupReport};
+
+    let tmp_dir = tempdir().unwrap();
+    let source = Maildir::from(tmp_dir.path().join("source"));
+    let destination = Maildir::from(tmp_dir.path().join("backup"));
+    source.create_dirs().unwrap();
+    let a = source.store_new(TEST_MAIL_BODY).unwrap();
+    let b = source.store_cur_with_flags(TEST_MAIL_BODY, "S").unwrap();
+    let sub = source.subfolder(".Sub").unwrap();
+    sub.create_dirs().unwrap();
+    let c = sub.store_new(TEST_MAIL_BODY).unwrap();
+
+    let backup = Backup::new(&source, &destination);
+    let report = backup.run().unwrap();
+    assert_eq!(report.copied, 3);
+    assert_eq!(report.bytes_copied, 3 * TEST_MAIL_BODY.len() as u64);
+    assert!(destination.find(&a).is_some());
+    assert_eq!(destination.find(&b).unwrap().flags(), "S");
+    let backup_sub = destination.subfolder(".Sub").unwrap();
+    assert!(backup_sub.find(&c).is_some());
+    assert_eq!(
+        backup.run().unwrap(),
+        BackupReport {
+            unchanged: 3,
+            ..BackupReport::default()
+        }
+    );
+
+    // flag changes and moves to cur are renames, not copies
+    source.move_new_to_cur_with_flags(&a, "F").unwrap();
+    source.add_flags(&b, "R").unwrap();
+    sub.delete(&c).unwrap();
+    let report = backup.run().unwrap();
+    assert_eq!((report.copied, report.renamed, report.removed), (0, 2, 1));
+    assert_eq!(destination.count_new(), 0);
+    assert_eq!(destination.find(&a).unwrap().flags(), "F");
+    assert_eq!(destination.find(&b).unwrap().flags(), "RS");
+    assert!(backup_sub.find(&c).is_none());
+
+    // retained messages survive, and leftovers of an interrupted run are
+    // cleaned up
+    let d = source.store_new(TEST_MAIL_BODY).unwrap();
+    fs::write(destination.path().join("tmp/leftover.partial"), b"par").unwrap();
+    source.delete(&b).unwrap();
+    let report = Backup::new(&source, &destination)
+        .retain_deleted(true)
+        .run()
+        .unwrap();
+    assert_eq!((report.copied, report.retained), (1, 1));
+    assert!(destination.find(&b).is_some());
+    assert!(destination.find(&d).is_some());
+    assert_eq!(
+        fs::read_dir(destination.path().join("tmp"))
+            .unwrap()
+            .count(),
+        0
+    );
+
+    // a source that can't be read is an error, not an empty maildir
+    let missing = Maildir::from(tmp_dir.path().join("missing"));
+    assert!(Backup::new(&missing, &destination).run().is_err());
+    let partial = Maildir::from(tmp_dir.path().join("partial"));
+    fs::create_dir_all(partial.path().join("cur")).unwrap();
+    assert!(Backup::new(&partial, &destination).run().is_err());
+    assert_eq!(destination.count_new() + destination.count_cur(), 3);
+    assert!(destination.find(&b).is_some());
+}
+
+#[test]
+fn check_diff() {
+    use maildir::diff::{Diff, Difference};
+
+    let tmp_dir = tempdir().unwrap();
+    let left = Maildir::from(tmp_dir.path().join("left"));
+    let right = Maildir::from(tmp_dir.path().join("right"));
+    left.create_dirs().unwrap();
+    right.create_dirs().unwrap();
+
+    let same = left.store_cur_with_flags(TEST_MAIL_BODY, "S").unwrap();
+    left.copy_to(&same, &right).unwrap();
+    let flagged = left.store_cur_with_flags(TEST_MAIL_BODY, "S").unwrap();
+    left.copy_to(&flagged, &right).unwrap();
+    right.add_flags(&flagged, "F").unwrap();
+    let changed = left.store_new(b"Subject: one\r\n\r\nbody\r\n").unwrap();
+    fs::write(
+        right.path().join("new").join(&changed),
+        b"Subject: one\r\n\r\nbodY\r\n",
+    )
+    .unwrap();
+    let only_left = left.store_new(b"Subject: two\r\n\r\n").unwrap();
+    let renamed_left = left
+        .store_new(b"Message-ID: <x@example.com>\r\n\r\nbody\r\n")
+        .unwrap();
+    let renamed_right = right
+        .store_new(b"Message-ID:  <x@example.com>\r\n\r\nbody\r\n")
+        .unwrap();
+
+    let report = Diff::new(&left, &right).run().unwrap();
+    assert_eq!(report.matched, 3);
+    assert!(!report.is_identical());
+    let mut expected = vec![
+        Difference::OnlyLeft {
+            mailbox: String::new(),
+            id: only_left.clone(),
+        },
+        Difference::OnlyLeft {
+            mailbox: String::new(),
+            id: renamed_left.clone(),
+        },
+    ];
+    expected.sort_by_key(|d| format!("{:?}", d));
+    expected.push(Difference::OnlyRight {
+        mailbox: String::new(),
+        id: renamed_right.clone(),
+    });
+    let mut pairs = vec![
+        Difference::Flags {
+            mailbox: String::new(),
+            left_id: flagged.clone(),
+            right_id: flagged.clone(),
+            left_flags: "S".to_string(),
+            right_flags: "FS".to_string(),
+        },
+        Difference::Content {
+            mailbox: String::new(),
+            left_id: changed.clone(),
+            right_id: changed.clone(),
+        },
+    ];
+    pairs.sort_by_key(|d| match d {
+        Difference::Flags { left_id, .. } | Difference::Content { left_id, .. } => left_id.clone(),
+        _ => unreachable!(),
+    });
+    expected.extend(pairs);
+    assert_eq!(report.differences, expected);
+
+    // by Message-ID, the differing whitespace in the header value being
+    // ignored, but not the differing file content
+    let report = Diff::new(&left, &right).by_message_id(true).run().unwrap();
+    assert_eq!((report.matched, report.matched_by_message_id), (4, 1));
+    assert!(report.differences.contains(&Difference::Content {
+        mailbox: String::new(),
+        left_id: renamed_left,
+        right_id: renamed_right,
+    }));
+
+    let sub = right.subfolder(".Sub").unwrap();
+    sub.create_dirs().unwrap();
+    let id = sub.store_new(TEST_MAIL_BODY).unwrap();
+    let report = Diff::new(&left, &right)
+        .compare_content(false)
+        .run()
+        .unwrap();
+    assert!(report.differences.contains(&Difference::OnlyRight {
+        mailbox: ".Sub".to_string(),
+        id,
+    }));
+    assert!(!report
+        .differences
+        .iter()
+        .any(|d| matches!(d, Difference::Content { .. })));
+    assert!(Diff::new(&left, &left).run().unwrap().is_identical());
+}
+
+#[cfg(feature = "pop3")]
+#[test]
+fn check_pop3() {
+    use maildir::auth::StaticAuthenticator;
+    use maildir::pop3::Pop3Server;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::{TcpListener, TcpStream};
+
+    with_maildir_empty("maildir2", |maildir| {
+        maildir.create_dirs().unwrap();
+        let first = maildir
+            .store_new(b"Subject: one\n\nline 1\n.dotted\nline 3\n")
+            .unwrap();
+        let second = maildir.store_cur_with_flags(TEST_MAIL_BODY, "S").unwrap();
+        let mut ids = [first.clone(), second.clone()];
+        ids.sort();
+        let first_number = ids.iter().position(|id| *id == first).unwrap() + 1;
+        let second_number = 3 - first_number;
+
+        let mut users = StaticAuthenticator::new();
+        users.add_user("alice", "secret", maildir.path());
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        // the server thread is left running when the test ends
+        std::thread::spawn(move || Pop3Server::new(users).serve(listener));
+
+        let connect = || {
+            let stream = TcpStream::connect(address).unwrap();
+            stream.set_nodelay(true).unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut greeting = String::new();
+            reader.read_line(&mut greeting).unwrap();
+            assert!(greeting.starts_with("+OK"));
+            (stream, reader)
+        };
+        let send = |stream: &mut TcpStream, reader: &mut BufReader<TcpStream>, cmd: &str| {
+            write!(stream, "{}\r\n", cmd).unwrap();
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            line
+        };
+        let read_multiline = |reader: &mut BufReader<TcpStream>| {
+            let mut lines = Vec::new();
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                if line == ".\r\n" {
+                    return lines;
+                }
+                lines.push(line);
+            }
+        };
+
+        let (mut stream, mut reader) = connect();
+        let s = &mut stream;
+        let r = &mut reader;
+        assert!(send(s, r, "STAT").starts_with("-ERR"));
+        assert!(send(s, r, "USER alice").starts_with("+OK"));
+        assert!(send(s, r, "PASS wrong").starts_with("-ERR"));
+        assert!(send(s, r, "PASS secret").starts_with("-ERR"));
+        send(s, r, "USER alice");
+        assert!(send(s, r, "PASS secret").starts_with("+OK maildrop has 2 messages"));
+
+        let first_size: u64 = 41;
+        let second_size = maildir.find(&second).unwrap().sizes().unwrap().rfc822_size;
+        assert_eq!(
+            send(s, r, "STAT"),
+            format!("+OK 2 {}\r\n", first_size + second_size)
+        );
+        assert_eq!(
+            send(s, r, &format!("LIST {}", first_number)),
+            format!("+OK {} {}\r\n", first_number, first_size)
+        );
+        assert!(send(s, r, "UIDL").starts_with("+OK"));
+        let uidl = read_multiline(r);
+        assert!(uidl.contains(&format!("{} {}\r\n", first_number, first)));
+        assert_eq!(uidl.len(), 2);
+
+        assert!(send(s, r, &format!("RETR {}", first_number)).starts_with("+OK"));
+        assert_eq!(
+            read_multiline(r),
+            vec![
+                "Subject: one\r\n",
+                "\r\n",
+                "line 1\r\n",
+                "..dotted\r\n",
+                "line 3\r\n"
+            ]
+        );
+        assert!(send(s, r, &format!("TOP {} 1", first_number)).starts_with("+OK"));
+        assert_eq!(
+            read_multiline(r),
+            vec!["Subject: one\r\n", "\r\n", "line 1\r\n"]
+        );
+
+        assert!(send(s, r, &format!("DELE {}", second_number)).starts_with("+OK"));
+        assert!(send(s, r, &format!("RETR {}", second_number)).starts_with("-ERR"));
+        assert!(send(s, r, "STAT").starts_with("+OK 1 "));
+        assert!(send(s, r, "RSET").starts_with("+OK"));
+        assert!(send(s, r, "STAT").starts_with("+OK 2 "));
+        send(s, r, &format!("DELE {}", second_number));
+        assert!(send(s, r, "QUIT").starts_with("+OK"));
+
+        // the second session sees the deletion
+        let (mut stream, mut reader) = connect();
+        send(&mut stream, &mut reader, "USER alice");
+        assert!(send(&mut stream, &mut reader, "PASS secret").starts_with("+OK maildrop has 1 "));
+
+        // and has the maildrop to itself
+        let (mut other, mut other_reader) = connect();
+        send(&mut other, &mut other_reader, "USER alice");
+        assert_eq!(
+            send(&mut other, &mut other_reader, "PASS secret"),
+            "-ERR maildrop already locked\r\n"
+        );
+
+        // dropping the connection without QUIT
+        drop(stream);
+        drop(reader);
+        assert!(maildir.find(&first).is_some());
+        assert!(maildir.find(&second).is_none());
+    });
+}
+
+#[cfg(feature = "imap")]
+#[test]
+fn check_imap() {
+    use maildir::auth::StaticAuthenticator;
+    use maildir::imap::{ImapServer, UIDLIST_FILE_NAME};
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::{TcpListener, TcpStream};
+
+    with_maildir_empty("maildir2", |maildir| {
+        maildir.create_dirs().unwrap();
+        maildir.create_subfolder_dirs(".Archive").unwrap();
+        let first = maildir
+            .store_new(b"Subject: one\nFrom: Alice <alice@example.org>\n\nhello\n")
+            .unwrap();
+        let second = maildir.store_cur_with_flags(TEST_MAIL_BODY, "S").unwrap();
+        // UIDs are assigned in the order of the ids
+        let (first_number, second_number) = if first < second { (1, 2) } else { (2, 1) };
+
+        let mut users = StaticAuthenticator::new();
+        users.add_user("alice", "secret", maildir.path());
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = std::thread::spawn(move || {
+            let server = ImapServer::new(users);
+            for _ in 0..2 {
+                let (stream, _) = listener.accept().unwrap();
+                server.handle_stream(stream).unwrap();
+            }
+        });
+
+        let connect = || {
+            let stream = TcpStream::connect(address).unwrap();
+            stream.set_nodelay(true).unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut greeting = String::new();
+            reader.read_line(&mut greeting).unwrap();
+            assert!(greeting.starts_with("* OK"));
+            (stream, reader)
+        };
+        // sends a command and returns the lines of the response, up to and
+        // including the tagged one
+        let send = |stream: &mut TcpStream, reader: &mut BufReader<TcpStream>, cmd: &str| {
+            write!(stream, "a {}\r\n", cmd).unwrap();
+            let mut lines = Vec::new();
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                let done = line.starts_with("a ");
+                lines.push(line);
+                if done {
+                    return lines;
+                }
+            }
+        };
+        let tagged = |lines: &[String]| lines.last().unwrap().clone();
+
+        let (mut stream, mut reader) = connect();
+        let s = &mut stream;
+        let r = &mut reader;
+        assert!(tagged(&send(s, r, "SELECT INBOX")).starts_with("a BAD"));
+        assert!(tagged(&send(s, r, "LOGIN alice wrong")).starts_with("a NO"));
+        assert!(tagged(&send(s, r, "LOGIN alice \"secret\"")).starts_with("a OK"));
+
+        let list = send(s, r, "LIST \"\" *");
+        assert!(list.contains(&"* LIST () \".\" \"INBOX\"\r\n".to_string()));
+        assert!(list.contains(&"* LIST () \".\" \"Archive\"\r\n".to_string()));
+        assert_eq!(send(s, r, "LIST \"\" Arch%").len(), 2);
+        // patterns with many wildcards don't take exponential time
+        let list = send(s, r, "LIST \"\" *a*a*a*a*a*a*a*a*a*a*a*a*a*a*a*a*a*a*a*a*b");
+        assert_eq!(list.len(), 1);
+        assert_eq!(send(s, r, "LIST \"\" %r%h%e").len(), 2);
+
+        let select = send(s, r, "SELECT INBOX");
+        assert!(select.contains(&"* 2 EXISTS\r\n".to_string()));
+        assert!(select.contains(&"* 1 RECENT\r\n".to_string()));
+        assert!(tagged(&select).starts_with("a OK [READ-WRITE]"));
+        // selecting moves new messages to cur
+        assert_eq!(maildir.count_new(), 0);
+
+        let fetch = send(s, r, "UID FETCH 1:* (FLAGS)");
+        assert_eq!(fetch.len(), 3);
+        assert_eq!(
+            fetch[first_number - 1],
+            format!(
+                "* {} FETCH (UID {} FLAGS (\\Recent))\r\n",
+                first_number, first_number
+            )
+        );
+        let fetch = send(
+            s,
+            r,
+            &format!("FETCH {} BODY.PEEK[HEADER.FIELDS (SUBJECT)]", first_number),
+        );
+        assert_eq!(
+            fetch[..3],
+            [
+                format!(
+                    "* {} FETCH (BODY[HEADER.FIELDS (SUBJECT)] {{16}}\r\n",
+                    first_number
+                ),
+                "Subject: one\r\n".to_string(),
+                "\r\n".to_string(),
+            ]
+        );
+        let fetch = send(s, r, &format!("FETCH {} ENVELOPE", first_number));
+        assert!(fetch[0].contains("((\"Alice\" NIL \"alice\" \"example.org\"))"));
+
+        let search = send(s, r, "SEARCH UNSEEN");
+        assert_eq!(search[0], format!("* SEARCH {}\r\n", first_number));
+        let search = send(s, r, "UID SEARCH FROM alice");
+        assert_eq!(search[0], format!("* SEARCH {}\r\n", first_number));
+        let search = send(s, r, "SEARCH CHARSE\u{20ac} x");
+        assert!(search.last().unwrap().contains(" BAD "));
+
+        // fetching the body marks the message as seen
+        let fetch = send(s, r, &format!("FETCH {} BODY[TEXT]", first_number));
+        assert_eq!(fetch[1], "hello\r\n");
+        assert!(fetch[2].contains("FLAGS (\\Seen \\Recent)"));
+        assert_eq!(maildir.find(&first).unwrap().flags(), "S");
+
+        let store = send(s, r, &format!("STORE {} +FLAGS (\\Deleted)", second_number));
+        assert_eq!(
+            store[0],
+            format!("* {} FETCH (FLAGS (\\Deleted \\Seen))\r\n", second_number)
+        );
+        assert_eq!(maildir.find(&second).unwrap().flags(), "ST");
+        let expunge = send(s, r, "EXPUNGE");
+        assert_eq!(expunge[0], format!("* {} EXPUNGE\r\n", second_number));
+        assert!(tagged(&expunge).starts_with("a OK"));
+        assert!(maildir.find(&second).is_none());
+        assert!(send(s, r, "LOGOUT")[0].starts_with("* BYE"));
+
+        // the second session sees the same UIDs, and logs in with a literal
+        let (mut stream, mut reader) = connect();
+        let s = &mut stream;
+        let r = &mut reader;
+        let login = send(s, r, "LOGIN alice {6+}\r\nsecret");
+        assert!(tagged(&login).starts_with("a OK"));
+        assert!(tagged(&send(s, r, "EXAMINE INBOX")).starts_with("a OK [READ-ONLY]"));
+        let search = send(s, r, "UID SEARCH ALL");
+        assert_eq!(search[0], format!("* SEARCH {}\r\n", first_number));
+        assert!(tagged(&send(s, r, "STORE 1 +FLAGS (\\Flagged)")).starts_with("a NO"));
+        send(s, r, "LOGOUT");
+        server.join().unwrap();
+        assert!(maildir.path().join(UIDLIST_FILE_NAME).exists());
+    });
+}
+
+#[test]
+fn check_delivery_headers() {
+    use maildir::delivery::DeliveryHeaders;
+
+    let headers = DeliveryHeaders::new()
+        .return_path("<alice@example.org>")
+        .delivered_to("bob@example.org\r\nX-Injected: 1");
+    assert_eq!(
+        headers.prepend_to(b"Subject: hi\n\nhello\n"),
+        b"Return-Path: <alice@example.org>\nDelivered-To: bob@example.orgX-Injected: 1\nSubject: hi\n\nhello\n"
+    );
+    assert_eq!(
+        DeliveryHeaders::new()
+            .return_path("")
+            .prepend_to(b"Subject: hi\r\n\r\n"),
+        b"Return-Path: <>\r\nSubject: hi\r\n\r\n"
+    );
+}
+
+#[cfg(feature = "lmtp")]
+#[test]
+fn check_lmtp() {
+    use maildir::lmtp::LmtpServer;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::{TcpListener, TcpStream};
+
+    with_maildir_empty("maildir2", |maildir| {
+        maildir.create_dirs().unwrap();
+        let path = maildir.path().to_path_buf();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = std::thread::spawn(move || {
+            let server = LmtpServer::new(move |recipient: &str| match recipient {
+                "bob@example.org" => Some(Maildir::from(path.clone())),
+                "broken@example.org" => Some(Maildir::from(path.join("missing"))),
+                _ => None,
+            })
+            .hostname("mx.example.org")
+            .max_message_size(1000);
+            let (stream, _) = listener.accept().unwrap();
+            server.handle_stream(stream).unwrap();
+        });
+
+        let mut stream = TcpStream::connect(address).unwrap();
+        stream.set_nodelay(true).unwrap();
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let mut read_reply = || {
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            line
+        };
+        assert_eq!(read_reply(), "220 mx.example.org LMTP server ready\r\n");
+        let mut send = |cmd: &str| write!(stream, "{}\r\n", cmd).unwrap();
+
+        send("MAIL FROM:<alice@example.org>");
+        assert!(read_reply().starts_with("503 "));
+        send("LHLO client.example.org");
+        assert_eq!(read_reply(), "250-mx.example.org\r\n");
+        let mut last = read_reply();
+        while last.starts_with("250-") {
+            last = read_reply();
+        }
+        assert_eq!(last, "250 8BITMIME\r\n");
+
+        // the command prefix may end inside a multibyte character
+        send("MAIL FRO\u{20ac}:<a>");
+        assert!(read_reply().starts_with("501 "));
+        send("MAIL FROM:<alice@example.org> BODY=8BITMIME");
+        assert!(read_reply().starts_with("250 "));
+        send("RCPT TO:<bob@example.org>");
+        assert!(read_reply().starts_with("250 "));
+        send("RCPT TO:<nobody@example.org>");
+        assert!(read_reply().starts_with("550 5.1.1 "));
+        send("RCPT TO:<broken@example.org>");
+        assert!(read_reply().starts_with("250 "));
+        send("DATA");
+        assert!(read_reply().starts_with("354 "));
+        send("Subject: hi\r\n\r\nhello\r\n..dotted\r\n.");
+        // one reply for each accepted recipient, in order
+        let delivered = read_reply();
+        assert!(delivered.starts_with("250 2.0.0 <bob@example.org> delivered as "));
+        assert!(read_reply().starts_with("451 4.2.0 <broken@example.org> "));
+
+        let id = delivered.trim_end().rsplit(' ').next().unwrap();
+        let entry = maildir.find(id).unwrap();
+        assert_eq!(
+            fs::read(entry.path()).unwrap(),
+            &b"Return-Path: <alice@example.org>\nDelivered-To: bob@example.org\nSubject: hi\n\nhello\n.dotted\n"[..]
+        );
+
+        send("MAIL FROM:<>");
+        assert!(read_reply().starts_with("250 "));
+        send("RCPT TO:<bob@example.org>");
+        assert!(read_reply().starts_with("250 "));
+        send("DATA");
+        assert!(read_reply().starts_with("354 "));
+        send(&format!("Subject: big\r\n\r\n{}\r\n.", "x".repeat(2000)));
+        assert!(read_reply().starts_with("552 5.3.4 "));
+        send("QUIT");
+        assert!(read_reply().starts_with("221 "));
+        server.join().unwrap();
+        assert_eq!(maildir.count_new(), 1);
+    });
+}
+
+#[test]
+fn check_deliver_binary() {
+    use std::io::Write;
+    use std::process::{Command, Stdio};
+
+    let deliver = |args: &[&str], input: &[u8]| {
+        let mut child = Command::new(env!("CARGO_BIN_EXE_maildir-deliver"))
+            .args(args)
+            .stdin(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .unwrap();
+        // the child may exit before reading its input
+        let _ = child.stdin.take().unwrap().write_all(input);
+        child.wait().unwrap().code()
+    };
+
+    with_maildir_empty("maildir2", |maildir| {
+        let path = maildir.path().to_str().unwrap();
+        let args = [
+            "-f",
+            "alice@example.org",
+            "-d",
+            "bob@example.org",
+            "--received",
+            "-s",
+            "Lists",
+            path,
+        ];
+        assert_eq!(deliver(&args, b"Subject: hi\n\nhello\n"), Some(0));
+        let lists = maildir.subfolder(".Lists").unwrap();
+        let mut entry = lists.list_new().next().unwrap().unwrap();
+        let headers = entry.headers().unwrap();
+        assert_eq!(
+            headers.get_first_value("Return-Path").unwrap(),
+            "<alice@example.org>"
+        );
+        assert_eq!(
+            headers.get_first_value("Delivered-To").unwrap(),
+            "bob@example.org"
+        );
+        let received = headers.get_first_value("Received").unwrap();
+        assert!(received.starts_with("by "));
+        assert!(received.ends_with(" +0000"));
+        assert_eq!(headers.get_first_value("Subject").unwrap(), "hi");
+        assert_eq!(maildir.count_new(), 0);
+
+        // permanent and temporary failures
+        assert_eq!(deliver(&[path], b""), Some(65));
+        assert_eq!(deliver(&[], b"Subject: hi\n\n"), Some(64));
+        let blocked = maildir.path().join("blocked");
+        fs::write(&blocked, b"").unwrap();
+        assert_eq!(
+            deliver(&[blocked.to_str().unwrap()], b"Subject: hi\n\n"),
+            Some(75)
+        );
+    });
+}
+
+#[cfg(feature = "fsck")]
+#[test]
+fn check_cli() {
+    use std::process::Command;
+
+    let run = |args: &[&str]| {
+        let output = Command::new(env!("CARGO_BIN_EXE_maildir-tool"))
+            .args(args)
+            .output()
+            .unwrap();
+        (
+            output.status.code(),
+            String::from_utf8(output.stdout).unwrap(),
+        )
+    };
+
+    with_maildir_empty("maildir2", |maildir| {
+        let path = maildir.path().to_str().unwrap();
+        assert_eq!(run(&["mkdir", path]).0, Some(0));
+        assert_eq!(run(&["mkdir", path, "Archive"]).0, Some(0));
+        let id = maildir
+            .store_new(b"From: alice@example.org\nSubject: one\ttab\n\nhello\n")
+            .unwrap();
+
+        let (code, out) = run(&["ls", path]);
+        assert_eq!(code, Some(0));
+        assert_eq!(
+            out,
+            format!("\t{}\tnew\t\t48\t\talice@example.org\tone tab\n", id)
+        );
+        assert_eq!(
+            run(&["show", path, &id]).1,
+            "From: alice@example.org\nSubject: one\ttab\n\nhello\n"
+        );
+
+        // flagging a new message moves it to cur
+        assert_eq!(run(&["flag", "add", path, &id, "SF"]).0, Some(0));
+        assert_eq!(maildir.find(&id).unwrap().flags(), "FS");
+        assert_eq!(run(&["flag", "remove", path, &id, "F"]).0, Some(0));
+        assert_eq!(run(&["count", path]).1, "new\t0\ncur\t1\n");
+        assert!(run(&["stats", path]).1.contains("flag\tS\t1\t48\n"));
+
+        let archive = maildir.path().join(".Archive");
+        assert_eq!(
+            run(&["cp", path, &id, archive.to_str().unwrap()]).0,
+            Some(0)
+        );
+        let (code, out) = run(&["ls", "-r", path]);
+        assert_eq!(code, Some(0));
+        assert!(out.starts_with(&format!("\t{}\tcur\tS\t", id)));
+        assert!(out.contains(&format!("\n.Archive\t{}\tcur\tS\t", id)));
+        assert_eq!(run(&["rm", path, &id]).0, Some(0));
+        assert_eq!(run(&["rm", path, &id]).0, Some(1));
+        assert_eq!(run(&["frobnicate", path]).0, Some(2));
+
+        // a file renamed by hand, without the info suffix
+        let archived = maildir.subfolder(".Archive").unwrap();
+        let file = archived.find(&id).unwrap().path().clone();
+        fs::rename(&file, archive.join("cur").join("broken")).unwrap();
+        let (code, out) = run(&["fsck", "-r", path]);
+        assert_eq!(code, Some(1));
+        assert!(out.starts_with("found\tbad-name\t"));
+        assert_eq!(run(&["fsck", "-r", "--repair", path]).0, Some(0));
+        assert_eq!(run(&["fsck", "-r", path]), (Some(0), String::new()));
+        assert!(archived.find("broken").is_some());
+    });
+}
+
+#[cfg(feature = "fsck")]
+#[test]
+fn check_fsck() {
+    use maildir::fsck::{Fsck, Problem};
+    use std::time::Duration;
+
+    with_maildir_empty("maildir2", |maildir| {
+        maildir.create_dirs().unwrap();
+        let id = maildir.store_cur_with_flags(TEST_MAIL_BODY, "S").unwrap();
+        let cur = maildir.path().join("cur");
+        let new = maildir.path().join("new");
+        fs::write(cur.join("unsorted:2,SFS"), b"").unwrap();
+        fs::write(new.join("early:2,S"), b"").unwrap();
+        fs::copy(maildir.find(&id).unwrap().path(), new.join(&id)).unwrap();
+        fs::write(maildir.path().join("tmp").join("leftover"), b"").unwrap();
+
+        let report = Fsck::new(&maildir).run().unwrap();
+        assert_eq!(report.messages, 4);
+        assert!(!report.is_clean());
+        let problems: Vec<&Problem> = report.issues.iter().map(|i| &i.problem).collect();
+        assert_eq!(
+            problems,
+            vec![
+                &Problem::BadName {
+                    path: new.join("early:2,S"),
+                    fixed: cur.join("early:2,S"),
+                },
+                &Problem::BadName {
+                    path: cur.join("unsorted:2,SFS"),
+                    fixed: cur.join("unsorted:2,FS"),
+                },
+                &Problem::DuplicateId {
+                    id: id.clone(),
+                    paths: vec![new.join(&id), cur.join(format!("{}:2,S", id))],
+                },
+            ]
+        );
+
+        let report = Fsck::new(&maildir)
+            .repair(true)
+            .stale_after(Duration::from_secs(0))
+            .run()
+            .unwrap();
+        assert_eq!(report.issues.len(), 4);
+        assert!(matches!(
+            report.issues[3].problem,
+            Problem::StaleTemporaryFile(_)
+        ));
+        assert_eq!(report.issues.iter().filter(|i| !i.repaired).count(), 1);
+        assert!(cur.join("early:2,S").exists());
+        assert!(cur.join("unsorted:2,FS").exists());
+        assert!(!maildir.path().join("tmp").join("leftover").exists());
+    });
+}
+
+#[cfg(feature = "sieve")]
+#[test]
+fn check_sieve() {
+    use maildir::sieve::{Action, Envelope, Script, SieveFilter};
+
+    assert_eq!("fileinto \"Spam\";".parse::<Script>().unwrap_err().line, 1);
+    assert!("require \"foo\";".parse::<Script>().is_err());
+    assert!("if true { keep; } else discard;".parse::<Script>().is_err());
+
+    let script: Script = r#"
+        require ["fileinto", "imap4flags", "envelope", "body", "vacation"];
+        # spam goes to its own folder, seen
+        if anyof (header :contains "X-Spam" "yes", body :contains "viagra") {
+            fileinto :flags "\\Seen" "Spam";
+            stop;
+        }
+        if address :domain :is "From" "lists.example.org" {
+            addflag "\\Flagged";
+            fileinto "INBOX.Lists.rust";
+        } elsif allof (envelope :localpart :matches "from" "boss*", size :under 1K) {
+            vacation :days 3 :subject "Away" text:
+I'm away until Monday.
+.
+;
+        } elsif exists "X-Drop" {
+            discard;
+        }
+    "#
+    .parse()
+    .unwrap();
+    let envelope = Envelope::new("boss@example.org", "alice@example.org");
+    let message = b"From: Boss <boss@example.org>\nTo: alice@example.org\nMessage-ID: <1@example.org>\nSubject: status\n\nwhere are you?\n";
+    assert_eq!(
+        script.evaluate(message, &envelope)[0],
+        Action::Vacation(maildir::sieve::Vacation {
+            days: 3,
+            subject: Some("Away".to_string()),
+            from: None,
+            addresses: Vec::new(),
+            mime: false,
+            handle: None,
+            reason: "I'm away until Monday.\n".to_string(),
+        })
+    );
+    assert!(script
+        .evaluate(
+            b"X-Drop: 1\n\nbye\n",
+            &Envelope::new("", "alice@example.org")
+        )
+        .is_empty());
+
+    let tmp_dir = tempdir().unwrap();
+    let maildir = Maildir::from(tmp_dir.path().join("maildir"));
+    let outbox = Maildir::from(tmp_dir.path().join("outbox"));
+    maildir.create_dirs().unwrap();
+    let filter = SieveFilter::new(&script).outbox(&outbox).now(86400);
+
+    let report = filter
+        .deliver(&maildir, b"X-Spam: yes\n\nbuy now\n", &envelope)
+        .unwrap();
+    assert_eq!(report.stored[0].0, "Spam");
+    let spam = maildir.subfolder(".Spam").unwrap();
+    assert_eq!(spam.find(&report.stored[0].1).unwrap().flags(), "S");
+
+    let report = filter
+        .deliver(
+            &maildir,
+            b"From: someone@lists.example.org\n\nhi\n",
+            &envelope,
+        )
+        .unwrap();
+    let lists = maildir.subfolder(".Lists.rust").unwrap();
+    assert_eq!(lists.find(&report.stored[0].1).unwrap().flags(), "F");
+
+    let report = filter.deliver(&maildir, message, &envelope).unwrap();
+    assert_eq!(report.stored[0].0, "INBOX");
+    assert_eq!(maildir.count_new(), 1);
+    let response = outbox.find(&report.vacation.unwrap()).unwrap();
+    let response = fs::read_to_string(response.path()).unwrap();
+    assert!(response.starts_with("Return-Path: <>\n"));
+    assert!(response.contains("To: <boss@example.org>\n"));
+    assert!(response.contains("Subject: Away\n"));
+    assert!(response.contains("In-Reply-To: <1@example.org>\n"));
+    assert!(response.contains("Auto-Submitted: auto-replied (vacation)\n"));
+    assert!(response.ends_with("\n\nI'm away until Monday.\n"));
+
+    // only one response per sender within the days given
+    let report = filter.deliver(&maildir, message, &envelope).unwrap();
+    assert_eq!(report.vacation, None);
+    let later = SieveFilter::new(&script).outbox(&outbox).now(86400 * 5);
+    assert!(later
+        .deliver(&maildir, message, &envelope)
+        .unwrap()
+        .vacation
+        .is_some());
+    let bulk = b"From: boss@example.org\nTo: alice@example.org\nPrecedence: bulk\n\nnews\n";
+    let report = SieveFilter::new(&script)
+        .outbox(&outbox)
+        .now(86400 * 50)
+        .deliver(&maildir, bulk, &envelope)
+        .unwrap();
+    assert_eq!(report.vacation, None);
+
+    // a vacation response that can't be stored doesn't fail the delivery
+    fs::write(tmp_dir.path().join("file"), "").unwrap();
+    let broken = Maildir::from(tmp_dir.path().join("file").join("outbox"));
+    let report = SieveFilter::new(&script)
+        .outbox(&broken)
+        .now(86400 * 100)
+        .deliver(&maildir, message, &envelope)
+        .unwrap();
+    assert_eq!(report.stored[0].0, "INBOX");
+    assert!(report.vacation_error.is_some());
+}
+
+#[cfg(feature = "sieve")]
+#[test]
+fn check_sieve_parse_errors() {
+    use maildir::sieve::Script;
+
+    let error = |script: &str| script.parse::<Script>().unwrap_err();
+    assert_eq!(
+        error("keep;\nfrobnicate;").message,
+        "unknown command frobnicate"
+    );
+    assert_eq!(error("keep;\n\nkeep").line, 3);
+    assert_eq!(error("if true {\n  keep;\n").line, 2);
+    assert_eq!(
+        error("require \"vacation\";\nfileinto \"x\";").message,
+        "missing require \"fileinto\""
+    );
+    assert_eq!(
+        error("require \"fileinto\";\nfileinto :copy \"x\";").message,
+        "missing require \"copy\""
+    );
+    assert_eq!(
+        error("if header :is :is \"a\" \"b\" { keep; }").message,
+        "duplicate tag :is"
+    );
+    assert_eq!(
+        error("if header :comparator \"i;unknown\" \"a\" \"b\" { keep; }").message,
+        "unknown comparator i;unknown"
+    );
+    assert_eq!(error("if frob { keep; }").message, "unknown test frob");
+    assert_eq!(error("keep { stop; }").line, 1);
+    assert_eq!(error("else { keep; }").message, "else without if");
+    assert_eq!(error("if anyof () { keep; }").message, "expected a test");
+    assert_eq!(error("keep;\nif header \"a\" \"b\" { keep; }\n\"x").line, 3);
+}
+
+#[cfg(feature = "sieve")]
+#[test]
+fn check_sieve_delivery() {
+    use maildir::sieve::{Envelope, Script, SieveFilter};
+
+    let tmp_dir = tempdir().unwrap();
+    let maildir = Maildir::from(tmp_dir.path().join("maildir"));
+    maildir.create_dirs().unwrap();
+    let envelope = Envelope::new("bob@example.org", "alice@example.org");
+    let message = b"From: bob@example.org\nTo: alice@example.org\nSubject: hi\n\nhello\n";
+
+    // imap4flags: the internal variable, explicit flags and hasflag
+    let script: Script = r#"
+        require ["fileinto", "imap4flags", "copy"];
+        setflag ["\\Seen", "\\Draft"];
+        removeflag "\\Draft";
+        addflag "\\Answered";
+        if hasflag :is "\\Answered" {
+            fileinto :copy :flags "\\Flagged" "Flagged";
+        }
+    "#
+    .parse()
+    .unwrap();
+    let report = SieveFilter::new(&script)
+        .deliver(&maildir, message, &envelope)
+        .unwrap();
+    let stored: Vec<_> = report.stored.iter().map(|s| s.0.as_str()).collect();
+    assert_eq!(stored, vec!["Flagged", "INBOX"]);
+    let flagged = maildir.subfolder(".Flagged").unwrap();
+    assert_eq!(flagged.find(&report.stored[0].1).unwrap().flags(), "F");
+    assert_eq!(maildir.find(&report.stored[1].1).unwrap().flags(), "RS");
+
+    // a mailbox that can't be stored into falls back to the maildir itself,
+    // once, and with the flags of the action
+    fs::write(maildir.path().join(".Broken"), "").unwrap();
+    fs::write(maildir.path().join(".Blocked"), "").unwrap();
+    let script: Script = r#"
+        require ["fileinto", "imap4flags"];
+        fileinto :flags "\\Seen" "Broken";
+        fileinto "Blocked";
+    "#
+    .parse()
+    .unwrap();
+    let before = maildir.count_cur();
+    let report = SieveFilter::new(&script)
+        .deliver(&maildir, message, &envelope)
+        .unwrap();
+    assert_eq!(report.stored.len(), 1);
+    assert_eq!(report.stored[0].0, "INBOX");
+    assert_eq!(maildir.count_cur(), before + 1);
+    assert!(maildir.find(&report.stored[0].1).unwrap().is_seen());
+
+    // an explicit keep is not doubled by the fallback
+    let script: Script = "require \"fileinto\";\nkeep;\nfileinto \"Broken\";"
+        .parse()
+        .unwrap();
+    let report = SieveFilter::new(&script)
+        .deliver(&maildir, message, &envelope)
+        .unwrap();
+    assert_eq!(report.stored.len(), 1);
+}
+
+#[cfg(feature = "sieve")]
+#[test]
+fn check_sieve_vacation() {
+    use maildir::sieve::{Envelope, Script, SieveFilter, VACATION_FILE_NAME};
+
+    let script: Script = r#"
+        require "vacation";
+        vacation :days 2 :addresses "alice@work.example.org" "Away.";
+    "#
+    .parse()
+    .unwrap();
+    let tmp_dir = tempdir().unwrap();
+    let maildir = Maildir::from(tmp_dir.path().join("maildir"));
+    let outbox = Maildir::from(tmp_dir.path().join("outbox"));
+    maildir.create_dirs().unwrap();
+    let to_alice = Envelope::new("bob@example.org", "alice@example.org");
+    let respond = |now: i64, headers: &str, envelope: &Envelope| {
+        let message = format!("From: {}\n{}\nhello\n", envelope.from, headers);
+        SieveFilter::new(&script)
+            .outbox(&outbox)
+            .now(now)
+            .deliver(&maildir, message.as_bytes(), envelope)
+            .unwrap()
+            .vacation
+            .is_some()
+    };
+
+    // messages that must not get a response
+    let direct = "To: alice@example.org\n";
+    for sender in &[
+        "",
+        "MAILER-DAEMON@example.org",
+        "listserv@example.org",
+        "majordomo@example.org",
+        "owner-rust@example.org",
+        "rust-request@example.org",
+    ] {
+        let envelope = Envelope::new(sender, "alice@example.org");
+        assert!(!respond(0, direct, &envelope), "responded to {}", sender);
+    }
+    for headers in &[
+        "To: alice@example.org\nAuto-Submitted: auto-generated\n",
+        "To: alice@example.org\nList-Id: <rust.example.org>\n",
+        "To: alice@example.org\nPrecedence: junk\n",
+        "To: everyone@example.org\n",
+    ] {
+        assert!(
+            !respond(0, headers, &to_alice),
+            "responded to {:?}",
+            headers
+        );
+    }
+
+    // messages sent to one of the given addresses get one
+    let envelope = Envelope::new("carol@example.org", "alice@example.org");
+    assert!(respond(
+        0,
+        "Cc: Alice <alice@work.example.org>\n",
+        &envelope
+    ));
+    assert!(respond(
+        0,
+        "To: alice@example.org\nAuto-Submitted: no\n",
+        &to_alice
+    ));
+
+    // one response per sender until the days are over
+    assert!(!respond(86400, direct, &to_alice));
+    assert!(!respond(2 * 86400 - 1, direct, &to_alice));
+    assert!(respond(2 * 86400, direct, &to_alice));
+    assert!(!respond(2 * 86400 + 1, direct, &to_alice));
+
+    // the records use a handle that doesn't depend on the Rust release
+    let records = fs::read_to_string(outbox.path().join(VACATION_FILE_NAME)).unwrap();
+    assert!(
+        records.contains("\taed998f868359c19\tbob@example.org\n"),
+        "{}",
+        records
+    );
+
+    // concurrent deliveries keep each other's records
+    std::thread::scope(|scope| {
+        for i in 0..8 {
+            let respond = &respond;
+            scope.spawn(move || {
+                let sender = format!("sender{}@example.org", i);
+                assert!(respond(
+                    0,
+                    direct,
+                    &Envelope::new(&sender, "alice@example.org")
+                ));
+            });
+        }
+    });
+    let records = fs::read_to_string(outbox.path().join(VACATION_FILE_NAME)).unwrap();
+    for i in 0..8 {
+        assert!(records.contains(&format!("\tsender{}@example.org\n", i)));
+    }
+}
+
+#[cfg(feature = "rules")]
+#[test]
+fn check_rules() {
+    use maildir::rules::{Rule, RuleAction, RuleSet};
+
+    let error = "rule a\n    move .A\n    delete\n"
+        .parse::<RuleSet>()
+        .unwrap_err();
+    assert_eq!(error.line, 3);
+    assert_eq!(RuleSet::parse("rule a\nlarger 1X\n").unwrap_err().line, 2);
+    assert_eq!(RuleSet::parse("rule a\nlarger 1K\n").unwrap_err().line, 2);
+
+    with_maildir_empty("maildir2", |maildir| {
+        maildir.create_dirs().unwrap();
+        for name in &[
+            "cur/1559347200.a:2,S",
+            "cur/1640900000.b:2,",
+            "cur/1583020800.c:2,T",
+            "new/1640000000.d",
+        ] {
+            fs::write(maildir.path().join(name), TEST_MAIL_BODY).unwrap();
+        }
+        let rules: RuleSet = "
+            # old mail from the list
+            rule archive
+                header Subject (?i)^maildir delivery TEST
+                older-than 180d
+                unflagged T
+                add-flags F
+                copy .Backup
+                move .Archive
+
+            rule trash
+                flagged T
+                delete
+
+            rule recent
+                newer-than 30d
+                smaller 10K
+                add-flags S
+        "
+        .parse::<RuleSet>()
+        .unwrap()
+        .now(1_640_995_200);
+
+        let report = maildir.apply_rules(&rules.clone().dry_run(true)).unwrap();
+        assert!(report.dry_run);
+        let applied: Vec<_> = report
+            .applied
+            .iter()
+            .map(|a| (a.id.as_str(), a.rule))
+            .collect();
+        assert_eq!(
+            applied,
+            vec![
+                ("1559347200.a", 0),
+                ("1583020800.c", 1),
+                ("1640000000.d", 2),
+                ("1640900000.b", 2),
+            ]
+        );
+        assert_eq!(report.applied[1].actions, vec![RuleAction::Delete]);
+        assert_eq!(maildir.count_cur(), 3);
+
+        let report = maildir.apply_rules(&rules).unwrap();
+        assert!(!report.dry_run);
+        assert_eq!(maildir.count_new(), 0);
+        assert_eq!(maildir.count_cur(), 2);
+        assert_eq!(maildir.find("1640000000.d").unwrap().flags(), "S");
+        assert_eq!(maildir.find("1640900000.b").unwrap().flags(), "S");
+        let archive = maildir.subfolder(".Archive").unwrap();
+        assert_eq!(archive.find("1559347200.a").unwrap().flags(), "FS");
+        let backup = maildir.subfolder(".Backup").unwrap();
+        assert_eq!(backup.find("1559347200.a").unwrap().flags(), "FS");
+
+        // actions after a delete are refused before anything is deleted
+        let rules = RuleSet::new().rule(
+            Rule::new("bad")
+                .then(RuleAction::Delete)
+                .then(RuleAction::AddFlags("F".to_string())),
+        );
+        assert!(maildir.apply_rules(&rules).is_err());
+        assert_eq!(maildir.count_cur(), 2);
+
+        // a failing action reports what was done until then
+        fs::write(maildir.path().join(".Blocked"), "").unwrap();
+        let rules = RuleSet::new().rule(
+            Rule::new("blocked")
+                .then(RuleAction::AddFlags("F".to_string()))
+                .then(RuleAction::Copy(".Blocked".to_string())),
+        );
+        let error = maildir.apply_rules(&rules).unwrap_err();
+        assert_eq!(error.report.applied.len(), 1);
+        assert_eq!(error.report.applied[0].id, "1640000000.d");
+        assert_eq!(
+            error.report.applied[0].actions,
+            vec![RuleAction::AddFlags("F".to_string())]
+        );
+        assert_eq!(maildir.find("1640000000.d").unwrap().flags(), "FS");
+    });
+}
+
+#[cfg(feature = "compression")]
+#[test]
+fn check_compression() {
+    use maildir::compression::{is_compressed, Compression};
+    use maildir::MaildirBuilder;
+
+    let tmp_dir = tempdir().unwrap();
+    let maildir = MaildirBuilder::new(tmp_dir.path().join("maildir"))
+        .compression(Compression::Gzip)
+        .build();
+    maildir.create_dirs().unwrap();
+
+    let id = maildir.store_new(TEST_MAIL_BODY).unwrap();
+    assert!(id.contains(&format!(",S={},", TEST_MAIL_BODY.len())));
+    let mut entry = maildir.find(&id).unwrap();
+    let raw = fs::read(entry.path()).unwrap();
+    assert!(is_compressed(&raw));
+    assert!(raw.len() < TEST_MAIL_BODY.len());
+    assert_eq!(entry.size().unwrap(), TEST_MAIL_BODY.len() as u64);
+    assert_eq!(entry.data().unwrap(), TEST_MAIL_BODY);
+    assert_eq!(
+        entry.headers().unwrap().get_first_value("Subject"),
+        Some("maildir delivery test mail".to_string())
+    );
+    assert_eq!(
+        entry.parsed().unwrap().get_body_raw().unwrap(),
+        b"Today is Boomtime, the 59th day of Discord in the YOLD 3183"
+    );
+
+    // the flag marking compressed files is added in cur and kept there
+    maildir.move_new_to_cur_with_flags(&id, "S").unwrap();
+    assert_eq!(maildir.find(&id).unwrap().flags(), "SZ");
+    maildir.set_flags(&id, "F").unwrap();
+    assert_eq!(maildir.find(&id).unwrap().flags(), "FZ");
+    maildir.remove_flags(&id, "FZ").unwrap();
+    assert_eq!(maildir.find(&id).unwrap().flags(), "Z");
+    let id = maildir.store_cur_with_flags(TEST_MAIL_BODY, "R").unwrap();
+    assert_eq!(maildir.find(&id).unwrap().flags(), "RZ");
+
+    // uncompressed messages in the same maildir still read fine
+    let plain = Maildir::from(maildir.path().to_path_buf());
+    let id = plain.store_new(TEST_MAIL_BODY).unwrap();
+    assert_eq!(maildir.find(&id).unwrap().data().unwrap(), TEST_MAIL_BODY);
+    plain.move_new_to_cur_with_flags(&id, "S").unwrap();
+    assert_eq!(maildir.find(&id).unwrap().flags(), "S");
+
+    #[cfg(feature = "zstd")]
+    {
+        let zstd = MaildirBuilder::new(maildir.path())
+            .compression(Compression::Zstd)
+            .build();
+        let id = zstd.store_cur_with_flags(TEST_MAIL_BODY, "").unwrap();
+        let mut entry = zstd.find(&id).unwrap();
+        assert!(is_compressed(&fs::read(entry.path()).unwrap()));
+        assert_eq!(entry.data().unwrap(), TEST_MAIL_BODY);
+    }
+
+    // only the Z flag marks a compressed file in cur, and a file in new
+    // that merely starts like one is read as it is
+    let lookalike = [&[0x1f, 0x8b][..], b"Subject: gzip?\n\n"].concat();
+    let cur_path = maildir.path().join("cur").join("1463868506.gz.example:2,S");
+    let new_path = maildir.path().join("new").join("1463868507.gz.example");
+    fs::write(&cur_path, &lookalike).unwrap();
+    fs::write(&new_path, &lookalike).unwrap();
+    assert_eq!(
+        maildir
+            .find("1463868506.gz.example")
+            .unwrap()
+            .data()
+            .unwrap(),
+        &lookalike[..]
+    );
+    assert_eq!(
+        maildir
+            .find("1463868507.gz.example")
+            .unwrap()
+            .data()
+            .unwrap(),
+        &lookalike[..]
+    );
+    maildir
+        .move_new_to_cur_with_flags("1463868507.gz.example", "")
+        .unwrap();
+    assert_eq!(maildir.find("1463868507.gz.example").unwrap().flags(), "");
+    fs::remove_file(&cur_path).unwrap();
+    maildir.delete("1463868507.gz.example").unwrap();
+
+    // compressed messages keep their size attributes
+    let unsized_maildir = MaildirBuilder::new(maildir.path())
+        .compression(Compression::Gzip)
+        .size_attributes(false)
+        .build();
+    let id = unsized_maildir.store_new(TEST_MAIL_BODY).unwrap();
+    let entry = unsized_maildir.find(&id).unwrap();
+    assert_eq!(entry.size().unwrap(), TEST_MAIL_BODY.len() as u64);
+
+    // a compressed copy is matched to the original by its Message-ID
+    let original = Maildir::from(tmp_dir.path().join("original"));
+    let copy = MaildirBuilder::new(tmp_dir.path().join("copy"))
+        .compression(Compression::Gzip)
+        .build();
+    original.create_dirs().unwrap();
+    copy.create_dirs().unwrap();
+    let message = b"Message-ID: <z@example.org>\nSubject: zipped\n\nhi\n";
+    original.store_new(message).unwrap();
+    copy.store_new(message).unwrap();
+    let report = maildir::diff::Diff::new(&original, &copy)
+        .by_message_id(true)
+        .compare_content(false)
+        .run()
+        .unwrap();
+    assert_eq!(report.matched_by_message_id, 1);
+
+    // and compares as identical, its Z flag left aside
+    let seen = original.store_cur_with_flags(TEST_MAIL_BODY, "S").unwrap();
+    original.copy_to(&seen, &copy).unwrap();
+    assert_eq!(copy.find(&seen).unwrap().flags(), "S");
+    let zipped = copy.store_cur_with_flags(TEST_MAIL_BODY, "S").unwrap();
+    assert_eq!(copy.find(&zipped).unwrap().flags(), "SZ");
+    fs::copy(
+        copy.find(&zipped).unwrap().path(),
+        copy.path().join("cur").join(format!("{}:2,SZ", seen)),
+    )
+    .unwrap();
+    copy.delete(&zipped).unwrap();
+    fs::remove_file(copy.path().join("cur").join(format!("{}:2,S", seen))).unwrap();
+    let report = maildir::diff::Diff::new(&original, &copy)
+        .by_message_id(true)
+        .run()
+        .unwrap();
+    assert!(report.is_identical(), "{:?}", report.differences);
+    assert_eq!(report.matched, 2);
+}